@@ -0,0 +1,175 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::Path;
+use std::rc::Rc;
+use crate::dbformat::{parse_internal_key, InternalKeyComparator};
+use crate::env::{new_random_access_file, new_writable_file};
+use crate::filename::table_file_name;
+use crate::iterator::InternalIterator;
+use crate::options::{Options, ReadOptions};
+use crate::range_del::RangeTombstone;
+use crate::Result;
+use crate::table::Table;
+use crate::table::table_builder::TableBuilder;
+use crate::version_edit::FileMetaData;
+
+/// Build a Table file from the contents of `iter` and the range
+/// tombstones `range_tombstones`. The generated file will be named
+/// according to meta.number. On success, the rest of `meta` will be
+/// filled with metadata about the generated table. If no data is present
+/// in `iter` and there is no tombstone, meta.file_size will be set to
+/// zero, and no Table file will be produced.
+pub fn build_table(dbname: &Path, options: &Options, iter: &mut dyn InternalIterator, range_tombstones: &[RangeTombstone], meta: &mut FileMetaData) -> Result<()> {
+    meta.file_size = 0;
+    iter.seek_to_first();
+
+    let fname = table_file_name(dbname, meta.number);
+    let mut result = Ok(());
+    if iter.valid() || !range_tombstones.is_empty() {
+        result = write_table(&fname, options, iter, range_tombstones, meta);
+    }
+
+    // Check for input iterator errors
+    if result.is_ok() {
+        result = iter.status();
+    }
+    if result.is_err() || meta.file_size == 0 {
+        let _ = std::fs::remove_file(&fname);
+    }
+    result
+}
+
+fn write_table(fname: &Path, options: &Options, iter: &mut dyn InternalIterator, range_tombstones: &[RangeTombstone], meta: &mut FileMetaData) -> Result<()> {
+    let file = new_writable_file(fname, options.allow_mmap_writes)?;
+    let mut builder = TableBuilder::new(options, file.clone());
+    meta.smallest.clear();
+    meta.largest.clear();
+    meta.smallest_seqno = u64::MAX;
+    meta.largest_seqno = 0;
+    while iter.valid() {
+        let key = iter.key();
+        if meta.smallest.is_empty() {
+            meta.smallest.extend_from_slice(key.data());
+        }
+        let sequence = parse_internal_key(&key)?.sequence;
+        meta.smallest_seqno = meta.smallest_seqno.min(sequence);
+        meta.largest_seqno = meta.largest_seqno.max(sequence);
+        if let Err(e) = builder.add(key.data(), iter.value().data()) {
+            builder.abandon();
+            return Err(e);
+        }
+        meta.largest.clear();
+        meta.largest.extend_from_slice(key.data());
+        iter.next();
+    }
+    let icmp = InternalKeyComparator::new(options.comparator);
+    for tombstone in range_tombstones {
+        builder.add_range_tombstone(tombstone);
+        meta.add_range_tombstone(&icmp, tombstone);
+    }
+
+    // Finish and check for file errors
+    builder.finish()?;
+    let file_size = builder.file_size();
+    file.borrow().sync()?;
+    file.borrow().close()?;
+
+    // Verify that the table is usable
+    let table = Rc::new(Table::open(options, new_random_access_file(fname)?, meta.number, file_size)?);
+    let mut it = table.iter(&ReadOptions { fill_cache: false, ..ReadOptions::default() });
+    it.seek_to_first();
+    it.status()?;
+    meta.file_size = file_size;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::dbformat::{append_internal_key, InternalKeyComparator, ValueType};
+    use crate::memtable::MemTable;
+    use crate::slice::Slice;
+    use super::*;
+
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("revel_builder_test_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_build_table() {
+        let dbname = db_path("build_table");
+        let options = Options::default();
+        let mem = MemTable::new(InternalKeyComparator::new(options.comparator));
+        mem.add(7, ValueType::KTypeValue, &Slice::from_str("b"), &Slice::from_str("v2"));
+        mem.add(5, ValueType::KTypeValue, &Slice::from_str("a"), &Slice::from_str("v1"));
+        mem.add(9, ValueType::KTypeDeletion, &Slice::from_str("c"), &Slice::from_str(""));
+
+        let mut meta = FileMetaData { number: 12, ..FileMetaData::default() };
+        build_table(&dbname, &options, &mut mem.iter(), &[], &mut meta).unwrap();
+        let fname = table_file_name(&dbname, 12);
+        assert_eq!(std::fs::metadata(&fname).unwrap().len(), meta.file_size);
+        let mut smallest = Vec::new();
+        append_internal_key(&mut smallest, &Slice::from_str("a"), 5, ValueType::KTypeValue);
+        let mut largest = Vec::new();
+        append_internal_key(&mut largest, &Slice::from_str("c"), 9, ValueType::KTypeDeletion);
+        assert_eq!(smallest, meta.smallest);
+        assert_eq!(largest, meta.largest);
+        assert_eq!((5, 9), (meta.smallest_seqno, meta.largest_seqno));
+
+        let table = Rc::new(Table::open(&options, new_random_access_file(&fname).unwrap(), meta.number, meta.file_size).unwrap());
+        assert_eq!(3, table.properties().num_entries);
+        assert_eq!(1, table.properties().num_deletions);
+        let mut iter = table.iter(&ReadOptions::default());
+        iter.seek_to_first();
+        assert_eq!(smallest, iter.key().data());
+        assert_eq!(b"v1", iter.value().data());
+
+        // An empty memtable produces no file
+        let mem = MemTable::new(InternalKeyComparator::new(options.comparator));
+        let mut meta = FileMetaData { number: 13, ..FileMetaData::default() };
+        build_table(&dbname, &options, &mut mem.iter(), &[], &mut meta).unwrap();
+        assert_eq!(0, meta.file_size);
+        assert!(!table_file_name(&dbname, 13).exists());
+    }
+
+    #[test]
+    fn test_build_table_with_range_tombstones() {
+        let dbname = db_path("build_table_with_range_tombstones");
+        let options = Options::default();
+        let mem = MemTable::new(InternalKeyComparator::new(options.comparator));
+        mem.add(5, ValueType::KTypeValue, &Slice::from_str("c"), &Slice::from_str("v"));
+        let tombstones = [RangeTombstone::new(b"d", b"f", 7), RangeTombstone::new(b"a", b"b", 3)];
+
+        let mut meta = FileMetaData { number: 12, ..FileMetaData::default() };
+        build_table(&dbname, &options, &mut mem.iter(), &tombstones, &mut meta).unwrap();
+        assert_eq!(tombstones[1].internal_key(), meta.smallest);
+        assert_eq!(tombstones[0].end_internal_key(), meta.largest);
+        assert_eq!((3, 7), (meta.smallest_seqno, meta.largest_seqno));
+        let fname = table_file_name(&dbname, 12);
+        let table = Table::open(&options, new_random_access_file(&fname).unwrap(), meta.number, meta.file_size).unwrap();
+        assert_eq!(1, table.properties().num_entries);
+        assert_eq!(2, table.properties().num_range_deletions);
+        assert_eq!(&[tombstones[1].clone(), tombstones[0].clone()], table.range_tombstones());
+
+        // Tombstones alone produce a file
+        let mem = MemTable::new(InternalKeyComparator::new(options.comparator));
+        let mut meta = FileMetaData { number: 13, ..FileMetaData::default() };
+        build_table(&dbname, &options, &mut mem.iter(), &tombstones[..1], &mut meta).unwrap();
+        assert!(meta.file_size > 0);
+        assert_eq!(tombstones[0].internal_key(), meta.smallest);
+        assert_eq!(tombstones[0].end_internal_key(), meta.largest);
+    }
+}
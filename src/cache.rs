@@ -0,0 +1,770 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A Cache is an interface that maps keys to values. It has internal
+//! synchronization and may be safely accessed concurrently from
+//! multiple threads. It may automatically evict entries to make room
+//! for new entries. Values have a specified charge against the cache
+//! capacity. For example, a cache where the values are variable
+//! length strings, may use the length of the string as the charge for
+//! the string.
+//!
+//! The cache is split into shards, each with its own lock and LRU list,
+//! so that concurrent readers only contend when they hash to the same
+//! shard.
+//!
+//! A ShardedLRUCache may be backed by a SecondaryCache, which receives the
+//! entries evicted for lack of room and is consulted on a miss, before the
+//! caller goes to disk.
+
+pub mod compressed_secondary_cache;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::Error::MemoryLimit;
+use crate::Result;
+use crate::util::hash::hash;
+
+const K_NUM_SHARD_BITS: usize = 4;
+const K_NUM_SHARDS: usize = 1 << K_NUM_SHARD_BITS;
+
+/// Priority of a cache entry. When the cache is configured with a high
+/// priority pool, high priority entries are only evicted once no low
+/// priority entry is left to evict, or when they overflow the pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePriority {
+    High,
+    Low
+}
+
+/// Options of a ShardedLRUCache.
+#[derive(Clone, Copy, Debug)]
+pub struct LRUCacheOptions {
+
+    /// Combined charge of the entries the cache holds.
+    pub capacity: usize,
+
+    /// If true, an insert that cannot make room by evicting unused entries
+    /// fails with MemoryLimit. Otherwise the cache grows past its capacity
+    /// while the entries in use are held.
+    pub strict_capacity_limit: bool,
+
+    /// Fraction of the capacity reserved for high priority entries, such as
+    /// index and filter blocks, so that a scan loading many data blocks
+    /// cannot push them out. 0 puts every entry in the same LRU list.
+    pub high_pri_pool_ratio: f64
+}
+
+impl LRUCacheOptions {
+    pub fn new(capacity: usize) -> Self {
+        LRUCacheOptions {
+            capacity,
+            strict_capacity_limit: false,
+            high_pri_pool_ratio: 0.0
+        }
+    }
+}
+
+/// A second cache level behind a ShardedLRUCache. It is handed the entries
+/// the primary cache evicts to make room, and looked up when the primary
+/// cache misses; entries found there are promoted back into the primary
+/// cache. Implementations are expected to hold more entries than the same
+/// memory would in the primary cache, for example by compressing them.
+pub trait SecondaryCache<V>: Send + Sync {
+
+    /// Offer an entry evicted from the primary cache, along with the charge
+    /// and priority it had there. The cache may decline to keep it.
+    fn insert(&self, key: &[u8], value: &V, charge: usize, priority: CachePriority);
+
+    /// Returns a copy of the entry of `key` with its primary cache charge
+    /// and priority, or None.
+    fn lookup(&self, key: &[u8]) -> Option<(V, usize, CachePriority)>;
+
+    /// Drop the entry of `key`, if any.
+    fn erase(&self, key: &[u8]);
+}
+
+/// An entry is a variable length heap-allocated structure. Entries are
+/// kept in a hash table keyed by the cache key, and ordered by their last
+/// use in the LRU list of their pool.
+struct LRUHandle<V> {
+
+    value: Arc<V>,
+
+    charge: usize,
+
+    priority: CachePriority,
+
+    // Position in the LRU list; larger is more recently used
+    tick: u64,
+
+    in_high_pri_pool: bool
+}
+
+impl<V> LRUHandle<V> {
+
+    /// True while a client still holds the value returned by insert or
+    /// lookup. Such entries are never evicted.
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.value) > 1
+    }
+}
+
+/// A single shard of sharded cache.
+struct LRUCache<V> {
+
+    // Initialized before use.
+    capacity: usize,
+
+    strict_capacity_limit: bool,
+
+    high_pri_pool_ratio: f64,
+
+    high_pri_pool_capacity: usize,
+
+    usage: usize,
+
+    high_pri_pool_usage: usize,
+
+    tick: u64,
+
+    table: HashMap<Vec<u8>, LRUHandle<V>>,
+
+    // Keys of the low priority pool, from least to most recently used
+    lru: BTreeMap<u64, Vec<u8>>,
+
+    // Keys of the high priority pool, from least to most recently used
+    lru_high_pri: BTreeMap<u64, Vec<u8>>,
+
+    // If true, entries evicted to make room are kept in `evicted` until
+    // the owner hands them to its secondary cache
+    keep_evicted: bool,
+
+    evicted: Vec<(Vec<u8>, LRUHandle<V>)>
+}
+
+impl<V> LRUCache<V> {
+
+    fn new(capacity: usize, strict_capacity_limit: bool, high_pri_pool_ratio: f64) -> Self {
+        assert!((0.0..=1.0).contains(&high_pri_pool_ratio));
+        LRUCache {
+            capacity,
+            strict_capacity_limit,
+            high_pri_pool_ratio,
+            high_pri_pool_capacity: (capacity as f64 * high_pri_pool_ratio) as usize,
+            usage: 0,
+            high_pri_pool_usage: 0,
+            tick: 0,
+            table: HashMap::new(),
+            lru: BTreeMap::new(),
+            lru_high_pri: BTreeMap::new(),
+            keep_evicted: false,
+            evicted: Vec::new()
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    /// Unlink the entry of `key` from its LRU list.
+    fn lru_remove(&mut self, key: &[u8]) -> Vec<u8> {
+        let e = &self.table[key];
+        if e.in_high_pri_pool {
+            self.high_pri_pool_usage -= e.charge;
+            self.lru_high_pri.remove(&e.tick)
+        } else {
+            self.lru.remove(&e.tick)
+        }.expect("entry missing from lru list")
+    }
+
+    /// Make `key` the most recently used entry of the pool matching its
+    /// priority.
+    fn lru_insert(&mut self, key: Vec<u8>) {
+        let tick = self.next_tick();
+        let e = self.table.get_mut(&key).expect("entry missing from table");
+        e.tick = tick;
+        e.in_high_pri_pool = self.high_pri_pool_ratio > 0.0 && e.priority == CachePriority::High;
+        if e.in_high_pri_pool {
+            self.high_pri_pool_usage += e.charge;
+            self.lru_high_pri.insert(tick, key);
+            self.maintain_pool_size();
+        } else {
+            self.lru.insert(tick, key);
+        }
+    }
+
+    /// Overflow the least recently used entries of the high priority pool
+    /// into the low priority one, as its most recently used entries.
+    fn maintain_pool_size(&mut self) {
+        while self.high_pri_pool_usage > self.high_pri_pool_capacity {
+            let (_, key) = self.lru_high_pri.pop_first().expect("high pri pool usage without entries");
+            let tick = self.next_tick();
+            let e = self.table.get_mut(&key).expect("entry missing from table");
+            self.high_pri_pool_usage -= e.charge;
+            e.tick = tick;
+            e.in_high_pri_pool = false;
+            self.lru.insert(tick, key);
+        }
+    }
+
+    fn insert(&mut self, key: &[u8], value: V, charge: usize, priority: CachePriority) -> Result<Arc<V>> {
+        let value = Arc::new(value);
+        self.erase(key);
+
+        // Free space following strict LRU policy until enough space
+        // is freed or the lru lists are empty
+        self.evict(charge);
+        if self.usage + charge > self.capacity {
+            if self.strict_capacity_limit {
+                return Err(MemoryLimit);
+            }
+            if self.capacity == 0 {
+                // capacity == 0 is supported and turns off caching
+                return Ok(value);
+            }
+        }
+
+        self.table.insert(key.to_vec(), LRUHandle {
+            value: value.clone(),
+            charge,
+            priority,
+            tick: 0,
+            in_high_pri_pool: false
+        });
+        self.usage += charge;
+        self.lru_insert(key.to_vec());
+        Ok(value)
+    }
+
+    /// Drop the least recently used entries that are not in use, low
+    /// priority ones first, until `charge` more fits the capacity.
+    fn evict(&mut self, charge: usize) {
+        if self.usage + charge <= self.capacity {
+            return;
+        }
+        let mut victims = Vec::new();
+        let mut usage = self.usage;
+        for key in self.lru.values().chain(self.lru_high_pri.values()) {
+            if usage + charge <= self.capacity {
+                break;
+            }
+            let e = &self.table[key];
+            if !e.in_use() {
+                usage -= e.charge;
+                victims.push(key.clone());
+            }
+        }
+        for key in victims {
+            if let Some(e) = self.remove(&key) {
+                if self.keep_evicted {
+                    self.evicted.push((key, e));
+                }
+            }
+        }
+    }
+
+    fn lookup(&mut self, key: &[u8]) -> Option<Arc<V>> {
+        let value = self.table.get(key)?.value.clone();
+        let key = self.lru_remove(key);
+        self.lru_insert(key);
+        Some(value)
+    }
+
+    fn erase(&mut self, key: &[u8]) {
+        self.remove(key);
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<LRUHandle<V>> {
+        if !self.table.contains_key(key) {
+            return None;
+        }
+        self.lru_remove(key);
+        let e = self.table.remove(key).unwrap();
+        self.usage -= e.charge;
+        Some(e)
+    }
+
+    /// Combined charge of the entries held by clients.
+    fn pinned_usage(&self) -> usize {
+        self.table.values().filter(|e| e.in_use()).map(|e| e.charge).sum()
+    }
+
+    fn prune(&mut self) {
+        let unused = self.table.iter()
+            .filter(|(_, e)| !e.in_use())
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in unused {
+            self.erase(&key);
+        }
+    }
+}
+
+/// An LRU cache split into 16 shards by the hash of the key.
+///
+/// Values are handed out as `Arc`s, which pin their entries: an entry whose
+/// value is still held by a client is never evicted, and becomes evictable
+/// once every clone has been dropped.
+pub struct ShardedLRUCache<V> {
+
+    shards: Vec<Mutex<LRUCache<V>>>,
+
+    capacity: usize,
+
+    secondary_cache: Option<Arc<dyn SecondaryCache<V>>>,
+
+    last_id: AtomicU64,
+
+    hits: AtomicU64,
+
+    misses: AtomicU64
+}
+
+impl<V> ShardedLRUCache<V> {
+
+    /// Create a new cache with a fixed size capacity.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_options(LRUCacheOptions::new(capacity))
+    }
+
+    pub fn with_options(options: LRUCacheOptions) -> Self {
+        let per_shard = options.capacity.div_ceil(K_NUM_SHARDS);
+        ShardedLRUCache {
+            shards: (0..K_NUM_SHARDS)
+                .map(|_| Mutex::new(LRUCache::new(per_shard, options.strict_capacity_limit, options.high_pri_pool_ratio)))
+                .collect(),
+            capacity: options.capacity,
+            secondary_cache: None,
+            last_id: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0)
+        }
+    }
+
+    /// Create a cache that hands the entries it evicts for lack of room to
+    /// `secondary_cache`, and consults it on a miss.
+    pub fn with_secondary_cache(options: LRUCacheOptions, secondary_cache: Arc<dyn SecondaryCache<V>>) -> Self {
+        let mut cache = Self::with_options(options);
+        for shard in &mut cache.shards {
+            shard.get_mut().unwrap().keep_evicted = true;
+        }
+        cache.secondary_cache = Some(secondary_cache);
+        cache
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<LRUCache<V>> {
+        let h = hash(key, 0);
+        &self.shards[(h >> (32 - K_NUM_SHARD_BITS)) as usize]
+    }
+
+    /// Insert a mapping from key->value into the cache and assign it
+    /// the specified charge against the total cache capacity.
+    ///
+    /// Returns the cached value; the entry cannot be evicted while the
+    /// returned `Arc` or any clone of it is alive. Fails with MemoryLimit
+    /// if the cache has a strict capacity limit and no room can be made.
+    pub fn insert(&self, key: &[u8], value: V, charge: usize, priority: CachePriority) -> Result<Arc<V>> {
+        let Some(secondary_cache) = &self.secondary_cache else {
+            return self.shard(key).lock().unwrap().insert(key, value, charge, priority);
+        };
+        // The new value supersedes any copy kept by the secondary cache
+        secondary_cache.erase(key);
+        let (result, evicted) = {
+            let mut shard = self.shard(key).lock().unwrap();
+            let result = shard.insert(key, value, charge, priority);
+            (result, std::mem::take(&mut shard.evicted))
+        };
+        // Demote outside of the shard lock, as compressing may be slow
+        for (key, e) in evicted {
+            secondary_cache.insert(&key, &e.value, e.charge, e.priority);
+        }
+        result
+    }
+
+    /// If the cache has no mapping for "key", returns None. Else returns
+    /// the value, marking the entry as most recently used.
+    ///
+    /// On a miss, the secondary cache is consulted, and an entry found
+    /// there is inserted back into this cache. Only lookups that find the
+    /// key in this cache count as hits.
+    pub fn lookup(&self, key: &[u8]) -> Option<Arc<V>> {
+        let value = self.shard(key).lock().unwrap().lookup(key);
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if value.is_some() {
+            return value;
+        }
+        let (value, charge, priority) = self.secondary_cache.as_ref()?.lookup(key)?;
+        // With a strict capacity limit, an entry that cannot be promoted
+        // is reported as missing
+        self.insert(key, value, charge, priority).ok()
+    }
+
+    /// If the cache contains entry for key, erase it. Clients that still
+    /// hold its value can keep using it.
+    pub fn erase(&self, key: &[u8]) {
+        self.shard(key).lock().unwrap().erase(key);
+        if let Some(secondary_cache) = &self.secondary_cache {
+            secondary_cache.erase(key);
+        }
+    }
+
+    /// Return a new numeric id. May be used by multiple clients who are
+    /// sharing the same cache to partition the key space. Typically the
+    /// client will allocate a new id at startup and prepend the id to
+    /// its cache keys.
+    pub fn new_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Remove all cache entries that are not actively in use.
+    pub fn prune(&self) {
+        for shard in &self.shards {
+            shard.lock().unwrap().prune();
+        }
+    }
+
+    /// Return the capacity the cache was created with.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Return an estimate of the combined charges of all elements stored in
+    /// the cache.
+    pub fn usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().usage).sum()
+    }
+
+    /// Return the combined charges of the elements that are pinned because
+    /// a client still holds their values.
+    pub fn pinned_usage(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().pinned_usage()).sum()
+    }
+
+    /// Return the number of elements stored in the cache.
+    pub fn entry_count(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap().table.len()).sum()
+    }
+
+    /// Return the number of lookups that found an entry.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of lookups that found no entry.
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::cache::compressed_secondary_cache::CompressedSecondaryCache;
+    use crate::coding::encode_fixed32;
+    use super::*;
+
+    const K_CACHE_SIZE: usize = 1000;
+
+    // Conversions between numeric keys/values and the types expected by Cache.
+    fn encode_key(k: u32) -> Vec<u8> {
+        let mut buf = vec![0; 4];
+        encode_fixed32(&mut buf, k, 0);
+        buf
+    }
+
+    fn lookup(cache: &ShardedLRUCache<u32>, key: u32) -> Option<u32> {
+        cache.lookup(&encode_key(key)).map(|v| *v)
+    }
+
+    fn insert(cache: &ShardedLRUCache<u32>, key: u32, value: u32, charge: usize) {
+        cache.insert(&encode_key(key), value, charge, CachePriority::Low).unwrap();
+    }
+
+    #[test]
+    fn test_hit_and_miss() {
+        let cache = ShardedLRUCache::new(K_CACHE_SIZE);
+        assert_eq!(None, lookup(&cache, 100));
+
+        insert(&cache, 100, 101, 1);
+        assert_eq!(Some(101), lookup(&cache, 100));
+        assert_eq!(None, lookup(&cache, 200));
+        assert_eq!(None, lookup(&cache, 300));
+
+        insert(&cache, 200, 201, 1);
+        assert_eq!(Some(101), lookup(&cache, 100));
+        assert_eq!(Some(201), lookup(&cache, 200));
+        assert_eq!(None, lookup(&cache, 300));
+
+        insert(&cache, 100, 102, 1);
+        assert_eq!(Some(102), lookup(&cache, 100));
+        assert_eq!(Some(201), lookup(&cache, 200));
+        assert_eq!(None, lookup(&cache, 300));
+        assert_eq!(2, cache.usage());
+        assert_eq!(2, cache.entry_count());
+        assert_eq!(5, cache.hits());
+        assert_eq!(5, cache.misses());
+    }
+
+    #[test]
+    fn test_erase() {
+        let cache = ShardedLRUCache::new(K_CACHE_SIZE);
+        cache.erase(&encode_key(200));
+
+        insert(&cache, 100, 101, 1);
+        insert(&cache, 200, 201, 1);
+        cache.erase(&encode_key(100));
+        assert_eq!(None, lookup(&cache, 100));
+        assert_eq!(Some(201), lookup(&cache, 200));
+
+        cache.erase(&encode_key(100));
+        assert_eq!(None, lookup(&cache, 100));
+        assert_eq!(Some(201), lookup(&cache, 200));
+    }
+
+    #[test]
+    fn test_entries_are_pinned() {
+        let cache = ShardedLRUCache::new(K_CACHE_SIZE);
+        insert(&cache, 100, 101, 1);
+        let h1 = cache.lookup(&encode_key(100)).unwrap();
+        assert_eq!(101, *h1);
+
+        insert(&cache, 100, 102, 1);
+        let h2 = cache.lookup(&encode_key(100)).unwrap();
+        assert_eq!(102, *h2);
+        // The replaced value stays valid for its holder
+        assert_eq!(101, *h1);
+
+        assert_eq!(1, cache.usage());
+        assert_eq!(1, cache.pinned_usage());
+
+        cache.erase(&encode_key(100));
+        assert_eq!(None, lookup(&cache, 100));
+        assert_eq!(102, *h2);
+        assert_eq!(0, cache.pinned_usage());
+    }
+
+    #[test]
+    fn test_eviction_policy() {
+        let cache = ShardedLRUCache::new(K_CACHE_SIZE);
+        insert(&cache, 100, 101, 1);
+        insert(&cache, 200, 201, 1);
+        insert(&cache, 300, 301, 1);
+        let h = cache.lookup(&encode_key(300)).unwrap();
+
+        // Frequently used entry must be kept around,
+        // as must things that are still in use.
+        for i in 0..(K_CACHE_SIZE + 100) as u32 {
+            insert(&cache, 1000 + i, 2000 + i, 1);
+            assert_eq!(Some(2000 + i), lookup(&cache, 1000 + i));
+            assert_eq!(Some(101), lookup(&cache, 100));
+        }
+        assert_eq!(Some(101), lookup(&cache, 100));
+        assert_eq!(None, lookup(&cache, 200));
+        assert_eq!(Some(301), lookup(&cache, 300));
+        drop(h);
+    }
+
+    #[test]
+    fn test_use_exceeds_cache_size() {
+        let cache = ShardedLRUCache::new(K_CACHE_SIZE);
+        // Overfill the cache, keeping handles on all inserted entries.
+        let handles = (0..(K_CACHE_SIZE + 100) as u32)
+            .map(|i| cache.insert(&encode_key(1000 + i), 2000 + i, 1, CachePriority::Low).unwrap())
+            .collect::<Vec<_>>();
+
+        // Check that all the entries can be found in the cache.
+        for i in 0..handles.len() as u32 {
+            assert_eq!(Some(2000 + i), lookup(&cache, 1000 + i));
+        }
+    }
+
+    #[test]
+    fn test_heavy_entries() {
+        let cache = ShardedLRUCache::new(K_CACHE_SIZE);
+        // Add a bunch of light and heavy entries and then count the combined
+        // size of items still in the cache, which must be approximately the
+        // same as the total capacity.
+        const K_LIGHT: usize = 1;
+        const K_HEAVY: usize = 10;
+        let mut added = 0;
+        let mut index = 0;
+        while added < 2 * K_CACHE_SIZE {
+            let weight = if index & 1 == 1 { K_LIGHT } else { K_HEAVY };
+            insert(&cache, index, 1000 + index, weight);
+            added += weight;
+            index += 1;
+        }
+
+        let mut cached_weight = 0;
+        for i in 0..index {
+            let weight = if i & 1 == 1 { K_LIGHT } else { K_HEAVY };
+            if let Some(r) = lookup(&cache, i) {
+                cached_weight += weight;
+                assert_eq!(1000 + i, r);
+            }
+        }
+        assert!(cached_weight <= K_CACHE_SIZE + K_CACHE_SIZE / 10);
+    }
+
+    #[test]
+    fn test_new_id() {
+        let cache = ShardedLRUCache::<u32>::new(K_CACHE_SIZE);
+        let a = cache.new_id();
+        let b = cache.new_id();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_prune() {
+        let cache = ShardedLRUCache::new(K_CACHE_SIZE);
+        insert(&cache, 1, 100, 1);
+        insert(&cache, 2, 200, 1);
+
+        let handle = cache.lookup(&encode_key(1)).unwrap();
+        cache.prune();
+        drop(handle);
+
+        assert_eq!(Some(100), lookup(&cache, 1));
+        assert_eq!(None, lookup(&cache, 2));
+    }
+
+    #[test]
+    fn test_zero_size_cache() {
+        let cache = ShardedLRUCache::new(0);
+        insert(&cache, 1, 100, 1);
+        assert_eq!(None, lookup(&cache, 1));
+    }
+
+    #[test]
+    fn test_strict_capacity_limit() {
+        let mut shard = LRUCache::new(10, true, 0.0);
+        let handles = (0..10u32)
+            .map(|i| shard.insert(&encode_key(i), i, 1, CachePriority::Low).unwrap())
+            .collect::<Vec<_>>();
+        // Every entry is in use, so nothing can be evicted
+        assert_eq!(Err(MemoryLimit), shard.insert(&encode_key(100), 100, 1, CachePriority::Low).map(|_| ()));
+        assert_eq!(None, shard.lookup(&encode_key(100)));
+        assert_eq!(10, shard.usage);
+
+        drop(handles);
+        shard.insert(&encode_key(100), 100, 1, CachePriority::Low).unwrap();
+        assert_eq!(10, shard.usage);
+        // An entry larger than the whole cache never fits
+        assert_eq!(Err(MemoryLimit), shard.insert(&encode_key(200), 200, 11, CachePriority::Low).map(|_| ()));
+
+        // Without the limit the cache grows while its entries are in use
+        let mut shard = LRUCache::new(10, false, 0.0);
+        let handles = (0..11u32)
+            .map(|i| shard.insert(&encode_key(i), i, 1, CachePriority::Low).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(11, shard.usage);
+        drop(handles);
+    }
+
+    #[test]
+    fn test_high_pri_pool() {
+        let mut shard = LRUCache::new(10, false, 0.5);
+        for i in 0..5u32 {
+            shard.insert(&encode_key(i), i, 1, CachePriority::High).unwrap();
+        }
+        // A scan of low priority entries cannot push out the high pool
+        for i in 100..120u32 {
+            shard.insert(&encode_key(i), i, 1, CachePriority::Low).unwrap();
+        }
+        for i in 0..5u32 {
+            assert_eq!(Some(i), shard.lookup(&encode_key(i)).map(|v| *v));
+        }
+        assert_eq!(5, shard.high_pri_pool_usage);
+
+        // Overflowing the pool turns its least recently used entry into
+        // the most recently used low priority one
+        shard.insert(&encode_key(5), 5, 1, CachePriority::High).unwrap();
+        assert_eq!(5, shard.high_pri_pool_usage);
+        for i in 200..204u32 {
+            shard.insert(&encode_key(i), i, 1, CachePriority::Low).unwrap();
+        }
+        assert!(shard.table.contains_key(&encode_key(0)));
+        shard.insert(&encode_key(204), 204, 1, CachePriority::Low).unwrap();
+        assert!(!shard.table.contains_key(&encode_key(0)));
+        for i in 1..6u32 {
+            assert!(shard.table.contains_key(&encode_key(i)));
+        }
+
+        // Without a pool, priorities are ignored
+        let mut shard = LRUCache::new(2, false, 0.0);
+        shard.insert(&encode_key(0), 0, 1, CachePriority::High).unwrap();
+        shard.insert(&encode_key(1), 1, 1, CachePriority::Low).unwrap();
+        shard.insert(&encode_key(2), 2, 1, CachePriority::Low).unwrap();
+        assert_eq!(None, shard.lookup(&encode_key(0)));
+        assert_eq!(0, shard.high_pri_pool_usage);
+    }
+
+    #[test]
+    fn test_secondary_cache() {
+        let secondary = Arc::new(CompressedSecondaryCache::new(1 << 20));
+        // One block of room per shard
+        let cache = ShardedLRUCache::with_secondary_cache(LRUCacheOptions::new(K_NUM_SHARDS * 100), secondary.clone());
+        let block = |i: u32| vec![i as u8; 100];
+        for i in 0..100 {
+            cache.insert(&encode_key(i), block(i), 100, CachePriority::Low).unwrap();
+        }
+        assert!(cache.entry_count() <= K_NUM_SHARDS);
+        // Every evicted block was demoted, compressed
+        assert_eq!(100, cache.entry_count() + secondary.entry_count());
+        assert!(secondary.usage() < secondary.entry_count() * 100);
+
+        // Misses are served from the secondary cache and promoted
+        for i in 0..100 {
+            assert_eq!(block(i), *cache.lookup(&encode_key(i)).unwrap());
+        }
+        assert!(cache.misses() > 0);
+        assert_eq!(cache.misses(), secondary.hits());
+        assert_eq!(100, cache.entry_count() + secondary.entry_count());
+
+        // Erasing drops the block from both levels
+        let evicted = (0..100).find(|&i| cache.shard(&encode_key(i)).lock().unwrap().lookup(&encode_key(i)).is_none()).unwrap();
+        cache.erase(&encode_key(evicted));
+        cache.erase(&encode_key(99));
+        assert!(cache.lookup(&encode_key(evicted)).is_none());
+        assert!(cache.lookup(&encode_key(99)).is_none());
+        assert_eq!(98, cache.entry_count() + secondary.entry_count());
+
+        // A new value replaces the demoted one
+        let key = (0..99).find(|&i| cache.shard(&encode_key(i)).lock().unwrap().lookup(&encode_key(i)).is_none()).unwrap();
+        cache.insert(&encode_key(key), vec![0xff; 100], 100, CachePriority::Low).unwrap();
+        assert_eq!(vec![0xff; 100], *cache.lookup(&encode_key(key)).unwrap());
+    }
+
+    #[test]
+    fn test_concurrent_lookups() {
+        let cache = Arc::new(ShardedLRUCache::new(K_CACHE_SIZE));
+        for i in 0..100 {
+            insert(&cache, i, i + 1, 1);
+        }
+        let readers = (0..8).map(|_| {
+            let cache = cache.clone();
+            thread::spawn(move || {
+                for round in 0..1000 {
+                    let key = round % 100;
+                    assert_eq!(Some(key + 1), lookup(&cache, key));
+                }
+            })
+        }).collect::<Vec<_>>();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,156 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A SecondaryCache that keeps the blocks evicted from the block cache
+//! compressed with Snappy. When the working set slightly exceeds the block
+//! cache, most misses are then served from memory at the cost of a
+//! decompression instead of a read from disk.
+
+use crate::cache::{CachePriority, LRUCacheOptions, SecondaryCache, ShardedLRUCache};
+
+struct CompressedEntry {
+
+    // The Snappy compressed value, or the value itself if it does not
+    // compress well
+    data: Vec<u8>,
+
+    compressed: bool,
+
+    // Charge of the value in the primary cache
+    charge: usize,
+
+    priority: CachePriority
+}
+
+/// A secondary cache for blocks, charged by their compressed size.
+pub struct CompressedSecondaryCache {
+
+    cache: ShardedLRUCache<CompressedEntry>
+}
+
+impl CompressedSecondaryCache {
+
+    /// Create a cache holding up to `capacity` bytes of compressed blocks.
+    pub fn new(capacity: usize) -> Self {
+        CompressedSecondaryCache {
+            cache: ShardedLRUCache::with_options(LRUCacheOptions::new(capacity))
+        }
+    }
+
+    /// Return the capacity the cache was created with.
+    pub fn capacity(&self) -> usize {
+        self.cache.capacity()
+    }
+
+    /// Return the combined compressed size of the blocks stored in the cache.
+    pub fn usage(&self) -> usize {
+        self.cache.usage()
+    }
+
+    /// Return the number of blocks stored in the cache.
+    pub fn entry_count(&self) -> usize {
+        self.cache.entry_count()
+    }
+
+    /// Return the number of lookups that found a block.
+    pub fn hits(&self) -> u64 {
+        self.cache.hits()
+    }
+
+    /// Return the number of lookups that found no block.
+    pub fn misses(&self) -> u64 {
+        self.cache.misses()
+    }
+}
+
+impl SecondaryCache<Vec<u8>> for CompressedSecondaryCache {
+
+    fn insert(&self, key: &[u8], value: &Vec<u8>, charge: usize, priority: CachePriority) {
+        // Keep the value uncompressed if compression saves less than 12.5%
+        let entry = match snap::raw::Encoder::new().compress_vec(value) {
+            Ok(data) if data.len() < value.len() - value.len() / 8 => CompressedEntry {
+                data,
+                compressed: true,
+                charge,
+                priority
+            },
+            _ => CompressedEntry {
+                data: value.clone(),
+                compressed: false,
+                charge,
+                priority
+            }
+        };
+        let size = entry.data.len();
+        // Nothing is pinned for long and the limit is not strict, so this
+        // cannot fail
+        let _ = self.cache.insert(key, entry, size, priority);
+    }
+
+    fn lookup(&self, key: &[u8]) -> Option<(Vec<u8>, usize, CachePriority)> {
+        let entry = self.cache.lookup(key)?;
+        let value = if entry.compressed {
+            snap::raw::Decoder::new().decompress_vec(&entry.data).ok()?
+        } else {
+            entry.data.clone()
+        };
+        Some((value, entry.charge, entry.priority))
+    }
+
+    fn erase(&self, key: &[u8]) {
+        self.cache.erase(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::random::Random;
+    use super::*;
+
+    fn compressible_block(seed: u8) -> Vec<u8> {
+        (0..4096u32).map(|i| seed.wrapping_add((i / 64) as u8)).collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cache = CompressedSecondaryCache::new(1 << 20);
+        assert!(cache.lookup(b"block").is_none());
+
+        let block = compressible_block(1);
+        cache.insert(b"block", &block, block.len(), CachePriority::High);
+        let (value, charge, priority) = cache.lookup(b"block").unwrap();
+        assert_eq!(block, value);
+        assert_eq!(4096, charge);
+        assert_eq!(CachePriority::High, priority);
+        assert!(cache.usage() < block.len() / 4);
+        assert_eq!(1, cache.entry_count());
+        assert_eq!(1, cache.hits());
+        assert_eq!(1, cache.misses());
+
+        cache.erase(b"block");
+        assert!(cache.lookup(b"block").is_none());
+        assert_eq!(0, cache.usage());
+    }
+
+    #[test]
+    fn test_incompressible_block() {
+        let cache = CompressedSecondaryCache::new(1 << 20);
+        let mut rnd = Random::new(301);
+        let block = (0..4096).map(|_| rnd.next() as u8).collect::<Vec<_>>();
+        cache.insert(b"block", &block, block.len(), CachePriority::Low);
+        assert_eq!(block.len(), cache.usage());
+        assert_eq!(block, cache.lookup(b"block").unwrap().0);
+
+        cache.insert(b"empty", &Vec::new(), 0, CachePriority::Low);
+        assert_eq!(Vec::<u8>::new(), cache.lookup(b"empty").unwrap().0);
+    }
+}
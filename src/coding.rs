@@ -48,6 +48,18 @@ pub fn encode_varint32(buf: &mut [u8], v: u32, offset: usize) -> usize {
     }
 }
 
+pub fn encode_varint64(buf: &mut [u8], mut v: u64, offset: usize) -> usize {
+    const B: u64 = 128;
+    let mut i = offset;
+    while v >= B {
+        buf[i] = (v | B) as u8;
+        v >>= 7;
+        i += 1;
+    }
+    buf[i] = v as u8;
+    i + 1 - offset
+}
+
 pub fn encode_fixed32(buf: &mut [u8], value: u32, offset: usize) -> usize {
     let buffer = buf[offset..].as_mut_ptr();
 
@@ -122,6 +134,24 @@ fn get_varint32_fallback(buf: &[u8], offset: usize, limit: usize) -> Result<(u32
     Err("")
 }
 
+pub fn get_varint64(buf: &[u8], offset: usize, limit: usize) -> Result<(u64, usize), &str> {
+    let mut result: u64 = 0;
+    let mut new_offset = offset;
+    let mut shift = 0;
+    while shift <= 63 && new_offset < limit {
+        let byte = buf[new_offset] as u64;
+        new_offset += 1;
+        if byte & 128 != 0 {
+            result |= (byte & 127) << shift
+        } else {
+            result |= byte << shift;
+            return Ok((result, new_offset - offset));
+        }
+        shift += 7;
+    }
+    Err("")
+}
+
 pub fn decode_fixed64(buf: &[u8], offset: usize) -> u64 {
     let buffer = buf[offset..].as_ptr();
     unsafe {
@@ -143,12 +173,23 @@ pub fn decode_fix32(buf: &[u8]) -> u32 {
         ((buf[3] as u32) << 24);
 }
 
+pub fn put_fixed32(dst: &mut Vec<u8>, value: u32) {
+    dst.extend_from_slice(&value.to_le_bytes());
+}
+
 pub fn put_varint32(dst: &mut Vec<u8>, v: u32) -> usize {
     let mut buf = vec![0;5];
     let size = encode_varint32(&mut buf, v, 0);
     dst.write(&buf[..size]).expect("put varint32 failed")
 }
 
+pub fn put_varint64(dst: &mut Vec<u8>, v: u64) -> usize {
+    let mut buf = [0; 10];
+    let size = encode_varint64(&mut buf, v, 0);
+    dst.extend_from_slice(&buf[..size]);
+    size
+}
+
 pub fn put_length_prefixed_slice(dst: &mut Vec<u8>, value: &Slice) {
     put_varint32(dst, value.size() as u32);
     dst.extend_from_slice(value.data());
@@ -158,11 +199,66 @@ pub fn put_length_prefixed_slice(dst: &mut Vec<u8>, value: &Slice) {
 /// before the start of the returned slice
 pub fn get_length_prefixed_slice(input: &[u8]) -> crate::Result<(Slice, usize)> {
     match get_varint32(input, 0, input.len()) {
-        Ok((len, idx)) => Ok((Slice::from_bytes(&input[idx..idx+len as usize]), idx)),
+        Ok((len, idx)) if idx + len as usize <= input.len() => {
+            Ok((Slice::from_bytes(&input[idx..idx + len as usize]), idx))
+        },
+        Ok(_) => Err(Error::Corruption),
         Err(_) => Err(Error::Corruption)
     }
 }
 
+/// Returns the number of bytes group varint encoding uses for `v`.
+pub fn group_varint32_value_length(v: u32) -> usize {
+    match v {
+        0..=0xff => 1,
+        0x100..=0xffff => 2,
+        0x10000..=0xffffff => 3,
+        _ => 4
+    }
+}
+
+/// Append `values` in group varint encoding: every group of up to four
+/// values starts with a tag byte holding, two bits per value, the number
+/// of bytes of the value minus one, followed by the values themselves in
+/// little-endian order.
+pub fn put_group_varint32(dst: &mut Vec<u8>, values: &[u32]) {
+    for group in values.chunks(4) {
+        let mut tag = 0u8;
+        for (i, v) in group.iter().enumerate() {
+            tag |= ((group_varint32_value_length(*v) - 1) as u8) << (i * 2);
+        }
+        dst.push(tag);
+        for v in group {
+            dst.extend_from_slice(&v.to_le_bytes()[..group_varint32_value_length(*v)]);
+        }
+    }
+}
+
+/// Decode `count` group varint encoded values from the start of `input`,
+/// return the values and the number of bytes they took.
+pub fn get_group_varint32(input: &[u8], count: usize) -> crate::Result<(Vec<u32>, usize)> {
+    let mut values = Vec::with_capacity(count);
+    let mut offset = 0;
+    while values.len() < count {
+        if offset >= input.len() {
+            return Err(Error::Corruption);
+        }
+        let tag = input[offset];
+        offset += 1;
+        for i in 0..(count - values.len()).min(4) {
+            let len = ((tag >> (i * 2)) & 3) as usize + 1;
+            if offset + len > input.len() {
+                return Err(Error::Corruption);
+            }
+            let mut buf = [0u8; 4];
+            buf[..len].copy_from_slice(&input[offset..offset + len]);
+            values.push(u32::from_le_bytes(buf));
+            offset += len;
+        }
+    }
+    Ok((values, offset))
+}
+
 #[cfg(test)]
 mod tests {
     use std::env::var;
@@ -190,6 +286,25 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_coding_group_varint32() {
+        let values = [0, 1, 255, 256, 65535, 65536, 1 << 24, u32::MAX, 7];
+        let mut dst = Vec::new();
+        put_group_varint32(&mut dst, &values);
+        // Three tag bytes and 1 + 1 + 1 + 2 + 2 + 3 + 4 + 4 + 1 value bytes
+        assert_eq!(22, dst.len());
+        assert_eq!(0b01_00_00_00, dst[0]);
+        dst.push(0xab);
+        let (decoded, len) = get_group_varint32(&dst, values.len()).unwrap();
+        assert_eq!(values.to_vec(), decoded);
+        assert_eq!(22, len);
+
+        for truncated in 0..22 {
+            assert!(get_group_varint32(&dst[..truncated], values.len()).is_err());
+        }
+        assert_eq!((vec![], 0), get_group_varint32(&[], 0).unwrap());
+    }
+
     #[test]
     fn test_coding_varint32_overflow() {
         let input = vec![129, 130, 131, 132, 133, 17];
@@ -209,4 +324,42 @@ mod tests {
         let result = get_varint32(buf.as_slice(), 0, buf.len()).expect("large value truncation failed");
         assert_eq!(large_value, result.0)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_coding_varint64() {
+        // Construct the list of values to check
+        let mut values = vec![0, 100, !0u64, !0u64 - 1];
+        for k in 0..64 {
+            // Test values near powers of two
+            let power = 1u64 << k;
+            values.push(power);
+            values.push(power - 1);
+            values.push(power + 1);
+        }
+
+        let mut s = Vec::new();
+        for v in &values {
+            put_varint64(&mut s, *v);
+        }
+
+        let mut offset = 0;
+        for expected in &values {
+            let (actual, var_size) = get_varint64(&s, offset, s.len()).expect("get varint64 failed");
+            assert_eq!(*expected, actual);
+            assert_eq!(varint_length(actual), var_size);
+            offset += var_size;
+        }
+        assert_eq!(s.len(), offset);
+    }
+
+    #[test]
+    fn test_coding_varint64_truncation() {
+        let large_value = (1u64 << 63) + 100;
+        let mut buf = vec![];
+        put_varint64(&mut buf, large_value);
+        for len in 0..buf.len() {
+            assert!(get_varint64(buf.as_slice(), 0, len).is_err());
+        }
+        assert_eq!(large_value, get_varint64(buf.as_slice(), 0, buf.len()).unwrap().0);
+    }
+}
@@ -0,0 +1,1158 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! compaction
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use crate::comparator::Comparator;
+use crate::dbformat::{append_internal_key, extract_user_key, kValueTypeForSeek, parse_internal_key, InternalKeyComparator, SequenceNumber, ValueType, K_MAX_SEQUENCE_NUMBER};
+use crate::env::{new_random_access_file, new_writable_file, WritableFile};
+use crate::filename::table_file_name;
+use crate::iterator::InternalIterator;
+use crate::options::{Options, ReadOptions};
+use crate::range_del::{RangeDelAggregator, RangeTombstone};
+use crate::Result;
+use crate::slice::Slice;
+use crate::table::merger::MergingIterator;
+use crate::table::Table;
+use crate::table::table_builder::TableBuilder;
+use crate::version_edit::{FileMetaData, K_NUM_LEVELS, VersionEdit};
+use crate::version_set::Version;
+
+/// A Compaction encapsulates information about a compaction: files of
+/// "level" and the files of "level+1" they overlap are merged into new
+/// files of "level+1".
+pub struct Compaction {
+
+    level: usize,
+
+    max_output_file_size: u64,
+
+    // The version the inputs were picked from. Holding it keeps the input
+    // files live until the compaction is installed.
+    input_version: Rc<Version>,
+
+    // Each compaction reads inputs from "level" and "level+1"
+    inputs: [Vec<Rc<FileMetaData>>; 2],
+
+    // State used to check for number of overlapping grandparent files
+    // (parent == level+1; grandparent == level+2)
+    grandparents: Vec<Rc<FileMetaData>>,
+
+    edit: VersionEdit
+}
+
+impl Compaction {
+
+    pub fn new(level: usize, input_version: Rc<Version>, inputs: [Vec<Rc<FileMetaData>>; 2],
+               grandparents: Vec<Rc<FileMetaData>>, max_output_file_size: u64) -> Self {
+        assert!(level + 1 < K_NUM_LEVELS);
+        Compaction {
+            level,
+            max_output_file_size,
+            input_version,
+            inputs,
+            grandparents,
+            edit: VersionEdit::new()
+        }
+    }
+
+    /// Return the level that is being compacted.  Inputs from "level"
+    /// and "level+1" will be merged to produce a set of "level+1" files.
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    /// Return the object that holds the edits to the descriptor done
+    /// by this compaction.
+    pub fn edit(&mut self) -> &mut VersionEdit {
+        &mut self.edit
+    }
+
+    /// Return the input files of "level+which", which is either 0 or 1.
+    pub fn inputs(&self, which: usize) -> &[Rc<FileMetaData>] {
+        &self.inputs[which]
+    }
+
+    /// Is this a trivial compaction that can be implemented by just
+    /// moving a single input file to the next level (no merging or splitting)
+    pub fn is_trivial_move(&self) -> bool {
+        // Avoid a move if there is lots of overlapping grandparent data.
+        // Otherwise, the move could create a parent file that will require
+        // a very expensive merge later on.
+        self.inputs[0].len() == 1 && self.inputs[1].is_empty()
+            && self.grandparents.iter().map(|f| f.file_size).sum::<u64>() <= self.max_grandparent_overlap_bytes()
+    }
+
+    /// Maximum bytes of overlaps in grandparent (i.e., level+2) before we
+    /// stop building a single file in a level->level+1 compaction.
+    fn max_grandparent_overlap_bytes(&self) -> u64 {
+        10 * self.max_output_file_size
+    }
+
+    /// Add all inputs to this compaction as delete operations to *edit.
+    pub fn add_input_deletions(&self, edit: &mut VersionEdit) {
+        for which in 0..2 {
+            for f in &self.inputs[which] {
+                edit.remove_file(self.level + which, f.number);
+            }
+        }
+    }
+
+    /// Returns the jobs merging the inputs, to run on background threads.
+    /// The key range of the compaction is split into up to
+    /// `options.max_subcompactions` jobs of about the same input size.
+    /// Entries hidden from `smallest_snapshot` are dropped, and the output
+    /// files are numbered from `file_numbers`.
+    /// `newest_snapshot` is the sequence number of the newest live snapshot,
+    /// or 0 if there is none.
+    pub fn jobs(&self, dbname: PathBuf, options: &Options, smallest_snapshot: SequenceNumber, newest_snapshot: SequenceNumber,
+                file_numbers: Arc<AtomicU64>) -> Vec<CompactionJob> {
+        let inputs: Vec<(u64, u64)> = self.inputs.iter()
+            .flatten()
+            .map(|f| (f.number, f.file_size))
+            .collect();
+        let lower_levels: Vec<Vec<(Vec<u8>, Vec<u8>)>> = (self.level + 2..K_NUM_LEVELS)
+            .map(|level| {
+                self.input_version.files(level).iter()
+                    .map(|f| (extract_user_key(&Slice::from_bytes(&f.smallest)).data().to_vec(),
+                              extract_user_key(&Slice::from_bytes(&f.largest)).data().to_vec()))
+                    .collect()
+            })
+            .collect();
+        let grandparents = GrandparentOverlap {
+            grandparents: self.grandparents.iter().map(|f| (f.largest.clone(), f.file_size)).collect(),
+            index: 0,
+            seen_key: false,
+            overlapped_bytes: 0,
+            max_overlapped_bytes: self.max_grandparent_overlap_bytes()
+        };
+
+        let files: Vec<&FileMetaData> = self.inputs.iter().flatten().map(|f| f.as_ref()).collect();
+        let boundaries = subcompaction_boundaries(options.comparator, &files, options.max_subcompactions);
+        let mut begin = None;
+        let mut jobs = Vec::with_capacity(boundaries.len() + 1);
+        for end in boundaries.into_iter().map(Some).chain(std::iter::once(None)) {
+            jobs.push(CompactionJob {
+                dbname: dbname.clone(),
+                options: options.clone(),
+                inputs: inputs.clone(),
+                lower_levels: lower_levels.clone(),
+                grandparents: grandparents.clone(),
+                begin: begin.take(),
+                end: end.clone(),
+                smallest_snapshot,
+                newest_snapshot,
+                max_output_file_size: self.max_output_file_size,
+                file_numbers: file_numbers.clone()
+            });
+            begin = end;
+        }
+        jobs
+    }
+}
+
+/// Picks up to `max_subcompactions - 1` user keys that split the input
+/// `files` of a compaction into ranges of about the same size. Only the
+/// smallest keys of the files are candidates, and every boundary is larger
+/// than the one before it.
+fn subcompaction_boundaries(ucmp: fn(&Slice, &Slice) -> Ordering, files: &[&FileMetaData], max_subcompactions: usize) -> Vec<Vec<u8>> {
+    let mut boundaries: Vec<Vec<u8>> = Vec::new();
+    if max_subcompactions <= 1 || files.len() <= 1 {
+        return boundaries;
+    }
+    let mut files = files.to_vec();
+    files.sort_by(|a, b| ucmp(&extract_user_key(&Slice::from_bytes(&a.smallest)),
+                              &extract_user_key(&Slice::from_bytes(&b.smallest))));
+    let total_size: u64 = files.iter().map(|f| f.file_size).sum();
+    let mut lowest = extract_user_key(&Slice::from_bytes(&files[0].smallest));
+    let mut seen_size = 0;
+    for f in &files {
+        let key = extract_user_key(&Slice::from_bytes(&f.smallest));
+        let target = total_size * (boundaries.len() as u64 + 1) / max_subcompactions as u64;
+        if seen_size >= target && ucmp(&key, &lowest) == Ordering::Greater {
+            boundaries.push(key.data().to_vec());
+            lowest = key;
+            if boundaries.len() + 1 == max_subcompactions {
+                break;
+            }
+        }
+        seen_size += f.file_size;
+    }
+    boundaries
+}
+
+/// Run the jobs of a compaction, the first on the calling thread and the
+/// others on threads of their own, and return the files written by all of
+/// them in key order. If any job fails, the files of the others are removed
+/// as well.
+pub fn run_subcompactions(jobs: Vec<CompactionJob>) -> Result<Vec<FileMetaData>> {
+    let dbname = jobs.first().expect("compaction without jobs").dbname.clone();
+    let mut results: Vec<Result<Vec<FileMetaData>>> = std::thread::scope(|scope| {
+        let mut jobs = jobs.into_iter();
+        let first = jobs.next().unwrap();
+        let handles: Vec<_> = jobs.map(|job| scope.spawn(move || job.run())).collect();
+        let mut results = vec![first.run()];
+        results.extend(handles.into_iter().map(|handle| handle.join().expect("sub-compaction panicked")));
+        results
+    });
+    if let Some(i) = results.iter().position(|result| result.is_err()) {
+        for meta in results.iter().flatten().flatten() {
+            let _ = std::fs::remove_file(table_file_name(&dbname, meta.number));
+        }
+        return results.swap_remove(i).map(|_| vec![]);
+    }
+    Ok(results.into_iter().flat_map(|result| result.unwrap()).collect())
+}
+
+/// The part of a compaction that runs off the db thread: merges the input
+/// files, leaves out the obsolete entries and writes the rest to new tables
+/// of the output level. It only holds copies of what it needs from the
+/// version the compaction was picked from.
+pub struct CompactionJob {
+
+    dbname: PathBuf,
+
+    options: Options,
+
+    // Input files as (number, size)
+    inputs: Vec<(u64, u64)>,
+
+    // User key ranges of the files of every level below the output level,
+    // sorted by smallest key
+    lower_levels: Vec<Vec<(Vec<u8>, Vec<u8>)>>,
+
+    grandparents: GrandparentOverlap,
+
+    // User key range [begin, end) of the entries this job compacts, None
+    // being unbounded
+    begin: Option<Vec<u8>>,
+
+    end: Option<Vec<u8>>,
+
+    smallest_snapshot: SequenceNumber,
+
+    // Values newer than this no snapshot can read, so they are handed to
+    // the compaction filter
+    newest_snapshot: SequenceNumber,
+
+    max_output_file_size: u64,
+
+    file_numbers: Arc<AtomicU64>
+}
+
+impl CompactionJob {
+
+    /// Run the compaction of the job's key range and return the metadata of the files written, in
+    /// key order. On error, the files written so far are removed.
+    pub fn run(mut self) -> Result<Vec<FileMetaData>> {
+        let mut outputs = Vec::new();
+        let result = self.write_outputs(&mut outputs);
+        if result.is_err() {
+            for meta in &outputs {
+                let _ = std::fs::remove_file(table_file_name(&self.dbname, meta.number));
+            }
+            outputs.clear();
+        }
+        result.map(|_| outputs)
+    }
+
+    fn write_outputs(&mut self, outputs: &mut Vec<FileMetaData>) -> Result<()> {
+        let read_options = ReadOptions {
+            verify_checksums: self.options.paranoid_checks,
+            fill_cache: false,
+            snapshot: None
+        };
+        let ucmp = self.options.comparator;
+        let mut iters: Vec<Box<dyn InternalIterator>> = Vec::with_capacity(self.inputs.len());
+        let mut tombstones = Vec::new();
+        for &(number, file_size) in &self.inputs {
+            let file = new_random_access_file(table_file_name(&self.dbname, number))?;
+            let table = Rc::new(Table::open(&self.options, file, number, file_size)?);
+            iters.push(Box::new(table.compaction_iter(&read_options)));
+            tombstones.extend(table.range_tombstones().iter()
+                .filter_map(|t| t.truncate(ucmp, self.begin.as_deref(), self.end.as_deref())));
+        }
+        let icmp = Rc::new(InternalKeyComparator::new(self.options.comparator));
+        let lower_levels = &self.lower_levels;
+        let mut input = CompactionIterator::new(
+            Box::new(MergingIterator::new(icmp.clone(), iters)),
+            ObsoleteEntryFilter::new(ucmp, self.smallest_snapshot).with_range_tombstones(tombstones.clone()),
+            Box::new(move |user_key| is_base_level_for_key(ucmp, lower_levels, user_key)));
+        // Tombstones visible to the oldest snapshot have nothing left to
+        // delete once no lower level holds data in their range
+        tombstones.retain(|t| t.sequence > self.smallest_snapshot
+            || !is_base_level_for_range(ucmp, lower_levels, &t.start_key, &t.end_key));
+        let mut output_tombstones = OutputTombstones { user_comparator: ucmp, tombstones, lower: self.begin.clone() };
+
+        let mut output: Option<(TableBuilder, Rc<RefCell<dyn WritableFile>>)> = None;
+        match &self.begin {
+            Some(begin) => {
+                let mut target = Vec::new();
+                append_internal_key(&mut target, &Slice::from_bytes(begin), K_MAX_SEQUENCE_NUMBER, kValueTypeForSeek);
+                input.seek(&Slice::from_bytes(&target));
+            },
+            None => input.seek_to_first()
+        }
+        let mut result = Ok(());
+        // The entries of a user key all go to the same output, so outputs
+        // are only closed when the user key changes
+        let mut last_user_key: Vec<u8> = Vec::new();
+        let mut close_pending = false;
+        while input.valid() && result.is_ok() {
+            let key = input.key();
+            let user_key = extract_user_key(&key);
+            if let Some(end) = &self.end {
+                if ucmp(&user_key, &Slice::from_bytes(end)) != Ordering::Less {
+                    break;
+                }
+            }
+            // Values removed by the compaction filter turn into deletion
+            // markers, unless there is nothing left for a marker to hide
+            let mut deletion = Vec::new();
+            if let Some(sequence) = self.filtered_sequence(&key, &input.value()) {
+                if sequence <= self.smallest_snapshot && is_base_level_for_key(ucmp, lower_levels, &user_key) {
+                    input.next();
+                    continue;
+                }
+                append_internal_key(&mut deletion, &user_key, sequence, ValueType::KTypeDeletion);
+            }
+            close_pending |= self.grandparents.should_stop_before(icmp.as_ref(), &key);
+            if let Some((builder, _)) = &output {
+                // Close output file if it is big enough
+                close_pending |= builder.file_size() >= self.max_output_file_size;
+                if close_pending && ucmp(&user_key, &Slice::from_bytes(&last_user_key)) != Ordering::Equal {
+                    close_pending = false;
+                    result = self.close_output(&mut output, outputs, output_tombstones.take_until(Some(user_key.data())));
+                    if result.is_err() {
+                        break;
+                    }
+                }
+            }
+            if output.is_none() {
+                result = self.open_output(&mut output, outputs);
+                if result.is_err() {
+                    break;
+                }
+            }
+            result = if deletion.is_empty() {
+                self.add_to_output(&mut output, outputs, &key, &input.value())
+            } else {
+                self.add_to_output(&mut output, outputs, &Slice::from_bytes(&deletion), &Slice::from_bytes(&[]))
+            };
+            last_user_key.clear();
+            last_user_key.extend_from_slice(user_key.data());
+            input.next();
+        }
+        if result.is_ok() {
+            result = input.status();
+        }
+        if result.is_ok() && output.is_none() {
+            // Tombstones with no entries left in their range still need a
+            // file of their own
+            let tombstones = output_tombstones.take_until(self.end.as_deref());
+            if tombstones.is_empty() {
+                return Ok(());
+            }
+            self.open_output(&mut output, outputs)?;
+            return self.close_output(&mut output, outputs, tombstones);
+        }
+        match output.as_mut() {
+            Some(_) if result.is_ok() => self.close_output(&mut output, outputs, output_tombstones.take_until(self.end.as_deref())),
+            Some((builder, _)) => {
+                builder.abandon();
+                result
+            },
+            None => result
+        }
+    }
+
+    /// Returns the sequence number of the entry with `key` if it is a value
+    /// no snapshot can read that the compaction filter removes.
+    fn filtered_sequence(&self, key: &Slice, value: &Slice) -> Option<SequenceNumber> {
+        let filter = self.options.compaction_filter.as_ref()?;
+        let ikey = parse_internal_key(key).ok()?;
+        if ikey.value_type != ValueType::KTypeValue || ikey.sequence <= self.newest_snapshot {
+            return None;
+        }
+        filter.filter(ikey.user_key.data(), value.data()).then_some(ikey.sequence)
+    }
+
+    /// Start a new output file, whose metadata is pushed to `outputs`.
+    fn open_output(&self, output: &mut Option<(TableBuilder, Rc<RefCell<dyn WritableFile>>)>,
+                   outputs: &mut Vec<FileMetaData>) -> Result<()> {
+        let number = self.file_numbers.fetch_add(1, AtomicOrdering::Relaxed);
+        let file = new_writable_file(table_file_name(&self.dbname, number), self.options.allow_mmap_writes)?;
+        outputs.push(FileMetaData {
+            number,
+            smallest_seqno: SequenceNumber::MAX,
+            largest_seqno: 0,
+            ..FileMetaData::default()
+        });
+        *output = Some((TableBuilder::new(&self.options, file.clone()), file));
+        Ok(())
+    }
+
+    fn add_to_output(&self, output: &mut Option<(TableBuilder, Rc<RefCell<dyn WritableFile>>)>,
+                     outputs: &mut [FileMetaData], key: &Slice, value: &Slice) -> Result<()> {
+        let sequence = parse_internal_key(key)?.sequence;
+        let (builder, _) = output.as_mut().expect("no output file");
+        let meta = outputs.last_mut().unwrap();
+        if meta.smallest.is_empty() {
+            meta.smallest.extend_from_slice(key.data());
+        }
+        meta.largest.clear();
+        meta.largest.extend_from_slice(key.data());
+        meta.smallest_seqno = meta.smallest_seqno.min(sequence);
+        meta.largest_seqno = meta.largest_seqno.max(sequence);
+        if let Err(e) = builder.add(key.data(), value.data()) {
+            builder.abandon();
+            *output = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Finish the current output file, whose metadata is the last of
+    /// `outputs`, after adding `tombstones` to it.
+    fn close_output(&self, output: &mut Option<(TableBuilder, Rc<RefCell<dyn WritableFile>>)>,
+                    outputs: &mut [FileMetaData], tombstones: Vec<RangeTombstone>) -> Result<()> {
+        let (mut builder, file) = output.take().expect("no output file");
+        let meta = outputs.last_mut().unwrap();
+        let icmp = InternalKeyComparator::new(self.options.comparator);
+        for tombstone in &tombstones {
+            builder.add_range_tombstone(tombstone);
+            meta.add_range_tombstone(&icmp, tombstone);
+        }
+        self.finish_output(&mut builder, &file, meta)
+    }
+
+    fn finish_output(&self, builder: &mut TableBuilder, file: &Rc<RefCell<dyn WritableFile>>, meta: &mut FileMetaData) -> Result<()> {
+        builder.finish()?;
+        meta.file_size = builder.file_size();
+        file.borrow().sync()?;
+        file.borrow().close()?;
+
+        // Verify that the table is usable
+        let fname = table_file_name(&self.dbname, meta.number);
+        let table = Rc::new(Table::open(&self.options, new_random_access_file(&fname)?, meta.number, meta.file_size)?);
+        let mut it = table.iter(&ReadOptions { fill_cache: false, ..ReadOptions::default() });
+        it.seek_to_first();
+        it.status()
+    }
+}
+
+/// Returns true if the information we have available guarantees that
+/// the compaction is producing data in "level+1" for which no data
+/// exists in levels greater than "level+1". `lower_levels` holds the user
+/// key ranges of the files of those levels.
+fn is_base_level_for_key(ucmp: fn(&Slice, &Slice) -> Ordering, lower_levels: &[Vec<(Vec<u8>, Vec<u8>)>], user_key: &Slice) -> bool {
+    lower_levels.iter().all(|files| {
+        // Files are sorted and do not overlap, so only the first file
+        // whose largest key is not before user_key may contain it
+        let i = files.partition_point(|(_, largest)| ucmp(&Slice::from_bytes(largest), user_key) == Ordering::Less);
+        i == files.len() || ucmp(user_key, &Slice::from_bytes(&files[i].0)) == Ordering::Less
+    })
+}
+
+/// Returns true if no level below the output level may hold data for a
+/// user key in [start, end), see is_base_level_for_key().
+fn is_base_level_for_range(ucmp: fn(&Slice, &Slice) -> Ordering, lower_levels: &[Vec<(Vec<u8>, Vec<u8>)>], start: &[u8], end: &[u8]) -> bool {
+    lower_levels.iter().all(|files| {
+        let i = files.partition_point(|(_, largest)| ucmp(&Slice::from_bytes(largest), &Slice::from_bytes(start)) == Ordering::Less);
+        i == files.len() || ucmp(&Slice::from_bytes(&files[i].0), &Slice::from_bytes(end)) != Ordering::Less
+    })
+}
+
+/// The range tombstones written by a compaction job, handed out to its
+/// output files in key order. Each file gets the part of the tombstones
+/// between the first key of the file and the first key of the next one.
+struct OutputTombstones {
+
+    user_comparator: fn(&Slice, &Slice) -> Ordering,
+
+    tombstones: Vec<RangeTombstone>,
+
+    // Start of the range of the next output, None being unbounded
+    lower: Option<Vec<u8>>
+}
+
+impl OutputTombstones {
+
+    /// Returns the tombstones truncated to [lower, upper), and moves lower
+    /// up to `upper`.
+    fn take_until(&mut self, upper: Option<&[u8]>) -> Vec<RangeTombstone> {
+        let result = self.tombstones.iter()
+            .filter_map(|t| t.truncate(self.user_comparator, self.lower.as_deref(), upper))
+            .collect();
+        self.lower = upper.map(|k| k.to_vec());
+        result
+    }
+}
+
+/// Iterates over the entries of the merged compaction inputs that make it
+/// into the output, in order, skipping the ones the ObsoleteEntryFilter
+/// drops. Only forward iteration is supported.
+pub struct CompactionIterator<'a> {
+
+    input: Box<dyn InternalIterator + 'a>,
+
+    filter: ObsoleteEntryFilter,
+
+    is_base_level_for_key: Box<dyn Fn(&Slice) -> bool + 'a>
+}
+
+impl<'a> CompactionIterator<'a> {
+
+    /// `input` yields the entries of all inputs in internal key order.
+    /// `is_base_level_for_key` is handed to ObsoleteEntryFilter::should_drop.
+    pub fn new(input: Box<dyn InternalIterator + 'a>, filter: ObsoleteEntryFilter,
+               is_base_level_for_key: Box<dyn Fn(&Slice) -> bool + 'a>) -> Self {
+        CompactionIterator {
+            input,
+            filter,
+            is_base_level_for_key
+        }
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.input.seek_to_first();
+        self.skip_dropped_entries();
+    }
+
+    /// Position at the first entry kept at or past `target`, which must be
+    /// the first entry of its user key or come before all of them.
+    pub fn seek(&mut self, target: &Slice) {
+        self.input.seek(target);
+        self.skip_dropped_entries();
+    }
+
+    pub fn valid(&self) -> bool {
+        self.input.valid()
+    }
+
+    pub fn key(&self) -> Slice<'_> {
+        self.input.key()
+    }
+
+    pub fn value(&self) -> Slice<'_> {
+        self.input.value()
+    }
+
+    pub fn next(&mut self) {
+        self.input.next();
+        self.skip_dropped_entries();
+    }
+
+    pub fn status(&self) -> Result<()> {
+        self.input.status()
+    }
+
+    fn skip_dropped_entries(&mut self) {
+        while self.input.valid()
+            && self.filter.should_drop(&self.input.key(), |user_key| (self.is_base_level_for_key)(user_key)) {
+            self.input.next();
+        }
+    }
+}
+
+/// Tracks how much of the grandparent level the current output file of a
+/// compaction overlaps, so the file can be cut before a later compaction
+/// of it into the grandparent level becomes too expensive.
+#[derive(Clone)]
+struct GrandparentOverlap {
+
+    // Largest key and size of the grandparent files, sorted by key
+    grandparents: Vec<(Vec<u8>, u64)>,
+
+    index: usize,
+
+    // Some output key has been seen
+    seen_key: bool,
+
+    // Bytes of overlap between current output and grandparent files
+    overlapped_bytes: u64,
+
+    max_overlapped_bytes: u64
+}
+
+impl GrandparentOverlap {
+
+    /// Returns true iff we should stop building the current output
+    /// before processing "internal_key".
+    fn should_stop_before(&mut self, icmp: &InternalKeyComparator, internal_key: &Slice) -> bool {
+        // Scan to find earliest grandparent file that contains key.
+        while self.index < self.grandparents.len()
+            && icmp.compare(internal_key, &Slice::from_bytes(&self.grandparents[self.index].0)) == Ordering::Greater {
+            if self.seen_key {
+                self.overlapped_bytes += self.grandparents[self.index].1;
+            }
+            self.index += 1;
+        }
+        self.seen_key = true;
+
+        if self.overlapped_bytes > self.max_overlapped_bytes {
+            // Too much overlap for current output; start new output
+            self.overlapped_bytes = 0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Decides which entries of a compaction input can be left out of the
+/// output. Entries must be fed in internal key order, i.e. by user key and
+/// then by decreasing sequence number.
+///
+/// An entry is dropped when
+///
+///  * a newer entry for the same user key exists whose sequence number is
+///    visible to the oldest snapshot, so no reader can observe this one, or
+///
+///  * it is a deletion marker visible to the oldest snapshot and no level
+///    below the output level can hold data for its user key, so there is
+///    nothing left for the marker to hide, or
+///
+///  * it is deleted by a range tombstone visible to the oldest snapshot.
+pub struct ObsoleteEntryFilter {
+
+    user_comparator: fn(a: &Slice, b: &Slice) -> Ordering,
+
+    smallest_snapshot: SequenceNumber,
+
+    current_user_key: Option<Vec<u8>>,
+
+    last_sequence_for_key: SequenceNumber,
+
+    range_del: RangeDelAggregator
+}
+
+impl ObsoleteEntryFilter {
+
+    /// `smallest_snapshot` is the sequence number of the oldest live
+    /// snapshot, or the last sequence of the db if there is none.
+    pub fn new(user_comparator: fn(a: &Slice, b: &Slice) -> Ordering, smallest_snapshot: SequenceNumber) -> Self {
+        ObsoleteEntryFilter {
+            user_comparator,
+            smallest_snapshot,
+            current_user_key: None,
+            last_sequence_for_key: K_MAX_SEQUENCE_NUMBER,
+            range_del: RangeDelAggregator::new(user_comparator, smallest_snapshot)
+        }
+    }
+
+    /// Drop the entries deleted by `tombstones`, the range tombstones of
+    /// the compaction inputs.
+    pub fn with_range_tombstones(mut self, tombstones: Vec<RangeTombstone>) -> Self {
+        self.range_del.add(tombstones);
+        self
+    }
+
+    /// Returns true if the entry with `internal_key` can be dropped.
+    /// `is_base_level_for_key` is only consulted for deletion markers and
+    /// must return true if no level below the compaction output may contain
+    /// the given user key, which always holds when compacting into the
+    /// bottommost level.
+    pub fn should_drop<F>(&mut self, internal_key: &Slice, is_base_level_for_key: F) -> bool
+        where F: FnOnce(&Slice) -> bool {
+        let ikey = match parse_internal_key(internal_key) {
+            Ok(ikey) => ikey,
+            Err(_) => {
+                // Do not hide error keys
+                self.current_user_key = None;
+                self.last_sequence_for_key = K_MAX_SEQUENCE_NUMBER;
+                return false;
+            }
+        };
+
+        let first_occurrence = match &self.current_user_key {
+            Some(current) => (self.user_comparator)(&ikey.user_key, &Slice::from_bytes(current)) != Ordering::Equal,
+            None => true
+        };
+        if first_occurrence {
+            // First occurrence of this user key
+            self.current_user_key = Some(ikey.user_key.data().to_vec());
+            self.last_sequence_for_key = K_MAX_SEQUENCE_NUMBER;
+        }
+
+        let drop = if self.last_sequence_for_key <= self.smallest_snapshot {
+            // Hidden by a newer entry for same user key
+            true
+        } else {
+            // For this user key:
+            // (1) there is no data in higher levels
+            // (2) data in lower levels will have larger sequence numbers
+            // (3) data in layers that are being compacted here and have
+            //     smaller sequence numbers will be dropped in the next
+            //     few iterations of this loop (by the rule above).
+            // Therefore this deletion marker is obsolete and can be dropped.
+            let obsolete_deletion = ikey.value_type == ValueType::KTypeDeletion
+                && ikey.sequence <= self.smallest_snapshot
+                && is_base_level_for_key(&ikey.user_key);
+            // Entries deleted by a range tombstone that every snapshot sees
+            // are dropped as well
+            obsolete_deletion || self.range_del.should_delete(&ikey.user_key, ikey.sequence)
+        };
+
+        self.last_sequence_for_key = ikey.sequence;
+        drop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compaction_filter::CompactionFilter;
+    use crate::comparator::bytewise_compare;
+    use super::*;
+
+    fn ikey(user_key: &str, seq: SequenceNumber, t: ValueType) -> Vec<u8> {
+        let mut buf = vec![];
+        append_internal_key(&mut buf, &Slice::from_str(user_key), seq, t);
+        buf
+    }
+
+    fn run(entries: &[Vec<u8>], smallest_snapshot: SequenceNumber, bottommost: bool) -> Vec<bool> {
+        let mut filter = ObsoleteEntryFilter::new(bytewise_compare, smallest_snapshot);
+        entries.iter()
+            .map(|e| filter.should_drop(&Slice::from_bytes(e), |_| bottommost))
+            .collect()
+    }
+
+    #[test]
+    fn test_drop_tombstones_at_bottommost_level() {
+        let entries = vec![
+            ikey("a", 10, ValueType::KTypeDeletion),
+            ikey("a", 5, ValueType::KTypeValue),
+            ikey("b", 7, ValueType::KTypeValue),
+        ];
+        assert_eq!(vec![true, true, false], run(&entries, 100, true));
+    }
+
+    #[test]
+    fn test_keep_tombstones_above_bottommost_level() {
+        let entries = vec![
+            ikey("a", 10, ValueType::KTypeDeletion),
+            ikey("a", 5, ValueType::KTypeValue),
+        ];
+        // The deletion still has to shadow "a" in the levels below, but the
+        // value it hides is dropped.
+        assert_eq!(vec![false, true], run(&entries, 100, false));
+    }
+
+    #[test]
+    fn test_keep_entries_visible_to_snapshot() {
+        let entries = vec![
+            ikey("a", 10, ValueType::KTypeDeletion),
+            ikey("a", 5, ValueType::KTypeValue),
+            ikey("a", 3, ValueType::KTypeValue),
+            ikey("b", 9, ValueType::KTypeValue),
+            ikey("b", 8, ValueType::KTypeValue),
+        ];
+        // A snapshot at 6 still sees a@5 and b@9 is newer than the snapshot,
+        // so b@8 stays visible to it as well.
+        assert_eq!(vec![false, false, true, false, false], run(&entries, 6, true));
+    }
+
+    #[test]
+    fn test_keep_corrupted_keys() {
+        let entries = vec![
+            "x".as_bytes().to_vec(),
+            ikey("a", 5, ValueType::KTypeDeletion),
+        ];
+        assert_eq!(vec![false, true], run(&entries, 10, true));
+    }
+
+    #[test]
+    fn test_drop_entries_deleted_by_range_tombstones() {
+        let entries = [
+            ikey("a", 5, ValueType::KTypeValue),
+            ikey("b", 12, ValueType::KTypeValue),
+            ikey("b", 8, ValueType::KTypeDeletion),
+            ikey("c", 3, ValueType::KTypeValue),
+        ];
+        let run = |smallest_snapshot| {
+            let mut filter = ObsoleteEntryFilter::new(bytewise_compare, smallest_snapshot)
+                .with_range_tombstones(vec![RangeTombstone::new(b"a", b"c", 10)]);
+            entries.iter().map(|e| filter.should_drop(&Slice::from_bytes(e), |_| false)).collect::<Vec<_>>()
+        };
+        assert_eq!(vec![true, false, true, false], run(100));
+        // A snapshot older than the tombstone still sees the entries
+        assert_eq!(vec![false, false, false, false], run(9));
+    }
+
+    #[test]
+    fn test_grandparent_overlap() {
+        let mut overlap = GrandparentOverlap {
+            grandparents: vec![(ikey("b", 1, ValueType::KTypeValue), 40), (ikey("d", 1, ValueType::KTypeValue), 40),
+                               (ikey("f", 1, ValueType::KTypeValue), 40)],
+            index: 0,
+            seen_key: false,
+            overlapped_bytes: 0,
+            max_overlapped_bytes: 50
+        };
+        let icmp = InternalKeyComparator::new(bytewise_compare);
+        let mut stop = |key: &str| overlap.should_stop_before(&icmp, &Slice::from_bytes(&ikey(key, 1, ValueType::KTypeValue)));
+        // Files the output starts after do not count
+        assert!(!stop("c"));
+        assert!(!stop("d"));
+        // Past d the output overlaps [c, d] and [e, f]
+        assert!(!stop("e"));
+        assert!(stop("g"));
+        // The next output starts over
+        assert!(!stop("h"));
+    }
+
+    fn write_table(dbname: &std::path::Path, number: u64, entries: &[(Vec<u8>, &str)]) -> u64 {
+        write_table_with_tombstones(dbname, number, entries, &[])
+    }
+
+    fn write_table_with_tombstones(dbname: &std::path::Path, number: u64, entries: &[(Vec<u8>, &str)],
+                                   tombstones: &[RangeTombstone]) -> u64 {
+        let file = new_writable_file(table_file_name(dbname, number), false).unwrap();
+        let mut builder = TableBuilder::new(&Options::default(), file.clone());
+        for (key, value) in entries {
+            builder.add(key, value.as_bytes()).unwrap();
+        }
+        for tombstone in tombstones {
+            builder.add_range_tombstone(tombstone);
+        }
+        builder.finish().unwrap();
+        file.borrow().close().unwrap();
+        builder.file_size()
+    }
+
+    fn read_table(dbname: &std::path::Path, meta: &FileMetaData) -> Vec<Vec<u8>> {
+        let file = new_random_access_file(table_file_name(dbname, meta.number)).unwrap();
+        let table = Rc::new(Table::open(&Options::default(), file, meta.number, meta.file_size).unwrap());
+        let mut iter = table.iter(&ReadOptions::default());
+        let mut keys = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            keys.push(iter.key().data().to_vec());
+            iter.next();
+        }
+        keys
+    }
+
+    #[test]
+    fn test_compaction_job() {
+        let dbname = std::env::temp_dir().join("revel_compaction_test_job");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let newer = vec![
+            (ikey("a", 20, ValueType::KTypeValue), "a2"),
+            (ikey("b", 21, ValueType::KTypeDeletion), ""),
+            (ikey("c", 23, ValueType::KTypeValue), "c2"),
+            (ikey("n", 22, ValueType::KTypeDeletion), ""),
+        ];
+        let older = vec![
+            (ikey("a", 10, ValueType::KTypeValue), "a1"),
+            (ikey("b", 11, ValueType::KTypeValue), "b1"),
+            (ikey("c", 5, ValueType::KTypeValue), "c1"),
+            (ikey("n", 12, ValueType::KTypeValue), "n1"),
+        ];
+        let inputs = vec![(10, write_table(&dbname, 10, &newer)), (11, write_table(&dbname, 11, &older))];
+        let run = |smallest_snapshot: SequenceNumber| -> Vec<Vec<u8>> {
+            let job = CompactionJob {
+                dbname: dbname.clone(),
+                options: Options::default(),
+                inputs: inputs.clone(),
+                // A file two levels down holds keys "m" to "p"
+                lower_levels: vec![vec![], vec![(b"m".to_vec(), b"p".to_vec())]],
+                grandparents: GrandparentOverlap {
+                    grandparents: vec![],
+                    index: 0,
+                    seen_key: false,
+                    overlapped_bytes: 0,
+                    max_overlapped_bytes: 0
+                },
+                begin: None,
+                end: None,
+                smallest_snapshot,
+                newest_snapshot: 0,
+                max_output_file_size: 1 << 20,
+                file_numbers: Arc::new(AtomicU64::new(20))
+            };
+            let outputs = job.run().unwrap();
+            assert_eq!(1, outputs.len());
+            assert_eq!(20, outputs[0].number);
+            read_table(&dbname, &outputs[0])
+        };
+
+        // Without older snapshots, only the newest entry of every key is
+        // kept, and deletions only where a lower level may hold the key
+        assert_eq!(vec![ikey("a", 20, ValueType::KTypeValue), ikey("c", 23, ValueType::KTypeValue),
+                        ikey("n", 22, ValueType::KTypeDeletion)], run(100));
+
+        // A snapshot at 15 still reads the older entries, and does not see
+        // the deletions
+        let mut all: Vec<Vec<u8>> = newer.iter().chain(older.iter()).map(|(key, _)| key.clone()).collect();
+        all.sort_by(|a, b| InternalKeyComparator::new(bytewise_compare).compare(&Slice::from_bytes(a), &Slice::from_bytes(b)));
+        assert_eq!(all, run(15));
+    }
+
+    #[test]
+    fn test_compaction_job_range_tombstones() {
+        let dbname = std::env::temp_dir().join("revel_compaction_test_job_range_tombstones");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let newer = vec![(ikey("d", 31, ValueType::KTypeValue), "d2")];
+        let older = vec![
+            (ikey("a", 10, ValueType::KTypeValue), "a1"),
+            (ikey("c", 11, ValueType::KTypeValue), "c1"),
+            (ikey("n", 12, ValueType::KTypeValue), "n1"),
+        ];
+        let tombstone = RangeTombstone::new(b"b", b"z", 30);
+        let inputs = vec![(10, write_table_with_tombstones(&dbname, 10, &newer, std::slice::from_ref(&tombstone))),
+                          (11, write_table(&dbname, 11, &older))];
+        let run = |smallest_snapshot: SequenceNumber, max_output_file_size: u64| -> Vec<FileMetaData> {
+            let job = CompactionJob {
+                dbname: dbname.clone(),
+                // Every entry is written out as soon as it is added
+                options: Options { block_size: 1, ..Options::default() },
+                inputs: inputs.clone(),
+                // A file two levels down holds keys "m" to "p"
+                lower_levels: vec![vec![], vec![(b"m".to_vec(), b"p".to_vec())]],
+                grandparents: GrandparentOverlap {
+                    grandparents: vec![],
+                    index: 0,
+                    seen_key: false,
+                    overlapped_bytes: 0,
+                    max_overlapped_bytes: 0
+                },
+                begin: None,
+                end: None,
+                smallest_snapshot,
+                newest_snapshot: 0,
+                max_output_file_size,
+                file_numbers: Arc::new(AtomicU64::new(20))
+            };
+            job.run().unwrap()
+        };
+        let tombstones = |meta: &FileMetaData| {
+            let file = new_random_access_file(table_file_name(&dbname, meta.number)).unwrap();
+            Table::open(&Options::default(), file, meta.number, meta.file_size).unwrap().range_tombstones().to_vec()
+        };
+
+        // The covered entries are dropped, and the tombstone is kept for
+        // the file below that overlaps it
+        let outputs = run(100, 1 << 20);
+        assert_eq!(1, outputs.len());
+        assert_eq!(vec![ikey("a", 10, ValueType::KTypeValue), ikey("d", 31, ValueType::KTypeValue)],
+                   read_table(&dbname, &outputs[0]));
+        assert_eq!(vec![tombstone.clone()], tombstones(&outputs[0]));
+        assert_eq!(tombstone.end_internal_key(), outputs[0].largest);
+        assert_eq!((10, 31), (outputs[0].smallest_seqno, outputs[0].largest_seqno));
+
+        // Every output gets the part of the tombstone up to the next one
+        let outputs = run(15, 1);
+        assert_eq!(4, outputs.len());
+        for (meta, (start, end)) in outputs.iter().zip([("b", "c"), ("c", "d"), ("d", "n"), ("n", "z")]) {
+            let expected = RangeTombstone::new(start.as_bytes(), end.as_bytes(), 30);
+            assert_eq!(vec![expected.clone()], tombstones(meta));
+            assert_eq!(expected.end_internal_key(), meta.largest);
+        }
+    }
+
+    #[test]
+    fn test_compaction_job_tombstone_only_output() {
+        let dbname = std::env::temp_dir().join("revel_compaction_test_job_tombstone_only");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let tombstone = RangeTombstone::new(b"b", b"f", 30);
+        let inputs = vec![(10, write_table_with_tombstones(&dbname, 10, &[], std::slice::from_ref(&tombstone))),
+                          (11, write_table(&dbname, 11, &[(ikey("c", 11, ValueType::KTypeValue), "c1")]))];
+        let run = |lower_levels: Vec<Vec<(Vec<u8>, Vec<u8>)>>| -> Vec<FileMetaData> {
+            let job = CompactionJob {
+                dbname: dbname.clone(),
+                options: Options::default(),
+                inputs: inputs.clone(),
+                lower_levels,
+                grandparents: GrandparentOverlap {
+                    grandparents: vec![],
+                    index: 0,
+                    seen_key: false,
+                    overlapped_bytes: 0,
+                    max_overlapped_bytes: 0
+                },
+                begin: None,
+                end: None,
+                smallest_snapshot: 100,
+                newest_snapshot: 0,
+                max_output_file_size: 1 << 20,
+                file_numbers: Arc::new(AtomicU64::new(20))
+            };
+            job.run().unwrap()
+        };
+
+        // A lower level overlapping the range keeps the tombstone alive
+        let outputs = run(vec![vec![(b"e".to_vec(), b"g".to_vec())]]);
+        assert_eq!(1, outputs.len());
+        assert!(read_table(&dbname, &outputs[0]).is_empty());
+        assert_eq!(tombstone.internal_key(), outputs[0].smallest);
+        assert_eq!(tombstone.end_internal_key(), outputs[0].largest);
+
+        // Otherwise nothing is left
+        assert!(run(vec![vec![(b"f".to_vec(), b"g".to_vec())]]).is_empty());
+    }
+
+    struct DropValues;
+
+    impl CompactionFilter for DropValues {
+        fn name(&self) -> &str {
+            "DropValues"
+        }
+
+        fn filter(&self, _key: &[u8], value: &[u8]) -> bool {
+            value == b"drop"
+        }
+    }
+
+    #[test]
+    fn test_compaction_job_filter() {
+        let dbname = std::env::temp_dir().join("revel_compaction_test_job_filter");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let newer = vec![
+            (ikey("a", 20, ValueType::KTypeValue), "a2"),
+            (ikey("b", 21, ValueType::KTypeValue), "drop"),
+            (ikey("n", 22, ValueType::KTypeValue), "drop"),
+        ];
+        let older = vec![
+            (ikey("b", 11, ValueType::KTypeValue), "b1"),
+            (ikey("n", 12, ValueType::KTypeValue), "n1"),
+        ];
+        let inputs = vec![(10, write_table(&dbname, 10, &newer)), (11, write_table(&dbname, 11, &older))];
+        let run = |smallest_snapshot: SequenceNumber, newest_snapshot: SequenceNumber| -> Vec<Vec<u8>> {
+            let job = CompactionJob {
+                dbname: dbname.clone(),
+                options: Options {
+                    compaction_filter: Some(Arc::new(DropValues)),
+                    ..Options::default()
+                },
+                inputs: inputs.clone(),
+                // A file two levels down holds keys "m" to "p"
+                lower_levels: vec![vec![], vec![(b"m".to_vec(), b"p".to_vec())]],
+                grandparents: GrandparentOverlap {
+                    grandparents: vec![],
+                    index: 0,
+                    seen_key: false,
+                    overlapped_bytes: 0,
+                    max_overlapped_bytes: 0
+                },
+                begin: None,
+                end: None,
+                smallest_snapshot,
+                newest_snapshot,
+                max_output_file_size: 1 << 20,
+                file_numbers: Arc::new(AtomicU64::new(20))
+            };
+            job.run().unwrap().iter().flat_map(|meta| read_table(&dbname, meta)).collect()
+        };
+
+        // Removed values leave a deletion marker only where a lower level
+        // may hold the key
+        assert_eq!(vec![ikey("a", 20, ValueType::KTypeValue), ikey("n", 22, ValueType::KTypeDeletion)], run(100, 0));
+
+        // Values a snapshot can read are not filtered
+        assert_eq!(vec![ikey("a", 20, ValueType::KTypeValue), ikey("b", 21, ValueType::KTypeValue),
+                        ikey("b", 11, ValueType::KTypeValue), ikey("n", 22, ValueType::KTypeValue),
+                        ikey("n", 12, ValueType::KTypeValue)], run(15, 25));
+
+        // Only the values past the newest snapshot are
+        assert_eq!(vec![ikey("a", 20, ValueType::KTypeValue), ikey("b", 21, ValueType::KTypeValue),
+                        ikey("b", 11, ValueType::KTypeValue), ikey("n", 22, ValueType::KTypeDeletion),
+                        ikey("n", 12, ValueType::KTypeValue)], run(15, 21));
+    }
+
+    fn file(number: u64, smallest: &str, largest: &str, file_size: u64) -> FileMetaData {
+        FileMetaData {
+            number,
+            file_size,
+            smallest: ikey(smallest, 1, ValueType::KTypeValue),
+            largest: ikey(largest, 1, ValueType::KTypeValue),
+            ..FileMetaData::default()
+        }
+    }
+
+    #[test]
+    fn test_subcompaction_boundaries() {
+        let files = [file(1, "a", "z", 100), file(2, "a", "f", 100), file(3, "g", "m", 100),
+                     file(4, "g", "k", 100), file(5, "n", "r", 100), file(6, "s", "z", 100)];
+        let files: Vec<&FileMetaData> = files.iter().collect();
+        let boundaries = |max| subcompaction_boundaries(bytewise_compare, &files, max);
+        assert!(boundaries(1).is_empty());
+        assert_eq!(vec![b"g".to_vec()], boundaries(2));
+        assert_eq!(vec![b"g".to_vec(), b"n".to_vec()], boundaries(3));
+        // Files sharing a smallest key are never split
+        assert_eq!(vec![b"g".to_vec(), b"n".to_vec(), b"s".to_vec()], boundaries(6));
+        assert!(subcompaction_boundaries(bytewise_compare, &files[..1], 4).is_empty());
+    }
+
+    #[test]
+    fn test_run_subcompactions() {
+        let dbname = std::env::temp_dir().join("revel_compaction_test_subcompactions");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("key{:03}", i)).collect();
+        let newer: Vec<(Vec<u8>, &str)> = keys.iter().step_by(2)
+            .map(|k| (ikey(k, 200, ValueType::KTypeDeletion), "")).collect();
+        let older: Vec<(Vec<u8>, &str)> = keys.iter()
+            .map(|k| (ikey(k, 100, ValueType::KTypeValue), "v")).collect();
+        let inputs = vec![(10, write_table(&dbname, 10, &newer)), (11, write_table(&dbname, 11, &older))];
+        let file_numbers = Arc::new(AtomicU64::new(20));
+        let job = |begin: Option<&str>, end: Option<&str>| CompactionJob {
+            dbname: dbname.clone(),
+            options: Options::default(),
+            inputs: inputs.clone(),
+            lower_levels: vec![],
+            grandparents: GrandparentOverlap {
+                grandparents: vec![],
+                index: 0,
+                seen_key: false,
+                overlapped_bytes: 0,
+                max_overlapped_bytes: 0
+            },
+            begin: begin.map(|k| k.as_bytes().to_vec()),
+            end: end.map(|k| k.as_bytes().to_vec()),
+            smallest_snapshot: 1000,
+            newest_snapshot: 0,
+            max_output_file_size: 1 << 20,
+            file_numbers: file_numbers.clone()
+        };
+        let outputs = run_subcompactions(vec![job(None, Some("key030")), job(Some("key030"), Some("key071")),
+                                              job(Some("key071"), None)]).unwrap();
+        assert_eq!(3, outputs.len());
+        let keys: Vec<Vec<u8>> = outputs.iter().flat_map(|meta| read_table(&dbname, meta)).collect();
+        let expected: Vec<Vec<u8>> = (0..100).filter(|i| i % 2 == 1)
+            .map(|i| ikey(&format!("key{:03}", i), 100, ValueType::KTypeValue))
+            .collect();
+        assert_eq!(expected, keys);
+        assert_eq!(ikey("key031", 100, ValueType::KTypeValue), outputs[1].smallest);
+        assert_eq!(ikey("key069", 100, ValueType::KTypeValue), outputs[1].largest);
+
+        // A failing job takes the outputs of the others with it
+        let numbers = file_numbers.load(AtomicOrdering::Relaxed);
+        let mut failing = job(Some("key050"), None);
+        failing.inputs = vec![(99, inputs[1].1)];
+        assert!(run_subcompactions(vec![job(None, Some("key050")), failing]).is_err());
+        assert!(file_numbers.load(AtomicOrdering::Relaxed) > numbers);
+        for number in numbers..file_numbers.load(AtomicOrdering::Relaxed) {
+            assert!(!table_file_name(&dbname, number).exists());
+        }
+    }
+}
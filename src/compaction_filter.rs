@@ -0,0 +1,27 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A CompactionFilter lets an application remove values while they are
+//! compacted, e.g. to expire data, see Options::compaction_filter.
+
+pub trait CompactionFilter: Send + Sync {
+
+    /// Return the name of this filter.
+    fn name(&self) -> &str;
+
+    /// Returns true if the value of `key` is to be removed from the db.
+    /// Removed values read as deleted once the compaction is installed.
+    ///
+    /// The filter is only consulted for values that no snapshot can read,
+    /// and may run on several compaction threads at once.
+    fn filter(&self, key: &[u8], value: &[u8]) -> bool;
+}
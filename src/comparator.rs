@@ -18,4 +18,13 @@ pub trait Comparator {
     fn compare(&self, a: &Slice, b: &Slice) -> Ordering;
 
     fn name(&self) -> &str;
-}
\ No newline at end of file
+}
+
+/// Name of the ordering implemented by bytewise_compare.
+pub const BYTEWISE_COMPARATOR_NAME: &str = "leveldb.BytewiseComparator";
+
+/// Orders keys lexicographically by their bytes, the default ordering used
+/// by the db.
+pub fn bytewise_compare(a: &Slice, b: &Slice) -> Ordering {
+    a.data().cmp(b.data())
+}
@@ -10,25 +10,193 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::fs::{File, OpenOptions};
-use std::path::Path;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
+use std::fmt::Write;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::sync::{Arc, Condvar, Mutex, MutexGuard};
-use crate::options::{Options, ReadOptions, WriteOptions};
+use std::time::{Duration, Instant};
+use crate::options::{DataBlockIndexType, FlushOptions, FormatFeature, IndexType, K_LATEST_FORMAT_VERSION, Options, ReadOptions, WriteOptions};
 use crate::{log_writer, Result};
-use crate::dbformat::{InternalKeyComparator, LookupKey};
-use crate::env::{PosixWritableFile, WritableFile};
-use crate::error::Error::NotFound;
+use crate::builder::build_table;
+use crate::cache::ShardedLRUCache;
+use crate::db_iter::{DBIter, DBIterator, Keys, Scan, Values};
+use crate::dump::{DumpFormat, read_dump, write_dump};
+use crate::dbformat::{append_internal_key, extract_user_key, kValueTypeForSeek, InternalKeyComparator, LookupKey, SequenceNumber, K_MAX_SEQUENCE_NUMBER};
+use crate::env::{BackgroundPools, FileLock, generate_session_id, generate_unique_id, new_sequential_file, new_writable_file, PeriodicFileSync, Priority, try_lock_file, WritableFile};
+use crate::error::Error::{Corruption, InvalidArgument, IOError, NotFound};
+use crate::iterator::InternalIterator;
+use crate::filename::{current_file_name, descriptor_file_name, FileType, identity_file_name, lock_file_name, log_file_name, parse_file_name, set_current_file, table_file_name, write_string_to_file_sync};
 use crate::memtable::MemTable;
+use crate::range_del::{RangeDelAggregator, RangeTombstone};
 use crate::slice::Slice;
+use crate::snapshot::{Snapshot, SnapshotList};
+use crate::statistics::{HistogramType, Statistics, Ticker};
+use crate::compaction::{run_subcompactions, Compaction};
+use crate::table::merger::MergingIterator;
+use crate::table_cache::TableCache;
 use crate::util::crc::value;
-use crate::version_set::VersionSet;
-use crate::write_batch::{append, byte_size, insert_into, WriteBatch};
+use crate::version_edit::{FileMetaData, K_NUM_LEVELS, VersionEdit};
+use crate::log_reader::Reader;
+use crate::version_set::{CorruptionReporter, Version, VersionSet};
+use crate::write_batch_with_index::{SequenceOffsetIterator, SnapshotIterator};
+use crate::write_batch::{byte_size, check_insertable, count, insert_into, mark_begin_group, mark_commit_group, sequence, GroupReplay, WriteBatch};
+use crate::write_buffer_manager::WriteBufferMember;
+
+// Approximate size of the batches DB::import writes
+const K_IMPORT_BATCH_SIZE: usize = 1 << 20;
+
+// Number of table files kept open, leaving room for the other files of the
+// db under a budget of 1000 open files
+const K_TABLE_CACHE_SIZE: usize = 1000 - 10;
+
+/// Outcome of a job run on the background pools, handed back to the db
+/// thread, which installs it.
+struct JobResult<T> {
+    result: Mutex<Option<Result<T>>>,
+    done: Condvar
+}
+
+impl<T> JobResult<T> {
+
+    fn new() -> Arc<Self> {
+        Arc::new(JobResult { result: Mutex::new(None), done: Condvar::new() })
+    }
+
+    /// Called by the job once done.
+    fn set(&self, result: Result<T>) {
+        *self.result.lock().unwrap() = Some(result);
+        self.done.notify_all();
+    }
+
+    /// Take the result of the job if it is done, or once it is if `wait`
+    /// is set.
+    fn take(&self, wait: bool) -> Option<Result<T>> {
+        let mut result = self.result.lock().unwrap();
+        if wait {
+            result = self.done.wait_while(result, |result| result.is_none()).unwrap();
+        }
+        result.take()
+    }
+}
+
+/// Handle the db keeps on a job running on the background pools.
+type PendingJob<T> = Arc<JobResult<T>>;
+
+/// Why writes are currently being slowed down or stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StallReason {
+    None,
+    Level0Slowdown,
+    Level0Stop,
+    PendingCompactionBytesSlowdown,
+    PendingCompactionBytesStop
+}
+
+impl StallReason {
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StallReason::None => "none",
+            StallReason::Level0Slowdown => "level0-slowdown",
+            StallReason::Level0Stop => "level0-stop",
+            StallReason::PendingCompactionBytesSlowdown => "pending-compaction-bytes-slowdown",
+            StallReason::PendingCompactionBytesStop => "pending-compaction-bytes-stop"
+        }
+    }
+}
+
+/// Returns the reason writes have to be stalled given the number of level-0
+/// files and the estimated pending compaction bytes. A stop always wins over
+/// a slowdown.
+fn stall_reason(options: &Options, num_level0_files: usize, pending_compaction_bytes: u64) -> StallReason {
+    let hard_limit = options.hard_pending_compaction_bytes_limit;
+    let soft_limit = options.soft_pending_compaction_bytes_limit;
+    if num_level0_files >= options.level0_stop_writes_trigger {
+        StallReason::Level0Stop
+    } else if hard_limit > 0 && pending_compaction_bytes >= hard_limit {
+        StallReason::PendingCompactionBytesStop
+    } else if num_level0_files >= options.level0_slowdown_writes_trigger {
+        StallReason::Level0Slowdown
+    } else if soft_limit > 0 && pending_compaction_bytes >= soft_limit {
+        StallReason::PendingCompactionBytesSlowdown
+    } else {
+        StallReason::None
+    }
+}
+
+/// A range of user keys, from start (included) to limit (not included).
+#[derive(Debug)]
+pub struct Range<'a> {
+    pub start: Slice<'a>,
+    pub limit: Slice<'a>
+}
+
+impl<'a> Range<'a> {
+    pub fn new(start: Slice<'a>, limit: Slice<'a>) -> Self {
+        Range { start, limit }
+    }
+}
+
+/// Metadata of a table file that is part of the current version.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LiveFileMetaData {
+    /// Name of the file relative to the db directory, e.g. "/000012.ldb"
+    pub name: String,
+    /// Level the file belongs to
+    pub level: usize,
+    pub file_number: u64,
+    /// File size in bytes
+    pub size: u64,
+    /// Smallest user key in the file
+    pub smallest_key: Vec<u8>,
+    /// Largest user key in the file
+    pub largest_key: Vec<u8>,
+    /// Smallest sequence number in the file
+    pub smallest_seqno: u64,
+    /// Largest sequence number in the file
+    pub largest_seqno: u64
+}
+
+/// Per level compaction stats. level_stats[level] stores the stats for
+/// compactions that produced data for the specified "level".
+#[derive(Clone, Copy, Default)]
+struct CompactionStats {
+    bytes_read: u64,
+    bytes_written: u64
+}
 
 pub struct DB {
+    dbname: PathBuf,
+
+    // Lock on the LOCK file, held while the db is open
+    _db_lock: Box<dyn FileLock>,
+
+    // Unique id of the db, kept in the IDENTITY file across opens
+    db_id: String,
+
+    // Unique id of this open of the db
+    db_session_id: String,
+
+    options: Options,
+
     logfile: Rc<RefCell<dyn WritableFile>>,
+
+    logfile_number: u64,
+
+    // For sampling the reads of iterators
+    seed: Cell<u32>,
+
+    // Snapshots handed out by get_snapshot() and not released yet
+    snapshots: Rc<RefCell<SnapshotList>>,
+
+    // Number of outstanding disable_file_deletions() calls. Obsolete files
+    // must not be deleted while this is greater than zero.
+    disable_delete_obsolete_files: usize,
+
     // Queue of writers
     writers: Mutex<VecDeque<Writer>>,
 
@@ -38,31 +206,155 @@ pub struct DB {
 
     log: log_writer::Writer,
 
-    mem: MemTable
+    // Shared with the iterators reading it, which can outlive a switch
+    mem: Arc<MemTable>,
+
+    // Memtable being flushed
+    imm: Option<Arc<MemTable>>,
+
+    // Flush of imm running in the background. The table is written by the
+    // flush pool, and installed in the version set by the db once done.
+    // Along with the number of the table being written.
+    pending_flush: Option<(u64, PendingJob<FileMetaData>)>,
+
+    // Compaction running in the background, with the files it writes. Along
+    // with the first file number its outputs may use: they are allocated
+    // as the compaction goes.
+    bg_compaction: Option<(Compaction, u64, PendingJob<Vec<FileMetaData>>)>,
+
+    table_cache: TableCache,
+
+    bg_pools: BackgroundPools,
+
+    stats: Arc<Statistics>,
+
+    level_stats: [CompactionStats; K_NUM_LEVELS],
+
+    // Syncs the log in the background if options.wal_sync_interval is set
+    wal_sync: Option<PeriodicFileSync>,
+
+    // Registration of the memtable with options.write_buffer_manager
+    write_buffer: Option<WriteBufferMember>,
+
+    // Set once the db is shut down by close() or drop
+    closed: bool
 }
 
 impl DB {
-    pub fn open(options: &Options, str: &str) -> Result<DB> {
-        let path = <Path as AsRef<Path>>::as_ref(Path::new(str));
-        let mut create = true;
-        if path.exists() && File::open(path)?.metadata()?.len() > 0 {
-            create = false;
-        }
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(create)
-            .open(path)? ;
-        let logfile = Rc::new(RefCell::new(PosixWritableFile::new(str, file)));
+    pub fn open(options: &Options, dbname: impl AsRef<Path>) -> Result<DB> {
+        let dbname = dbname.as_ref();
+        if options.format_version == 0 || options.format_version > K_LATEST_FORMAT_VERSION {
+            return Err(InvalidArgument);
+        }
+        if options.index_type == IndexType::TwoLevelIndexSearch && !options.supports(FormatFeature::PartitionedIndex) {
+            return Err(InvalidArgument);
+        }
+        if options.data_block_index_type == DataBlockIndexType::BinaryAndHash
+            && !options.supports(FormatFeature::DataBlockHashIndex) {
+            return Err(InvalidArgument);
+        }
+        if options.wal_sync_interval == Some(Duration::ZERO) {
+            return Err(InvalidArgument);
+        }
+        let options = &sanitize_options(options);
+        create_dir_all(dbname)?;
+        let db_lock = lock_db(dbname, options.lock_wait_timeout)?;
+        if !current_file_name(dbname).exists() {
+            new_db(options, dbname)?;
+        }
+        let mut versions = VersionSet::new(dbname, options);
+        versions.recover()?;
+        let stats = Arc::new(Statistics::new());
+
+        // Recover the updates logged since the last flush, writing them to
+        // level-0 tables. Besides the logs named in the descriptor, all
+        // newer logs are replayed: they may have been added by the
+        // previous incarnation without registering them in the descriptor.
+        let min_log = versions.log_number();
+        let prev_log = versions.prev_log_number();
+        let mut expected = HashSet::new();
+        versions.add_live_files(&mut expected);
+        let mut logs = Vec::new();
+        for entry in std::fs::read_dir(dbname)? {
+            let name = entry?.file_name();
+            if let Some((number, file_type)) = name.to_str().and_then(parse_file_name) {
+                expected.remove(&number);
+                if file_type == FileType::kLogFile && (number >= min_log || number == prev_log) {
+                    logs.push(number);
+                }
+            }
+        }
+        if !expected.is_empty() {
+            // "N missing files; e.g.: <dbname>/<number>.ldb"
+            return Err(Corruption);
+        }
+
+        // Recover in the order in which the logs were generated
+        logs.sort_unstable();
+        let mut edit = VersionEdit::new();
+        let mut max_sequence = 0;
+        for log_number in logs {
+            recover_log_file(dbname, options, &stats, &mut versions, log_number, &mut edit, &mut max_sequence)?;
+            // The previous incarnation may not have written any MANIFEST
+            // records after allocating this log number.  So we manually
+            // update the file number allocation counter in VersionSet.
+            versions.mark_file_number_used(log_number);
+        }
+        if versions.last_sequence() < max_sequence {
+            versions.set_last_sequence(max_sequence);
+        }
+
+        let logfile_number = versions.new_file_number();
+        let log_name = log_file_name(dbname, logfile_number);
+        let logfile = new_writable_file(&log_name, options.allow_mmap_wal_writes)?;
+        let db_id = get_or_create_db_identity(dbname)?;
+
+        edit.set_prev_log_number(0);  // No older logs needed after recovery.
+        edit.set_log_number(logfile_number);
+        versions.log_and_apply(&mut edit)?;
+
+        let wal_sync = match options.wal_sync_interval {
+            Some(interval) => {
+                let stats = stats.clone();
+                Some(PeriodicFileSync::new(&log_name, interval, move |result| {
+                    if result.is_ok() {
+                        stats.record_tick(Ticker::WalFileSynced, 1);
+                    }
+                })?)
+            },
+            None => None
+        };
+
         let internalKeyComparator = InternalKeyComparator::new(options.comparator);
-        let db = DB {
+        let mut db = DB {
+            dbname: dbname.to_path_buf(),
+            _db_lock: db_lock,
+            db_id,
+            db_session_id: generate_session_id(),
+            options: options.clone(),
             logfile: logfile.clone(),
+            logfile_number,
+            seed: Cell::new(0),
+            snapshots: Rc::new(RefCell::new(SnapshotList::new())),
+            disable_delete_obsolete_files: 0,
             writers: Mutex::new(VecDeque::new()),
-            versions: VersionSet::new(str),
+            versions,
             temp_batch: RefCell::new(WriteBatch::new()),
             log: log_writer::Writer::new(logfile.clone()),
-            mem: MemTable::new(internalKeyComparator)
+            mem: Arc::new(MemTable::new(internalKeyComparator)),
+            imm: None,
+            pending_flush: None,
+            bg_compaction: None,
+            level_stats: [CompactionStats::default(); K_NUM_LEVELS],
+            table_cache: TableCache::new(dbname, options, K_TABLE_CACHE_SIZE),
+            bg_pools: BackgroundPools::new(options.background_job_limits()),
+            stats,
+            wal_sync,
+            write_buffer: options.write_buffer_manager.as_ref().map(|manager| manager.register()),
+            closed: false
         };
+        db.delete_obsolete_files();
+        db.maybe_schedule_compaction()?;
         Ok(db)
     }
 
@@ -72,54 +364,937 @@ impl DB {
         self.write(opt, write_batch)
     }
 
+    /// Set the database entry for every key in `pairs` to its value. All
+    /// pairs are applied atomically as a single batch and committed in one
+    /// write. If a key appears several times, the last value wins.
+    pub fn put_batch<I, K, V>(&mut self, opt: &WriteOptions, pairs: I) -> Result<()>
+        where I: IntoIterator<Item=(K, V)>, K: AsRef<[u8]>, V: AsRef<[u8]> {
+        let mut write_batch = WriteBatch::new();
+        for (key, value) in pairs {
+            write_batch.put(&Slice::from_bytes(key.as_ref()), &Slice::from_bytes(value.as_ref()));
+        }
+        self.write(opt, write_batch)
+    }
+
     pub fn delete(&mut self, opt: &WriteOptions, key: &Slice) -> Result<()> {
         let mut write_batch = WriteBatch::new();
         write_batch.delete(key);
         self.write(opt, write_batch)
     }
+
+    /// Remove the database entries for every key in [begin, end). The
+    /// range is recorded as a single tombstone, so the cost does not
+    /// depend on the number of keys deleted. Fails with InvalidArgument if
+    /// begin sorts after end.
+    pub fn delete_range(&mut self, opt: &WriteOptions, begin: &Slice, end: &Slice) -> Result<()> {
+        if (self.options.comparator)(begin, end) == Ordering::Greater {
+            return Err(InvalidArgument);
+        }
+        let mut write_batch = WriteBatch::new();
+        write_batch.delete_range(begin, end);
+        self.write(opt, write_batch)
+    }
+
+    /// Atomically replace the value of `key` with the result of `f`, which
+    /// is called with the current value, or None if there is none. A None
+    /// result deletes the key. Returns the new value.
+    ///
+    /// The db is borrowed mutably for the whole read-modify-write, so no
+    /// other write can come in between the read and the write.
+    pub fn update<F>(&mut self, opt: &WriteOptions, key: &Slice, f: F) -> Result<Option<Vec<u8>>>
+        where F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>> {
+        let old = match self.get(&ReadOptions::default(), key) {
+            Ok(value) => Some(value),
+            Err(NotFound) => None,
+            Err(e) => return Err(e)
+        };
+        let new = f(old.as_deref());
+        match &new {
+            Some(value) => self.put(opt, key, &Slice::from_bytes(value))?,
+            None if old.is_some() => self.delete(opt, key)?,
+            // Nothing to delete
+            None => {}
+        }
+        Ok(new)
+    }
     
     pub fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Vec<u8>> {
-        let snapshot;
-        {
-            let lock = self.writers.lock();
-            snapshot = self.versions.last_sequence();
-            drop(lock);
+        let start = Instant::now();
+        let snapshot = self.read_sequence(options);
+        let result = self.get_from(options, &self.versions.current(), &LookupKey::new(key, snapshot));
+        self.stats.measure_time(HistogramType::Get, start.elapsed());
+        result
+    }
+
+    /// Looks up each of `keys`, and returns the results in the same order,
+    /// as get() would. All the keys are read from the same state of the db,
+    /// and they are looked up in key order, so that lookups of nearby keys
+    /// find the table blocks they need already read.
+    pub fn multi_get(&self, options: &ReadOptions, keys: &[Slice]) -> Vec<Result<Vec<u8>>> {
+        let snapshot = self.read_sequence(options);
+        let current = self.versions.current();
+        let mut order: Vec<usize> = (0..keys.len()).collect();
+        order.sort_by(|&a, &b| (self.options.comparator)(&keys[a], &keys[b]));
+        let mut results = vec![Err(NotFound); keys.len()];
+        for i in order {
+            results[i] = self.get_from(options, &current, &LookupKey::new(&keys[i], snapshot));
         }
+        results
+    }
+
+    /// Looks `key` up in the memtable, the immutable memtable, and then the
+    /// tables of `version`.
+    fn get_from(&self, options: &ReadOptions, version: &Version, key: &LookupKey) -> Result<Vec<u8>> {
+        match self.mem.get(key) {
+            (true, result) => result,
+            (false, _) => match self.imm.as_ref().map(|imm| imm.get(key)) {
+                Some((true, result)) => result,
+                _ => version.get(options, key, &self.table_cache, self.options.comparator)
+            }
+        }
+    }
+
+    /// Returns whether the database holds a value for `key`. Cheaper than
+    /// get() when only existence matters: the lookup stops at the newest
+    /// visible version of the key without copying its value.
+    pub fn contains_key(&self, options: &ReadOptions, key: &Slice) -> Result<bool> {
+        let snapshot = self.read_sequence(options);
         let lkey = LookupKey::new(key, snapshot);
-        match self.mem.get(&lkey) {
-            (true, Ok(value)) => Ok(value),
-            _ => Err(NotFound)
+        if let Some(found) = self.mem.contains_key(&lkey) {
+            return Ok(found);
+        }
+        if let Some(found) = self.imm.as_ref().and_then(|imm| imm.contains_key(&lkey)) {
+            return Ok(found);
+        }
+        match self.versions.current().get(options, &lkey, &self.table_cache, self.options.comparator) {
+            Ok(_) => Ok(true),
+            Err(NotFound) => Ok(false),
+            Err(e) => Err(e)
         }
     }
 
+    /// Returns a scan over the entries with keys in [lower, upper) as of
+    /// now, or as of options.snapshot; a missing bound leaves that side of
+    /// the range open. The scan
+    /// can be consumed from both ends, e.g. with rev() or next_back().
+    pub fn scan(&self, options: &ReadOptions, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Scan<'_> {
+        let snapshot = self.read_sequence(options);
+        let user_comparator = self.options.comparator;
+        let current = self.versions.current();
+        let front = DBIter::new(user_comparator, self.new_internal_iterator(options), snapshot)
+            .sample_reads(&current, self.next_seed())
+            .with_range_tombstones(self.new_range_del_aggregator(&current, snapshot));
+        let back = DBIter::new(user_comparator, self.new_internal_iterator(options), snapshot)
+            .sample_reads(&current, self.next_seed())
+            .with_range_tombstones(self.new_range_del_aggregator(&current, snapshot));
+        Scan::new(user_comparator, front, back, lower, upper)
+            .pin_version(current)
+            .record_seeks(self.stats.clone())
+    }
+
+    /// Returns an iterator over the contents of the database as of now, or
+    /// as of options.snapshot: the memtables and the tables of the current
+    /// version. The iterator starts unpositioned, call one of the seek
+    /// methods before using it.
+    ///
+    /// The iterator does not borrow the db: the db can be written to while
+    /// it is in use, and the memtables and files it reads stay around until
+    /// it is dropped. Call DBIterator::refresh() to make it see the writes
+    /// made since.
+    pub fn new_iterator(&self, options: &ReadOptions) -> DBIterator<'static> {
+        let snapshot = self.read_sequence(options);
+        let current = self.versions.current();
+        let iter = DBIter::new(self.options.comparator, self.new_internal_iterator(options), snapshot)
+            .sample_reads(&current, self.next_seed())
+            .with_range_tombstones(self.new_range_del_aggregator(&current, snapshot));
+        // An iterator reading at a snapshot must keep reading at it
+        let refresh = options.snapshot.is_none().then(|| options.without_snapshot());
+        DBIterator::new(iter, current, self.stats.clone(), refresh)
+    }
+
+    /// Like new_iterator(), with the `count` updates of `batch`, the index
+    /// of a WriteBatchWithIndex, applied on top. The updates are read as if
+    /// they were written right after the snapshot the iterator reads at.
+    pub(crate) fn new_iterator_with_batch<'a>(&'a self, options: &ReadOptions, batch: &'a MemTable, count: SequenceNumber) -> DBIterator<'a> {
+        let snapshot = self.read_sequence(options);
+        let current = self.versions.current();
+        let iters: Vec<Box<dyn InternalIterator + 'a>> = vec![
+            Box::new(SequenceOffsetIterator::new(batch.iter(), snapshot)),
+            // Entries past the snapshot would look older than the batch
+            Box::new(SnapshotIterator::new(self.new_internal_iterator(options), snapshot))
+        ];
+        let internal_iter = MergingIterator::new(Rc::new(InternalKeyComparator::new(self.options.comparator)), iters);
+        let mut range_del = RangeDelAggregator::new(self.options.comparator, snapshot + count);
+        range_del.add(self.range_tombstones(&current).into_iter().filter(|t| t.sequence <= snapshot));
+        range_del.add(batch.range_tombstones().into_iter()
+            .map(|t| RangeTombstone { sequence: t.sequence + snapshot, ..t }));
+        let iter = DBIter::new(self.options.comparator, Box::new(internal_iter), snapshot + count)
+            .sample_reads(&current, self.next_seed())
+            .with_range_tombstones(range_del);
+        DBIterator::new(iter, current, self.stats.clone(), None)
+    }
+
+    /// Return a handle to the current DB state. Reads created with this
+    /// handle in ReadOptions::snapshot observe a stable snapshot of the
+    /// current DB state, and compactions keep the entries it can see. The
+    /// snapshot is released when dropped.
+    pub fn get_snapshot(&self) -> Snapshot {
+        let lock = self.writers.lock();
+        let snapshot = Snapshot::new(&self.snapshots, self.versions.last_sequence());
+        drop(lock);
+        snapshot
+    }
+
+    /// Release a previously acquired snapshot, the same as dropping it.
+    pub fn release_snapshot(&self, snapshot: Snapshot) {
+        snapshot.release();
+    }
+
+    /// Returns the sequence number a read with `options` reads at: the
+    /// snapshot's if it has one, the latest one otherwise.
+    fn read_sequence(&self, options: &ReadOptions) -> SequenceNumber {
+        if let Some(snapshot) = options.snapshot {
+            return snapshot.sequence_number();
+        }
+        let lock = self.writers.lock();
+        let sequence = self.versions.last_sequence();
+        drop(lock);
+        sequence
+    }
+
+    /// Returns a new seed for the random numbers of an iterator.
+    fn next_seed(&self) -> u32 {
+        let seed = self.seed.get();
+        self.seed.set(seed.wrapping_add(1));
+        seed
+    }
+
+    /// Returns an iterator over the entries of the memtables and the tables
+    /// of the current version, as internal keys.
+    /// The iterator shares the memtables and does not borrow the db.
+    fn new_internal_iterator(&self, options: &ReadOptions) -> Box<dyn InternalIterator> {
+        let mut iters: Vec<Box<dyn InternalIterator>> = vec![Box::new(self.mem.owned_iter())];
+        if let Some(imm) = &self.imm {
+            iters.push(Box::new(imm.owned_iter()));
+        }
+        self.versions.current().add_iterators(options, &self.table_cache, &mut iters);
+        Box::new(MergingIterator::new(Rc::new(InternalKeyComparator::new(self.options.comparator)), iters))
+    }
+
+    /// Collect the range tombstones of the memtables and of the tables of
+    /// `version` visible at `sequence`, the ones iterators must honor.
+    fn new_range_del_aggregator(&self, version: &Version, sequence: SequenceNumber) -> RangeDelAggregator {
+        let mut range_del = RangeDelAggregator::new(self.options.comparator, sequence);
+        range_del.add(self.range_tombstones(version));
+        range_del
+    }
+
+    /// Returns the range tombstones of the memtables and of the tables of
+    /// `version`.
+    fn range_tombstones(&self, version: &Version) -> Vec<RangeTombstone> {
+        let mut tombstones = self.mem.range_tombstones();
+        if let Some(imm) = &self.imm {
+            tombstones.extend(imm.range_tombstones());
+        }
+        version.add_range_tombstones(&self.table_cache, &mut tombstones);
+        tombstones
+    }
+
+    /// Like scan(), but yields only the keys of the entries, without
+    /// copying their values.
+    pub fn keys(&self, options: &ReadOptions, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Keys<'_> {
+        Keys::new(self.scan(options, lower, upper))
+    }
+
+    /// Like scan(), but yields only the values of the entries, in the order
+    /// of their keys.
+    pub fn values(&self, options: &ReadOptions, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Values<'_> {
+        Values::new(self.scan(options, lower, upper))
+    }
+
     pub fn write(&mut self, opt: &WriteOptions, updates: WriteBatch) -> Result<()> {
+        if opt.sync && opt.disable_wal {
+            // Nothing to sync without the log
+            return Err(InvalidArgument);
+        }
+        // A batch that cannot be inserted in full must not be logged
+        check_insertable(&updates)?;
+        let start = Instant::now();
+        self.make_room_for_write()?;
         let mut last_sequence;
         {
             let mut writers = self.writers.lock().unwrap();
-            writers.push_back(Writer::new(updates, opt.sync));
+            writers.push_back(Writer::new(updates, opt));
             last_sequence = self.versions.last_sequence();
             self.build_batch_group(writers);
             let mut write_batch = self.temp_batch.borrow_mut();
             write_batch.set_sequence(last_sequence + 1);
             last_sequence += write_batch.count() as u64;
         }
+        // clean up, whether the write succeeds or not
+        let _clear = ClearOnDrop(&self.temp_batch);
         {
             let write_batch = self.temp_batch.borrow();
-            self.log.add_record(&write_batch.contents())?;
+            if !opt.disable_wal {
+                self.log.add_record(&write_batch.contents())?;
+            }
             if opt.sync {
                 self.logfile.borrow().sync()?;
             }
-            insert_into(&write_batch, &mut self.mem);
+            // Cannot fail for a batch accepted by check_insertable()
+            insert_into(&write_batch, &self.mem)?;
+            if let Some(write_buffer) = &self.write_buffer {
+                write_buffer.set_memory_usage(self.mem.approximate_memory_usage());
+            }
+            self.stats.record_tick(Ticker::NumberKeysWritten, write_batch.count() as u64);
+            self.stats.record_tick(Ticker::BytesWritten, byte_size(&write_batch) as u64);
         }
-        {
-            // clean up
-            self.temp_batch.borrow_mut().clear();
-            self.versions.set_last_sequence(last_sequence);
+        self.versions.set_last_sequence(last_sequence);
+        self.stats.measure_time(HistogramType::Write, start.elapsed());
+        Ok(())
+    }
+
+    /// Apply `batches` atomically, as a group: readers see either none or
+    /// all of them, and so does recovery after a crash in the middle of the
+    /// group. Unlike a single large batch, each batch is logged as its own
+    /// record, bracketed by markers opening and committing the group, so
+    /// the group is not limited by the maximum size of a batch.
+    pub fn write_group<I>(&mut self, opt: &WriteOptions, batches: I) -> Result<()>
+        where I: IntoIterator<Item=WriteBatch> {
+        if opt.sync && opt.disable_wal {
+            // Nothing to sync without the log
+            return Err(InvalidArgument);
+        }
+        // Check every batch before any of them is logged
+        let batches: Vec<WriteBatch> = batches.into_iter().collect();
+        for batch in &batches {
+            check_insertable(batch)?;
+        }
+        let start = Instant::now();
+        self.make_room_for_write()?;
+        let mut batches = batches.into_iter().peekable();
+        let mut group = Vec::new();
+        let mut last_sequence = {
+            let _writers = self.writers.lock().unwrap();
+            self.versions.last_sequence()
+        };
+        // The first sequence number of the group identifies it
+        let group_id = last_sequence + 1;
+        while let Some(mut batch) = batches.next() {
+            if group.is_empty() {
+                mark_begin_group(&mut batch, group_id);
+            }
+            if batches.peek().is_none() {
+                mark_commit_group(&mut batch, group_id);
+            }
+            batch.set_sequence(last_sequence + 1);
+            last_sequence += batch.count() as u64;
+            if !opt.disable_wal {
+                self.log.add_record(&batch.contents())?;
+            }
+            group.push(batch);
+        }
+        if opt.sync {
+            self.logfile.borrow().sync()?;
+        }
+        // Only now that the commit marker is logged do the batches become
+        // visible
+        for batch in &group {
+            // Cannot fail for a batch accepted by check_insertable()
+            insert_into(batch, &self.mem)?;
+            self.stats.record_tick(Ticker::NumberKeysWritten, batch.count() as u64);
+            self.stats.record_tick(Ticker::BytesWritten, byte_size(batch) as u64);
+        }
+        if let Some(write_buffer) = &self.write_buffer {
+            write_buffer.set_memory_usage(self.mem.approximate_memory_usage());
+        }
+        self.versions.set_last_sequence(last_sequence);
+        self.stats.measure_time(HistogramType::Write, start.elapsed());
+        Ok(())
+    }
+
+    /// Sync the log to stable storage, making every write logged so far
+    /// durable, as if it had been written with WriteOptions::sync. This
+    /// lets writes skip the sync and have it done once for many of them.
+    pub fn sync_wal(&self) -> Result<()> {
+        self.logfile.borrow().sync()?;
+        self.stats.record_tick(Ticker::WalFileSynced, 1);
+        Ok(())
+    }
+
+    /// Flush all the data of the memtable to a new level-0 table, e.g. so
+    /// that the logs need not be replayed by the next open. With
+    /// options.wait the flush is done once this returns, otherwise it goes
+    /// on in the background. A flush of the immutable memtable that is
+    /// still running is finished first.
+    pub fn flush(&mut self, options: &FlushOptions) -> Result<()> {
+        if self.mem.approximate_memory_usage() > 0 {
+            if self.imm.is_some() {
+                if self.pending_flush.is_none() {
+                    // The last flush failed, try it again
+                    self.schedule_flush();
+                }
+                self.finish_flush(true)?;
+            }
+            self.switch_memtable()?;
+            self.schedule_flush();
+        } else if self.imm.is_some() && self.pending_flush.is_none() {
+            self.schedule_flush();
+        }
+        if options.wait {
+            self.finish_flush(true)?;
+        }
+        self.maybe_schedule_compaction()
+    }
+
+    /// Close the db: wait for the background flush and compaction, stop
+    /// the background sync of the log, then flush, sync and close the log
+    /// and release the LOCK file. Dropping the db does the same but
+    /// ignores errors, close() returns the first one.
+    pub fn close(mut self) -> Result<()> {
+        self.shutdown()
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+        // Do not leave jobs behind, their files would never be installed
+        let flushed = self.finish_flush(true);
+        let compacted = self.finish_compaction(true);
+        self.wal_sync = None;
+        let mut logfile = self.logfile.borrow_mut();
+        let closed = logfile.flush().and_then(|_| logfile.sync()).and_then(|_| logfile.close());
+        flushed.and(compacted).and(closed)
+    }
+
+    /// Write every entry of the db, as of now, to a new dump at `path` in
+    /// the given format. Returns the number of entries written.
+    pub fn export(&self, path: impl AsRef<Path>, format: DumpFormat) -> Result<u64> {
+        write_dump(path.as_ref(), format, self.scan(&ReadOptions::default(), None, None))
+    }
+
+    /// Put every entry of the dump at `path`, written by export() in any
+    /// format, into the db. The entries are written in batches of about
+    /// K_IMPORT_BATCH_SIZE bytes, so a dump failing to load part way
+    /// leaves the entries before the failing batch in the db. Returns the
+    /// number of entries read.
+    pub fn import(&mut self, path: impl AsRef<Path>) -> Result<u64> {
+        let mut batch = WriteBatch::new();
+        let count = read_dump(path.as_ref(), |key, value| {
+            batch.put(&Slice::from_bytes(key), &Slice::from_bytes(value));
+            if batch.approximate_size() >= K_IMPORT_BATCH_SIZE {
+                let full = std::mem::replace(&mut batch, WriteBatch::new());
+                self.write(&WriteOptions { sync: false, ..WriteOptions::default() }, full)?;
+            }
+            Ok(())
+        })?;
+        self.write(&WriteOptions::default(), batch)?;
+        Ok(count)
+    }
+
+    /// Prevent file deletions. Compactions will continue to occur, but no
+    /// obsolete files will be deleted. Calling this multiple times has the
+    /// same effect as calling it once per matching enable_file_deletions().
+    pub fn disable_file_deletions(&mut self) {
+        self.disable_delete_obsolete_files += 1;
+    }
+
+    /// Allow file deletions again. Each call undoes one earlier
+    /// disable_file_deletions(); if `force` is true, deletions are enabled
+    /// regardless of how many times they were disabled.
+    pub fn enable_file_deletions(&mut self, force: bool) {
+        if force {
+            self.disable_delete_obsolete_files = 0;
+        } else if self.disable_delete_obsolete_files > 0 {
+            self.disable_delete_obsolete_files -= 1;
+        }
+    }
+
+    /// Returns true if obsolete files may currently be deleted.
+    pub fn file_deletions_enabled(&self) -> bool {
+        self.disable_delete_obsolete_files == 0
+    }
+
+    /// Returns the names of all files the database currently depends on,
+    /// relative to the database directory (e.g. "/000001.log"), together
+    /// with the size of the MANIFEST file.
+    ///
+    /// Paired with disable_file_deletions(), the returned files can be copied
+    /// to take a consistent backup of a running database. The MANIFEST keeps
+    /// growing while the db is open, only its first manifest size bytes
+    /// belong to the backup.
+    pub fn get_live_files(&self) -> Result<(Vec<String>, u64)> {
+        let manifest_number = self.versions.manifest_file_number();
+        let mut live = vec![
+            relative_file_name(&current_file_name(&self.dbname)),
+            relative_file_name(&identity_file_name(&self.dbname)),
+            relative_file_name(&descriptor_file_name(&self.dbname, manifest_number)),
+            relative_file_name(&log_file_name(&self.dbname, self.logfile_number))
+        ];
+        live.extend(self.get_live_files_metadata().into_iter().map(|f| f.name));
+        let manifest_size = std::fs::metadata(descriptor_file_name(&self.dbname, manifest_number))?.len();
+        Ok((live, manifest_size))
+    }
+
+    /// Returns the metadata of every table file in the current version,
+    /// ordered by level and, within a level, by the order of the files in
+    /// the version.
+    pub fn get_live_files_metadata(&self) -> Vec<LiveFileMetaData> {
+        let current = self.versions.current();
+        let mut metadata = Vec::new();
+        for level in 0..K_NUM_LEVELS {
+            for f in current.files(level) {
+                metadata.push(LiveFileMetaData {
+                    name: relative_file_name(&table_file_name(&self.dbname, f.number)),
+                    level,
+                    file_number: f.number,
+                    size: f.file_size,
+                    smallest_key: extract_user_key(&Slice::from_bytes(&f.smallest)).data().to_vec(),
+                    largest_key: extract_user_key(&Slice::from_bytes(&f.largest)).data().to_vec(),
+                    smallest_seqno: f.smallest_seqno,
+                    largest_seqno: f.largest_seqno
+                });
+            }
+        }
+        metadata
+    }
+
+    /// Returns, for each of `ranges`, the approximate file system space
+    /// used by the keys in the range. The sizes are those of the table
+    /// files, so data still in the memtables is not counted, and they
+    /// include the effects of compression.
+    pub fn get_approximate_sizes(&self, ranges: &[Range]) -> Vec<u64> {
+        let current = self.versions.current();
+        let offset_of = |user_key: &Slice| {
+            let mut ikey = Vec::new();
+            append_internal_key(&mut ikey, user_key, K_MAX_SEQUENCE_NUMBER, kValueTypeForSeek);
+            current.approximate_offset_of(self.options.comparator, &self.table_cache, &Slice::from_bytes(&ikey))
+        };
+        ranges.iter()
+            .map(|range| offset_of(&range.limit).saturating_sub(offset_of(&range.start)))
+            .collect()
+    }
+
+    /// Suggests that the files overlapping the user key range [begin, end]
+    /// be compacted, e.g. because the range went cold or holds many
+    /// tombstones. A missing bound leaves that side of the range open.
+    ///
+    /// Unlike a manual compaction this does not wait for anything: the
+    /// overlapping files are only marked, and the background compaction
+    /// picks them up later. Files of the last non-empty level are not
+    /// marked since there is no level below them to push data to.
+    pub fn suggest_compact_range(&mut self, begin: Option<&Slice>, end: Option<&Slice>) -> Result<()> {
+        let current = self.versions.current();
+        let last_level = (0..K_NUM_LEVELS).rev().find(|&level| current.num_files(level) > 0);
+        if let Some(last_level) = last_level {
+            for level in 0..last_level {
+                if !current.overlap_in_level(self.options.comparator, level, begin, end) {
+                    continue;
+                }
+                for f in current.get_overlapping_inputs(self.options.comparator, level, begin, end) {
+                    f.marked_for_compaction.set(true);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the unique id of the db. The id is created with the db, stored
+    /// in its IDENTITY file and stays the same across opens, so copies of the
+    /// db made from its live files share it.
+    pub fn get_db_identity(&self) -> &str {
+        &self.db_id
+    }
+
+    /// Returns the unique id of this open of the db, regenerated every time
+    /// the db is opened.
+    pub fn get_db_session_id(&self) -> &str {
+        &self.db_session_id
+    }
+
+    /// Returns the sequence number of the most recent update. Every update
+    /// is assigned the next sequence number when it is written, so a copy
+    /// or scan taken at this number contains exactly the updates numbered
+    /// up to it.
+    pub fn latest_sequence_number(&self) -> u64 {
+        let _writers = self.writers.lock().unwrap();
+        self.versions.last_sequence()
+    }
+
+    /// DB implementations can export properties about their state via this
+    /// method. If "property" is a valid property understood by this DB
+    /// implementation, returns its current value. Otherwise returns None.
+    ///
+    /// Valid property names include:
+    ///
+    ///  "revel.num-files-at-level<N>" - return the number of files at level
+    ///     <N>, where <N> is an ASCII representation of a level number
+    ///     (e.g. "0").
+    ///
+    ///  "revel.levelsummary" - returns a single-line summary of the number
+    ///     of files per level, e.g. "files[ 2 4 8 0 0 0 0 ]".
+    ///
+    ///  "revel.stats" - returns a multi-line string that describes
+    ///     statistics about the internal operation of the DB: the files
+    ///     and size of every level, and the bytes read and written by the
+    ///     flushes and compactions into it.
+    ///
+    ///  "revel.sstables" - returns a multi-line string that describes all
+    ///     of the sstables that make up the db contents.
+    ///
+    ///  "revel.approximate-memory-usage" - returns the approximate number
+    ///     of bytes of memory in use by the DB: the memtables and the block
+    ///     cache.
+    ///
+    ///  "revel.num-snapshots" - returns the number of unreleased snapshots.
+    ///
+    ///  "revel.oldest-snapshot-sequence" - returns the sequence number of
+    ///     the oldest unreleased snapshot, or 0 if there is none.
+    ///
+    ///  "revel.histograms" - returns a multi-line string with the latency
+    ///     histogram (count, average, percentiles and buckets, in
+    ///     microseconds) of every operation type recorded so far.
+    ///
+    ///  "revel.stall-reason" - returns why writes are currently slowed down
+    ///     or stopped, or "none".
+    ///
+    ///  "revel.db-id" - returns the unique id of the db.
+    ///
+    ///  "revel.db-session-id" - returns the unique id of this open of the db.
+    ///
+    ///  "revel.cur-size-active-mem-table" - returns the approximate size of
+    ///     the memtable in bytes.
+    ///
+    ///  "revel.num-immutable-mem-table" - returns the number of memtables
+    ///     waiting to be flushed, 0 or 1.
+    ///
+    ///  "revel.num-live-versions" - returns the number of versions in use,
+    ///     i.e. the current one and the older ones still pinned by scans.
+    ///
+    ///  "revel.compaction-pending" - returns 1 if a compaction is running,
+    ///     some level exceeds its limit or some file ran out of allowed
+    ///     seeks, 0 otherwise.
+    ///
+    ///  "revel.estimate-pending-compaction-bytes" - returns the estimated
+    ///     number of bytes compactions need to rewrite to bring every level
+    ///     under its limit.
+    pub fn get_property(&self, property: &str) -> Option<String> {
+        let rest = property.strip_prefix("revel.")?;
+        if let Some(level) = rest.strip_prefix("num-files-at-level") {
+            let level = level.parse::<usize>().ok()?;
+            if level >= K_NUM_LEVELS {
+                return None;
+            }
+            return Some(self.versions.current().num_files(level).to_string());
+        }
+        match rest {
+            "histograms" => Some(self.stats.histogram_string()),
+            "stall-reason" => Some(self.write_stall_reason().name().to_string()),
+            "db-id" => Some(self.db_id.clone()),
+            "db-session-id" => Some(self.db_session_id.clone()),
+            "levelsummary" => Some(self.versions.level_summary()),
+            "stats" => Some(self.stats_string()),
+            "sstables" => Some(self.versions.current().debug_string()),
+            "approximate-memory-usage" => {
+                let memtables = self.mem.approximate_memory_usage()
+                    + self.imm.as_ref().map_or(0, |imm| imm.approximate_memory_usage());
+                Some((memtables + self.block_cache().usage()).to_string())
+            },
+            "num-snapshots" => Some(self.snapshots.borrow().len().to_string()),
+            "oldest-snapshot-sequence" => {
+                let snapshots = self.snapshots.borrow();
+                Some(if snapshots.is_empty() { 0 } else { snapshots.oldest() }.to_string())
+            },
+            "tickers" => Some(self.stats.ticker_string()),
+            "cur-size-active-mem-table" => Some(self.mem.approximate_memory_usage().to_string()),
+            "num-immutable-mem-table" => Some(self.imm.iter().count().to_string()),
+            "num-live-versions" => Some(self.versions.num_live_versions().to_string()),
+            "compaction-pending" => {
+                let current = self.versions.current();
+                let pending = self.bg_compaction.is_some() || current.compaction_score() >= 1.0
+                    || current.file_to_compact().is_some();
+                Some((pending as u8).to_string())
+            },
+            "estimate-pending-compaction-bytes" => Some(self.versions.current().estimated_compaction_needed_bytes().to_string()),
+            "block-cache-capacity" => Some(self.block_cache().capacity().to_string()),
+            "block-cache-usage" => Some(self.block_cache().usage().to_string()),
+            "block-cache-pinned-usage" => Some(self.block_cache().pinned_usage().to_string()),
+            "block-cache-entries" => Some(self.block_cache().entry_count().to_string()),
+            "block-cache-hits" => Some(self.block_cache().hits().to_string()),
+            "block-cache-misses" => Some(self.block_cache().misses().to_string()),
+            _ => None
+        }
+    }
+
+    /// Returns the table of the "revel.stats" property, listing the levels
+    /// that hold files or had data compacted into them.
+    fn stats_string(&self) -> String {
+        const MB: f64 = 1048576.0;
+        let mut value = String::from("                       Compactions\n\
+                                      Level  Files Size(MB) Read(MB) Write(MB)\n\
+                                      ----------------------------------------\n");
+        for (level, stats) in self.level_stats.iter().enumerate() {
+            let files = self.versions.num_level_files(level);
+            if files == 0 && stats.bytes_read == 0 && stats.bytes_written == 0 {
+                continue;
+            }
+            let _ = writeln!(value, "{:3} {:8} {:8.0} {:8.0} {:9.0}", level, files,
+                             self.versions.num_level_bytes(level) as f64 / MB,
+                             stats.bytes_read as f64 / MB, stats.bytes_written as f64 / MB);
+        }
+        value
+    }
+
+    fn block_cache(&self) -> &ShardedLRUCache<Vec<u8>> {
+        self.options.block_cache.as_ref().expect("block cache is set when the db is opened")
+    }
+
+    /// Returns the reason writes are currently stalled, if any.
+    pub fn write_stall_reason(&self) -> StallReason {
+        let current = self.versions.current();
+        stall_reason(&self.options, current.num_files(0), current.estimated_compaction_needed_bytes())
+    }
+
+    /// Make room in the memtable for a write. Once the memtable holds more
+    /// than options.write_buffer_size bytes, or the memtables sharing the
+    /// write buffer manager exceed its budget, it becomes the immutable
+    /// memtable and is flushed in the background, while writes go to a new
+    /// memtable and log. If the previous flush is still running by then,
+    /// this waits for it. A write is delayed once while writes are stalled
+    /// (see write_stall_reason()), and waits for the running compaction
+    /// while they are stopped.
+    fn make_room_for_write(&mut self) -> Result<()> {
+        let mut allow_delay = true;
+        loop {
+            self.finish_flush(false)?;
+            self.finish_compaction(false)?;
+            self.maybe_schedule_compaction()?;
+            let stall_reason = self.write_stall_reason();
+            if allow_delay && stall_reason != StallReason::None {
+                // We are getting close to hitting a hard limit on the number of
+                // L0 files or pending compaction bytes.  Rather than delaying a
+                // single write by several seconds when we hit the hard limit,
+                // start delaying each individual write by 1ms to reduce latency
+                // variance.
+                std::thread::sleep(Duration::from_millis(1));
+                // Do not delay a single write more than once
+                allow_delay = false;
+            } else if self.mem.approximate_memory_usage() <= self.options.write_buffer_size
+                && !self.write_buffer.as_ref().is_some_and(|write_buffer| write_buffer.should_flush()) {
+                // There is room in current memtable
+                return Ok(());
+            } else if self.imm.is_some() {
+                // We have filled up the current memtable, but the previous
+                // one is still being flushed, so we wait. A flush that
+                // failed is tried again.
+                if self.pending_flush.is_none() {
+                    self.schedule_flush();
+                }
+                self.finish_flush(true)?;
+            } else if matches!(stall_reason, StallReason::Level0Stop | StallReason::PendingCompactionBytesStop)
+                && self.bg_compaction.is_some() {
+                // There are too many level-0 files, or too many bytes waiting
+                // to be compacted.
+                self.finish_compaction(true)?;
+            } else {
+                // Attempt to switch to a new memtable and trigger flush of old
+                self.switch_memtable()?;
+                self.schedule_flush();
+                return Ok(());
+            }
+        }
+    }
+
+    /// Make the memtable immutable, and continue with a new memtable and
+    /// log. REQUIRES: there is no immutable memtable.
+    fn switch_memtable(&mut self) -> Result<()> {
+        assert!(self.imm.is_none());
+        let logfile_number = self.versions.new_file_number();
+        let log_name = log_file_name(&self.dbname, logfile_number);
+        let logfile = new_writable_file(&log_name, self.options.allow_mmap_wal_writes)?;
+        if let Some(wal_sync) = &self.wal_sync {
+            wal_sync.set_file(&log_name)?;
+        }
+        self.logfile = logfile.clone();
+        self.logfile_number = logfile_number;
+        self.log = log_writer::Writer::new(logfile);
+        let mem = std::mem::replace(&mut self.mem, Arc::new(MemTable::new(InternalKeyComparator::new(self.options.comparator))));
+        self.imm = Some(mem);
+        if let Some(write_buffer) = &self.write_buffer {
+            write_buffer.mark_immutable();
+        }
+        Ok(())
+    }
+
+    /// Write imm to a new level-0 table on the flush pool.
+    fn schedule_flush(&mut self) {
+        let imm = self.imm.clone().expect("no memtable to flush");
+        let mut meta = FileMetaData { number: self.versions.new_file_number(), ..FileMetaData::default() };
+        let meta_number = meta.number;
+        let pending = JobResult::new();
+        let result = pending.clone();
+        let (dbname, options, stats) = (self.dbname.clone(), self.options.clone(), self.stats.clone());
+        self.bg_pools.schedule(Priority::High, move || {
+            result.set(write_level0_table(&dbname, &options, &stats, &imm, &mut meta).map(|_| meta));
+        });
+        self.pending_flush = Some((meta_number, pending));
+    }
+
+    /// Install the table written by the flush of imm if the flush is done,
+    /// or once it is if `wait` is set. The memtable is then dropped, along
+    /// with the logs before the current one. A failed flush keeps imm, to
+    /// be flushed again by a later write.
+    fn finish_flush(&mut self, wait: bool) -> Result<()> {
+        let Some(result) = self.pending_flush.as_ref().and_then(|(_, pending)| pending.take(wait)) else {
+            return Ok(());
+        };
+        self.pending_flush = None;
+        let meta = result?;
+
+        let mut edit = VersionEdit::new();
+        // Note that if file_size is zero, the file has been deleted and
+        // should not be added to the manifest.
+        self.level_stats[0].bytes_written += meta.file_size;
+        if meta.file_size > 0 {
+            edit.add_file(0, meta);
+        }
+        // Earlier logs no longer needed
+        edit.set_prev_log_number(0);
+        edit.set_log_number(self.logfile_number);
+        self.versions.log_and_apply(&mut edit)?;
+        self.imm = None;
+        if let Some(write_buffer) = &self.write_buffer {
+            write_buffer.free_immutable();
+        }
+        self.delete_obsolete_files();
+        Ok(())
+    }
+
+    /// Start the compaction picked by the version set, unless one is
+    /// already running. Compactions that only move a file down a level
+    /// are applied right away.
+    fn maybe_schedule_compaction(&mut self) -> Result<()> {
+        while self.bg_compaction.is_none() {
+            let Some(mut compaction) = self.versions.pick_compaction(&self.table_cache) else {
+                return Ok(());
+            };
+            if compaction.is_trivial_move() {
+                // Move file to next level
+                let f = compaction.inputs(0)[0].clone();
+                let mut edit = std::mem::take(compaction.edit());
+                edit.remove_file(compaction.level(), f.number);
+                // The move is the compaction the file may have been marked
+                // for, and its seeks are counted anew at the next level
+                edit.add_file(compaction.level() + 1, FileMetaData {
+                    marked_for_compaction: Cell::new(false),
+                    allowed_seeks: Cell::new(0),
+                    ..(*f).clone()
+                });
+                self.versions.log_and_apply(&mut edit)?;
+                continue;
+            }
+            // Entries visible to the oldest snapshot must be kept
+            let snapshots = self.snapshots.borrow();
+            let (smallest_snapshot, newest_snapshot) = if snapshots.is_empty() {
+                (self.versions.last_sequence(), 0)
+            } else {
+                (snapshots.oldest(), snapshots.newest())
+            };
+            drop(snapshots);
+            let first_output = self.versions.next_file_number();
+            let jobs = compaction.jobs(self.dbname.clone(), &self.options, smallest_snapshot, newest_snapshot, self.versions.file_numbers());
+            let pending = JobResult::new();
+            let (result, stats) = (pending.clone(), self.stats.clone());
+            self.bg_pools.schedule(Priority::Low, move || {
+                let start = Instant::now();
+                let outputs = run_subcompactions(jobs);
+                stats.measure_time(HistogramType::Compaction, start.elapsed());
+                result.set(outputs);
+            });
+            self.bg_compaction = Some((compaction, first_output, pending));
+        }
+        Ok(())
+    }
+
+    /// Install the files written by the running compaction in place of
+    /// its inputs if the compaction is done, or once it is if `wait` is
+    /// set.
+    fn finish_compaction(&mut self, wait: bool) -> Result<()> {
+        let Some(result) = self.bg_compaction.as_ref().and_then(|(_, _, pending)| pending.take(wait)) else {
+            return Ok(());
+        };
+        let (mut compaction, _, _) = self.bg_compaction.take().unwrap();
+        let outputs = result?;
+
+        let mut edit = std::mem::take(compaction.edit());
+        compaction.add_input_deletions(&mut edit);
+        for meta in &outputs {
+            edit.add_file(compaction.level() + 1, meta.clone());
+        }
+        if let Err(e) = self.versions.log_and_apply(&mut edit) {
+            for meta in &outputs {
+                let _ = std::fs::remove_file(table_file_name(&self.dbname, meta.number));
+            }
+            return Err(e);
         }
+        let stats = &mut self.level_stats[compaction.level() + 1];
+        stats.bytes_read += (0..2).flat_map(|which| compaction.inputs(which)).map(|f| f.file_size).sum::<u64>();
+        stats.bytes_written += outputs.iter().map(|meta| meta.file_size).sum::<u64>();
+        // The inputs stay live for as long as the compaction holds its
+        // version
+        drop(compaction);
+        self.delete_obsolete_files();
         Ok(())
     }
 
-    fn build_batch_group(&self, writers: MutexGuard<VecDeque<Writer>>) {
+    /// Delete any files in the db directory that are no longer needed:
+    /// logs older than the ones still to be recovered, MANIFESTs other
+    /// than the current one, and tables not part of any live version.
+    /// Tables being written by background jobs are kept. Errors are
+    /// ignored, the files are retried by the next call.
+    fn delete_obsolete_files(&mut self) {
+        if !self.file_deletions_enabled() {
+            return;
+        }
+        // Make a set of all of the live files
+        let mut live = HashSet::new();
+        self.versions.add_live_files(&mut live);
+        let min_pending_output = [
+            self.pending_flush.as_ref().map(|(number, _)| *number),
+            self.bg_compaction.as_ref().map(|(_, number, _)| *number)
+        ].into_iter().flatten().min().unwrap_or(u64::MAX);
+
+        let Ok(entries) = std::fs::read_dir(&self.dbname) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let Some((number, file_type)) = name.to_str().and_then(parse_file_name) else {
+                continue;
+            };
+            let keep = match file_type {
+                FileType::kLogFile => number >= self.versions.log_number() || number == self.versions.prev_log_number(),
+                // (We keep the current manifest, and any newer one in case
+                // there is a race that allows other incarnations)
+                FileType::KDescriptorFile => number >= self.versions.manifest_file_number(),
+                FileType::kTableFile => live.contains(&number) || number >= min_pending_output,
+                // Any temp files that are currently being written to must
+                // be recorded in pending outputs
+                FileType::kTempFile => number >= min_pending_output,
+                _ => true
+            };
+            if !keep {
+                if file_type == FileType::kTableFile {
+                    self.table_cache.evict(number);
+                }
+                let _ = std::fs::remove_file(self.dbname.join(&name));
+            }
+        }
+    }
+
+    fn build_batch_group(&self, mut writers: MutexGuard<VecDeque<Writer>>) {
         let front = writers.front();
         let first = front.expect("writers should not be empty");
         let mut size = byte_size(&first.batch);
@@ -133,13 +1308,19 @@ impl DB {
         }
 
         let mut result = self.temp_batch.borrow_mut();
+        result.append(&first.batch);
 
-        let mut iter = writers.iter();
-        while let Some(w) = iter.next() {
+        // The first writer always makes it into the group, however large
+        let mut grouped = 1;
+        for w in writers.iter().skip(1) {
             if !first.sync && w.sync {
                 // Do not include a sync write into a batch handled by a non-sync write.
                 break
             }
+            if w.disable_wal != first.disable_wal {
+                // The batch is either logged as a whole or not at all
+                break;
+            }
 
             size += byte_size(&w.batch);
             if size > max_size {
@@ -147,52 +1328,2147 @@ impl DB {
                 break;
             }
             result.append(&w.batch);
+            grouped += 1;
         }
+        // The grouped writers are committed by the caller
+        writers.drain(..grouped);
     }
 }
 
-struct Writer {
-
-    batch: WriteBatch,
-
-    sync: bool,
-
-    done: bool
-
-    //cv: Condvar
+impl Drop for DB {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
 }
 
-impl Writer {
+/// Write the contents of `mem` to the level-0 table meta.number, and fill
+/// in the rest of `meta`. An empty memtable produces no table, and leaves
+/// meta.file_size at zero.
+fn write_level0_table(dbname: &Path, options: &Options, stats: &Statistics, mem: &MemTable, meta: &mut FileMetaData) -> Result<()> {
+    let start = Instant::now();
+    let result = build_table(dbname, options, &mut mem.iter(), &mem.range_tombstones(), meta);
+    stats.measure_time(HistogramType::Flush, start.elapsed());
+    result
+}
 
-    fn new(batch: WriteBatch, sync: bool) -> Self {
-        Writer{
-            batch,
-            sync,
-            done: false
+/// Replay the batches logged in the log file `log_number` into memtables.
+/// A memtable is written to a level-0 table added to `edit` whenever it
+/// grows past write_buffer_size, and at the end of the log. `max_sequence`
+/// is raised to the last sequence number replayed. Corrupted records are
+/// skipped, unless paranoid_checks is set, and so are the batches of a
+/// group whose commit was never logged or that lost one of its records.
+fn recover_log_file(dbname: &Path, options: &Options, stats: &Statistics, versions: &mut VersionSet,
+                    log_number: u64, edit: &mut VersionEdit, max_sequence: &mut SequenceNumber) -> Result<()> {
+    let file = new_sequential_file(log_file_name(dbname, log_number))?;
+    let corrupted = Rc::new(Cell::new(false));
+    let reporter = CorruptionReporter { corrupted: corrupted.clone() };
+    // We intentionally make the reader do checksumming even if
+    // paranoid_checks is not set so that corruptions cause entire commits
+    // to be skipped instead of propagating bad information (like overly
+    // large sequence numbers).
+    let mut reader = Reader::new(file, Some(Box::new(reporter)), true, 0);
+    let mut replay = GroupReplay::new();
+    let mut mem: Option<MemTable> = None;
+    let mut scratch = Vec::new();
+    while let Some(record) = reader.read_record(&mut scratch) {
+        // The records dropped before this one may belong to the open group
+        if corrupted.replace(false) {
+            if options.paranoid_checks {
+                return Err(Corruption);
+            }
+            replay.abort();
         }
-    }
-
-    fn wait(&self) {
+        let batches = WriteBatch::from_data(record.data().to_vec()).and_then(|batch| replay.add(batch));
+        let batches = match batches {
+            Ok(batches) => batches,
+            Err(e) if options.paranoid_checks => return Err(e),
+            Err(_) => {
+                replay.abort();
+                continue;
+            }
+        };
+        for batch in batches {
+            let mem = mem.get_or_insert_with(|| MemTable::new(InternalKeyComparator::new(options.comparator)));
+            // Cannot fail for a batch accepted by replay.add()
+            insert_into(&batch, mem)?;
+            if count(&batch) > 0 {
+                let last_seq = sequence(&batch) + count(&batch) as u64 - 1;
+                *max_sequence = (*max_sequence).max(last_seq);
+            }
+        }
+        if mem.as_ref().is_some_and(|mem| mem.approximate_memory_usage() > options.write_buffer_size) {
+            write_recovered_table(dbname, options, stats, versions, &mem.take().unwrap(), edit)?;
+        }
+    }
+    if corrupted.get() && options.paranoid_checks {
+        return Err(Corruption);
+    }
+    match mem {
+        Some(mem) => write_recovered_table(dbname, options, stats, versions, &mem, edit),
+        None => Ok(())
+    }
+}
+
+/// Write a memtable rebuilt from a log to a new level-0 table, added to
+/// `edit`.
+fn write_recovered_table(dbname: &Path, options: &Options, stats: &Statistics, versions: &mut VersionSet,
+                         mem: &MemTable, edit: &mut VersionEdit) -> Result<()> {
+    let mut meta = FileMetaData { number: versions.new_file_number(), ..FileMetaData::default() };
+    write_level0_table(dbname, options, stats, mem, &mut meta)?;
+    // Note that if file_size is zero, the file has been deleted and
+    // should not be added to the manifest.
+    if meta.file_size > 0 {
+        edit.add_file(0, meta);
+    }
+    Ok(())
+}
+
+/// Lock the LOCK file of the db, retrying for up to `timeout` while it is
+/// held elsewhere.
+fn lock_db(dbname: &Path, timeout: Duration) -> Result<Box<dyn FileLock>> {
+    let lock_name = lock_file_name(dbname);
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(lock) = try_lock_file(&lock_name)? {
+            return Ok(lock);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            // "lock <dbname>/LOCK: already held by process"
+            return Err(IOError);
+        }
+        std::thread::sleep((deadline - now).min(Duration::from_millis(10)));
+    }
+}
+
+/// Returns the id stored in the IDENTITY file of the db, creating the file
+/// with a new unique id first if it does not exist.
+fn get_or_create_db_identity(dbname: &Path) -> Result<String> {
+    let identity = identity_file_name(dbname);
+    if identity.exists() {
+        let id = std::fs::read_to_string(&identity)?;
+        let id = id.trim_end();
+        if id.is_empty() {
+            return Err(Corruption);
+        }
+        return Ok(id.to_string());
+    }
+    let id = generate_unique_id();
+    write_string_to_file_sync(format!("{}\n", id).as_bytes(), &identity)?;
+    Ok(id)
+}
+
+/// Returns the name of a file of the db relative to the db directory,
+/// e.g. "/000003.log".
+fn relative_file_name(path: &Path) -> String {
+    format!("/{}", path.file_name().expect("db file name").to_string_lossy())
+}
+
+/// Fill in the defaults of the options left unset.
+fn sanitize_options(src: &Options) -> Options {
+    let mut result = src.clone();
+    if result.block_cache.is_none() {
+        result.block_cache = Some(Arc::new(ShardedLRUCache::new(8 << 20)));
+    }
+    result
+}
+
+/// Create the files of an empty db: a MANIFEST holding a single edit with
+/// the initial counters and a CURRENT file pointing to it.
+fn new_db(options: &Options, dbname: &Path) -> Result<()> {
+    let mut new_db = VersionEdit::new();
+    new_db.set_comparator_name(options.comparator_name);
+    new_db.set_format_version(options.format_version);
+    new_db.set_log_number(0);
+    new_db.set_next_file(2);
+    new_db.set_last_sequence(0);
+
+    let manifest = descriptor_file_name(dbname, 1);
+    let result = (|| {
+        let file = new_writable_file(&manifest, options.allow_mmap_writes)?;
+        let mut log = log_writer::Writer::new(file.clone());
+        let mut record = Vec::new();
+        new_db.encode_to(&mut record);
+        log.add_record(&Slice::from_bytes(&record))?;
+        file.borrow().sync()?;
+        file.borrow().close()?;
+        // Make "CURRENT" file that points to the new manifest file.
+        set_current_file(dbname, 1)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&manifest);
+    }
+    result
+}
+
+struct Writer {
+
+    batch: WriteBatch,
+
+    sync: bool,
+
+    disable_wal: bool,
+
+    done: bool
+
+    //cv: Condvar
+}
+
+impl Writer {
+
+    fn new(batch: WriteBatch, opt: &WriteOptions) -> Self {
+        Writer{
+            batch,
+            sync: opt.sync,
+            disable_wal: opt.disable_wal,
+            done: false
+        }
+    }
+
+    fn wait(&self) {
         //self.cv.wait()
     }
 }
 
+/// Empties the batch of a write group when dropped, so that a write that
+/// fails part way does not leave its records to the next write.
+struct ClearOnDrop<'a>(&'a RefCell<WriteBatch>);
+
+impl Drop for ClearOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::cmp::Ordering;
+    use crate::cache::CachePriority;
+    use crate::comparator::Comparator;
+    use crate::dbformat::ValueType;
+    use crate::dump::dump_manifest;
+    use crate::env::{new_random_access_file, new_sequential_file};
+    use crate::error::Error::NotSupport;
+    use crate::log_reader::Reader;
+    use crate::options::CompressionType;
+    use crate::env::RandomAccessFile;
+    use crate::coding::decode_fix32;
+    use crate::table::block::Block;
+    use crate::table::block_builder::K_DELTA_ENCODED_RESTARTS_BIT;
+    use crate::table::data_block_hash_index::decode_block_footer;
+    use crate::table::format::{read_block, BlockHandle, Footer};
+    use crate::table::Table;
+    use crate::table_properties::TableProperties;
+    use crate::write_batch::GroupReplay;
+    use crate::version_set::K_L0_COMPACTION_TRIGGER;
+    use crate::write_buffer_manager::WriteBufferManager;
     use super::*;
 
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("revel_db_test_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
     #[test]
     fn test() {
         let user_comparator: fn(a: &Slice, b: &Slice) -> Ordering = |a: &Slice, b: &Slice| {
             a.data().cmp(b.data())
         };
         let options = Options {
-            comparator: user_comparator
+            comparator: user_comparator,
+            ..Options::default()
         };
-        let mut db = DB::open(&options, "./text").expect("error");
+        let mut db = DB::open(&options, db_path("test")).expect("error");
         db.put(&WriteOptions::default(), &Slice::from_str("key"), &Slice::from_str("value")).expect("put error");
         let value = db.get(&ReadOptions::default(), &Slice::from_str("key")).expect("read error");
         assert_eq!("value", String::from_utf8(value).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_histograms_property() {
+        let user_comparator: fn(a: &Slice, b: &Slice) -> Ordering = |a: &Slice, b: &Slice| {
+            a.data().cmp(b.data())
+        };
+        let options = Options {
+            comparator: user_comparator,
+            ..Options::default()
+        };
+        let mut db = DB::open(&options, db_path("histograms")).expect("error");
+        db.put(&WriteOptions::default(), &Slice::from_str("key"), &Slice::from_str("value")).expect("put error");
+        db.get(&ReadOptions::default(), &Slice::from_str("key")).expect("read error");
+        db.get(&ReadOptions::default(), &Slice::from_str("missing")).expect_err("should not found");
+        let histograms = db.get_property("revel.histograms").expect("property should exist");
+        assert!(histograms.contains("** get (micros) **\nCount: 2 "));
+        assert!(histograms.contains("** write (micros) **\nCount: 1 "));
+        assert!(!histograms.contains("** seek (micros) **"));
+        assert!(!histograms.contains("** compaction (micros) **"));
+
+        let mut iter = db.new_iterator(&ReadOptions::default());
+        iter.seek(&Slice::from_str("key"));
+        iter.seek_to_first();
+        iter.seek_to_last();
+        drop(iter);
+        let histograms = db.get_property("revel.histograms").expect("property should exist");
+        assert!(histograms.contains("** seek (micros) **\nCount: 3 "));
+
+        // Scans record a seek for each end they are read from
+        assert_eq!(1, db.scan(&ReadOptions::default(), None, None).count());
+        assert_eq!(1, db.keys(&ReadOptions::default(), Some(b"a"), Some(b"z")).rev().count());
+        let mut values = db.values(&ReadOptions::default(), None, None);
+        assert!(values.next().is_some());
+        assert!(values.next_back().is_none());
+        drop(values);
+        let histograms = db.get_property("revel.histograms").expect("property should exist");
+        assert!(histograms.contains("** seek (micros) **\nCount: 7 "));
+        assert!(db.get_property("revel.unknown").is_none());
+        assert!(db.get_property("histograms").is_none());
+    }
+
+    #[test]
+    fn test_put_batch() {
+        let mut db = DB::open(&Options::default(), db_path("put_batch")).expect("error");
+        let pairs = vec![("k1", "v1"), ("k2", "v2"), ("k1", "v3")];
+        db.put_batch(&WriteOptions::default(), pairs).expect("put batch error");
+        db.put_batch(&WriteOptions::default(), vec![(b"k4".to_vec(), b"v4".to_vec())]).expect("put batch error");
+        db.put_batch(&WriteOptions::default(), Vec::<(&str, &str)>::new()).expect("put batch error");
+
+        let get = |key: &str| {
+            String::from_utf8(db.get(&ReadOptions::default(), &Slice::from_str(key)).expect("read error")).unwrap()
+        };
+        assert_eq!("v3", get("k1"));
+        assert_eq!("v2", get("k2"));
+        assert_eq!("v4", get("k4"));
+        assert_eq!(4, db.versions.last_sequence());
+    }
+
+    #[test]
+    fn test_large_batch() {
+        let mut db = DB::open(&Options::default(), db_path("large_batch")).expect("error");
+        let big = vec![7; 3 << 19];
+        db.put(&WriteOptions::default(), &Slice::from_str("big"), &Slice::from_bytes(&big)).expect("put error");
+        db.put(&WriteOptions::default(), &Slice::from_str("small"), &Slice::from_str("v")).expect("put error");
+
+        assert_eq!(big, db.get(&ReadOptions::default(), &Slice::from_str("big")).expect("read error"));
+        assert_eq!(b"v".to_vec(), db.get(&ReadOptions::default(), &Slice::from_str("small")).expect("read error"));
+        assert_eq!(2, db.latest_sequence_number());
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut db = DB::open(&Options::default(), db_path("contains_key")).expect("error");
+        let opt = ReadOptions::default();
+        db.put_batch(&WriteOptions::default(), vec![("k1", "v1"), ("k2", "")]).expect("put batch error");
+        db.delete(&WriteOptions::default(), &Slice::from_str("k1")).expect("delete error");
+        db.put(&WriteOptions::default(), &Slice::from_str("k3"), &Slice::from_str("v3")).expect("put error");
+        db.delete(&WriteOptions::default(), &Slice::from_str("k3")).expect("delete error");
+        db.put(&WriteOptions::default(), &Slice::from_str("k3"), &Slice::from_str("v4")).expect("put error");
+
+        assert_eq!(Ok(false), db.contains_key(&opt, &Slice::from_str("k1")));
+        assert_eq!(Ok(true), db.contains_key(&opt, &Slice::from_str("k2")));
+        assert_eq!(Ok(true), db.contains_key(&opt, &Slice::from_str("k3")));
+        assert_eq!(Ok(false), db.contains_key(&opt, &Slice::from_str("k0")));
+        assert_eq!(Ok(false), db.contains_key(&opt, &Slice::from_str("k4")));
+    }
+
+    #[test]
+    fn test_stall_reason() {
+        let options = Options {
+            level0_slowdown_writes_trigger: 4,
+            level0_stop_writes_trigger: 6,
+            soft_pending_compaction_bytes_limit: 100,
+            hard_pending_compaction_bytes_limit: 200,
+            ..Options::default()
+        };
+        assert_eq!(StallReason::None, stall_reason(&options, 3, 99));
+        assert_eq!(StallReason::Level0Slowdown, stall_reason(&options, 4, 0));
+        assert_eq!(StallReason::Level0Stop, stall_reason(&options, 6, 0));
+        assert_eq!(StallReason::PendingCompactionBytesSlowdown, stall_reason(&options, 0, 100));
+        assert_eq!(StallReason::PendingCompactionBytesStop, stall_reason(&options, 5, 200));
+        assert_eq!(StallReason::Level0Stop, stall_reason(&options, 6, 200));
+
+        let unlimited = Options {
+            soft_pending_compaction_bytes_limit: 0,
+            hard_pending_compaction_bytes_limit: 0,
+            ..Options::default()
+        };
+        assert_eq!(StallReason::None, stall_reason(&unlimited, 0, u64::MAX));
+
+        let db = DB::open(&options, db_path("stall_reason")).expect("error");
+        assert_eq!(Some("none".to_string()), db.get_property("revel.stall-reason"));
+    }
+
+    #[test]
+    fn test_pending_compaction_bytes_stop() {
+        let options = Options {
+            // Every write fills the memtable
+            write_buffer_size: 1 << 10,
+            soft_pending_compaction_bytes_limit: 1,
+            hard_pending_compaction_bytes_limit: 1,
+            ..Options::default()
+        };
+        let mut db = DB::open(&options, db_path("pending_compaction_bytes_stop")).expect("error");
+        let value = vec![b'x'; 1000];
+        let mut stopped = false;
+        for i in 0..100 {
+            // Install every flush right away, so level-0 fills up before the
+            // next write
+            db.finish_flush(true).unwrap();
+            stopped |= db.write_stall_reason() == StallReason::PendingCompactionBytesStop;
+            // Overwrite the same keys, so the level-0 files overlap and
+            // cannot simply be moved down
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i % 10)), &Slice::from_bytes(&value)).unwrap();
+            // While writes are stopped, a full memtable is only switched once
+            // the running compaction is done
+            let switched = db.imm.is_some();
+            assert!(!switched || db.write_stall_reason() != StallReason::PendingCompactionBytesStop || db.bg_compaction.is_none());
+        }
+        assert!(stopped);
+    }
+
+    #[test]
+    fn test_get_live_files_metadata() {
+        let options = Options::default();
+        let mut db = DB::open(&options, db_path("live_files_metadata")).expect("error");
+        assert!(db.get_live_files_metadata().is_empty());
+
+        let ikey = |user_key: &str, seq: u64| {
+            let mut key = user_key.as_bytes().to_vec();
+            key.extend_from_slice(&((seq << 8) | ValueType::KTypeValue as u64).to_le_bytes());
+            key
+        };
+        let mut edit = VersionEdit::default();
+        edit.add_file(2, FileMetaData {
+            number: 7,
+            file_size: 300,
+            smallest: ikey("b", 5),
+            largest: ikey("d", 9),
+            smallest_seqno: 3,
+            largest_seqno: 9,
+            ..FileMetaData::default()
+        });
+        edit.add_file(0, FileMetaData {
+            number: 8,
+            file_size: 100,
+            smallest: ikey("a", 10),
+            largest: ikey("z", 12),
+            smallest_seqno: 10,
+            largest_seqno: 12,
+            ..FileMetaData::default()
+        });
+        db.versions.log_and_apply(&mut edit).expect("log and apply failed");
+
+        let metadata = db.get_live_files_metadata();
+        assert_eq!(2, metadata.len());
+        assert_eq!(LiveFileMetaData {
+            name: "/000008.ldb".to_string(),
+            level: 0,
+            file_number: 8,
+            size: 100,
+            smallest_key: b"a".to_vec(),
+            largest_key: b"z".to_vec(),
+            smallest_seqno: 10,
+            largest_seqno: 12
+        }, metadata[0]);
+        assert_eq!(("/000007.ldb", 2, 300), (metadata[1].name.as_str(), metadata[1].level, metadata[1].size));
+        assert_eq!((b"b".to_vec(), b"d".to_vec()), (metadata[1].smallest_key.clone(), metadata[1].largest_key.clone()));
+        assert_eq!((3, 9), (metadata[1].smallest_seqno, metadata[1].largest_seqno));
+    }
+
+    #[test]
+    fn test_suggest_compact_range() {
+        let options = Options::default();
+        let mut db = DB::open(&options, db_path("suggest_compact_range")).expect("error");
+        let file = |number: u64, smallest: &str, largest: &str| {
+            let ikey = |user_key: &str| {
+                let mut key = user_key.as_bytes().to_vec();
+                key.extend_from_slice(&((1 << 8) | ValueType::KTypeValue as u64).to_le_bytes());
+                key
+            };
+            FileMetaData { number, file_size: 100, smallest: ikey(smallest), largest: ikey(largest), ..FileMetaData::default() }
+        };
+        let mut edit = VersionEdit::default();
+        edit.add_file(0, file(10, "a", "z"));
+        edit.add_file(1, file(11, "a", "c"));
+        edit.add_file(1, file(12, "d", "f"));
+        edit.add_file(1, file(13, "g", "i"));
+        edit.add_file(2, file(14, "a", "z"));
+        db.versions.log_and_apply(&mut edit).expect("log and apply failed");
+
+        db.suggest_compact_range(Some(&Slice::from_str("e")), Some(&Slice::from_str("g"))).expect("suggest failed");
+        let marked: Vec<(usize, u64)> = db.versions.current().files_marked_for_compaction().iter()
+            .map(|(level, f)| (*level, f.number))
+            .collect();
+        assert_eq!(vec![(0, 10), (1, 12), (1, 13)], marked);
+
+        // The marks survive later versions
+        let mut edit = VersionEdit::default();
+        edit.add_file(3, file(15, "x", "y"));
+        db.versions.log_and_apply(&mut edit).expect("log and apply failed");
+        assert_eq!(3, db.versions.current().files_marked_for_compaction().len());
+
+        db.suggest_compact_range(None, Some(&Slice::from_str("b"))).expect("suggest failed");
+        let marked: Vec<(usize, u64)> = db.versions.current().files_marked_for_compaction().iter()
+            .map(|(level, f)| (*level, f.number))
+            .collect();
+        assert_eq!(vec![(0, 10), (1, 11), (1, 12), (1, 13), (2, 14)], marked);
+    }
+
+    #[test]
+    fn test_suggest_compact_range_trivial_move() {
+        let mut db = DB::open(&Options::default(), db_path("suggest_compact_range_trivial_move")).expect("error");
+        let file = |number: u64, smallest: &str, largest: &str| {
+            let ikey = |user_key: &str| {
+                let mut key = user_key.as_bytes().to_vec();
+                key.extend_from_slice(&((1 << 8) | ValueType::KTypeValue as u64).to_le_bytes());
+                key
+            };
+            FileMetaData { number, file_size: 100, smallest: ikey(smallest), largest: ikey(largest), ..FileMetaData::default() }
+        };
+        let mut edit = VersionEdit::default();
+        edit.add_file(1, file(10, "a", "c"));
+        edit.add_file(3, file(11, "x", "z"));
+        db.versions.log_and_apply(&mut edit).expect("log and apply failed");
+
+        db.suggest_compact_range(None, Some(&Slice::from_str("c"))).expect("suggest failed");
+        db.maybe_schedule_compaction().expect("compaction failed");
+        // Nothing overlaps the marked file below, so it is moved down a
+        // single level and is no longer marked
+        let current = db.versions.current();
+        let files: Vec<Vec<u64>> = (0..K_NUM_LEVELS).map(|level| current.files(level).iter().map(|f| f.number).collect()).collect();
+        assert_eq!(vec![vec![], vec![], vec![10], vec![11], vec![], vec![], vec![]], files);
+        assert!(current.files_marked_for_compaction().is_empty());
+        assert!(db.bg_compaction.is_none());
+    }
+
+    #[test]
+    fn test_get_live_files() {
+        let user_comparator: fn(a: &Slice, b: &Slice) -> Ordering = |a: &Slice, b: &Slice| {
+            a.data().cmp(b.data())
+        };
+        let options = Options {
+            comparator: user_comparator,
+            ..Options::default()
+        };
+        let dbname = db_path("live_files");
+        let mut db = DB::open(&options, &dbname).expect("error");
+        db.put(&WriteOptions::default(), &Slice::from_str("key"), &Slice::from_str("value")).expect("put error");
+
+        let (files, manifest_size) = db.get_live_files().expect("get live files failed");
+        assert_eq!(vec!["/CURRENT".to_string(), "/IDENTITY".to_string(), "/MANIFEST-000002".to_string(), "/000003.log".to_string()], files);
+        assert!(manifest_size > 0);
+        for file in files {
+            assert!(dbname.join(&file[1..]).exists());
+        }
+
+        assert!(db.file_deletions_enabled());
+        db.disable_file_deletions();
+        db.disable_file_deletions();
+        db.enable_file_deletions(false);
+        assert!(!db.file_deletions_enabled());
+        db.enable_file_deletions(false);
+        assert!(db.file_deletions_enabled());
+        db.enable_file_deletions(false);
+        assert!(db.file_deletions_enabled());
+
+        db.disable_file_deletions();
+        db.disable_file_deletions();
+        db.enable_file_deletions(true);
+        assert!(db.file_deletions_enabled());
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut db = DB::open(&Options::default(), db_path("scan")).expect("error");
+        let pairs = vec![("k1", "v1"), ("k2", "v2"), ("k3", "v3"), ("k4", "v4")];
+        db.put_batch(&WriteOptions::default(), pairs).expect("put batch error");
+        db.delete(&WriteOptions::default(), &Slice::from_str("k2")).expect("delete error");
+
+        let keys = |entries: Result<Vec<(Vec<u8>, Vec<u8>)>>| {
+            entries.expect("scan error").into_iter().map(|(k, _)| String::from_utf8(k).unwrap()).collect::<Vec<_>>()
+        };
+        let opt = ReadOptions::default();
+        assert_eq!(vec!["k1", "k3", "k4"], keys(db.scan(&opt, None, None).collect()));
+        assert_eq!(vec!["k4", "k3", "k1"], keys(db.scan(&opt, None, None).rev().collect()));
+        assert_eq!(vec!["k3", "k1"], keys(db.scan(&opt, Some(b"k1"), Some(b"k4")).rev().collect()));
+        let mut scan = db.scan(&opt, Some(b"k2"), None);
+        assert_eq!(Some(Ok((b"k4".to_vec(), b"v4".to_vec()))), scan.next_back());
+        assert_eq!(Some(Ok((b"k3".to_vec(), b"v3".to_vec()))), scan.next());
+        assert_eq!(None, scan.next_back());
+        assert_eq!(Ok(()), scan.status());
+
+        let keys: Result<Vec<Vec<u8>>> = db.keys(&opt, Some(b"k2"), None).collect();
+        assert_eq!(Ok(vec![b"k3".to_vec(), b"k4".to_vec()]), keys);
+        let values: Result<Vec<Vec<u8>>> = db.values(&opt, None, Some(b"k4")).rev().collect();
+        assert_eq!(Ok(vec![b"v3".to_vec(), b"v1".to_vec()]), values);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dbname = db_path("reopen");
+        let db = DB::open(&Options::default(), &dbname).expect("open failed");
+        assert_eq!(2, db.versions.manifest_file_number());
+        drop(db);
+        let db = DB::open(&Options::default(), &dbname).expect("reopen failed");
+        // Every open recovers the previous MANIFEST and starts a new one
+        assert_eq!(4, db.versions.manifest_file_number());
+        assert_eq!(5, db.logfile_number);
+        assert_eq!(5, db.versions.log_number());
+    }
+
+    #[test]
+    fn test_write_level0_table() {
+        let dbname = db_path("write_level0_table");
+        let options = Options::default();
+        let mut db = DB::open(&options, &dbname).unwrap();
+        for i in 0..100 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:03}", i)), &Slice::from_str("value")).unwrap();
+        }
+        db.delete(&WriteOptions::default(), &Slice::from_str("key050")).unwrap();
+
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        assert_eq!(Some("1".to_string()), db.get_property("revel.num-immutable-mem-table"));
+        db.finish_flush(true).unwrap();
+        assert_eq!(Some("0".to_string()), db.get_property("revel.num-immutable-mem-table"));
+        assert_eq!(Some("1".to_string()), db.get_property("revel.num-files-at-level0"));
+        assert!(db.get_property("revel.histograms").unwrap().contains("** flush (micros) **\nCount: 1 "));
+
+        let files = db.get_live_files_metadata();
+        assert_eq!(b"key000", files[0].smallest_key.as_slice());
+        assert_eq!(b"key099", files[0].largest_key.as_slice());
+        assert_eq!((1, 101), (files[0].smallest_seqno, files[0].largest_seqno));
+        let fname = table_file_name(&dbname, files[0].file_number);
+        assert_eq!(std::fs::metadata(&fname).unwrap().len(), files[0].size);
+        let table = Table::open(&options, new_random_access_file(&fname).unwrap(), files[0].file_number, files[0].size).unwrap();
+        assert_eq!(101, table.properties().num_entries);
+
+        // An empty memtable writes no table
+        let mem = MemTable::new(InternalKeyComparator::new(options.comparator));
+        let mut meta = FileMetaData { number: 100, ..FileMetaData::default() };
+        write_level0_table(&dbname, &options, &db.stats, &mem, &mut meta).unwrap();
+        assert_eq!(0, meta.file_size);
+        assert!(!table_file_name(&dbname, 100).exists());
+
+        // The table is part of the db once reopened
+        drop(db);
+        let db = DB::open(&options, &dbname).unwrap();
+        assert_eq!(files, db.get_live_files_metadata());
+    }
+
+    #[test]
+    fn test_immutable_memtable() {
+        let dbname = db_path("immutable_memtable");
+        let options = Options { write_buffer_size: 16 << 10, ..Options::default() };
+        let mut db = DB::open(&options, &dbname).unwrap();
+        let value = "v".repeat(100);
+        for i in 0..1000 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i)), &Slice::from_str(&value)).unwrap();
+        }
+        db.delete(&WriteOptions::default(), &Slice::from_str("key0000")).unwrap();
+        db.finish_flush(true).unwrap();
+        let level0_files: usize = db.get_property("revel.num-files-at-level0").unwrap().parse().unwrap();
+        assert!(level0_files > 1);
+
+        // Reads see the memtable, and the tables written by the flushes
+        let check = |db: &DB| {
+            let read_options = ReadOptions::default();
+            assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str("key0000")));
+            assert_eq!(Ok(false), db.contains_key(&read_options, &Slice::from_str("key0000")));
+            for i in 1..1000 {
+                let key = format!("key{:04}", i);
+                assert_eq!(value.as_bytes(), db.get(&read_options, &Slice::from_str(&key)).unwrap().as_slice());
+            }
+            let keys: Vec<Vec<u8>> = db.scan(&read_options, None, None).map(|entry| entry.unwrap().0).collect();
+            assert_eq!(999, keys.len());
+            assert_eq!(b"key0001", keys[0].as_slice());
+            assert_eq!(b"key0999", keys[998].as_slice());
+            let keys: Vec<Vec<u8>> = db.scan(&read_options, None, None).rev().map(|entry| entry.unwrap().0).collect();
+            assert_eq!(999, keys.len());
+            assert_eq!(b"key0999", keys[0].as_slice());
+        };
+        check(&db);
+
+        // Flush the rest, so the reopened db finds everything in tables
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        db.finish_flush(true).unwrap();
+        check(&db);
+        drop(db);
+        let db = DB::open(&options, &dbname).unwrap();
+        check(&db);
+        let reopened_files: usize = db.get_property("revel.num-files-at-level0").unwrap().parse().unwrap();
+        assert!(reopened_files >= level0_files);
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dbname = db_path("compaction");
+        let options = Options { write_buffer_size: 16 << 10, ..Options::default() };
+        let mut db = DB::open(&options, &dbname).unwrap();
+        let value = "v".repeat(100);
+        // Every round overwrites the keys still there, and deletes one more
+        for round in 0..3 {
+            for i in round..500 {
+                db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i)), &Slice::from_str(&format!("{}{}", value, round))).unwrap();
+            }
+            db.delete(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", round))).unwrap();
+        }
+        db.finish_flush(true).unwrap();
+        db.finish_compaction(true).unwrap();
+        let files = |db: &DB, level: usize| db.versions.current().num_files(level);
+        assert!(files(&db, 1) > 0);
+        assert!(files(&db, 0) < K_L0_COMPACTION_TRIGGER);
+        // The summary follows the shape of the tree as files are flushed
+        // and compacted
+        let summary = (0..K_NUM_LEVELS).map(|level| format!(" {}", files(&db, level))).collect::<String>();
+        assert_eq!(Some(format!("files[{} ]", summary)), db.get_property("revel.levelsummary"));
+        assert_eq!(Some("0".to_string()), db.get_property("revel.compaction-pending"));
+        assert_eq!(Some("0".to_string()), db.get_property("revel.estimate-pending-compaction-bytes"));
+        assert!(db.get_property("revel.histograms").unwrap().contains("** compaction (micros) **\nCount: "));
+
+        // Level-1 holds a single entry per key, as no level lies below it
+        // and no snapshot keeps the overwritten values
+        let level1 = db.versions.current().files(1).to_vec();
+        for f in &level1 {
+            let fname = table_file_name(&dbname, f.number);
+            let table = Table::open(&options, new_random_access_file(&fname).unwrap(), f.number, f.file_size).unwrap();
+            assert!(table.properties().num_entries <= 500);
+        }
+
+        let read_options = ReadOptions::default();
+        for i in 0..500 {
+            let key = format!("key{:04}", i);
+            let key = Slice::from_str(&key);
+            if i < 3 {
+                assert_eq!(Err(NotFound), db.get(&read_options, &key));
+            } else {
+                assert_eq!(format!("{}2", value).into_bytes(), db.get(&read_options, &key).unwrap());
+            }
+        }
+        assert_eq!(497, db.scan(&read_options, None, None).count());
+    }
+
+    #[test]
+    fn test_subcompactions() {
+        let dbname = db_path("subcompactions");
+        let options = Options { write_buffer_size: 16 << 10, max_subcompactions: 4, ..Options::default() };
+        let mut db = DB::open(&options, &dbname).unwrap();
+        let value = "v".repeat(100);
+        for i in 0..2000 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i)), &Slice::from_str(&value)).unwrap();
+        }
+        db.finish_flush(true).unwrap();
+        db.finish_compaction(true).unwrap();
+
+        // Every sub-compaction writes files of its own, although all the
+        // data would fit in a single one
+        let level1 = db.versions.current().files(1).to_vec();
+        assert!(level1.len() >= 4);
+        let icmp = InternalKeyComparator::new(options.comparator);
+        for pair in level1.windows(2) {
+            assert_eq!(Ordering::Less, icmp.compare(&Slice::from_bytes(&pair[0].largest), &Slice::from_bytes(&pair[1].smallest)));
+        }
+        let read_options = ReadOptions::default();
+        for i in 0..2000 {
+            assert_eq!(value.as_bytes(), db.get(&read_options, &Slice::from_str(&format!("key{:04}", i))).unwrap());
+        }
+    }
+
+    fn file_numbers(dbname: &Path, file_type: FileType) -> Vec<u64> {
+        let mut numbers: Vec<u64> = std::fs::read_dir(dbname).unwrap()
+            .filter_map(|entry| parse_file_name(entry.unwrap().file_name().to_str().unwrap()))
+            .filter(|(_, t)| *t == file_type)
+            .map(|(number, _)| number)
+            .collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    #[test]
+    fn test_delete_obsolete_files() {
+        let dbname = db_path("delete_obsolete_files");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        let flush = |db: &mut DB, round: usize| {
+            for i in 0..100 {
+                db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:03}", i)), &Slice::from_str(&round.to_string())).unwrap();
+            }
+            db.switch_memtable().unwrap();
+            db.schedule_flush();
+        };
+
+        // The log of the memtable being flushed, and the table being
+        // written, are kept until the flush is installed
+        flush(&mut db, 0);
+        let old_log = db.versions.log_number();
+        let (table, _) = db.pending_flush.clone().unwrap();
+        while !table_file_name(&dbname, table).exists() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        db.delete_obsolete_files();
+        assert_eq!(vec![old_log, db.logfile_number], file_numbers(&dbname, FileType::kLogFile));
+        assert_eq!(vec![table], file_numbers(&dbname, FileType::kTableFile));
+        db.finish_flush(true).unwrap();
+        assert_eq!(vec![db.logfile_number], file_numbers(&dbname, FileType::kLogFile));
+        assert_eq!(vec![table], file_numbers(&dbname, FileType::kTableFile));
+
+        // Nothing is deleted while file deletions are disabled
+        db.disable_file_deletions();
+        flush(&mut db, 1);
+        db.finish_flush(true).unwrap();
+        assert_eq!(2, file_numbers(&dbname, FileType::kLogFile).len());
+        db.enable_file_deletions(false);
+        db.delete_obsolete_files();
+        assert_eq!(vec![db.logfile_number], file_numbers(&dbname, FileType::kLogFile));
+
+        // Compacted level-0 files go away with the compaction
+        for round in 2..K_L0_COMPACTION_TRIGGER {
+            flush(&mut db, round);
+            db.finish_flush(true).unwrap();
+        }
+        db.maybe_schedule_compaction().unwrap();
+        db.finish_compaction(true).unwrap();
+        let current = db.versions.current();
+        assert_eq!(0, current.num_files(0));
+        let live: Vec<u64> = current.files(1).iter().map(|f| f.number).collect();
+        assert_eq!(live, file_numbers(&dbname, FileType::kTableFile));
+        drop(current);
+
+        // Reopening starts a new MANIFEST and log, the old ones go
+        drop(db);
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(vec![db.versions.manifest_file_number()], file_numbers(&dbname, FileType::KDescriptorFile));
+        assert_eq!(vec![db.logfile_number], file_numbers(&dbname, FileType::kLogFile));
+        assert_eq!(b"3".to_vec(), db.get(&ReadOptions::default(), &Slice::from_str("key042")).unwrap());
+    }
+
+    #[test]
+    fn test_seek_compaction() {
+        let dbname = db_path("seek_compaction");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        // Two level-0 files covering the same range, the newer one without
+        // the odd keys
+        for round in 0..2 {
+            for i in (0..100).filter(|i| round == 0 || i % 2 == 0) {
+                db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:03}", i)), &Slice::from_str("v")).unwrap();
+            }
+            db.switch_memtable().unwrap();
+            db.schedule_flush();
+            db.finish_flush(true).unwrap();
+        }
+        assert_eq!(2, db.versions.current().num_files(0));
+        assert_eq!(Some("0".to_string()), db.get_property("revel.compaction-pending"));
+
+        // Reads of the odd keys within the range of the newer file go
+        // through both files and charge the newer one
+        let read_options = ReadOptions::default();
+        for i in 0..100 {
+            assert_eq!(b"v".to_vec(), db.get(&read_options, &Slice::from_str(&format!("key{:03}", i % 49 * 2 + 1))).unwrap());
+        }
+        assert_eq!(Some("1".to_string()), db.get_property("revel.compaction-pending"));
+
+        // The next write starts the compaction
+        db.put(&WriteOptions::default(), &Slice::from_str("key100"), &Slice::from_str("v")).unwrap();
+        db.finish_compaction(true).unwrap();
+        assert_eq!(0, db.versions.current().num_files(0));
+        assert_eq!(1, db.versions.current().num_files(1));
+        for i in 0..100 {
+            assert_eq!(b"v".to_vec(), db.get(&read_options, &Slice::from_str(&format!("key{:03}", i))).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_scan_read_sampling() {
+        let dbname = db_path("scan_read_sampling");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        // Two level-0 files holding every key, over 2MB each so that a
+        // scan takes at least one sample
+        let value = "v".repeat(10 << 10);
+        for _ in 0..2 {
+            for i in 0..250 {
+                db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:03}", i)), &Slice::from_str(&value)).unwrap();
+            }
+            db.finish_flush(true).unwrap();
+            db.switch_memtable().unwrap();
+            db.schedule_flush();
+            db.finish_flush(true).unwrap();
+        }
+        let current = db.versions.current();
+        assert_eq!(2, current.num_files(0));
+        assert_eq!(Some("0".to_string()), db.get_property("revel.compaction-pending"));
+        for f in current.files(0) {
+            f.allowed_seeks.set(1);
+        }
+
+        // The sampled keys are found in both files, the newer one pays
+        assert_eq!(250, db.scan(&ReadOptions::default(), None, None).count());
+        let (level, f) = current.file_to_compact().unwrap();
+        assert_eq!((0, current.files(0)[0].number.max(current.files(0)[1].number)), (level, f.number));
+        assert_eq!(Some("1".to_string()), db.get_property("revel.compaction-pending"));
+    }
+
+    #[test]
+    fn test_recover_log() {
+        let dbname = db_path("recover_log");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        for i in 0..100 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:03}", i)), &Slice::from_str("v1")).unwrap();
+        }
+        db.delete(&WriteOptions::default(), &Slice::from_str("key000")).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("key001"), &Slice::from_str("v2")).unwrap();
+        // A group whose commit never made it to the log
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("uncommitted"), &Slice::from_str("v"));
+        mark_begin_group(&mut batch, 103);
+        batch.set_sequence(103);
+        db.log.add_record(&batch.contents()).unwrap();
+        drop(db);
+
+        // The logged updates come back without having been flushed
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(102, db.versions.last_sequence());
+        assert_eq!(1, db.versions.current().num_files(0));
+        let read_options = ReadOptions::default();
+        assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str("key000")));
+        assert_eq!(b"v2".to_vec(), db.get(&read_options, &Slice::from_str("key001")).unwrap());
+        for i in 2..100 {
+            assert_eq!(b"v1".to_vec(), db.get(&read_options, &Slice::from_str(&format!("key{:03}", i))).unwrap());
+        }
+        assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str("uncommitted")));
+
+        // Writes after recovery continue the sequence, and are recovered
+        // from the new log
+        db.put(&WriteOptions::default(), &Slice::from_str("key000"), &Slice::from_str("v3")).unwrap();
+        assert_eq!(103, db.versions.last_sequence());
+        drop(db);
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(b"v3".to_vec(), db.get(&read_options, &Slice::from_str("key000")).unwrap());
+        assert_eq!(100, db.scan(&read_options, None, None).count());
+    }
+
+    #[test]
+    fn test_recover_multiple_logs() {
+        let dbname = db_path("recover_multiple_logs");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("b"), &Slice::from_str("1")).unwrap();
+        let first_log = db.logfile_number;
+        // The memtable is switched, but never flushed, so the descriptor
+        // still names the first log
+        db.switch_memtable().unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("2")).unwrap();
+        let second_log = db.logfile_number;
+        assert_eq!(first_log, db.versions.log_number());
+        drop(db);
+
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        let read_options = ReadOptions::default();
+        assert_eq!(b"2".to_vec(), db.get(&read_options, &Slice::from_str("a")).unwrap());
+        assert_eq!(b"1".to_vec(), db.get(&read_options, &Slice::from_str("b")).unwrap());
+        assert_eq!(3, db.versions.last_sequence());
+        assert!(db.logfile_number > second_log);
+    }
+
+    #[test]
+    fn test_recover_prev_log() {
+        let dbname = db_path("recover_prev_log");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        let prev_log = db.logfile_number;
+        db.switch_memtable().unwrap();
+        let skipped_log = db.logfile_number;
+        db.put(&WriteOptions::default(), &Slice::from_str("b"), &Slice::from_str("1")).unwrap();
+        // Drop the memtables unflushed, their updates only live in the logs
+        db.imm = None;
+        db.switch_memtable().unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("c"), &Slice::from_str("1")).unwrap();
+        // A descriptor naming a previous log besides the current one
+        let mut edit = VersionEdit::new();
+        edit.set_prev_log_number(prev_log);
+        edit.set_log_number(db.logfile_number);
+        db.versions.log_and_apply(&mut edit).unwrap();
+        db.imm = None;
+        drop(db);
+
+        // Logs between the previous and the current one are not replayed
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        let read_options = ReadOptions::default();
+        assert!(db.get(&read_options, &Slice::from_str("a")).is_ok());
+        assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str("b")));
+        assert!(db.get(&read_options, &Slice::from_str("c")).is_ok());
+        assert!(prev_log < skipped_log);
+    }
+
+    #[test]
+    fn test_comparator_name_mismatch() {
+        let dbname = db_path("comparator_name_mismatch");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        drop(db);
+
+        let options = Options { comparator_name: "revel.ReverseComparator", ..Options::default() };
+        assert_eq!(Err(InvalidArgument), DB::open(&options, &dbname).map(|_| ()));
+        // The rejected open left the db untouched
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(b"1".to_vec(), db.get(&ReadOptions::default(), &Slice::from_str("a")).unwrap());
+        drop(db);
+
+        // The name survives the MANIFEST being rewritten on reopen
+        assert_eq!(Err(InvalidArgument), DB::open(&options, &dbname).map(|_| ()));
+    }
+
+    #[test]
+    fn test_recover_missing_table() {
+        let dbname = db_path("recover_missing_table");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        db.finish_flush(true).unwrap();
+        let table = db.versions.current().files(0)[0].number;
+        drop(db);
+        std::fs::remove_file(table_file_name(&dbname, table)).unwrap();
+        assert_eq!(Err(Corruption), DB::open(&Options::default(), &dbname).map(|_| ()));
+    }
+
+    #[test]
+    fn test_recover_large_log() {
+        let dbname = db_path("recover_large_log");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        let value = "v".repeat(100);
+        for i in 0..1000 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i)), &Slice::from_str(&value)).unwrap();
+        }
+        drop(db);
+
+        // The replayed memtable is flushed whenever it fills up
+        let options = Options { write_buffer_size: 16 << 10, ..Options::default() };
+        let db = DB::open(&options, &dbname).unwrap();
+        assert!(db.versions.current().num_files(0) > 1);
+        assert_eq!(1000, db.scan(&ReadOptions::default(), None, None).count());
+    }
+
+    #[test]
+    fn test_recover_corrupted_log() {
+        let dbname = db_path("recover_corrupted_log");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        for key in ["a", "b", "c"] {
+            db.put(&WriteOptions::default(), &Slice::from_str(key), &Slice::from_str("v")).unwrap();
+        }
+        let log_name = log_file_name(&dbname, db.logfile_number);
+        drop(db);
+
+        // Flip a byte of the second record
+        let mut contents = std::fs::read(&log_name).unwrap();
+        let record_size = contents.len() / 3;
+        contents[record_size + 10] ^= 0xff;
+        std::fs::write(&log_name, &contents).unwrap();
+
+        let paranoid = Options { paranoid_checks: true, ..Options::default() };
+        assert_eq!(Err(Corruption), DB::open(&paranoid, &dbname).map(|_| ()));
+        // Otherwise the log is replayed up to the damage, the rest of the
+        // block being dropped by the reader
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        let read_options = ReadOptions::default();
+        assert!(db.get(&read_options, &Slice::from_str("a")).is_ok());
+        assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str("b")));
+        assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str("c")));
+    }
+
+    #[test]
+    fn test_write_group() {
+        let dbname = db_path("write_group");
+        let mut db = DB::open(&Options::default(), &dbname).expect("open failed");
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        let batches = ["b", "c", "d"].iter().map(|key| {
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::from_str(key), &Slice::from_str("2"));
+            batch
+        });
+        db.write_group(&WriteOptions::default(), batches).unwrap();
+        assert_eq!(4, db.versions.last_sequence());
+        for key in ["a", "b", "c", "d"] {
+            assert!(db.get(&ReadOptions::default(), &Slice::from_str(key)).is_ok());
+        }
+        db.write_group(&WriteOptions::default(), Vec::new()).unwrap();
+        assert_eq!(4, db.versions.last_sequence());
+
+        // Replaying the log applies the group once its last batch is read
+        let log_name = log_file_name(&dbname, db.logfile_number);
+        let mut reader = Reader::new(new_sequential_file(&log_name).unwrap(), None, true, 0);
+        let mut replay = GroupReplay::new();
+        let mut scratch = Vec::new();
+        let mut applied = Vec::new();
+        while let Some(record) = reader.read_record(&mut scratch) {
+            let batch = WriteBatch::from_data(record.data().to_vec()).unwrap();
+            applied.push(replay.add(batch).unwrap().len());
+        }
+        assert_eq!(vec![1, 0, 0, 3], applied);
+        assert_eq!(0, replay.finish());
+    }
+
+    #[test]
+    fn test_write_malformed_batch() {
+        let dbname = db_path("write_malformed_batch");
+        let mut db = DB::open(&Options::default(), &dbname).expect("open failed");
+        let batch = |key: &str| {
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::from_str(key), &Slice::from_str("v"));
+            batch
+        };
+        // The header counts one record more than the batch holds
+        let mut data = batch("a").data().to_vec();
+        data[8] += 1;
+        let malformed = || WriteBatch::from_data(data.clone()).unwrap();
+        let mut marked = batch("m");
+        mark_begin_group(&mut marked, 1);
+
+        assert_eq!(Err(Corruption), db.write(&WriteOptions::default(), malformed()));
+        assert_eq!(Err(InvalidArgument), db.write(&WriteOptions::default(), marked));
+        assert_eq!(Err(Corruption), db.write_group(&WriteOptions::default(), [batch("b"), malformed()]));
+        assert_eq!(0, db.latest_sequence_number());
+        // Nothing of the failed writes is left for the next one
+        db.write(&WriteOptions::default(), batch("c")).unwrap();
+        assert_eq!(1, db.latest_sequence_number());
+
+        let check = |db: &DB| {
+            for key in ["a", "b", "m"] {
+                assert_eq!(Err(NotFound), db.get(&ReadOptions::default(), &Slice::from_str(key)));
+            }
+            assert_eq!(Ok(b"v".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("c")));
+            assert_eq!(1, db.scan(&ReadOptions::default(), None, None).count());
+        };
+        check(&db);
+        drop(db);
+        let db = DB::open(&Options::default(), &dbname).expect("reopen failed");
+        check(&db);
+        assert_eq!(1, db.latest_sequence_number());
+    }
+
+    #[test]
+    fn test_recover_corrupted_group() {
+        let dbname = db_path("recover_corrupted_group");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("v")).unwrap();
+        // Records large enough to span blocks, so that damaging one block
+        // leaves the commit marker readable
+        let value = vec![b'v'; 40000];
+        let batches = ["g1", "g2", "g3", "g4"].iter().map(|key| {
+            let mut batch = WriteBatch::new();
+            batch.put(&Slice::from_str(key), &Slice::from_bytes(&value));
+            batch
+        });
+        db.write_group(&WriteOptions::default(), batches).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("b"), &Slice::from_str("v")).unwrap();
+        let log_name = log_file_name(&dbname, db.logfile_number);
+        drop(db);
+
+        // Damage the third block, which holds the middle of the group
+        let mut contents = std::fs::read(&log_name).unwrap();
+        contents[70000] ^= 0xff;
+        std::fs::write(&log_name, &contents).unwrap();
+
+        // The whole group is dropped, not only the records that were lost
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        let read_options = ReadOptions::default();
+        for key in ["g1", "g2", "g3", "g4"] {
+            assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str(key)));
+        }
+        assert!(db.get(&read_options, &Slice::from_str("a")).is_ok());
+        assert!(db.get(&read_options, &Slice::from_str("b")).is_ok());
+    }
+
+    #[test]
+    fn test_recover_failed_write_group() {
+        let dbname = db_path("recover_failed_write_group");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("v")).unwrap();
+        // A write_group() failing after logging its first batch leaves the
+        // last sequence as it was
+        let group_id = db.versions.last_sequence() + 1;
+        let mut batch = WriteBatch::new();
+        mark_begin_group(&mut batch, group_id);
+        batch.put(&Slice::from_str("g"), &Slice::from_str("v"));
+        batch.set_sequence(group_id);
+        db.log.add_record(&batch.contents()).unwrap();
+        for key in ["b", "c"] {
+            db.put(&WriteOptions::default(), &Slice::from_str(key), &Slice::from_str("v")).unwrap();
+        }
+        drop(db);
+
+        // The writes following the failed group are not swallowed by it
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        let read_options = ReadOptions::default();
+        assert_eq!(Err(NotFound), db.get(&read_options, &Slice::from_str("g")));
+        for key in ["a", "b", "c"] {
+            assert!(db.get(&read_options, &Slice::from_str(key)).is_ok());
+        }
+        assert_eq!(3, db.latest_sequence_number());
+    }
+
+    #[test]
+    fn test_new_iterator() {
+        let dbname = db_path("new_iterator");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        for key in ["a", "b", "c", "d"] {
+            db.put(&WriteOptions::default(), &Slice::from_str(key), &Slice::from_str("1")).unwrap();
+        }
+        // Tables, the immutable memtable and the memtable are merged
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        db.finish_flush(true).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("b"), &Slice::from_str("2")).unwrap();
+        db.delete(&WriteOptions::default(), &Slice::from_str("c")).unwrap();
+        db.switch_memtable().unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("e"), &Slice::from_str("3")).unwrap();
+
+        let pinned = Rc::strong_count(&db.versions.current());
+        let mut iter = db.new_iterator(&ReadOptions::default());
+        assert_eq!(pinned + 1, Rc::strong_count(&db.versions.current()));
+        assert_eq!(7, iter.sequence());
+        assert!(!iter.valid());
+
+        let entry = |iter: &DBIterator| format!("{}->{}", iter.key(), iter.value());
+        let mut forward = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            forward.push(entry(&iter));
+            iter.next();
+        }
+        assert_eq!(vec!["a->1", "b->2", "d->1", "e->3"], forward);
+
+        let mut backward = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            backward.push(entry(&iter));
+            iter.prev();
+        }
+        assert_eq!(vec!["e->3", "d->1", "b->2", "a->1"], backward);
+
+        // Seeks land on the next visible key, and the iterator can turn
+        // around
+        iter.seek(&Slice::from_str("c"));
+        assert_eq!("d->1", entry(&iter));
+        iter.prev();
+        assert_eq!("b->2", entry(&iter));
+        iter.next();
+        assert_eq!("d->1", entry(&iter));
+        iter.seek(&Slice::from_str("f"));
+        assert!(!iter.valid());
+        assert_eq!(Ok(()), iter.status());
+
+        drop(iter);
+        assert_eq!(pinned, Rc::strong_count(&db.versions.current()));
+    }
+
+    #[test]
+    fn test_refresh_iterator() {
+        let dbname = db_path("refresh_iterator");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        let put = |db: &mut DB, key: &str, value: &str| {
+            db.put(&WriteOptions::default(), &Slice::from_str(key), &Slice::from_str(value)).unwrap();
+        };
+        for key in ["a", "b", "c"] {
+            put(&mut db, key, "1");
+        }
+        let entry = |iter: &DBIterator| format!("{}->{}", iter.key(), iter.value());
+        let mut iter = db.new_iterator(&ReadOptions::default());
+        iter.seek(&Slice::from_str("b"));
+        assert_eq!("b->1", entry(&iter));
+
+        // The db is written to and its memtable flushed while the iterator
+        // is in use, the iterator keeps reading what it was created on
+        put(&mut db, "b", "2");
+        put(&mut db, "d", "1");
+        db.delete(&WriteOptions::default(), &Slice::from_str("c")).unwrap();
+        db.flush(&FlushOptions::default()).unwrap();
+        assert_eq!(3, iter.sequence());
+        iter.next();
+        assert_eq!("c->1", entry(&iter));
+        iter.prev();
+        assert_eq!("b->1", entry(&iter));
+
+        // Refreshing keeps the position and shows the writes
+        iter.refresh(&db).unwrap();
+        assert_eq!(6, iter.sequence());
+        assert_eq!("b->2", entry(&iter));
+        iter.next();
+        assert_eq!("d->1", entry(&iter));
+
+        // Range tombstones written since are honored too
+        db.delete_range(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("c")).unwrap();
+        iter.refresh(&db).unwrap();
+        assert_eq!("d->1", entry(&iter));
+        iter.seek_to_first();
+        assert_eq!("d->1", entry(&iter));
+
+        // An unpositioned iterator stays unpositioned
+        iter.next();
+        assert!(!iter.valid());
+        put(&mut db, "e", "1");
+        iter.refresh(&db).unwrap();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert_eq!("e->1", entry(&iter));
+
+        // An iterator reading at a snapshot cannot be refreshed
+        let snapshot = db.get_snapshot();
+        let mut at_snapshot = db.new_iterator(&ReadOptions { snapshot: Some(&snapshot), ..ReadOptions::default() });
+        assert_eq!(Err(NotSupport), at_snapshot.refresh(&db));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let dbname = db_path("snapshot");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        let put = |db: &mut DB, key: &str, value: &str| {
+            db.put(&WriteOptions::default(), &Slice::from_str(key), &Slice::from_str(value)).unwrap();
+        };
+        put(&mut db, "a", "1");
+        put(&mut db, "b", "1");
+        let s1 = db.get_snapshot();
+        assert_eq!(2, s1.sequence_number());
+        put(&mut db, "a", "2");
+        db.delete(&WriteOptions::default(), &Slice::from_str("b")).unwrap();
+        put(&mut db, "c", "2");
+        let s2 = db.get_snapshot();
+
+        fn at(snapshot: &Snapshot) -> ReadOptions<'_> {
+            ReadOptions { snapshot: Some(snapshot), ..ReadOptions::default() }
+        }
+        let get = |db: &DB, options: &ReadOptions, key: &str| db.get(options, &Slice::from_str(key)).ok();
+        let check = |db: &DB, s1: &Snapshot, s2: &Snapshot| {
+            assert_eq!(Some(b"1".to_vec()), get(db, &at(s1), "a"));
+            assert_eq!(Some(b"1".to_vec()), get(db, &at(s1), "b"));
+            assert_eq!(None, get(db, &at(s1), "c"));
+            assert_eq!(Ok(false), db.contains_key(&at(s1), &Slice::from_str("c")));
+            assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], db.keys(&at(s1), None, None).collect::<Result<Vec<_>>>().unwrap());
+            let mut iter = db.new_iterator(&at(s1));
+            iter.seek_to_last();
+            assert_eq!((b"b".as_ref(), b"1".as_ref()), (iter.key().data(), iter.value().data()));
+
+            assert_eq!(Some(b"2".to_vec()), get(db, &at(s2), "a"));
+            assert_eq!(None, get(db, &at(s2), "b"));
+            assert_eq!(vec![b"a".to_vec(), b"c".to_vec()], db.keys(&at(s2), None, None).collect::<Result<Vec<_>>>().unwrap());
+        };
+        check(&db, &s1, &s2);
+
+        // Writes after the snapshots do not show through them
+        put(&mut db, "a", "3");
+        put(&mut db, "b", "3");
+        check(&db, &s1, &s2);
+        assert_eq!(Some(b"3".to_vec()), get(&db, &ReadOptions::default(), "a"));
+
+        // Nor are the entries they see dropped by compactions
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        db.finish_flush(true).unwrap();
+        let mut edit = VersionEdit::new();
+        let f = db.versions.current().files(0)[0].clone();
+        edit.remove_file(0, f.number);
+        edit.add_file(1, (*f).clone());
+        db.versions.log_and_apply(&mut edit).unwrap();
+        for _ in 0..K_L0_COMPACTION_TRIGGER {
+            put(&mut db, "a", "4");
+            put(&mut db, "z", "4");
+            db.switch_memtable().unwrap();
+            db.schedule_flush();
+            db.finish_flush(true).unwrap();
+        }
+        db.maybe_schedule_compaction().unwrap();
+        db.finish_compaction(true).unwrap();
+        assert_eq!(0, db.versions.current().num_files(0));
+        check(&db, &s1, &s2);
+        assert_eq!(2, db.snapshots.borrow().len());
+        db.release_snapshot(s1);
+        drop(s2);
+        assert!(db.snapshots.borrow().is_empty());
+
+        // Dropped snapshots no longer hold back compactions
+        {
+            let _snapshot = db.get_snapshot();
+        }
+        assert!(db.snapshots.borrow().is_empty());
+        // A snapshot may outlive its db
+        let snapshot = db.get_snapshot();
+        drop(db);
+        drop(snapshot);
+    }
+
+    #[test]
+    fn test_compaction_drops_entries_after_release() {
+        let dbname = db_path("compaction_after_release");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        drop(db.get_snapshot());
+        for i in 0..K_L0_COMPACTION_TRIGGER {
+            db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str(&i.to_string())).unwrap();
+            db.switch_memtable().unwrap();
+            db.schedule_flush();
+            db.finish_flush(true).unwrap();
+        }
+        db.maybe_schedule_compaction().unwrap();
+        db.finish_compaction(true).unwrap();
+
+        // Only the newest entry of "a" survives without snapshots
+        let files = db.versions.current().files(1).to_vec();
+        assert_eq!(1, files.len());
+        assert_eq!((4, 4), (files[0].smallest_seqno, files[0].largest_seqno));
+    }
+
+    #[test]
+    fn test_disable_wal() {
+        let dbname = db_path("disable_wal");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        let no_wal = WriteOptions { sync: false, disable_wal: true };
+        let log_size = |db: &DB| std::fs::metadata(log_file_name(&dbname, db.logfile_number)).unwrap().len();
+        db.put(&WriteOptions::default(), &Slice::from_str("logged"), &Slice::from_str("1")).unwrap();
+        let logged = log_size(&db);
+        assert!(logged > 0);
+        db.put(&no_wal, &Slice::from_str("unlogged"), &Slice::from_str("2")).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("grouped"), &Slice::from_str("3"));
+        db.write_group(&no_wal, vec![batch]).unwrap();
+        assert_eq!(logged, log_size(&db));
+        assert_eq!(Ok(b"2".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("unlogged")));
+        assert_eq!(Ok(b"3".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("grouped")));
+        let sync_no_wal = WriteOptions { sync: true, disable_wal: true };
+        assert_eq!(Err(InvalidArgument), db.put(&sync_no_wal, &Slice::from_str("a"), &Slice::from_str("b")));
+        drop(db);
+
+        // Only the logged write survives a reopen without a flush
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(Ok(b"1".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("logged")));
+        assert_eq!(Err(NotFound), db.get(&ReadOptions::default(), &Slice::from_str("unlogged")));
+        assert_eq!(Err(NotFound), db.get(&ReadOptions::default(), &Slice::from_str("grouped")));
+
+        // A flush makes unlogged writes durable
+        db.put(&no_wal, &Slice::from_str("unlogged"), &Slice::from_str("2")).unwrap();
+        db.flush(&FlushOptions::default()).unwrap();
+        drop(db);
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(Ok(b"2".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("unlogged")));
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let dbname = db_path("delete_range");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        let key = |i: usize| format!("key{:02}", i);
+        for i in 0..20 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&key(i)), &Slice::from_str("v1")).unwrap();
+        }
+        let snapshot = db.get_snapshot();
+        db.delete_range(&WriteOptions::default(), &Slice::from_str(&key(5)), &Slice::from_str(&key(15))).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str(&key(10)), &Slice::from_str("v2")).unwrap();
+        assert_eq!(Err(InvalidArgument), db.delete_range(&WriteOptions::default(), &Slice::from_str("b"), &Slice::from_str("a")));
+
+        let check = |db: &DB| {
+            let expected: Vec<Vec<u8>> = (0..5).chain([10]).chain(15..20).map(|i| key(i).into_bytes()).collect();
+            for i in 0..20 {
+                let found = expected.contains(&key(i).into_bytes());
+                assert_eq!(found, db.get(&ReadOptions::default(), &Slice::from_str(&key(i))).is_ok());
+                assert_eq!(Ok(found), db.contains_key(&ReadOptions::default(), &Slice::from_str(&key(i))));
+            }
+            assert_eq!(Ok(b"v2".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str(&key(10))));
+            let keys: Vec<Vec<u8>> = db.keys(&ReadOptions::default(), None, None).map(Result::unwrap).collect();
+            assert_eq!(expected, keys);
+            let mut keys: Vec<Vec<u8>> = db.keys(&ReadOptions::default(), None, None).rev().map(Result::unwrap).collect();
+            keys.reverse();
+            assert_eq!(expected, keys);
+            let mut iter = db.new_iterator(&ReadOptions::default());
+            iter.seek(&Slice::from_str(&key(5)));
+            assert_eq!(key(10).as_bytes(), iter.key().data());
+            iter.next();
+            assert_eq!(key(15).as_bytes(), iter.key().data());
+        };
+        check(&db);
+        // The snapshot taken before the deletion still sees the keys
+        let at_snapshot = ReadOptions { snapshot: Some(&snapshot), ..ReadOptions::default() };
+        assert_eq!(Ok(b"v1".to_vec()), db.get(&at_snapshot, &Slice::from_str(&key(7))));
+        assert_eq!(20, db.scan(&at_snapshot, None, None).count());
+
+        // The tombstone is written to the level-0 table
+        db.flush(&FlushOptions::default()).unwrap();
+        check(&db);
+        assert_eq!(Ok(b"v1".to_vec()), db.get(&at_snapshot, &Slice::from_str(&key(7))));
+        drop(snapshot);
+        drop(db);
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        check(&db);
+
+        // Compacting into the bottommost level drops the tombstone along with
+        // the entries it deletes
+        for i in 1..K_L0_COMPACTION_TRIGGER {
+            db.put(&WriteOptions::default(), &Slice::from_str(&key(i)), &Slice::from_str("v1")).unwrap();
+            db.flush(&FlushOptions::default()).unwrap();
+        }
+        db.finish_compaction(true).unwrap();
+        assert_eq!(0, db.versions.current().num_files(0));
+        check(&db);
+        let level1 = db.versions.current().files(1).to_vec();
+        assert_eq!(1, level1.len());
+        for f in &level1 {
+            let fname = table_file_name(&dbname, f.number);
+            let table = Table::open(&Options::default(), new_random_access_file(&fname).unwrap(), f.number, f.file_size).unwrap();
+            assert_eq!(0, table.properties().num_range_deletions);
+            assert_eq!(11, table.properties().num_entries);
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let mut db = DB::open(&Options::default(), db_path("update")).unwrap();
+        let increment = |old: Option<&[u8]>| {
+            let count: u64 = old.map_or(0, |value| std::str::from_utf8(value).unwrap().parse().unwrap());
+            Some((count + 1).to_string().into_bytes())
+        };
+        for i in 1..=3 {
+            assert_eq!(Ok(Some(i.to_string().into_bytes())), db.update(&WriteOptions::default(), &Slice::from_str("counter"), increment));
+        }
+        assert_eq!(Ok(b"3".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("counter")));
+
+        // A None result deletes the key, or leaves it missing
+        assert_eq!(Ok(None), db.update(&WriteOptions::default(), &Slice::from_str("counter"), |old| {
+            assert_eq!(Some(&b"3"[..]), old);
+            None
+        }));
+        assert_eq!(Err(NotFound), db.get(&ReadOptions::default(), &Slice::from_str("counter")));
+        let sequence = db.latest_sequence_number();
+        assert_eq!(Ok(None), db.update(&WriteOptions::default(), &Slice::from_str("counter"), |_| None));
+        assert_eq!(sequence, db.latest_sequence_number());
+    }
+
+    #[test]
+    fn test_sync_wal() {
+        let mut db = DB::open(&Options::default(), db_path("sync_wal")).unwrap();
+        db.put(&WriteOptions { sync: false, ..WriteOptions::default() }, &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        assert_eq!(0, db.stats.ticker_count(Ticker::WalFileSynced));
+        db.sync_wal().unwrap();
+        assert_eq!(1, db.stats.ticker_count(Ticker::WalFileSynced));
+    }
+
+    #[test]
+    fn test_flush() {
+        let dbname = db_path("flush");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        let level0_files = |db: &DB| db.versions.current().num_files(0);
+        // Nothing to flush
+        db.flush(&FlushOptions::default()).unwrap();
+        assert_eq!(0, level0_files(&db));
+
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        db.flush(&FlushOptions::default()).unwrap();
+        assert_eq!(1, level0_files(&db));
+        assert!(db.imm.is_none());
+        assert_eq!("0", db.get_property("revel.cur-size-active-mem-table").unwrap());
+        // Only the new, empty log is left
+        assert_eq!(vec![db.logfile_number], file_numbers(&dbname, FileType::kLogFile));
+
+        // Without waiting, the table is installed by a later flush or write
+        db.put(&WriteOptions::default(), &Slice::from_str("b"), &Slice::from_str("2")).unwrap();
+        db.flush(&FlushOptions { wait: false }).unwrap();
+        assert_eq!("0", db.get_property("revel.cur-size-active-mem-table").unwrap());
+        db.put(&WriteOptions::default(), &Slice::from_str("c"), &Slice::from_str("3")).unwrap();
+        db.flush(&FlushOptions::default()).unwrap();
+        assert_eq!(3, level0_files(&db));
+        drop(db);
+
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(3, level0_files(&db));
+        for (key, value) in [("a", "1"), ("b", "2"), ("c", "3")] {
+            assert_eq!(Ok(value.as_bytes().to_vec()), db.get(&ReadOptions::default(), &Slice::from_str(key)));
+        }
+    }
+
+    #[test]
+    fn test_multi_get() {
+        let dbname = db_path("multi_get");
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        for i in 0..100 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:03}", i)), &Slice::from_str(&format!("v{}", i))).unwrap();
+        }
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        db.finish_flush(true).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("key050"), &Slice::from_str("new")).unwrap();
+        db.delete(&WriteOptions::default(), &Slice::from_str("key010")).unwrap();
+        let snapshot = db.get_snapshot();
+        db.put(&WriteOptions::default(), &Slice::from_str("key020"), &Slice::from_str("later")).unwrap();
+
+        let keys = ["key099", "missing", "key050", "key010", "key000", "key020", "key099"];
+        let keys: Vec<Slice> = keys.iter().map(|key| Slice::from_str(key)).collect();
+        let read_options = ReadOptions { snapshot: Some(&snapshot), ..ReadOptions::default() };
+        let results = db.multi_get(&read_options, &keys);
+        let expected = vec![Ok(b"v99".to_vec()), Err(NotFound), Ok(b"new".to_vec()), Err(NotFound),
+                            Ok(b"v0".to_vec()), Ok(b"v20".to_vec()), Ok(b"v99".to_vec())];
+        assert_eq!(expected, results);
+        // The same as getting the keys one by one
+        for (key, result) in keys.iter().zip(&results) {
+            assert_eq!(*result, db.get(&read_options, key));
+        }
+        assert_eq!(Ok(b"later".to_vec()), db.multi_get(&ReadOptions::default(), &keys[5..6]).remove(0));
+        assert!(db.multi_get(&ReadOptions::default(), &[]).is_empty());
+    }
+
+    #[test]
+    fn test_approximate_sizes() {
+        let dbname = db_path("approximate_sizes");
+        let options = Options { compression: CompressionType::NoCompression, ..Options::default() };
+        let mut db = DB::open(&options, &dbname).unwrap();
+        let key = |i: usize| format!("key{:06}", i);
+        let size = |db: &DB, start: usize, limit: usize| {
+            let (start, limit) = (key(start), key(limit));
+            db.get_approximate_sizes(&[Range::new(Slice::from_str(&start), Slice::from_str(&limit))])[0]
+        };
+        let value = vec![b'x'; 1000];
+        for i in 0..80 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&key(i)), &Slice::from_bytes(&value)).unwrap();
+        }
+        // Data in the memtable is not counted
+        assert_eq!(0, size(&db, 0, 80));
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        db.finish_flush(true).unwrap();
+
+        let ranges = [Range::new(Slice::from_str("a"), Slice::from_str("z")), Range::new(Slice::from_str("z"), Slice::from_str("a"))];
+        let sizes = db.get_approximate_sizes(&ranges);
+        assert!(sizes[0] >= 80000 && sizes[0] <= 90000, "{}", sizes[0]);
+        // An empty or reversed range holds nothing
+        assert_eq!(0, sizes[1]);
+        for (start, limit) in [(0, 10), (10, 30), (50, 80)] {
+            let expected = (limit - start) as u64 * 1000;
+            let size = size(&db, start, limit);
+            assert!(size >= expected && size <= expected + 5000, "[{}, {}) {}", start, limit, size);
+        }
+        assert_eq!(size(&db, 0, 80), size(&db, 0, 40) + size(&db, 40, 80));
+    }
+
+    #[test]
+    fn test_scan_pins_version() {
+        let db = DB::open(&Options::default(), db_path("scan_pins_version")).expect("open failed");
+        let pinned = Rc::strong_count(&db.versions.current());
+        let scan = db.scan(&ReadOptions::default(), None, None);
+        assert_eq!(pinned + 1, Rc::strong_count(&db.versions.current()));
+        drop(scan);
+        assert_eq!(pinned, Rc::strong_count(&db.versions.current()));
+        assert_eq!("1", db.get_property("revel.num-live-versions").unwrap());
+    }
+
+    #[test]
+    fn test_pinned_version_files_survive_compaction() {
+        let dbname = db_path("pinned_version_files");
+        let mut db = DB::open(&Options::default(), &dbname).expect("open failed");
+        let key = |i: usize| format!("key{:03}", i);
+        // Overlapping level-0 files, which the compaction has to rewrite
+        let flush_round = |db: &mut DB, round: usize| {
+            for i in 0..100 {
+                db.put(&WriteOptions::default(), &Slice::from_str(&key(i)), &Slice::from_str(&format!("v{}", round))).unwrap();
+            }
+            db.flush(&FlushOptions { wait: true }).unwrap();
+        };
+        for round in 1..K_L0_COMPACTION_TRIGGER {
+            flush_round(&mut db, round);
+        }
+        let pinned = db.versions.current();
+        let sequence = db.latest_sequence_number();
+        let pinned_files = pinned.files(0).to_vec();
+        assert_eq!(K_L0_COMPACTION_TRIGGER - 1, pinned_files.len());
+
+        flush_round(&mut db, K_L0_COMPACTION_TRIGGER);
+        db.maybe_schedule_compaction().unwrap();
+        db.finish_compaction(true).unwrap();
+        db.delete_obsolete_files();
+        assert_eq!(0, db.versions.current().num_files(0));
+
+        // The files of the pinned version are still on disk, and read back
+        // the state of the db when it was pinned
+        for f in &pinned_files {
+            assert!(table_file_name(&dbname, f.number).exists());
+            db.table_cache.evict(f.number);
+        }
+        for i in 0..100 {
+            let value = pinned.get(&ReadOptions::default(), &LookupKey::new(&Slice::from_str(&key(i)), sequence), &db.table_cache, db.options.comparator);
+            assert_eq!(Ok(format!("v{}", K_L0_COMPACTION_TRIGGER - 1).into_bytes()), value);
+        }
+
+        drop(pinned);
+        db.delete_obsolete_files();
+        for f in &pinned_files {
+            assert!(!table_file_name(&dbname, f.number).exists());
+        }
+    }
+
+    #[test]
+    fn test_latest_sequence_number() {
+        let mut db = DB::open(&Options::default(), db_path("latest_sequence_number")).expect("open failed");
+        assert_eq!(0, db.latest_sequence_number());
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        db.put_batch(&WriteOptions::default(), [("b", "2"), ("c", "3")]).unwrap();
+        assert_eq!(3, db.latest_sequence_number());
+
+        let scan = db.scan(&ReadOptions::default(), None, None);
+        assert_eq!(3, scan.sequence());
+        assert_eq!(3, db.keys(&ReadOptions::default(), None, None).sequence());
+        assert_eq!(3, db.values(&ReadOptions::default(), None, None).sequence());
+        drop(scan);
+
+        db.delete(&WriteOptions::default(), &Slice::from_str("a")).unwrap();
+        assert_eq!(4, db.latest_sequence_number());
+    }
+
+    #[test]
+    fn test_export_import() {
+        let mut db = DB::open(&Options::default(), db_path("export")).expect("open failed");
+        db.put_batch(&WriteOptions::default(), [("a", "1"), ("b,c", "2\n"), ("d", "")]).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("big"), &Slice::from_bytes(&vec![7; K_IMPORT_BATCH_SIZE])).unwrap();
+        db.delete(&WriteOptions::default(), &Slice::from_str("d")).unwrap();
+        let contents = |db: &DB| db.scan(&ReadOptions::default(), None, None).collect::<Result<Vec<_>>>().unwrap();
+
+        for format in [DumpFormat::Binary, DumpFormat::Csv] {
+            let dump = std::env::temp_dir().join(format!("revel_db_test_export_{:?}.dump", format));
+            assert_eq!(3, db.export(&dump, format).unwrap());
+
+            let mut imported = DB::open(&Options::default(), db_path("import")).expect("open failed");
+            imported.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("old")).unwrap();
+            assert_eq!(3, imported.import(&dump).unwrap());
+            assert_eq!(contents(&db), contents(&imported));
+            // The big entry filled the first batch, leaving the last one empty
+            assert_eq!(vec![7; K_IMPORT_BATCH_SIZE], imported.get(&ReadOptions::default(), &Slice::from_str("big")).unwrap());
+            assert_eq!(4, imported.latest_sequence_number());
+        }
+    }
+
+    #[test]
+    fn test_import_large_dump() {
+        let mut db = DB::open(&Options::default(), db_path("export_large")).expect("open failed");
+        let value = vec![b'v'; 1000];
+        let keys: Vec<String> = (0..5000).map(|i| format!("key{:06}", i)).collect();
+        for chunk in keys.chunks(100) {
+            db.put_batch(&WriteOptions::default(), chunk.iter().map(|key| (key.as_str(), value.as_slice()))).unwrap();
+        }
+        let dump = std::env::temp_dir().join("revel_db_test_export_large.dump");
+        assert_eq!(5000, db.export(&dump, DumpFormat::Binary).unwrap());
+        assert!(std::fs::metadata(&dump).unwrap().len() > 4 * K_IMPORT_BATCH_SIZE as u64);
+
+        let mut imported = DB::open(&Options::default(), db_path("import_large")).expect("open failed");
+        assert_eq!(5000, imported.import(&dump).unwrap());
+        // Every entry read from the dump was written
+        assert_eq!(5000, imported.latest_sequence_number());
+        let entries = imported.scan(&ReadOptions::default(), None, None).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(5000, entries.len());
+        for ((key, v), expected) in entries.iter().zip(&keys) {
+            assert_eq!(expected.as_bytes(), key.as_slice());
+            assert_eq!(&value, v);
+        }
+    }
+
+    #[test]
+    fn test_mmap_writes() {
+        let dbname = db_path("mmap_writes");
+        let options = Options { allow_mmap_writes: true, allow_mmap_wal_writes: true, ..Options::default() };
+        let mut db = DB::open(&options, &dbname).expect("open failed");
+        for i in 0..100 {
+            let key = format!("key{:03}", i);
+            db.put(&WriteOptions::default(), &Slice::from_str(&key), &Slice::from_str("value")).unwrap();
+        }
+        assert_eq!(Ok(b"value".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("key042")));
+        drop(db);
+
+        // Closing the files cut them back from the mapped size
+        let log_size = std::fs::metadata(log_file_name(&dbname, 3)).unwrap().len();
+        assert!(log_size > 0 && log_size < 65536);
+        let db = DB::open(&options, &dbname).expect("reopen failed");
+        assert_eq!(4, db.versions.manifest_file_number());
+    }
+
+    #[test]
+    fn test_db_identity() {
+        let dbname = db_path("identity");
+        let db = DB::open(&Options::default(), &dbname).expect("open failed");
+        let id = db.get_db_identity().to_string();
+        let session = db.get_db_session_id().to_string();
+        assert_eq!(36, id.len());
+        assert_eq!(20, session.len());
+        assert_eq!(Some(id.clone()), db.get_property("revel.db-id"));
+        assert_eq!(Some(session.clone()), db.get_property("revel.db-session-id"));
+        drop(db);
+
+        // The db id survives a reopen, the session id does not
+        let db = DB::open(&Options::default(), &dbname).expect("reopen failed");
+        assert_eq!(id, db.get_db_identity());
+        assert_ne!(session, db.get_db_session_id());
+        drop(db);
+
+        std::fs::write(identity_file_name(&dbname), "").unwrap();
+        assert_eq!(Some(Corruption), DB::open(&Options::default(), &dbname).err());
+    }
+
+    #[test]
+    fn test_sstables_property() {
+        let dbname = db_path("sstables");
+        let db = DB::open(&Options::default(), &dbname).expect("open failed");
+        // Nothing has been flushed, so every level is listed without files
+        let sstables = db.get_property("revel.sstables").expect("missing property");
+        let expected: String = (0..K_NUM_LEVELS).map(|level| format!("--- level {} ---\n", level)).collect();
+        assert_eq!(expected, sstables);
+    }
+
+    #[test]
+    fn test_stats_property() {
+        let dbname = db_path("stats_property");
+        let options = Options { compression: CompressionType::NoCompression, ..Options::default() };
+        let mut db = DB::open(&options, &dbname).expect("open failed");
+        let header = "                       Compactions\n\
+                      Level  Files Size(MB) Read(MB) Write(MB)\n\
+                      ----------------------------------------\n";
+        assert_eq!(Some(header.to_string()), db.get_property("revel.stats"));
+        let usage = |db: &DB| db.get_property("revel.approximate-memory-usage").unwrap().parse::<usize>().unwrap();
+        let empty_usage = usage(&db);
+
+        let value = vec![b'x'; 1 << 10];
+        for i in 0..3 << 10 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i)), &Slice::from_bytes(&value)).unwrap();
+        }
+        assert!(usage(&db) >= empty_usage + (3 << 20));
+        db.switch_memtable().unwrap();
+        db.schedule_flush();
+        db.finish_flush(true).unwrap();
+        // The flush wrote a single table of about 3MB to level 0
+        let expected = format!("{}  0        1        3        0         3\n", header);
+        assert_eq!(Some(expected), db.get_property("revel.stats"));
+    }
+
+    #[test]
+    fn test_snapshot_properties() {
+        let mut db = DB::open(&Options::default(), db_path("snapshot_properties")).expect("open failed");
+        let property = |db: &DB, name: &str| db.get_property(name).unwrap();
+        assert_eq!(("0", "0"), (&*property(&db, "revel.num-snapshots"), &*property(&db, "revel.oldest-snapshot-sequence")));
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("1")).unwrap();
+        let first = db.get_snapshot();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("2")).unwrap();
+        let second = db.get_snapshot();
+        assert_eq!(("2", "1"), (&*property(&db, "revel.num-snapshots"), &*property(&db, "revel.oldest-snapshot-sequence")));
+        db.release_snapshot(first);
+        assert_eq!(("1", "2"), (&*property(&db, "revel.num-snapshots"), &*property(&db, "revel.oldest-snapshot-sequence")));
+        drop(second);
+        assert_eq!("0", property(&db, "revel.num-snapshots"));
+    }
+
+    #[test]
+    fn test_num_files_at_level_property() {
+        let dbname = db_path("num_files_at_level");
+        let db = DB::open(&Options::default(), &dbname).expect("open failed");
+        for level in 0..K_NUM_LEVELS {
+            let property = format!("revel.num-files-at-level{}", level);
+            assert_eq!(Some("0".to_string()), db.get_property(&property));
+        }
+        let out_of_range = format!("revel.num-files-at-level{}", K_NUM_LEVELS);
+        assert_eq!(None, db.get_property(&out_of_range));
+        assert_eq!(None, db.get_property("revel.num-files-at-level"));
+        assert_eq!(None, db.get_property("revel.num-files-at-levelx"));
+        assert_eq!(Some(format!("files[{} ]", " 0".repeat(K_NUM_LEVELS))), db.get_property("revel.levelsummary"));
+    }
+
+    #[test]
+    fn test_block_cache_properties() {
+        let property = |db: &DB, name: &str| db.get_property(&format!("revel.block-cache-{}", name)).unwrap();
+        let db = DB::open(&Options::default(), db_path("block_cache_default")).expect("open failed");
+        assert_eq!((8 << 20).to_string(), property(&db, "capacity"));
+        assert_eq!("0", property(&db, "usage"));
+        drop(db);
+
+        // A cache shared by the caller reports every user's activity
+        let cache = Arc::new(ShardedLRUCache::new(1 << 20));
+        let options = Options { block_cache: Some(cache.clone()), ..Options::default() };
+        let mut db = DB::open(&options, db_path("block_cache_shared")).expect("open failed");
+        let pinned = cache.insert(b"block1", vec![0; 100], 100, CachePriority::High).unwrap();
+        cache.insert(b"block2", vec![0; 50], 50, CachePriority::Low).unwrap();
+        assert!(cache.lookup(b"block2").is_some());
+        assert!(cache.lookup(b"block3").is_none());
+        assert_eq!((1 << 20).to_string(), property(&db, "capacity"));
+        assert_eq!("150", property(&db, "usage"));
+        assert_eq!("100", property(&db, "pinned-usage"));
+        assert_eq!("2", property(&db, "entries"));
+        assert_eq!("1", property(&db, "hits"));
+        assert_eq!("1", property(&db, "misses"));
+        drop(pinned);
+        assert_eq!("0", property(&db, "pinned-usage"));
+
+        db.stats.record_tick(Ticker::BlockCacheHit, 3);
+        db.put(&WriteOptions::default(), &Slice::from_str("k"), &Slice::from_str("v")).unwrap();
+        let tickers = db.get_property("revel.tickers").unwrap();
+        assert!(tickers.contains("block.cache.hit COUNT : 3\n"));
+        assert!(tickers.contains("block.cache.miss COUNT : 0\n"));
+        assert!(tickers.contains("number.keys.written COUNT : 1\n"));
+    }
+
+    #[test]
+    fn test_close() {
+        let dbname = db_path("close");
+        let options = Options { wal_sync_interval: Some(Duration::from_millis(10)), ..Options::default() };
+        let mut db = DB::open(&options, &dbname).unwrap();
+        let no_sync = WriteOptions { sync: false, ..WriteOptions::default() };
+        db.put(&no_sync, &Slice::from_str("flushed"), &Slice::from_str("1")).unwrap();
+        db.flush(&FlushOptions { wait: false }).unwrap();
+        db.put(&no_sync, &Slice::from_str("logged"), &Slice::from_str("2")).unwrap();
+        db.close().unwrap();
+
+        // The running flush was installed, the lock released and the log
+        // kept what was written to it, replayed to a second table
+        let db = DB::open(&Options::default(), &dbname).expect("lock was not released");
+        assert_eq!(2, db.versions.current().num_files(0));
+        assert_eq!(Ok(b"1".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("flushed")));
+        assert_eq!(Ok(b"2".to_vec()), db.get(&ReadOptions::default(), &Slice::from_str("logged")));
+    }
+
+    #[test]
+    fn test_lock_wait_timeout() {
+        let dbname = db_path("lock_wait_timeout");
+        let db = DB::open(&Options::default(), &dbname).expect("error");
+        assert_eq!(Err(IOError), DB::open(&Options::default(), &dbname).map(|_| ()));
+        let options = Options { lock_wait_timeout: Duration::from_millis(20), ..Options::default() };
+        let start = Instant::now();
+        assert_eq!(Err(IOError), DB::open(&options, &dbname).map(|_| ()));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        drop(db);
+
+        // The lock is released by its holder while the open waits
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let lock_name = lock_file_name(&dbname);
+        let holder = std::thread::spawn(move || {
+            let lock = try_lock_file(&lock_name).unwrap().expect("lock is held");
+            locked_tx.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            drop(lock);
+        });
+        locked_rx.recv().unwrap();
+        assert_eq!(Err(IOError), DB::open(&Options::default(), &dbname).map(|_| ()));
+        let options = Options { lock_wait_timeout: Duration::from_secs(30), ..Options::default() };
+        DB::open(&options, &dbname).expect("open waiting for the lock failed");
+        holder.join().unwrap();
+    }
+
+    #[test]
+    fn test_wal_sync_interval() {
+        let dbname = db_path("wal_sync_interval");
+        let options = Options { wal_sync_interval: Some(Duration::ZERO), ..Options::default() };
+        assert_eq!(Err(InvalidArgument), DB::open(&options, &dbname).map(|_| ()));
+
+        let db = DB::open(&Options::default(), &dbname).expect("error");
+        assert!(db.wal_sync.is_none());
+        drop(db);
+
+        let options = Options { wal_sync_interval: Some(Duration::from_millis(5)), ..Options::default() };
+        let mut db = DB::open(&options, &dbname).expect("error");
+        db.put(&WriteOptions::default(), &Slice::from_str("key"), &Slice::from_str("value")).expect("put error");
+        let start = Instant::now();
+        while db.stats.ticker_count(Ticker::WalFileSynced) == 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_write_buffer_manager() {
+        let manager = Arc::new(WriteBufferManager::new(1 << 20));
+        let options = Options { write_buffer_manager: Some(manager.clone()), ..Options::default() };
+        let mut db1 = DB::open(&options, db_path("write_buffer_manager1")).expect("open failed");
+        let mut db2 = DB::open(&options, db_path("write_buffer_manager2")).expect("open failed");
+        db1.put(&WriteOptions::default(), &Slice::from_str("foo"), &Slice::from_bytes(&[b'x'; 1000])).unwrap();
+        db2.put(&WriteOptions::default(), &Slice::from_str("bar"), &Slice::from_str("v")).unwrap();
+
+        let size = |db: &DB| db.get_property("revel.cur-size-active-mem-table").unwrap().parse::<usize>().unwrap();
+        assert!(size(&db1) > 1000);
+        assert!(size(&db2) > 0);
+        assert_eq!(size(&db1) + size(&db2), manager.memory_usage());
+
+        drop(db1);
+        assert_eq!(size(&db2), manager.memory_usage());
+    }
+
+    #[test]
+    fn test_write_buffer_manager_idle_largest() {
+        let manager = Arc::new(WriteBufferManager::new(256 << 10));
+        let options = Options { write_buffer_manager: Some(manager.clone()), ..Options::default() };
+        let mut idle = DB::open(&options, db_path("write_buffer_manager_idle")).expect("open failed");
+        let mut busy = DB::open(&options, db_path("write_buffer_manager_busy")).expect("open failed");
+        let value = vec![b'x'; 1000];
+        for i in 0..200 {
+            idle.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i)), &Slice::from_bytes(&value)).unwrap();
+        }
+        // The memtable of the idle db holds most of the budget, so the busy
+        // db has to flush its own memtable to stay within it
+        for i in 0..1000 {
+            busy.put(&WriteOptions::default(), &Slice::from_str(&format!("key{:04}", i)), &Slice::from_bytes(&value)).unwrap();
+            // An immutable memtable counts until its flush is done
+            let imm = busy.imm.as_ref().map_or(0, |imm| imm.approximate_memory_usage());
+            assert_eq!(idle.mem.approximate_memory_usage() + busy.mem.approximate_memory_usage() + imm, manager.memory_usage());
+            // Exceeded by at most the last write
+            assert!(manager.memory_usage() <= (256 << 10) + 2 * value.len() + 1024, "{}", manager.memory_usage());
+        }
+        busy.finish_flush(true).unwrap();
+        assert!(!busy.get_live_files_metadata().is_empty());
+        assert!(idle.get_live_files_metadata().is_empty());
+        for i in 0..1000 {
+            assert!(busy.get(&ReadOptions::default(), &Slice::from_str(&format!("key{:04}", i))).is_ok());
+        }
+    }
+
+    /// Writes enough keys with `options` to fill many blocks, flushes them
+    /// to a single table and reopens the db, checking every key reads back.
+    fn check_table_round_trip(name: &str, options: &Options) -> DB {
+        let dbname = db_path(name);
+        let key = |i: usize| format!("key{:06}", i);
+        let mut db = DB::open(options, &dbname).expect("open failed");
+        for i in 0..2000 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&key(i)), &Slice::from_str(&format!("value{}", i))).unwrap();
+        }
+        db.flush(&FlushOptions { wait: true }).unwrap();
+        drop(db);
+
+        let db = DB::open(options, &dbname).expect("reopen failed");
+        assert_eq!(1, db.get_live_files_metadata().len());
+        for i in 0..2000 {
+            assert_eq!(format!("value{}", i).into_bytes(), db.get(&ReadOptions::default(), &Slice::from_str(&key(i))).unwrap());
+        }
+        assert_eq!(Err(NotFound), db.get(&ReadOptions::default(), &Slice::from_str("key0010005")));
+        let entries = db.scan(&ReadOptions::default(), None, None).collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!((0..2000).map(|i| key(i).into_bytes()).collect::<Vec<_>>(), entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+        db
+    }
+
+    /// Returns the index block of the only table of `db`, along with the
+    /// file and the properties of the table.
+    fn read_index_block(db: &DB) -> (Rc<dyn RandomAccessFile>, Vec<u8>, TableProperties) {
+        let meta = &db.get_live_files_metadata()[0];
+        let file_name = table_file_name(&db.dbname, meta.file_number);
+        let footer = Footer::decode_from(&std::fs::read(&file_name).unwrap()).unwrap();
+        assert_eq!(db.options.format_version, footer.format_version);
+        let file = new_random_access_file(&file_name).unwrap();
+        let index_block = read_block(file.as_ref(), meta.file_number, &footer.index_handle, true).unwrap();
+        (file, index_block, db.table_cache.get_properties(meta.file_number, meta.size).unwrap())
+    }
+
+    /// Returns the block handles held by the entries of `block`.
+    fn block_handles(options: &Options, block: Vec<u8>) -> Vec<BlockHandle> {
+        let mut iter = Rc::new(Block::new(block).unwrap()).iter(Rc::new(InternalKeyComparator::new(options.comparator)));
+        iter.seek_to_first();
+        let mut handles = Vec::new();
+        while iter.valid() {
+            handles.push(BlockHandle::decode_from(iter.value().data()).unwrap().0);
+            iter.next();
+        }
+        handles
+    }
+
+    #[test]
+    fn test_partitioned_index() {
+        let options = Options {
+            format_version: K_LATEST_FORMAT_VERSION,
+            block_size: 256,
+            index_type: IndexType::TwoLevelIndexSearch,
+            metadata_block_size: 128,
+            ..Options::default()
+        };
+        let db = check_table_round_trip("partitioned_index", &options);
+        let (file, index_block, properties) = read_index_block(&db);
+        // The top-level index points to partitions, which point to the
+        // data blocks
+        let partitions = block_handles(&options, index_block);
+        assert!(partitions.len() > 1);
+        let data_blocks: usize = partitions.iter()
+            .map(|handle| block_handles(&options, read_block(file.as_ref(), 0, handle, true).unwrap()).len())
+            .sum();
+        assert!(partitions.len() < data_blocks);
+        assert_eq!(properties.num_data_blocks, data_blocks as u64);
+    }
+
+    #[test]
+    fn test_data_block_hash_index() {
+        let options = Options {
+            format_version: K_LATEST_FORMAT_VERSION,
+            block_size: 256,
+            data_block_index_type: DataBlockIndexType::BinaryAndHash,
+            ..Options::default()
+        };
+        let db = check_table_round_trip("data_block_hash_index", &options);
+        let (file, index_block, _) = read_index_block(&db);
+        for handle in block_handles(&options, index_block) {
+            let block = read_block(file.as_ref(), 0, &handle, true).unwrap();
+            assert_eq!(DataBlockIndexType::BinaryAndHash, decode_block_footer(&block).0);
+        }
+    }
+
+    #[test]
+    fn test_delta_encoded_restarts() {
+        let is_delta_encoded = |block: &[u8]| decode_fix32(&block[block.len() - 4..]) & K_DELTA_ENCODED_RESTARTS_BIT != 0;
+        for format_version in [1, K_LATEST_FORMAT_VERSION] {
+            let options = Options { format_version, block_size: 256, ..Options::default() };
+            let db = check_table_round_trip(&format!("delta_encoded_restarts_{}", format_version), &options);
+            let (file, index_block, _) = read_index_block(&db);
+            assert_eq!(options.supports(FormatFeature::DeltaEncodedRestarts), is_delta_encoded(&index_block));
+            // Data blocks keep their fixed size restart arrays
+            let data_block = read_block(file.as_ref(), 0, &block_handles(&options, index_block)[0], true).unwrap();
+            assert!(!is_delta_encoded(&data_block));
+        }
+    }
+
+    #[test]
+    fn test_format_version() {
+        let dbname = db_path("format_version");
+        for format_version in [0, K_LATEST_FORMAT_VERSION + 1] {
+            let options = Options { format_version, ..Options::default() };
+            assert_eq!(Some(InvalidArgument), DB::open(&options, &dbname).err());
+        }
+        // Partitioned and hash indexes need a format version that supports them
+        let features = [
+            Options { index_type: IndexType::TwoLevelIndexSearch, ..Options::default() },
+            Options { data_block_index_type: DataBlockIndexType::BinaryAndHash, ..Options::default() }
+        ];
+        for options in &features {
+            assert_eq!(Some(InvalidArgument), DB::open(options, &dbname).err());
+        }
+
+        let db = DB::open(&Options::default(), &dbname).expect("open failed");
+        assert_eq!(1, recorded_format_version(&dbname));
+        drop(db);
+
+        // The latest version supports every feature
+        for options in features {
+            let options = Options { format_version: K_LATEST_FORMAT_VERSION, ..options };
+            let dbname = db_path("format_version_latest");
+            let mut db = DB::open(&options, &dbname).expect("open failed");
+            assert_eq!(K_LATEST_FORMAT_VERSION, recorded_format_version(&dbname));
+            db.put(&WriteOptions::default(), &Slice::from_str("k"), &Slice::from_str("v")).unwrap();
+            db.flush(&FlushOptions { wait: true }).unwrap();
+            drop(db);
+
+            let db = DB::open(&options, &dbname).expect("reopen failed");
+            assert_eq!(b"v".to_vec(), db.get(&ReadOptions::default(), &Slice::from_str("k")).unwrap());
+            drop(db);
+
+            // Going back to version 1 does not downgrade the MANIFEST, the
+            // table written at the latest version is still live
+            let mut db = DB::open(&Options::default(), &dbname).expect("reopen failed");
+            assert_eq!(K_LATEST_FORMAT_VERSION, recorded_format_version(&dbname));
+            db.put(&WriteOptions::default(), &Slice::from_str("k2"), &Slice::from_str("v2")).unwrap();
+            db.flush(&FlushOptions { wait: true }).unwrap();
+            drop(db);
+
+            let db = DB::open(&Options::default(), &dbname).expect("reopen failed");
+            assert_eq!(K_LATEST_FORMAT_VERSION, recorded_format_version(&dbname));
+            assert_eq!(b"v".to_vec(), db.get(&ReadOptions::default(), &Slice::from_str("k")).unwrap());
+            assert_eq!(b"v2".to_vec(), db.get(&ReadOptions::default(), &Slice::from_str("k2")).unwrap());
+        }
+    }
+
+    /// Returns the format version recorded by the last edit of the current
+    /// MANIFEST of the db that sets one.
+    fn recorded_format_version(dbname: &Path) -> u32 {
+        let current = std::fs::read_to_string(current_file_name(dbname)).unwrap();
+        let dump = dump_manifest(dbname.join(current.trim_end())).unwrap();
+        let version = dump.lines().filter_map(|line| line.trim().strip_prefix("FormatVersion: ")).last();
+        version.expect("no format version recorded").parse().unwrap()
+    }
+}
@@ -0,0 +1,1025 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cmp::Ordering;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::db::DB;
+use crate::dbformat::{append_internal_key, extract_user_key, kValueTypeForSeek, parse_internal_key, SequenceNumber, ValueType};
+use crate::Error::NotSupport;
+use crate::iterator::InternalIterator;
+use crate::options::ReadOptions;
+use crate::random::Random;
+use crate::range_del::RangeDelAggregator;
+use crate::Result;
+use crate::slice::Slice;
+use crate::statistics::{HistogramType, Statistics};
+use crate::version_set::Version;
+
+/// Iterators sample the keys they read about once every this many bytes.
+const K_READ_BYTES_PERIOD: usize = 1048576;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse
+}
+
+/// Memtables and sstables that make the DB representation contain
+/// (userkey,seq,type) => uservalue entries. DBIter combines multiple
+/// entries for the same userkey found in the DB representation into a
+/// single entry while accounting for sequence numbers, deletion markers,
+/// overwrites, etc.
+pub(crate) struct DBIter<'a> {
+
+    user_comparator: fn(a: &Slice, b: &Slice) -> Ordering,
+
+    iter: Box<dyn InternalIterator + 'a>,
+
+    sequence: SequenceNumber,
+
+    // Current key when direction is Reverse, or the key being skipped when
+    // stepping forward
+    saved_key: Vec<u8>,
+
+    // Current value when direction is Reverse
+    saved_value: Vec<u8>,
+
+    direction: Direction,
+
+    valid: bool,
+
+    status: Result<()>,
+
+    // Charges the keys read to the files of a version, see sample_reads()
+    sampler: Option<ReadSampler>,
+
+    // Range tombstones of the view iter reads, see with_range_tombstones()
+    range_del: Option<RangeDelAggregator>
+}
+
+/// Records a sample of the keys an iterator reads with the version they
+/// are read from, so that files reads keep going through get compacted.
+struct ReadSampler {
+
+    // Whoever owns the iterator keeps the version alive, there is nothing
+    // left to compact once it is gone
+    version: Weak<Version>,
+
+    rnd: Random,
+
+    bytes_until_read_sampling: usize
+}
+
+impl ReadSampler {
+
+    /// Picks the number of bytes to read before the next sample.
+    fn random_compaction_period(&mut self) -> usize {
+        self.rnd.uniform(2 * K_READ_BYTES_PERIOD as i32) as usize
+    }
+}
+
+impl<'a> DBIter<'a> {
+
+    /// Returns an iterator over the user entries of `iter` visible at
+    /// `sequence`.
+    pub fn new(user_comparator: fn(a: &Slice, b: &Slice) -> Ordering,
+               iter: Box<dyn InternalIterator + 'a>, sequence: SequenceNumber) -> Self {
+        DBIter {
+            user_comparator,
+            iter,
+            sequence,
+            saved_key: Vec::new(),
+            saved_value: Vec::new(),
+            direction: Direction::Forward,
+            valid: false,
+            status: Ok(()),
+            sampler: None,
+            range_del: None
+        }
+    }
+
+    /// Hide the entries deleted by the range tombstones of `range_del`,
+    /// which holds the tombstones of the view the internal iterator reads.
+    pub fn with_range_tombstones(mut self, range_del: RangeDelAggregator) -> Self {
+        self.range_del = (!range_del.is_empty()).then_some(range_del);
+        self
+    }
+
+    /// Charge the entries read from now on to the files of `version`, the
+    /// version the internal iterator reads, as Version::get() does for
+    /// point reads. Files that many reads go through before finding their
+    /// key then become candidates for compaction.
+    pub fn sample_reads(mut self, version: &Rc<Version>, seed: u32) -> Self {
+        let mut sampler = ReadSampler { version: Rc::downgrade(version), rnd: Random::new(seed), bytes_until_read_sampling: 0 };
+        sampler.bytes_until_read_sampling = sampler.random_compaction_period();
+        self.sampler = Some(sampler);
+        self
+    }
+
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    /// Returns the user key of the current entry.
+    /// REQUIRES: valid()
+    pub fn key(&self) -> Slice<'_> {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward => extract_user_key(&self.iter.key()),
+            Direction::Reverse => Slice::from_bytes(&self.saved_key)
+        }
+    }
+
+    /// REQUIRES: valid()
+    pub fn value(&self) -> Slice<'_> {
+        assert!(self.valid);
+        match self.direction {
+            Direction::Forward => self.iter.value(),
+            Direction::Reverse => Slice::from_bytes(&self.saved_value)
+        }
+    }
+
+    /// Returns the first error hit by this iterator or the one below it.
+    pub fn status(&self) -> Result<()> {
+        if self.status.is_err() {
+            self.status.clone()
+        } else {
+            self.iter.status()
+        }
+    }
+
+    /// REQUIRES: valid()
+    pub fn next(&mut self) {
+        assert!(self.valid);
+        if self.direction == Direction::Reverse {
+            // Switch directions?
+            self.direction = Direction::Forward;
+            // iter is pointing just before the entries for key(),
+            // so advance into the range of entries for key() and then
+            // use the normal skipping code below.
+            if !self.iter.valid() {
+                self.iter.seek_to_first();
+            } else {
+                self.iter.next();
+            }
+            if !self.iter.valid() {
+                self.valid = false;
+                self.saved_key.clear();
+                return;
+            }
+            // saved_key already contains the key to skip past.
+        } else {
+            // Store in saved_key the current key so we skip it below.
+            self.saved_key.clear();
+            self.saved_key.extend_from_slice(extract_user_key(&self.iter.key()).data());
+
+            // iter is pointing to current key. We can now safely move to the next to
+            // avoid checking current key.
+            self.iter.next();
+            if !self.iter.valid() {
+                self.valid = false;
+                self.saved_key.clear();
+                return;
+            }
+        }
+        self.find_next_user_entry(true);
+    }
+
+    /// REQUIRES: valid()
+    pub fn prev(&mut self) {
+        assert!(self.valid);
+        if self.direction == Direction::Forward {
+            // Switch directions?
+            // iter is pointing at the current entry. Scan backwards until
+            // the key changes so we can use the normal reverse scanning code.
+            assert!(self.iter.valid());
+            self.saved_key.clear();
+            self.saved_key.extend_from_slice(extract_user_key(&self.iter.key()).data());
+            loop {
+                self.iter.prev();
+                if !self.iter.valid() {
+                    self.valid = false;
+                    self.saved_key.clear();
+                    self.saved_value.clear();
+                    return;
+                }
+                if (self.user_comparator)(&extract_user_key(&self.iter.key()), &Slice::from_bytes(&self.saved_key)) == Ordering::Less {
+                    break;
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+        self.find_prev_user_entry();
+    }
+
+    /// Position at the first user key at or past target.
+    pub fn seek(&mut self, target: &Slice) {
+        self.direction = Direction::Forward;
+        self.saved_value.clear();
+        self.saved_key.clear();
+        append_internal_key(&mut self.saved_key, target, self.sequence, kValueTypeForSeek);
+        self.iter.seek(&Slice::from_bytes(&self.saved_key));
+        if self.iter.valid() {
+            self.find_next_user_entry(false);
+        } else {
+            self.valid = false;
+        }
+    }
+
+    pub fn seek_to_first(&mut self) {
+        self.direction = Direction::Forward;
+        self.saved_value.clear();
+        self.iter.seek_to_first();
+        if self.iter.valid() {
+            self.find_next_user_entry(false);
+        } else {
+            self.valid = false;
+        }
+    }
+
+    pub fn seek_to_last(&mut self) {
+        self.direction = Direction::Reverse;
+        self.saved_value.clear();
+        self.iter.seek_to_last();
+        self.find_prev_user_entry();
+    }
+
+    /// Account for reading the current entry of iter, recording a sample
+    /// for each period of bytes it completes.
+    fn sample_read(&mut self) {
+        let Some(sampler) = self.sampler.as_mut() else {
+            return;
+        };
+        let key = self.iter.key();
+        let bytes_read = key.size() + self.iter.value().size();
+        while sampler.bytes_until_read_sampling < bytes_read {
+            sampler.bytes_until_read_sampling += sampler.random_compaction_period();
+            if let Some(version) = sampler.version.upgrade() {
+                version.record_read_sample(self.user_comparator, &key);
+            }
+        }
+        sampler.bytes_until_read_sampling -= bytes_read;
+    }
+
+    /// Returns true if the entry of `user_key` at `sequence` is deleted by a
+    /// range tombstone.
+    fn range_deleted(&self, user_key: &Slice, sequence: SequenceNumber) -> bool {
+        self.range_del.as_ref().is_some_and(|range_del| range_del.should_delete(user_key, sequence))
+    }
+
+    /// Loop until we hit an acceptable entry to yield. When `skipping`,
+    /// entries for user keys up to saved_key are hidden.
+    fn find_next_user_entry(&mut self, mut skipping: bool) {
+        assert!(self.iter.valid());
+        assert!(self.direction == Direction::Forward);
+        loop {
+            self.sample_read();
+            let key = self.iter.key();
+            match parse_internal_key(&key) {
+                Err(e) => self.status = Err(e),
+                Ok(ikey) => if ikey.sequence <= self.sequence {
+                    let covered = self.range_deleted(&ikey.user_key, ikey.sequence);
+                    match ikey.value_type {
+                        ValueType::KTypeDeletion => {
+                            // Arrange to skip all upcoming entries for this key since
+                            // they are hidden by this deletion.
+                            self.saved_key.clear();
+                            self.saved_key.extend_from_slice(ikey.user_key.data());
+                            skipping = true;
+                        },
+                        ValueType::KTypeValue if covered => {
+                            // Deleted by a range tombstone, which hides the
+                            // older entries for this key as well
+                            self.saved_key.clear();
+                            self.saved_key.extend_from_slice(ikey.user_key.data());
+                            skipping = true;
+                        },
+                        ValueType::KTypeValue => {
+                            if !skipping || (self.user_comparator)(&ikey.user_key, &Slice::from_bytes(&self.saved_key)) == Ordering::Greater {
+                                self.valid = true;
+                                self.saved_key.clear();
+                                return;
+                            }
+                        },
+                        ValueType::KTypeRangeDeletion => {}
+                    }
+                }
+            }
+            self.iter.next();
+            if !self.iter.valid() {
+                break;
+            }
+        }
+        self.saved_key.clear();
+        self.valid = false;
+    }
+
+    fn find_prev_user_entry(&mut self) {
+        assert!(self.direction == Direction::Reverse);
+        let mut value_type = ValueType::KTypeDeletion;
+        while self.iter.valid() {
+            self.sample_read();
+            let key = self.iter.key();
+            match parse_internal_key(&key) {
+                Err(e) => self.status = Err(e),
+                Ok(ikey) => if ikey.sequence <= self.sequence && ikey.value_type != ValueType::KTypeRangeDeletion {
+                    if value_type != ValueType::KTypeDeletion
+                        && (self.user_comparator)(&ikey.user_key, &Slice::from_bytes(&self.saved_key)) == Ordering::Less {
+                        // We encountered a non-deleted value in entries for previous keys,
+                        break;
+                    }
+                    value_type = ikey.value_type;
+                    if self.range_deleted(&ikey.user_key, ikey.sequence) {
+                        value_type = ValueType::KTypeDeletion;
+                    }
+                    if value_type == ValueType::KTypeDeletion {
+                        self.saved_key.clear();
+                        self.saved_value.clear();
+                    } else {
+                        self.saved_key.clear();
+                        self.saved_key.extend_from_slice(ikey.user_key.data());
+                        self.saved_value.clear();
+                        self.saved_value.extend_from_slice(self.iter.value().data());
+                    }
+                }
+            }
+            self.iter.prev();
+        }
+
+        if value_type == ValueType::KTypeDeletion {
+            // End
+            self.valid = false;
+            self.saved_key.clear();
+            self.saved_value.clear();
+            self.direction = Direction::Forward;
+        } else {
+            self.valid = true;
+        }
+    }
+}
+
+/// A scan over the user keys of a DB in the range [lower, upper), as of the
+/// moment it was created. Entries can be consumed from both ends: next()
+/// walks up from the lower bound and next_back() walks down from the upper
+/// bound, and the scan ends when the two meet.
+pub struct Scan<'a> {
+
+    user_comparator: fn(a: &Slice, b: &Slice) -> Ordering,
+
+    front: DBIter<'a>,
+
+    back: DBIter<'a>,
+
+    // Inclusive lower bound
+    lower: Option<Vec<u8>>,
+
+    // Exclusive upper bound
+    upper: Option<Vec<u8>>,
+
+    // Last keys returned from each end
+    front_key: Option<Vec<u8>>,
+
+    back_key: Option<Vec<u8>>,
+
+    front_started: bool,
+
+    back_started: bool,
+
+    done: bool,
+
+    // Version the scan reads, kept alive so that its files are not deleted
+    // before the scan is dropped
+    version: Option<Rc<Version>>,
+
+    // Where the time taken to position each end is recorded, if set
+    stats: Option<Arc<Statistics>>
+}
+
+impl<'a> Scan<'a> {
+
+    pub(crate) fn new(user_comparator: fn(a: &Slice, b: &Slice) -> Ordering, front: DBIter<'a>, back: DBIter<'a>,
+                      lower: Option<&[u8]>, upper: Option<&[u8]>) -> Self {
+        Scan {
+            user_comparator,
+            front,
+            back,
+            lower: lower.map(|k| k.to_vec()),
+            upper: upper.map(|k| k.to_vec()),
+            front_key: None,
+            back_key: None,
+            front_started: false,
+            back_started: false,
+            done: false,
+            version: None,
+            stats: None
+        }
+    }
+
+    /// Pin `version` for the lifetime of the scan.
+    pub(crate) fn pin_version(mut self, version: Rc<Version>) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Record the time taken to position each end of the scan in the seek
+    /// histogram of `stats`.
+    pub(crate) fn record_seeks(mut self, stats: Arc<Statistics>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Returns the first error hit from either end of the scan. A scan that
+    /// ended with an Ok status went through its whole range.
+    pub fn status(&self) -> Result<()> {
+        self.front.status()?;
+        self.back.status()
+    }
+
+    /// Returns the sequence number the scan reads at: it sees every update
+    /// with a sequence number up to this one and none of the later ones.
+    pub fn sequence(&self) -> u64 {
+        self.front.sequence
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        (self.user_comparator)(&Slice::from_bytes(a), &Slice::from_bytes(b))
+    }
+
+    fn record_seek(&self, start: Instant) {
+        if let Some(stats) = &self.stats {
+            stats.measure_time(HistogramType::Seek, start.elapsed());
+        }
+    }
+}
+
+impl<'a> Scan<'a> {
+
+    /// Move the front end to the next entry of the range. Returns None once
+    /// the scan is over, otherwise the front iterator is positioned on the
+    /// entry and front_key holds its key.
+    fn step_front(&mut self) -> Option<Result<()>> {
+        if self.done {
+            return None;
+        }
+        if !self.front_started {
+            self.front_started = true;
+            let start = Instant::now();
+            match &self.lower {
+                Some(lower) => self.front.seek(&Slice::from_bytes(lower)),
+                None => self.front.seek_to_first()
+            }
+            self.record_seek(start);
+        } else {
+            self.front.next();
+        }
+        if let Err(e) = self.front.status() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if !self.front.valid() {
+            self.done = true;
+            return None;
+        }
+        let key = self.front.key();
+        let past_upper = self.upper.as_ref().is_some_and(|upper| self.compare(key.data(), upper) != Ordering::Less);
+        let past_back = self.back_key.as_ref().is_some_and(|back| self.compare(key.data(), back) != Ordering::Less);
+        if past_upper || past_back {
+            self.done = true;
+            return None;
+        }
+        self.front_key = Some(key.data().to_vec());
+        Some(Ok(()))
+    }
+
+    /// Move the back end to the previous entry of the range, the mirror of
+    /// step_front().
+    fn step_back(&mut self) -> Option<Result<()>> {
+        if self.done {
+            return None;
+        }
+        if !self.back_started {
+            self.back_started = true;
+            let start = Instant::now();
+            match &self.upper {
+                Some(upper) => {
+                    self.back.seek(&Slice::from_bytes(upper));
+                    if self.back.valid() {
+                        self.back.prev();
+                    } else if self.back.status().is_ok() {
+                        self.back.seek_to_last();
+                    }
+                },
+                None => self.back.seek_to_last()
+            }
+            self.record_seek(start);
+        } else {
+            self.back.prev();
+        }
+        if let Err(e) = self.back.status() {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if !self.back.valid() {
+            self.done = true;
+            return None;
+        }
+        let key = self.back.key();
+        let before_lower = self.lower.as_ref().is_some_and(|lower| self.compare(key.data(), lower) == Ordering::Less);
+        let before_front = self.front_key.as_ref().is_some_and(|front| self.compare(key.data(), front) != Ordering::Greater);
+        if before_lower || before_front {
+            self.done = true;
+            return None;
+        }
+        self.back_key = Some(key.data().to_vec());
+        Some(Ok(()))
+    }
+}
+
+impl<'a> Iterator for Scan<'a> {
+
+    /// Entries are yielded in order until the scan either reaches the end of
+    /// its range, after which None is returned, or hits an error, which is
+    /// yielded once as the final item.
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.step_front()?;
+        Some(result.map(|_| (self.front.key().data().to_vec(), self.front.value().data().to_vec())))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Scan<'a> {
+
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let result = self.step_back()?;
+        Some(result.map(|_| (self.back.key().data().to_vec(), self.back.value().data().to_vec())))
+    }
+}
+
+/// The keys of a Scan, without copying the values.
+pub struct Keys<'a>(Scan<'a>);
+
+impl<'a> Keys<'a> {
+
+    pub(crate) fn new(scan: Scan<'a>) -> Self {
+        Keys(scan)
+    }
+
+    /// See Scan::status().
+    pub fn status(&self) -> Result<()> {
+        self.0.status()
+    }
+
+    /// See Scan::sequence().
+    pub fn sequence(&self) -> u64 {
+        self.0.sequence()
+    }
+}
+
+impl<'a> Iterator for Keys<'a> {
+
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.0.step_front()?;
+        Some(result.map(|_| self.0.front.key().data().to_vec()))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Keys<'a> {
+
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let result = self.0.step_back()?;
+        Some(result.map(|_| self.0.back.key().data().to_vec()))
+    }
+}
+
+/// The values of a Scan, in the order of their keys.
+pub struct Values<'a>(Scan<'a>);
+
+impl<'a> Values<'a> {
+
+    pub(crate) fn new(scan: Scan<'a>) -> Self {
+        Values(scan)
+    }
+
+    /// See Scan::status().
+    pub fn status(&self) -> Result<()> {
+        self.0.status()
+    }
+
+    /// See Scan::sequence().
+    pub fn sequence(&self) -> u64 {
+        self.0.sequence()
+    }
+}
+
+impl<'a> Iterator for Values<'a> {
+
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let result = self.0.step_front()?;
+        Some(result.map(|_| self.0.front.value().data().to_vec()))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Values<'a> {
+
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let result = self.0.step_back()?;
+        Some(result.map(|_| self.0.back.value().data().to_vec()))
+    }
+}
+
+/// A cursor over the user entries of a DB as of the moment it was created.
+/// Unlike a Scan it can be positioned anywhere and moved in both
+/// directions; see DB::new_iterator().
+pub struct DBIterator<'a> {
+
+    iter: DBIter<'a>,
+
+    // Version the iterator reads, kept alive so that its files are not
+    // deleted before the iterator is dropped
+    _version: Rc<Version>,
+
+    // Statistics of the db, recording the latency of seeks
+    stats: Arc<Statistics>,
+
+    // Options to create the iterator again with on refresh(), None if the
+    // iterator cannot be refreshed
+    refresh: Option<ReadOptions<'static>>
+}
+
+impl<'a> DBIterator<'a> {
+
+    pub(crate) fn new(iter: DBIter<'a>, version: Rc<Version>, stats: Arc<Statistics>,
+                      refresh: Option<ReadOptions<'static>>) -> Self {
+        DBIterator { iter, _version: version, stats, refresh }
+    }
+
+    /// Re-read `db`, which must be the db the iterator was created on, so
+    /// that the iterator sees the writes made since it was created. A
+    /// positioned iterator stays on its key, or moves to the next one if
+    /// that key is gone; an unpositioned one stays unpositioned.
+    /// Iterators reading at a snapshot cannot be refreshed and return
+    /// NotSupport.
+    pub fn refresh(&mut self, db: &DB) -> Result<()> {
+        let options = self.refresh.clone().ok_or(NotSupport)?;
+        let key = self.valid().then(|| self.key().data().to_vec());
+        *self = db.new_iterator(&options);
+        if let Some(key) = key {
+            self.seek(&Slice::from_bytes(&key));
+        }
+        Ok(())
+    }
+
+    /// An iterator is either positioned at an entry, or not valid.
+    pub fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    /// Returns the key of the current entry.
+    /// REQUIRES: valid()
+    pub fn key(&self) -> Slice<'_> {
+        self.iter.key()
+    }
+
+    /// Returns the value of the current entry.
+    /// REQUIRES: valid()
+    pub fn value(&self) -> Slice<'_> {
+        self.iter.value()
+    }
+
+    /// Returns the first error hit by the iterator. An iterator that is not
+    /// valid with an Ok status went past the end of the data.
+    pub fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+
+    /// Returns the sequence number the iterator reads at: it sees every
+    /// update with a sequence number up to this one and none of the later
+    /// ones.
+    pub fn sequence(&self) -> u64 {
+        self.iter.sequence
+    }
+
+    /// Position at the first entry with a key at or past target.
+    pub fn seek(&mut self, target: &Slice) {
+        let start = Instant::now();
+        self.iter.seek(target);
+        self.stats.measure_time(HistogramType::Seek, start.elapsed());
+    }
+
+    /// Position at the first entry of the db, if any.
+    pub fn seek_to_first(&mut self) {
+        let start = Instant::now();
+        self.iter.seek_to_first();
+        self.stats.measure_time(HistogramType::Seek, start.elapsed());
+    }
+
+    /// Position at the last entry of the db, if any.
+    pub fn seek_to_last(&mut self) {
+        let start = Instant::now();
+        self.iter.seek_to_last();
+        self.stats.measure_time(HistogramType::Seek, start.elapsed());
+    }
+
+    /// Move to the next entry. After the last one the iterator is no
+    /// longer valid.
+    /// REQUIRES: valid()
+    pub fn next(&mut self) {
+        self.iter.next()
+    }
+
+    /// Move to the previous entry. Before the first one the iterator is no
+    /// longer valid.
+    /// REQUIRES: valid()
+    pub fn prev(&mut self) {
+        self.iter.prev()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparator::bytewise_compare;
+    use crate::dbformat::InternalKeyComparator;
+    use crate::Error::Corruption;
+    use crate::memtable::MemTable;
+    use crate::range_del::RangeTombstone;
+    use super::*;
+
+    fn new_mem() -> MemTable {
+        let mem = MemTable::new(InternalKeyComparator::new(bytewise_compare));
+        let entries = [
+            (1, ValueType::KTypeValue, "a", "a1"),
+            (2, ValueType::KTypeValue, "b", "b1"),
+            (3, ValueType::KTypeValue, "c", "c1"),
+            (4, ValueType::KTypeDeletion, "b", ""),
+            (5, ValueType::KTypeValue, "a", "a2"),
+            (6, ValueType::KTypeValue, "d", "d1"),
+            (7, ValueType::KTypeDeletion, "d", ""),
+        ];
+        for (seq, t, key, value) in entries {
+            mem.add(seq, t, &Slice::from_str(key), &Slice::from_str(value));
+        }
+        mem
+    }
+
+    fn entry(iter: &DBIter) -> String {
+        format!("{}->{}", String::from_utf8_lossy(iter.key().data()), String::from_utf8_lossy(iter.value().data()))
+    }
+
+    fn forward(iter: &mut DBIter) -> Vec<String> {
+        let mut result = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            result.push(entry(iter));
+            iter.next();
+        }
+        result
+    }
+
+    fn backward(iter: &mut DBIter) -> Vec<String> {
+        let mut result = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            result.push(entry(iter));
+            iter.prev();
+        }
+        result
+    }
+
+    #[test]
+    fn test_db_iter() {
+        let mem = new_mem();
+        let mut iter = DBIter::new(bytewise_compare, Box::new(mem.iter()), 7);
+        assert_eq!(vec!["a->a2", "c->c1"], forward(&mut iter));
+        assert_eq!(vec!["c->c1", "a->a2"], backward(&mut iter));
+
+        // Older snapshots see the overwritten and deleted entries
+        let mut iter = DBIter::new(bytewise_compare, Box::new(mem.iter()), 3);
+        assert_eq!(vec!["a->a1", "b->b1", "c->c1"], forward(&mut iter));
+        assert_eq!(vec!["c->c1", "b->b1", "a->a1"], backward(&mut iter));
+
+        let mut iter = DBIter::new(bytewise_compare, Box::new(mem.iter()), 6);
+        assert_eq!(vec!["a->a2", "c->c1", "d->d1"], forward(&mut iter));
+        assert_eq!(vec!["d->d1", "c->c1", "a->a2"], backward(&mut iter));
+
+        let mut iter = DBIter::new(bytewise_compare, Box::new(mem.iter()), 0);
+        assert!(forward(&mut iter).is_empty());
+        assert!(backward(&mut iter).is_empty());
+    }
+
+    #[test]
+    fn test_db_iter_range_tombstones() {
+        let mem = new_mem();
+        let range_del = |sequence| {
+            let mut range_del = RangeDelAggregator::new(bytewise_compare, sequence);
+            range_del.add(vec![RangeTombstone::new(b"b", b"d", 4)]);
+            range_del
+        };
+        let mut iter = DBIter::new(bytewise_compare, Box::new(mem.iter()), 6).with_range_tombstones(range_del(6));
+        assert_eq!(vec!["a->a2", "d->d1"], forward(&mut iter));
+        assert_eq!(vec!["d->d1", "a->a2"], backward(&mut iter));
+        iter.seek(&Slice::from_str("b"));
+        assert_eq!("d->d1", entry(&iter));
+        iter.prev();
+        assert_eq!("a->a2", entry(&iter));
+
+        // Snapshots older than the tombstone do not see it
+        let mut iter = DBIter::new(bytewise_compare, Box::new(mem.iter()), 3).with_range_tombstones(range_del(3));
+        assert_eq!(vec!["a->a1", "b->b1", "c->c1"], forward(&mut iter));
+        assert_eq!(vec!["c->c1", "b->b1", "a->a1"], backward(&mut iter));
+    }
+
+    #[test]
+    fn test_db_iter_switch_direction() {
+        let mem = new_mem();
+        let mut iter = DBIter::new(bytewise_compare, Box::new(mem.iter()), 6);
+        iter.seek(&Slice::from_str("b"));
+        assert_eq!("c->c1", entry(&iter));
+        iter.prev();
+        assert_eq!("a->a2", entry(&iter));
+        iter.next();
+        assert_eq!("c->c1", entry(&iter));
+        iter.next();
+        assert_eq!("d->d1", entry(&iter));
+        iter.prev();
+        assert_eq!("c->c1", entry(&iter));
+        iter.next();
+        assert_eq!("d->d1", entry(&iter));
+        iter.next();
+        assert!(!iter.valid());
+
+        iter.seek(&Slice::from_str("e"));
+        assert!(!iter.valid());
+    }
+
+    fn scan<'a>(mem: &'a MemTable, lower: Option<&str>, upper: Option<&str>) -> Scan<'a> {
+        let front = DBIter::new(bytewise_compare, Box::new(mem.iter()), 6);
+        let back = DBIter::new(bytewise_compare, Box::new(mem.iter()), 6);
+        Scan::new(bytewise_compare, front, back, lower.map(str::as_bytes), upper.map(str::as_bytes))
+    }
+
+    fn keys(entries: impl Iterator<Item=Result<(Vec<u8>, Vec<u8>)>>) -> Vec<String> {
+        entries.map(|e| String::from_utf8(e.expect("scan error").0).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_scan() {
+        let mem = new_mem();
+        assert_eq!(vec!["a", "c", "d"], keys(scan(&mem, None, None)));
+        assert_eq!(vec!["d", "c", "a"], keys(scan(&mem, None, None).rev()));
+        assert_eq!(vec!["c"], keys(scan(&mem, Some("b"), Some("d"))));
+        assert_eq!(vec!["c"], keys(scan(&mem, Some("b"), Some("d")).rev()));
+        assert_eq!(vec!["d", "c"], keys(scan(&mem, Some("c"), Some("x")).rev()));
+        assert!(keys(scan(&mem, Some("e"), None)).is_empty());
+        assert!(keys(scan(&mem, Some("e"), None).rev()).is_empty());
+        assert!(keys(scan(&mem, None, Some("a")).rev()).is_empty());
+
+        // Both ends meet in the middle without yielding an entry twice
+        let mut s = scan(&mem, None, None);
+        assert_eq!(Some(Ok((b"a".to_vec(), b"a2".to_vec()))), s.next());
+        assert_eq!(Some(Ok((b"d".to_vec(), b"d1".to_vec()))), s.next_back());
+        assert_eq!(Some(Ok((b"c".to_vec(), b"c1".to_vec()))), s.next_back());
+        assert_eq!(None, s.next());
+        assert_eq!(None, s.next_back());
+        assert_eq!(Ok(()), s.status());
+    }
+
+    /// An iterator over sorted in-memory entries that reports `status` once
+    /// it runs off either end, like a file iterator failing on a read.
+    struct VecIter {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        pos: Option<usize>,
+        status: Result<()>
+    }
+
+    impl InternalIterator for VecIter {
+        fn valid(&self) -> bool {
+            self.pos.is_some()
+        }
+
+        fn seek_to_first(&mut self) {
+            self.pos = if self.entries.is_empty() { None } else { Some(0) };
+        }
+
+        fn seek_to_last(&mut self) {
+            self.pos = self.entries.len().checked_sub(1);
+        }
+
+        fn seek(&mut self, target: &Slice) {
+            let cmp = InternalKeyComparator::new(bytewise_compare);
+            self.pos = self.entries.iter()
+                .position(|(k, _)| crate::comparator::Comparator::compare(&cmp, &Slice::from_bytes(k), target) != Ordering::Less);
+        }
+
+        fn next(&mut self) {
+            self.pos = self.pos.map(|p| p + 1).filter(|p| *p < self.entries.len());
+        }
+
+        fn prev(&mut self) {
+            self.pos = self.pos.and_then(|p| p.checked_sub(1));
+        }
+
+        fn key(&self) -> Slice<'_> {
+            Slice::from_bytes(&self.entries[self.pos.unwrap()].0)
+        }
+
+        fn value(&self) -> Slice<'_> {
+            Slice::from_bytes(&self.entries[self.pos.unwrap()].1)
+        }
+
+        fn status(&self) -> Result<()> {
+            if self.pos.is_none() { self.status.clone() } else { Ok(()) }
+        }
+    }
+
+    fn vec_scan<'a>(entries: &[&[u8]], status: Result<()>) -> Scan<'a> {
+        let entries = entries.iter().map(|k| (k.to_vec(), b"v".to_vec())).collect::<Vec<_>>();
+        let front = VecIter { entries: entries.clone(), pos: None, status: status.clone() };
+        let back = VecIter { entries, pos: None, status };
+        Scan::new(bytewise_compare, DBIter::new(bytewise_compare, Box::new(front), 10),
+                  DBIter::new(bytewise_compare, Box::new(back), 10), None, None)
+    }
+
+    fn ikey(user_key: &str, seq: SequenceNumber) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str(user_key), seq, ValueType::KTypeValue);
+        key
+    }
+
+    #[test]
+    fn test_keys_and_values() {
+        let mem = new_mem();
+        let strings = |entries: &mut dyn Iterator<Item=Result<Vec<u8>>>| -> Vec<String> {
+            entries.map(|e| String::from_utf8(e.expect("scan error")).unwrap()).collect()
+        };
+        assert_eq!(vec!["a", "c", "d"], strings(&mut Keys::new(scan(&mem, None, None))));
+        assert_eq!(vec!["d", "c"], strings(&mut Keys::new(scan(&mem, Some("b"), None)).rev()));
+        assert_eq!(vec!["a2", "c1", "d1"], strings(&mut Values::new(scan(&mem, None, None))));
+        assert_eq!(vec!["c1", "a2"], strings(&mut Values::new(scan(&mem, None, Some("d"))).rev()));
+
+        // Both ends meet without yielding an entry twice
+        let mut values = Values::new(scan(&mem, None, None));
+        assert_eq!(Some(Ok(b"d1".to_vec())), values.next_back());
+        assert_eq!(Some(Ok(b"a2".to_vec())), values.next());
+        assert_eq!(Some(Ok(b"c1".to_vec())), values.next());
+        assert_eq!(None, values.next_back());
+        assert_eq!(Ok(()), values.status());
+
+        let (a, b) = (ikey("a", 1), ikey("b", 2));
+        let mut keys = Keys::new(vec_scan(&[&a, b"bad", &b], Ok(())));
+        assert_eq!(Some(Ok(b"a".to_vec())), keys.next());
+        assert_eq!(Some(Err(Corruption)), keys.next());
+        assert_eq!(None, keys.next());
+        assert_eq!(Err(Corruption), keys.status());
+    }
+
+    #[test]
+    fn test_scan_errors() {
+        use crate::Error::IOError;
+
+        // A scan that reaches the end has an Ok status
+        let (a, b) = (ikey("a", 1), ikey("b", 2));
+        let mut scan = vec_scan(&[&a, &b], Ok(()));
+        assert_eq!(2, scan.by_ref().filter(|e| e.is_ok()).count());
+        assert_eq!(Ok(()), scan.status());
+
+        // A failing read stops the scan with an error instead of ending it
+        let mut scan = vec_scan(&[&a, &b], Err(IOError));
+        assert!(scan.next().unwrap().is_ok());
+        assert!(scan.next().unwrap().is_ok());
+        assert_eq!(Some(Err(IOError)), scan.next());
+        assert_eq!(None, scan.next());
+        assert_eq!(Err(IOError), scan.status());
+        // Reverse scans read one entry ahead, so the failure surfaces as
+        // soon as "a" has to be looked past
+        let mut scan = vec_scan(&[&a, &b], Err(IOError));
+        assert!(scan.next_back().unwrap().is_ok());
+        assert_eq!(Some(Err(IOError)), scan.next_back());
+        assert_eq!(Err(IOError), scan.status());
+
+        // Corrupted internal keys are reported as soon as they are met
+        let mut scan = vec_scan(&[&a, b"bad", &b], Ok(()));
+        assert_eq!(Some(Ok((b"a".to_vec(), b"v".to_vec()))), scan.next());
+        assert_eq!(Some(Err(Corruption)), scan.next());
+        assert_eq!(None, scan.next());
+        assert_eq!(Err(Corruption), scan.status());
+        let mut scan = vec_scan(&[&a, b"bad", &b], Ok(()));
+        assert_eq!(Some(Err(Corruption)), scan.next_back());
+        assert_eq!(None, scan.next_back());
+    }
+}
@@ -0,0 +1,339 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A DBWithTTL is a DB whose values expire some time after they are
+//! written, for cache-like data.
+//!
+//! Every value is stored with the time it was put, as a 4-byte timestamp
+//! in seconds appended to it. Reads leave out the values older than the
+//! ttl of the db, and compactions drop them for good with a builtin
+//! compaction filter. Expired values are only removed lazily, so until a
+//! compaction reaches them they still take space, and a DB opened without
+//! the wrapper reads them, timestamps included.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::coding::{decode_fix32, put_fixed32};
+use crate::compaction_filter::CompactionFilter;
+use crate::db::DB;
+use crate::db_iter::DBIterator;
+use crate::Error::{Corruption, NotFound};
+use crate::options::{FlushOptions, Options, ReadOptions, WriteOptions};
+use crate::Result;
+use crate::slice::Slice;
+
+const K_TS_LENGTH: usize = 4;
+
+pub struct DBWithTTL {
+
+    db: DB,
+
+    ttl: Duration
+}
+
+impl DBWithTTL {
+
+    /// Open the db at `dbname`, in which values expire `ttl` after they are
+    /// put. A ttl of zero keeps them forever. The db must only ever be
+    /// written through a DBWithTTL. If options.compaction_filter is set, it
+    /// is asked about the values that have not expired, without their
+    /// timestamps.
+    pub fn open(options: &Options, dbname: impl AsRef<Path>, ttl: Duration) -> Result<DBWithTTL> {
+        let options = Options {
+            compaction_filter: Some(Arc::new(TtlCompactionFilter {
+                ttl,
+                user_filter: options.compaction_filter.clone()
+            })),
+            ..options.clone()
+        };
+        Ok(DBWithTTL {
+            db: DB::open(&options, dbname)?,
+            ttl
+        })
+    }
+
+    /// Set the value of `key` to `value`, stamped with the current time.
+    pub fn put(&mut self, opt: &WriteOptions, key: &Slice, value: &Slice) -> Result<()> {
+        let value = append_timestamp(value.data(), now());
+        self.db.put(opt, key, &Slice::from_bytes(&value))
+    }
+
+    /// Remove the value of `key`, if any.
+    pub fn delete(&mut self, opt: &WriteOptions, key: &Slice) -> Result<()> {
+        self.db.delete(opt, key)
+    }
+
+    /// Returns the value of `key`, or NotFound if there is none or it has
+    /// expired.
+    pub fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Vec<u8>> {
+        let mut value = self.db.get(options, key)?;
+        if value.len() < K_TS_LENGTH {
+            // Value too short to hold a timestamp
+            return Err(Corruption);
+        }
+        if is_stale(&value, self.ttl, now()) {
+            return Err(NotFound);
+        }
+        value.truncate(value.len() - K_TS_LENGTH);
+        Ok(value)
+    }
+
+    /// Returns an iterator over the entries of the db that have not expired
+    /// when it is created, with their values stripped of the timestamps.
+    pub fn new_iterator(&self, options: &ReadOptions) -> TtlIterator<'_> {
+        TtlIterator {
+            iter: self.db.new_iterator(options),
+            ttl: self.ttl,
+            now: now()
+        }
+    }
+
+    /// See DB::flush().
+    pub fn flush(&mut self, options: &FlushOptions) -> Result<()> {
+        self.db.flush(options)
+    }
+
+    /// See DB::close().
+    pub fn close(self) -> Result<()> {
+        self.db.close()
+    }
+
+    /// Returns the underlying db, whose values carry the timestamps.
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    /// Returns how long values live after they are put.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+}
+
+/// An iterator over the entries of a DBWithTTL that skips the expired
+/// ones, see DBWithTTL::new_iterator().
+pub struct TtlIterator<'a> {
+
+    iter: DBIterator<'a>,
+
+    ttl: Duration,
+
+    // Time the iterator was created at, which expiry is judged against
+    now: u32
+}
+
+impl<'a> TtlIterator<'a> {
+
+    /// An iterator is either positioned at an entry, or not valid.
+    pub fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    /// Returns the key of the current entry.
+    /// REQUIRES: valid()
+    pub fn key(&self) -> Slice<'_> {
+        self.iter.key()
+    }
+
+    /// Returns the value of the current entry, without its timestamp.
+    /// REQUIRES: valid()
+    pub fn value(&self) -> Slice<'_> {
+        let value = self.iter.value().data();
+        Slice::from_bytes(&value[..value.len().saturating_sub(K_TS_LENGTH)])
+    }
+
+    /// Returns the first error hit by the iterator.
+    pub fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+
+    /// Position at the first live entry with a key at or past target.
+    pub fn seek(&mut self, target: &Slice) {
+        self.iter.seek(target);
+        self.skip_stale_forward();
+    }
+
+    /// Position at the first live entry of the db, if any.
+    pub fn seek_to_first(&mut self) {
+        self.iter.seek_to_first();
+        self.skip_stale_forward();
+    }
+
+    /// Position at the last live entry of the db, if any.
+    pub fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+        self.skip_stale_backward();
+    }
+
+    /// Move to the next live entry.
+    /// REQUIRES: valid()
+    pub fn next(&mut self) {
+        self.iter.next();
+        self.skip_stale_forward();
+    }
+
+    /// Move to the previous live entry.
+    /// REQUIRES: valid()
+    pub fn prev(&mut self) {
+        self.iter.prev();
+        self.skip_stale_backward();
+    }
+
+    fn skip_stale_forward(&mut self) {
+        while self.iter.valid() && is_stale(self.iter.value().data(), self.ttl, self.now) {
+            self.iter.next();
+        }
+    }
+
+    fn skip_stale_backward(&mut self) {
+        while self.iter.valid() && is_stale(self.iter.value().data(), self.ttl, self.now) {
+            self.iter.prev();
+        }
+    }
+}
+
+/// Drops the expired values, and hands the others to the filter of the
+/// user, if any.
+struct TtlCompactionFilter {
+
+    ttl: Duration,
+
+    user_filter: Option<Arc<dyn CompactionFilter>>
+}
+
+impl CompactionFilter for TtlCompactionFilter {
+
+    fn name(&self) -> &str {
+        "Delete By TTL"
+    }
+
+    fn filter(&self, key: &[u8], value: &[u8]) -> bool {
+        if is_stale(value, self.ttl, now()) {
+            return true;
+        }
+        match &self.user_filter {
+            Some(filter) if value.len() >= K_TS_LENGTH => filter.filter(key, &value[..value.len() - K_TS_LENGTH]),
+            _ => false
+        }
+    }
+}
+
+/// Returns the current time in seconds since the epoch.
+fn now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as u32).unwrap_or(0)
+}
+
+fn append_timestamp(value: &[u8], timestamp: u32) -> Vec<u8> {
+    let mut result = Vec::with_capacity(value.len() + K_TS_LENGTH);
+    result.extend_from_slice(value);
+    put_fixed32(&mut result, timestamp);
+    result
+}
+
+/// Returns true if the timestamped `value` is older than `ttl` at time
+/// `now`. Values too short to hold a timestamp are never stale.
+fn is_stale(value: &[u8], ttl: Duration, now: u32) -> bool {
+    if ttl.is_zero() || value.len() < K_TS_LENGTH {
+        return false;
+    }
+    let timestamp = decode_fix32(&value[value.len() - K_TS_LENGTH..]);
+    u64::from(timestamp) + ttl.as_secs() < u64::from(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::*;
+
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("revel_db_ttl_test_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    // Write `value` as if it had been put `age` seconds ago
+    fn put_aged(db: &mut DBWithTTL, key: &str, value: &str, age: u32) {
+        let value = append_timestamp(value.as_bytes(), now() - age);
+        db.db.put(&WriteOptions::default(), &Slice::from_str(key), &Slice::from_bytes(&value)).unwrap();
+    }
+
+    fn entries(iter: &mut TtlIterator, forward: bool) -> Vec<String> {
+        let mut result = Vec::new();
+        if forward {
+            iter.seek_to_first();
+        } else {
+            iter.seek_to_last();
+        }
+        while iter.valid() {
+            result.push(format!("{}->{}", String::from_utf8_lossy(iter.key().data()), String::from_utf8_lossy(iter.value().data())));
+            if forward {
+                iter.next();
+            } else {
+                iter.prev();
+            }
+        }
+        result
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut db = DBWithTTL::open(&Options::default(), db_path("expiry"), Duration::from_secs(100)).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("a1")).unwrap();
+        put_aged(&mut db, "b", "b1", 1000);
+        put_aged(&mut db, "c", "c1", 50);
+        put_aged(&mut db, "d", "d1", 1000);
+
+        let get = |db: &DBWithTTL, key: &str| db.get(&ReadOptions::default(), &Slice::from_str(key));
+        assert_eq!(Ok(b"a1".to_vec()), get(&db, "a"));
+        assert_eq!(Err(NotFound), get(&db, "b"));
+        assert_eq!(Ok(b"c1".to_vec()), get(&db, "c"));
+        assert_eq!(Err(NotFound), get(&db, "e"));
+        // The base db still holds the expired value
+        assert_eq!(6, db.db().get(&ReadOptions::default(), &Slice::from_str("b")).unwrap().len());
+
+        let mut iter = db.new_iterator(&ReadOptions::default());
+        assert_eq!(vec!["a->a1", "c->c1"], entries(&mut iter, true));
+        assert_eq!(vec!["c->c1", "a->a1"], entries(&mut iter, false));
+        iter.seek(&Slice::from_str("b"));
+        assert_eq!(b"c", iter.key().data());
+        iter.seek(&Slice::from_str("d"));
+        assert!(!iter.valid());
+        assert_eq!(Ok(()), iter.status());
+        drop(iter);
+
+        // Without a ttl nothing expires
+        let mut db = DBWithTTL::open(&Options::default(), db_path("no_ttl"), Duration::ZERO).unwrap();
+        put_aged(&mut db, "b", "b1", 1000);
+        assert_eq!(Ok(b"b1".to_vec()), get(&db, "b"));
+    }
+
+    #[test]
+    fn test_compaction_drops_expired_values() {
+        let dbname = db_path("compaction");
+        let mut db = DBWithTTL::open(&Options::default(), &dbname, Duration::from_secs(100)).unwrap();
+        // Enough level-0 files to trigger a compaction
+        for i in 0..4 {
+            put_aged(&mut db, &format!("old{}", i), "v", 1000);
+            db.put(&WriteOptions::default(), &Slice::from_str(&format!("new{}", i)), &Slice::from_str("v")).unwrap();
+            db.flush(&FlushOptions { wait: true }).unwrap();
+        }
+        db.close().unwrap();
+
+        // The compaction left the expired values out
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        for i in 0..4 {
+            assert_eq!(Err(NotFound), db.get(&ReadOptions::default(), &Slice::from_str(&format!("old{}", i))));
+            assert!(db.get(&ReadOptions::default(), &Slice::from_str(&format!("new{}", i))).is_ok());
+        }
+        assert_eq!(Some("0".to_string()), db.get_property("revel.num-files-at-level0"));
+    }
+}
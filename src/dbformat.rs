@@ -11,34 +11,82 @@
 // limitations under the License.
 
 use std::cmp::Ordering;
+use std::fmt;
+use std::ops::RangeInclusive;
 use crate::coding::{decode_fixed64, encode_fixed64, encode_varint32};
 use crate::comparator::Comparator;
 use crate::slice::Slice;
+use crate::util::logging::escape_string;
+use crate::Error;
+use crate::Error::{Corruption, NotSupport};
+use crate::Result;
 
 pub type SequenceNumber = u64;
 
-static kMaxSequenceNumber: SequenceNumber = ((1 as u64) << 56) - 1;
+pub const K_MAX_SEQUENCE_NUMBER: SequenceNumber = (1u64 << 56) - 1;
 
 #[derive(Clone, Copy, PartialEq, PartialOrd)]
 pub enum ValueType {
     
     KTypeDeletion = 0x0,
     
-    KTypeValue = 0x1
+    KTypeValue = 0x1,
+
+    /// Deletes every user key in [begin key, end key). Stored with the begin
+    /// key as user key and the end key as value.
+    KTypeRangeDeletion = 0xF
 }
 
+// Record tags occupy the low 8 bits of an internal key trailer. Every tag
+// up to kValueTypeForSeek is a valid tag, and the ones not assigned to a
+// ValueType yet are reserved in ranges for record types of later
+// releases, so that a database written by a newer release is rejected
+// with NotSupport instead of being reported as corrupted. Tags outside
+// the reserved ranges are never written and indicate corruption.
+
+/// Tags reserved for merge operands.
+pub const K_MERGE_TAGS: RangeInclusive<u8> = 0x2..=0x3;
+
+/// Tags reserved for references to values stored outside of the tables.
+pub const K_BLOB_REF_TAGS: RangeInclusive<u8> = 0x8..=0xB;
+
+/// Tags reserved for range deletions, including KTypeRangeDeletion.
+pub const K_RANGE_DELETION_TAGS: RangeInclusive<u8> = 0xC..=0xF;
+
 impl ValueType {
-    
-    pub fn from(ordinal: u8) -> Self {
-        match ordinal { 
-            0 => ValueType::KTypeDeletion,
-            1 => ValueType::KTypeValue,
-            _ => panic!("Unknown ValueType ordinal")
+
+    /// Returns the error to report for a tag that is not a known ValueType:
+    /// NotSupport if it is reserved for a newer record type, Corruption
+    /// otherwise.
+    pub fn unknown_tag_error(tag: u8) -> Error {
+        if K_MERGE_TAGS.contains(&tag) || K_BLOB_REF_TAGS.contains(&tag) || K_RANGE_DELETION_TAGS.contains(&tag) {
+            NotSupport
+        } else {
+            Corruption
         }
     }
 }
 
-static kValueTypeForSeek: ValueType = ValueType::KTypeValue;
+impl TryFrom<u8> for ValueType {
+    type Error = Error;
+
+    fn try_from(tag: u8) -> Result<Self> {
+        match tag {
+            0x0 => Ok(ValueType::KTypeDeletion),
+            0x1 => Ok(ValueType::KTypeValue),
+            0xF => Ok(ValueType::KTypeRangeDeletion),
+            _ => Err(ValueType::unknown_tag_error(tag))
+        }
+    }
+}
+
+/// kValueTypeForSeek defines the ValueType that should be passed when
+/// constructing a ParsedInternalKey object for seeking to a particular
+/// sequence number (since we sort sequence numbers in decreasing order
+/// and the value type is embedded as the low 8 bits in the sequence
+/// number in internal keys, we need to use the highest-numbered
+/// ValueType, not the lowest).
+pub static kValueTypeForSeek: ValueType = ValueType::KTypeRangeDeletion;
 
 pub struct InternalKeyComparator {
 
@@ -62,15 +110,12 @@ impl InternalKeyComparator {
 impl Comparator for InternalKeyComparator {
 
     fn compare(&self, akey: &Slice, bkey: &Slice) -> Ordering {
-        let mut r = (self.user_comparator)(akey, bkey);
+        let mut r = (self.user_comparator)(&extract_user_key(akey), &extract_user_key(bkey));
         if r == Ordering::Equal {
             let anum = decode_fixed64(akey.data(), akey.size() - 8);
             let bnum = decode_fixed64(bkey.data(), bkey.size() - 8);
-            if anum > bnum {
-                r = Ordering::Less
-            } else {
-                r = Ordering::Greater
-            }
+            // Decreasing sequence number
+            r = bnum.cmp(&anum);
         }
         r
     }
@@ -121,13 +166,87 @@ impl LookupKey {
         Slice::from_bytes(&self.buf[self.start..self.end])
     }
     
+    /// Return an internal key (suitable for passing to an internal iterator)
+    pub fn internal_key(&self) -> Slice<'_> {
+        Slice::from_bytes(&self.buf[self.kstart..self.end])
+    }
+
     pub fn user_key(&self) -> Slice {
         Slice::from_bytes(&self.buf[self.kstart..self.end-8])
     }
+
+    /// Return the sequence number the key looks up.
+    pub fn sequence(&self) -> SequenceNumber {
+        decode_fixed64(&self.buf, self.end - 8) >> 8
+    }
+}
+
+impl fmt::Display for LookupKey {
+    /// Formats the key as "'user key' @ sequence".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' @ {}", self.user_key(), self.sequence())
+    }
+}
+
+/// Returns the user key portion of an internal key.
+pub fn extract_user_key<'a>(internal_key: &Slice<'a>) -> Slice<'a> {
+    assert!(internal_key.size() >= 8);
+    Slice::from_bytes(&internal_key.data()[..internal_key.size() - 8])
+}
+
+pub struct ParsedInternalKey<'a> {
+
+    pub user_key: Slice<'a>,
+
+    pub sequence: SequenceNumber,
+
+    pub value_type: ValueType
+}
+
+impl<'a> fmt::Display for ParsedInternalKey<'a> {
+    /// Formats the key as "'user key' @ sequence : type".
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}' @ {} : {}", self.user_key, self.sequence, self.value_type as u8)
+    }
+}
+
+/// Return a human-readable version of an encoded internal key, as
+/// formatted by ParsedInternalKey, or "(bad)" followed by the escaped
+/// bytes if it cannot be parsed.
+pub fn internal_key_debug_string(internal_key: &[u8]) -> String {
+    match parse_internal_key(&Slice::from_bytes(internal_key)) {
+        Ok(parsed) => parsed.to_string(),
+        Err(_) => format!("(bad){}", escape_string(internal_key))
+    }
+}
+
+/// Append the serialization of "key" to buf.
+pub fn append_internal_key(buf: &mut Vec<u8>, user_key: &Slice, s: SequenceNumber, t: ValueType) {
+    buf.extend_from_slice(user_key.data());
+    let mut tag = [0; 8];
+    encode_fixed64(&mut tag, pack_sequence_and_type(s, t), 0);
+    buf.extend_from_slice(&tag);
+}
+
+/// Attempt to parse an internal key from "internal_key". On success,
+/// returns the parsed data. Returns NotSupport if the key was written
+/// with a record type reserved for a newer release, and Corruption if it
+/// is malformed.
+pub fn parse_internal_key<'a>(internal_key: &Slice<'a>) -> Result<ParsedInternalKey<'a>> {
+    let n = internal_key.size();
+    if n < 8 {
+        return Err(Corruption);
+    }
+    let num = decode_fixed64(internal_key.data(), n - 8);
+    Ok(ParsedInternalKey {
+        user_key: extract_user_key(internal_key),
+        sequence: num >> 8,
+        value_type: ValueType::try_from((num & 0xff) as u8)?
+    })
 }
 
 fn pack_sequence_and_type(seq: u64, t: ValueType) -> u64 {
-    assert!(seq <= kMaxSequenceNumber);
+    assert!(seq <= K_MAX_SEQUENCE_NUMBER);
     assert!(t <= kValueTypeForSeek);
     (seq << 8) | t as u64
 }
@@ -138,4 +257,50 @@ fn pack_sequence_and_type(seq: u64, t: ValueType) -> u64 {
 pub fn compare(akey: &Slice, bkey: &Slice) -> std::cmp::Ordering {
     // todo!()
     std::cmp::Ordering::Equal
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_formatting() {
+        let lkey = LookupKey::new(&Slice::from_bytes(b"foo\x00"), 100);
+        assert_eq!(100, lkey.sequence());
+        assert_eq!("'foo\\x00' @ 100", lkey.to_string());
+
+        let mut ikey = Vec::new();
+        append_internal_key(&mut ikey, &Slice::from_bytes(b"k\xff"), 7, ValueType::KTypeValue);
+        assert_eq!("'k\\xff' @ 7 : 1", internal_key_debug_string(&ikey));
+        ikey.truncate(5);
+        assert_eq!("(bad)k\\xff\\x01\\x07\\x00", internal_key_debug_string(&ikey));
+    }
+
+    #[test]
+    fn test_record_tags() {
+        assert!(ValueType::KTypeDeletion == ValueType::try_from(0x0).unwrap());
+        assert!(ValueType::KTypeValue == ValueType::try_from(0x1).unwrap());
+        assert!(ValueType::KTypeRangeDeletion == ValueType::try_from(0xF).unwrap());
+        for tag in [0x2, 0x3, 0x8, 0xB, 0xC, 0xE] {
+            assert!(ValueType::try_from(tag) == Err(NotSupport));
+        }
+        for tag in [0x4, 0x7, 0x10, 0xFF] {
+            assert!(ValueType::try_from(tag) == Err(Corruption));
+        }
+
+        let mut ikey = Vec::new();
+        append_internal_key(&mut ikey, &Slice::from_str("foo"), 9, ValueType::KTypeValue);
+        let parsed = parse_internal_key(&Slice::from_bytes(&ikey)).unwrap();
+        assert_eq!(b"foo", parsed.user_key.data());
+        assert_eq!(9, parsed.sequence);
+        assert!(ValueType::KTypeValue == parsed.value_type);
+
+        // A record type of a newer release
+        let n = ikey.len();
+        ikey[n - 8] = *K_BLOB_REF_TAGS.start();
+        assert!(parse_internal_key(&Slice::from_bytes(&ikey)).is_err_and(|e| e == NotSupport));
+        ikey[n - 8] = 0x42;
+        assert!(parse_internal_key(&Slice::from_bytes(&ikey)).is_err_and(|e| e == Corruption));
+        assert!(parse_internal_key(&Slice::from_str("short")).is_err_and(|e| e == Corruption));
+    }
+}
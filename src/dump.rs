@@ -0,0 +1,373 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Dumps hold the entries of a db in a file independent of its on-disk
+//! format, so that data can be moved between releases or in and out of
+//! other stores.
+//!
+//! A binary dump starts with an 8 byte magic and a fixed32 version,
+//! followed by one record per entry:
+//!
+//!    tag      : 1
+//!    key      : varint32 length + bytes
+//!    value    : varint32 length + bytes
+//!
+//! and ends with a record of tag 0 and the varint64 number of entries, which
+//! tells a complete dump from a truncated one.
+//!
+//! A CSV dump has a "key,value" header line, then one line per entry.
+//! Printable ASCII characters are written as is, except for '\' which is
+//! doubled; every other byte, including ',', '"' and line breaks, is
+//! written as \xHH. Fields are thus never quoted and any key or value
+//! survives a round trip.
+//!
+//! dump_manifest() lists the edits recorded in a MANIFEST file, to inspect
+//! how the files of a db came and went.
+
+use std::cell::Cell;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::path::Path;
+use std::rc::Rc;
+use crate::coding::{decode_fix32, put_fixed32, put_length_prefixed_slice, put_varint64};
+use crate::env::{new_sequential_file, new_writable_file};
+use crate::Error::{Corruption, NotSupport};
+use crate::log_reader::Reader;
+use crate::Result;
+use crate::slice::Slice;
+use crate::version_edit::VersionEdit;
+use crate::version_set::CorruptionReporter;
+
+const K_MAGIC: &[u8; 8] = b"REVELDMP";
+
+const K_VERSION: u32 = 1;
+
+const K_TAG_END: u8 = 0;
+const K_TAG_ENTRY: u8 = 1;
+
+const K_CSV_HEADER: &str = "key,value";
+
+/// Format of a dump written by DB::export.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DumpFormat {
+    /// Compact length-prefixed records.
+    Binary,
+
+    /// Escaped key,value lines, readable by other tools.
+    Csv
+}
+
+/// Write the entries to a new dump at `path`, replacing any existing file.
+/// Returns the number of entries written.
+pub(crate) fn write_dump<I>(path: &Path, format: DumpFormat, entries: I) -> Result<u64>
+    where I: IntoIterator<Item=Result<(Vec<u8>, Vec<u8>)>> {
+    let file = new_writable_file(path, false)?;
+    let mut file = file.borrow_mut();
+    let mut buf = Vec::new();
+    match format {
+        DumpFormat::Binary => {
+            buf.extend_from_slice(K_MAGIC);
+            put_fixed32(&mut buf, K_VERSION);
+        },
+        DumpFormat::Csv => {
+            buf.extend_from_slice(K_CSV_HEADER.as_bytes());
+            buf.push(b'\n');
+        }
+    }
+    let mut count = 0;
+    for entry in entries {
+        let (key, value) = entry?;
+        match format {
+            DumpFormat::Binary => {
+                buf.push(K_TAG_ENTRY);
+                put_length_prefixed_slice(&mut buf, &Slice::from_bytes(&key));
+                put_length_prefixed_slice(&mut buf, &Slice::from_bytes(&value));
+            },
+            DumpFormat::Csv => {
+                let line = format!("{},{}\n", escape_csv_field(&key), escape_csv_field(&value));
+                buf.extend_from_slice(line.as_bytes());
+            }
+        }
+        count += 1;
+        file.append(&Slice::from_bytes(&buf))?;
+        buf.clear();
+    }
+    if format == DumpFormat::Binary {
+        buf.push(K_TAG_END);
+        put_varint64(&mut buf, count);
+        file.append(&Slice::from_bytes(&buf))?;
+    }
+    file.flush()?;
+    file.sync()?;
+    file.close()?;
+    Ok(count)
+}
+
+/// Read the dump at `path`, whatever its format, calling `add` for every
+/// entry in order. Returns the number of entries read.
+pub(crate) fn read_dump<F>(path: &Path, mut add: F) -> Result<u64>
+    where F: FnMut(&[u8], &[u8]) -> Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if reader.fill_buf()?.starts_with(K_MAGIC) {
+        read_binary_dump(&mut reader, &mut add)
+    } else {
+        read_csv_dump(&mut reader, &mut add)
+    }
+}
+
+/// Return the edits recorded in the MANIFEST file at `path`, as formatted
+/// by VersionEdit::debug_string(), in the order they were logged. Returns
+/// Corruption if a record is damaged or does not hold an edit.
+pub fn dump_manifest(path: impl AsRef<Path>) -> Result<String> {
+    let file = new_sequential_file(path)?;
+    let corrupted = Rc::new(Cell::new(false));
+    let reporter = CorruptionReporter { corrupted: corrupted.clone() };
+    let mut reader = Reader::new(file, Some(Box::new(reporter)), true, 0);
+    let mut r = String::new();
+    let mut scratch = Vec::new();
+    while let Some(record) = reader.read_record(&mut scratch) {
+        r.push_str(&VersionEdit::decode_from(&record)?.debug_string());
+    }
+    if corrupted.get() {
+        return Err(Corruption);
+    }
+    Ok(r)
+}
+
+fn read_binary_dump<F>(reader: &mut BufReader<File>, add: &mut F) -> Result<u64>
+    where F: FnMut(&[u8], &[u8]) -> Result<()> {
+    let mut header = [0; 12];
+    read_exact(reader, &mut header)?;
+    if decode_fix32(&header[8..]) != K_VERSION {
+        // dump written by a newer release
+        return Err(NotSupport);
+    }
+    let mut count = 0;
+    let (mut key, mut value) = (Vec::new(), Vec::new());
+    loop {
+        let mut tag = [0];
+        read_exact(reader, &mut tag)?;
+        match tag[0] {
+            K_TAG_ENTRY => {
+                read_length_prefixed(reader, &mut key)?;
+                read_length_prefixed(reader, &mut value)?;
+                add(&key, &value)?;
+                count += 1;
+            },
+            K_TAG_END => {
+                if read_varint(reader)? != count {
+                    // number of entries does not match the end record
+                    return Err(Corruption);
+                }
+                return Ok(count);
+            },
+            _ => {
+                // unknown record tag
+                return Err(Corruption);
+            }
+        }
+    }
+}
+
+fn read_csv_dump<F>(reader: &mut BufReader<File>, add: &mut F) -> Result<u64>
+    where F: FnMut(&[u8], &[u8]) -> Result<()> {
+    let mut lines = reader.lines();
+    match lines.next() {
+        Some(Ok(header)) if header == K_CSV_HEADER => {},
+        // neither a binary nor a CSV dump
+        _ => return Err(Corruption)
+    }
+    let mut count = 0;
+    for line in lines {
+        let line = line.map_err(|_| Corruption)?;
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split(',');
+        let (Some(key), Some(value), None) = (fields.next(), fields.next(), fields.next()) else {
+            // not a key,value line
+            return Err(Corruption);
+        };
+        add(&unescape_csv_field(key)?, &unescape_csv_field(value)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Like Read::read_exact, but a dump ending early is a Corruption.
+fn read_exact(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => Corruption,
+        _ => e.into()
+    })
+}
+
+fn read_varint(reader: &mut impl Read) -> Result<u64> {
+    let mut result = 0;
+    for shift in (0..=63).step_by(7) {
+        let mut byte = [0];
+        read_exact(reader, &mut byte)?;
+        result |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    // varint too long
+    Err(Corruption)
+}
+
+fn read_length_prefixed(reader: &mut impl Read, buf: &mut Vec<u8>) -> Result<()> {
+    let len = read_varint(reader)?;
+    if len > u32::MAX as u64 {
+        return Err(Corruption);
+    }
+    buf.clear();
+    reader.take(len).read_to_end(buf)?;
+    if buf.len() as u64 != len {
+        // truncated dump
+        return Err(Corruption);
+    }
+    Ok(())
+}
+
+fn escape_csv_field(value: &[u8]) -> String {
+    let mut r = String::with_capacity(value.len());
+    for &c in value {
+        match c {
+            b'\\' => r.push_str("\\\\"),
+            b',' | b'"' => {
+                let _ = write!(r, "\\x{:02x}", c);
+            },
+            b' '..=b'~' => r.push(c as char),
+            _ => {
+                let _ = write!(r, "\\x{:02x}", c);
+            }
+        }
+    }
+    r
+}
+
+fn unescape_csv_field(field: &str) -> Result<Vec<u8>> {
+    let bytes = field.as_bytes();
+    let mut r = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            r.push(bytes[i]);
+            i += 1;
+        } else if bytes.get(i + 1) == Some(&b'\\') {
+            r.push(b'\\');
+            i += 2;
+        } else if bytes.get(i + 1) == Some(&b'x') && i + 4 <= bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 2..i + 4]).map_err(|_| Corruption)?;
+            r.push(u8::from_str_radix(hex, 16).map_err(|_| Corruption)?);
+            i += 4;
+        } else {
+            // bad escape sequence
+            return Err(Corruption);
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::db::DB;
+    use crate::Error::{InvalidArgument, IOError};
+    use crate::options::{FlushOptions, Options, WriteOptions};
+    use super::*;
+
+    fn dump_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("revel_dump_test_{}", name))
+    }
+
+    fn entries() -> Vec<(Vec<u8>, Vec<u8>)> {
+        vec![
+            (b"".to_vec(), b"empty key".to_vec()),
+            (b"a,b".to_vec(), b"\"quoted\"\nline".to_vec()),
+            (b"back\\slash".to_vec(), b"\\x41".to_vec()),
+            (b"bin\x00\xff".to_vec(), vec![0x80; 300]),
+            (b"plain".to_vec(), b"".to_vec())
+        ]
+    }
+
+    fn round_trip(path: &Path, format: DumpFormat) -> Vec<(Vec<u8>, Vec<u8>)> {
+        assert_eq!(5, write_dump(path, format, entries().into_iter().map(Ok)).unwrap());
+        let mut read = Vec::new();
+        assert_eq!(5, read_dump(path, |key, value| {
+            read.push((key.to_vec(), value.to_vec()));
+            Ok(())
+        }).unwrap());
+        read
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let path = dump_path("binary");
+        assert_eq!(entries(), round_trip(&path, DumpFormat::Binary));
+
+        // Every truncation of the dump is detected
+        let data = std::fs::read(&path).unwrap();
+        for len in 0..data.len() {
+            std::fs::write(&path, &data[..len]).unwrap();
+            assert_eq!(Err(Corruption), read_dump(&path, |_, _| Ok(())), "truncated at {}", len);
+        }
+
+        let mut newer = data.clone();
+        newer[8] = 2;
+        std::fs::write(&path, &newer).unwrap();
+        assert_eq!(Err(NotSupport), read_dump(&path, |_, _| Ok(())));
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let path = dump_path("csv");
+        assert_eq!(entries(), round_trip(&path, DumpFormat::Csv));
+        let csv = std::fs::read_to_string(&path).unwrap();
+        assert!(csv.starts_with("key,value\n,empty key\na\\x2cb,\\x22quoted\\x22\\x0aline\nback\\\\slash,\\\\x41\n"));
+
+        for bad in ["key,value\na,b,c\n", "key,value\nab\n", "key,value\na\\x4,b\n", "key,value\na\\q,b\n", "k,v\n"] {
+            std::fs::write(&path, bad).unwrap();
+            assert_eq!(Err(Corruption), read_dump(&path, |_, _| Ok(())), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_errors_are_propagated() {
+        let path = dump_path("errors");
+        let failing = vec![Ok((b"a".to_vec(), b"1".to_vec())), Err(Corruption)];
+        assert_eq!(Err(Corruption), write_dump(&path, DumpFormat::Binary, failing));
+
+        round_trip(&path, DumpFormat::Binary);
+        assert_eq!(Err(InvalidArgument), read_dump(&path, |_, _| Err(InvalidArgument)));
+        assert_eq!(Err(IOError), read_dump(&dump_path("missing"), |_, _| Ok(())));
+    }
+
+    #[test]
+    fn test_dump_manifest() {
+        let dbname = dump_path("manifest");
+        let _ = std::fs::remove_dir_all(&dbname);
+        let mut db = DB::open(&Options::default(), &dbname).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("a"), &Slice::from_str("v")).unwrap();
+        db.put(&WriteOptions::default(), &Slice::from_str("b"), &Slice::from_str("v")).unwrap();
+        db.flush(&FlushOptions { wait: true }).unwrap();
+        db.close().unwrap();
+
+        let current = std::fs::read_to_string(dbname.join("CURRENT")).unwrap();
+        let dump = dump_manifest(dbname.join(current.trim_end())).unwrap();
+        assert!(dump.starts_with("VersionEdit {\n  Comparator: "));
+        assert!(dump.contains(" 'a' @ 1 : 1 .. 'b' @ 2 : 1\n"));
+        assert_eq!(Err(IOError), dump_manifest(dbname.join("MANIFEST-999999")));
+    }
+}
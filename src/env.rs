@@ -10,33 +10,107 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, RefCell, RefMut};
 use std::cmp::min;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
 use std::fs::{File, OpenOptions};
 use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::hash::{BuildHasher, Hasher};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use crate::Error::IOError;
+use crate::options::BackgroundJobLimits;
 use crate::Result;
 use crate::slice::Slice;
 
 /// posix env implementation
 
-pub fn new_writable_file(filename: &str) -> Result<Box<dyn WritableFile>>{
+/// Create a new file with the specified name, truncating any existing one.
+/// If `use_mmap_writes` is set, data is written through a memory mapping
+/// of the file instead of buffered write calls.
+pub fn new_writable_file(filename: impl AsRef<Path>, use_mmap_writes: bool) -> Result<Rc<RefCell<dyn WritableFile>>>{
+    let filename = filename.as_ref();
     // todo!() O_CLOEXEC flag
     let opened_file = OpenOptions::new()
         .truncate(true)
+        .read(use_mmap_writes)
         .write(true)
         .create(true)
         .open(filename);
 
     match opened_file {
-        Ok(file) => Ok(Box::new(PosixWritableFile::new(filename, file))),
+        Ok(file) if use_mmap_writes => Ok(Rc::new(RefCell::new(PosixMmapFile::new(file)))),
+        Ok(file) => Ok(Rc::new(RefCell::new(PosixWritableFile::new(filename, file)))),
         Err(err) => Err(crate::Error::from(err))
     }
 
 }
 
+/// Open the file with the specified name for random reads.
+pub fn new_random_access_file(filename: impl AsRef<Path>) -> Result<Rc<dyn RandomAccessFile>> {
+    let filename = filename.as_ref();
+    let file = File::open(filename)?;
+    Ok(Rc::new(PosixRandomAccessFile {
+        has_permanent_file: true,
+        file: RefCell::new(file),
+        filename: filename.to_path_buf()
+    }))
+}
+
+/// Returns 16 random bytes from the system entropy source. If it cannot be
+/// read, falls back to randomly seeded hashes of the current time.
+fn random_bytes() -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    if File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut bytes)).is_err() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        for (i, chunk) in bytes.chunks_mut(8).enumerate() {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(now);
+            hasher.write_usize(i);
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+    }
+    bytes
+}
+
+/// Generates a unique id formatted as a random (version 4) UUID, e.g.
+/// "1b4e28ba-2fa1-41d2-883f-0016d3cca427".
+pub fn generate_unique_id() -> String {
+    let mut bytes = random_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Generates a random id of 20 upper case letters and digits, short enough
+/// to be embedded in file names and log lines.
+pub fn generate_session_id() -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut value = u128::from_le_bytes(random_bytes());
+    let mut id = String::with_capacity(20);
+    for _ in 0..20 {
+        id.push(DIGITS[(value % 36) as usize] as char);
+        value /= 36;
+    }
+    id
+}
+
+pub fn new_sequential_file(filename: impl AsRef<Path>) -> Result<Box<dyn SequentialFile>> {
+    let file = File::open(filename.as_ref())?;
+    Ok(Box::new(PosixSequentialFile {
+        file: RefCell::new(file),
+        filename: filename.as_ref().to_path_buf()
+    }))
+}
+
 pub trait WritableFile {
 
     fn append(&mut self, data: &Slice) -> Result<()>;
@@ -66,6 +140,32 @@ pub trait FileLock {
 
 }
 
+/// Lock on a file, taken with flock(2) and released when dropped.
+pub struct PosixFileLock {
+    _file: File
+}
+
+impl FileLock for PosixFileLock {
+
+}
+
+/// Lock the specified file, creating it if needed. Used to prevent
+/// concurrent access to the same db by multiple processes or by
+/// several opens within one process. Returns None if the lock is held
+/// by someone else.
+pub fn try_lock_file(filename: impl AsRef<Path>) -> Result<Option<Box<dyn FileLock>>> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(filename)?;
+    let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if result != 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(None);
+        }
+        return Err(crate::Error::from(err));
+    }
+    Ok(Some(Box::new(PosixFileLock { _file: file })))
+}
+
 const kWritableFileBufferSize: usize = 65536;
 
 pub struct PosixWritableFile {
@@ -77,31 +177,30 @@ pub struct PosixWritableFile {
 
     // True if the file's name starts with MANIFEST.
     is_manifest: bool,
-    filename: String,
+    filename: PathBuf,
     // The directory of filename_.
-    dirname: String
+    dirname: PathBuf
 }
 
 fn write_unbuffered(mut file: RefMut<File>, data: &[u8], size: usize) -> Result<()> {
-    let result = file.write(&data[0..size]);
+    let result = file.write_all(&data[0..size]);
     match result {
-        Ok(write_result) => Ok(()),
+        Ok(_) => Ok(()),
         Err(err) => Err(crate::Error::from(err))
     }
 }
 
 impl PosixWritableFile {
 
-    pub fn new(filename: &str, file: File) -> Self {
+    pub fn new(filename: impl AsRef<Path>, file: File) -> Self {
+        let filename = filename.as_ref();
         PosixWritableFile {
             pos: 0,
             buf: vec![0; kWritableFileBufferSize],
             file: RefCell::new(file),
-            // todo!() filename start with MANIFEST
-            filename: filename.to_string(),
-            is_manifest: false,
-            // todo!() parse dirname from filename
-            dirname: "".to_string()
+            filename: filename.to_path_buf(),
+            is_manifest: filename.file_name().is_some_and(|name| name.as_bytes().starts_with(b"MANIFEST")),
+            dirname: filename.parent().map(Path::to_path_buf).unwrap_or_default()
         }
     }
 
@@ -114,25 +213,27 @@ impl PosixWritableFile {
 
 impl WritableFile for PosixWritableFile {
     fn append(&mut self, data: &Slice) -> Result<()> {
-        let write_data = data.data();
-        let write_size = data.size();
-        let mut write_offset = 0;
-        let copy_size = std::cmp::min(write_size, kWritableFileBufferSize - self.pos);
-        let size = self.buf.write(&write_data[..copy_size]).expect("");
-        self.pos += size;
-        write_offset += size;
-        if write_size <= write_offset {
+        let mut write_data = data.data();
+
+        // Fit as much as possible into buffer.
+        let copy_size = min(write_data.len(), kWritableFileBufferSize - self.pos);
+        self.buf[self.pos..self.pos + copy_size].copy_from_slice(&write_data[..copy_size]);
+        write_data = &write_data[copy_size..];
+        self.pos += copy_size;
+        if write_data.is_empty() {
             return Ok(());
         }
 
         // Can't fit in buffer, so need to do at least one write.
         self.flush_buffer()?;
 
-        if write_size - write_offset < kWritableFileBufferSize {
-            self.buf.write(&write_data[size..]).expect("");
+        // Small writes go to buffer, large writes are written directly.
+        if write_data.len() < kWritableFileBufferSize {
+            self.buf[..write_data.len()].copy_from_slice(write_data);
+            self.pos = write_data.len();
             return Ok(());
         }
-        write_unbuffered(self.file.borrow_mut(), write_data, write_size - write_offset)
+        write_unbuffered(self.file.borrow_mut(), write_data, write_data.len())
     }
 
     fn flush(&mut self) -> Result<()> {
@@ -150,11 +251,184 @@ impl WritableFile for PosixWritableFile {
     }
 }
 
+/// Writable file that copies appended data into a shared memory mapping of
+/// the file. The mapping is extended in growing regions as data arrives,
+/// and the file is cut back to the bytes actually written on close.
+pub struct PosixMmapFile {
+
+    file: File,
+    page_size: usize,
+
+    // How much extra memory to map at a time
+    map_size: Cell<usize>,
+
+    // The mapped region
+    base: Cell<*mut u8>,
+
+    // Limit of the mapped region, relative to base
+    limit: Cell<usize>,
+
+    // Where to write next, relative to base
+    dst: Cell<usize>,
+
+    // Where have we synced up to, relative to base
+    last_sync: Cell<usize>,
+
+    // Offset of base in the file
+    file_offset: Cell<u64>,
+
+    // Have we done an munmap of unsynced data?
+    pending_sync: Cell<bool>,
+
+    closed: Cell<bool>
+}
+
+impl PosixMmapFile {
+
+    pub fn new(file: File) -> Self {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        assert!(page_size.is_power_of_two());
+        PosixMmapFile {
+            file,
+            page_size,
+            map_size: Cell::new(Self::roundup(65536, page_size)),
+            base: Cell::new(std::ptr::null_mut()),
+            limit: Cell::new(0),
+            dst: Cell::new(0),
+            last_sync: Cell::new(0),
+            file_offset: Cell::new(0),
+            pending_sync: Cell::new(false),
+            closed: Cell::new(false)
+        }
+    }
+
+    // Roundup x to a multiple of y
+    fn roundup(x: usize, y: usize) -> usize {
+        x.div_ceil(y) * y
+    }
+
+    fn truncate_to_page_boundary(&self, s: usize) -> usize {
+        s & !(self.page_size - 1)
+    }
+
+    fn unmap_current_region(&self) -> Result<()> {
+        let base = self.base.get();
+        if base.is_null() {
+            return Ok(());
+        }
+        let limit = self.limit.get();
+        if self.last_sync.get() < limit {
+            // Defer syncing this data until next sync()
+            self.pending_sync.set(true);
+        }
+        let result = unsafe { libc::munmap(base as *mut libc::c_void, limit) };
+        self.file_offset.set(self.file_offset.get() + limit as u64);
+        self.base.set(std::ptr::null_mut());
+        self.limit.set(0);
+        self.dst.set(0);
+        self.last_sync.set(0);
+
+        // Increase the amount we map the next time, but capped at 1MB
+        if self.map_size.get() < (1 << 20) {
+            self.map_size.set(self.map_size.get() * 2);
+        }
+        if result != 0 {
+            return Err(IOError);
+        }
+        Ok(())
+    }
+
+    fn map_new_region(&self) -> Result<()> {
+        assert!(self.base.get().is_null());
+        let map_size = self.map_size.get();
+        let file_offset = self.file_offset.get();
+        self.file.set_len(file_offset + map_size as u64)?;
+        let ptr = unsafe {
+            libc::mmap(std::ptr::null_mut(), map_size, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED, self.file.as_raw_fd(), file_offset as libc::off_t)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(IOError);
+        }
+        self.base.set(ptr as *mut u8);
+        self.limit.set(map_size);
+        self.dst.set(0);
+        self.last_sync.set(0);
+        Ok(())
+    }
+}
+
+impl WritableFile for PosixMmapFile {
+    fn append(&mut self, data: &Slice) -> Result<()> {
+        let mut src = data.data();
+        while !src.is_empty() {
+            assert!(self.dst.get() <= self.limit.get());
+            let mut avail = self.limit.get() - self.dst.get();
+            if avail == 0 {
+                self.unmap_current_region()?;
+                self.map_new_region()?;
+                avail = self.limit.get();
+            }
+
+            let n = min(src.len(), avail);
+            unsafe {
+                std::ptr::copy_nonoverlapping(src.as_ptr(), self.base.get().add(self.dst.get()), n);
+            }
+            self.dst.set(self.dst.get() + n);
+            src = &src[n..];
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn close(&self) -> Result<()> {
+        if self.closed.replace(true) {
+            return Ok(());
+        }
+        let file_length = self.file_offset.get() + self.dst.get() as u64;
+        let result = self.unmap_current_region();
+        self.file.set_len(file_length)?;
+        result
+    }
+
+    fn sync(&self) -> Result<()> {
+        if self.pending_sync.replace(false) {
+            // Some unmapped data was not synced
+            self.file.sync_data()?;
+        }
+
+        let dst = self.dst.get();
+        if dst > self.last_sync.get() {
+            // Find the beginnings of the pages that contain the first and last
+            // bytes to be synced.
+            let p1 = self.truncate_to_page_boundary(self.last_sync.get());
+            let p2 = self.truncate_to_page_boundary(dst - 1);
+            self.last_sync.set(dst);
+            let result = unsafe {
+                libc::msync(self.base.get().add(p1) as *mut libc::c_void, p2 - p1 + self.page_size, libc::MS_SYNC)
+            };
+            if result != 0 {
+                return Err(IOError);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PosixMmapFile {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
 pub struct PosixSequentialFile {
 
     file: RefCell<File>,
 
-    filename: String
+    filename: PathBuf
 }
 
 impl SequentialFile for PosixSequentialFile {
@@ -181,7 +455,7 @@ pub struct PosixRandomAccessFile {
 
     // todo!() Limiter
 
-    filename: String
+    filename: PathBuf
 
 }
 
@@ -192,9 +466,10 @@ impl RandomAccessFile for PosixRandomAccessFile {
             // todo!()
         }
 
-        self.file.borrow().read_at(scratch, offset)?;
+        let n = self.file.borrow().read_at(scratch, offset)?;
 
-        Ok(Slice::from_bytes(scratch))
+        // Fewer bytes than requested are returned at the end of the file
+        Ok(Slice::from_bytes(&scratch[..n]))
     }
 }
 
@@ -208,6 +483,11 @@ impl MemoryWritableFile {
             memory
         }
     }
+
+    /// Returns the bytes written so far.
+    pub fn contents(&self) -> &[u8] {
+        &self.memory
+    }
 }
 
 impl WritableFile for MemoryWritableFile {
@@ -263,4 +543,408 @@ impl SequentialFile for MemorySequentialFile {
         self.offset.replace(memory_offset);
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+pub struct MemoryRandomAccessFile {
+    memory: Rc<Vec<u8>>
+}
+
+impl MemoryRandomAccessFile {
+
+    pub fn new(memory: Rc<Vec<u8>>) -> Self {
+        MemoryRandomAccessFile {
+            memory
+        }
+    }
+}
+
+impl RandomAccessFile for MemoryRandomAccessFile {
+    fn read<'a>(&'a self, offset: u64, scratch: &'a mut [u8]) -> Result<Slice<'a>> {
+        let begin = min(offset as usize, self.memory.len());
+        let len = min(scratch.len(), self.memory.len() - begin);
+        scratch[..len].copy_from_slice(&self.memory[begin..begin + len]);
+        Ok(Slice::from_bytes(&scratch[..len]))
+    }
+}
+
+/// Priority of a background job. Flushes are scheduled with high priority
+/// and compactions with low priority, each on its own pool, so a backlog of
+/// long compactions can never delay the flushes that unblock writers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Low
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+struct PoolState {
+    queue: VecDeque<Job>,
+
+    // Number of threads that should be running
+    total_threads_limit: usize,
+
+    // Number of threads currently alive
+    num_threads: usize,
+
+    exit_all_threads: bool
+}
+
+/// A fixed size pool of background threads running jobs in FIFO order.
+pub struct ThreadPool {
+    shared: Arc<(Mutex<PoolState>, Condvar)>,
+
+    workers: Vec<JoinHandle<()>>
+}
+
+impl ThreadPool {
+
+    pub fn new(threads: usize) -> Self {
+        let mut pool = ThreadPool {
+            shared: Arc::new((Mutex::new(PoolState {
+                queue: VecDeque::new(),
+                total_threads_limit: 0,
+                num_threads: 0,
+                exit_all_threads: false
+            }), Condvar::new())),
+            workers: vec![]
+        };
+        pool.set_background_threads(threads);
+        pool
+    }
+
+    /// Resize the pool to `threads` threads. When shrinking, surplus threads
+    /// exit once they finish the job they are running.
+    pub fn set_background_threads(&mut self, threads: usize) {
+        let (lock, cv) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        state.total_threads_limit = threads;
+        while state.num_threads < threads {
+            let shared = self.shared.clone();
+            self.workers.push(std::thread::spawn(move || Self::bg_thread(shared)));
+            state.num_threads += 1;
+        }
+        // Wake up idle threads so that surplus ones notice the new limit
+        cv.notify_all();
+    }
+
+    /// Arrange to run `job` once on a background thread.
+    pub fn schedule<F>(&self, job: F) where F: FnOnce() + Send + 'static {
+        let (lock, cv) = &*self.shared;
+        lock.lock().unwrap().queue.push_back(Box::new(job));
+        cv.notify_one();
+    }
+
+    fn bg_thread(shared: Arc<(Mutex<PoolState>, Condvar)>) {
+        let (lock, cv) = &*shared;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if state.num_threads > state.total_threads_limit {
+                // The pool was shrunk, this thread is surplus
+                state.num_threads -= 1;
+                return;
+            }
+            match state.queue.pop_front() {
+                Some(job) => {
+                    drop(state);
+                    job();
+                    state = lock.lock().unwrap();
+                },
+                None if state.exit_all_threads => {
+                    state.num_threads -= 1;
+                    return;
+                },
+                None => state = cv.wait(state).unwrap()
+            }
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    /// Runs the jobs still queued, then joins every thread.
+    fn drop(&mut self) {
+        {
+            let (lock, cv) = &*self.shared;
+            lock.lock().unwrap().exit_all_threads = true;
+            cv.notify_all();
+        }
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The background thread pools of a db, sized from the options.
+pub struct BackgroundPools {
+    high: ThreadPool,
+
+    low: ThreadPool
+}
+
+impl BackgroundPools {
+
+    pub fn new(limits: BackgroundJobLimits) -> Self {
+        BackgroundPools {
+            high: ThreadPool::new(limits.max_flushes),
+            low: ThreadPool::new(limits.max_compactions)
+        }
+    }
+
+    /// Arrange to run `job` once on the pool of the given priority.
+    pub fn schedule<F>(&self, priority: Priority, job: F) where F: FnOnce() + Send + 'static {
+        match priority {
+            Priority::High => self.high.schedule(job),
+            Priority::Low => self.low.schedule(job)
+        }
+    }
+}
+
+struct PeriodicSyncState {
+    file: File,
+
+    shutdown: bool
+}
+
+/// Syncs the data of a file to stable storage every `interval` on a
+/// background thread, until dropped. The file is synced through a file
+/// descriptor of its own, so only data already handed to the operating
+/// system (i.e. flushed) is covered.
+pub struct PeriodicFileSync {
+    shared: Arc<(Mutex<PeriodicSyncState>, Condvar)>,
+
+    thread: Option<JoinHandle<()>>
+}
+
+impl PeriodicFileSync {
+
+    /// Start syncing `filename`. `on_sync` is called on the background
+    /// thread with the outcome of every sync.
+    pub fn new<F>(filename: impl AsRef<Path>, interval: Duration, on_sync: F) -> Result<Self>
+        where F: Fn(Result<()>) + Send + 'static {
+        let shared = Arc::new((Mutex::new(PeriodicSyncState {
+            file: File::open(filename.as_ref())?,
+            shutdown: false
+        }), Condvar::new()));
+        let thread_shared = shared.clone();
+        let thread = std::thread::spawn(move || {
+            let (lock, cv) = &*thread_shared;
+            let mut state = lock.lock().unwrap();
+            loop {
+                state = cv.wait_timeout_while(state, interval, |s| !s.shutdown).unwrap().0;
+                if state.shutdown {
+                    return;
+                }
+                let file = state.file.try_clone();
+                // Writers switching the file must not wait for the sync
+                drop(state);
+                on_sync(file.and_then(|f| f.sync_data()).map_err(crate::Error::from));
+                state = lock.lock().unwrap();
+            }
+        });
+        Ok(PeriodicFileSync { shared, thread: Some(thread) })
+    }
+
+    /// Sync `filename` from now on instead of the previous file, e.g. after
+    /// switching to a new log file.
+    pub fn set_file(&self, filename: impl AsRef<Path>) -> Result<()> {
+        let file = File::open(filename)?;
+        self.shared.0.lock().unwrap().file = file;
+        Ok(())
+    }
+}
+
+impl Drop for PeriodicFileSync {
+    fn drop(&mut self) {
+        {
+            let (lock, cv) = &*self.shared;
+            lock.lock().unwrap().shutdown = true;
+            cv.notify_all();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use super::*;
+
+    #[test]
+    fn test_generate_ids() {
+        let id = generate_unique_id();
+        assert_eq!(36, id.len());
+        let groups = id.split('-').map(|g| g.len()).collect::<Vec<_>>();
+        assert_eq!(vec![8, 4, 4, 4, 12], groups);
+        assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+        assert_eq!(Some('4'), id.chars().nth(14));
+        assert_ne!(id, generate_unique_id());
+
+        let session = generate_session_id();
+        assert_eq!(20, session.len());
+        assert!(session.chars().all(|c| c.is_ascii_digit() || c.is_ascii_uppercase()));
+        assert_ne!(session, generate_session_id());
+    }
+
+    #[test]
+    fn test_mmap_writable_file() {
+        let dir = std::env::temp_dir().join("revel_env_test_mmap_writable_file");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let fname = dir.join("000001.log");
+        let fname = fname.to_str().unwrap();
+
+        // Enough data to cross several mapped regions
+        let data = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let file = new_writable_file(fname, true).expect("create failed");
+        let mut written = 0;
+        for chunk in data.chunks(7_001) {
+            file.borrow_mut().append(&Slice::from_bytes(chunk)).unwrap();
+            written += chunk.len();
+            if written % 5 == 0 {
+                file.borrow().sync().unwrap();
+            }
+        }
+        file.borrow().sync().unwrap();
+        file.borrow().close().unwrap();
+        assert_eq!(data, std::fs::read(fname).unwrap());
+
+        // Closing on drop cuts the file back to the data written
+        let file = new_writable_file(fname, true).expect("create failed");
+        file.borrow_mut().append(&Slice::from_str("hello")).unwrap();
+        drop(file);
+        assert_eq!(b"hello".to_vec(), std::fs::read(fname).unwrap());
+    }
+
+    #[test]
+    fn test_thread_pool_runs_all_jobs() {
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(2);
+            for _ in 0..100 {
+                let done = done.clone();
+                pool.schedule(move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(100, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_thread_pool_limits_concurrency() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        {
+            let pool = ThreadPool::new(2);
+            for _ in 0..8 {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                pool.schedule(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(5));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+        assert_eq!(0, running.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_thread_pool_resize() {
+        let done = Arc::new(AtomicUsize::new(0));
+        {
+            let mut pool = ThreadPool::new(1);
+            pool.set_background_threads(4);
+            pool.set_background_threads(2);
+            for _ in 0..10 {
+                let done = done.clone();
+                pool.schedule(move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(10, done.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let dir = std::env::temp_dir().join("revel_env_test_lock_file");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let fname = dir.join("LOCK");
+        let fname = fname.to_str().unwrap();
+
+        let lock = try_lock_file(fname).unwrap();
+        assert!(lock.is_some());
+        assert!(try_lock_file(fname).unwrap().is_none());
+        drop(lock);
+        assert!(try_lock_file(fname).unwrap().is_some());
+        assert!(try_lock_file(dir.join("missing/LOCK").to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_periodic_file_sync() {
+        let dir = std::env::temp_dir().join("revel_env_test_periodic_file_sync");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("000001.log");
+        let second = dir.join("000002.log");
+        std::fs::write(&first, b"first").unwrap();
+        std::fs::write(&second, b"second").unwrap();
+
+        let syncs = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(AtomicUsize::new(0));
+        {
+            let (syncs, failures) = (syncs.clone(), failures.clone());
+            let sync = PeriodicFileSync::new(first.to_str().unwrap(), Duration::from_millis(5), move |result| {
+                match result {
+                    Ok(()) => syncs.fetch_add(1, Ordering::SeqCst),
+                    Err(_) => failures.fetch_add(1, Ordering::SeqCst)
+                };
+            }).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            sync.set_file(second.to_str().unwrap()).unwrap();
+            assert!(sync.set_file(dir.join("missing").to_str().unwrap()).is_err());
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        let count = syncs.load(Ordering::SeqCst);
+        assert!(count >= 2);
+        assert_eq!(0, failures.load(Ordering::SeqCst));
+
+        // Dropping stops the thread
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(count, syncs.load(Ordering::SeqCst));
+
+        // A long interval does not delay dropping
+        let start = std::time::Instant::now();
+        drop(PeriodicFileSync::new(first.to_str().unwrap(), Duration::from_secs(3600), |_| {}).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(PeriodicFileSync::new(dir.join("missing").to_str().unwrap(), Duration::from_millis(1), |_| {}).is_err());
+    }
+
+    #[test]
+    fn test_background_pools() {
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let compactions = Arc::new(AtomicUsize::new(0));
+        {
+            let pools = BackgroundPools::new(BackgroundJobLimits { max_flushes: 1, max_compactions: 3 });
+            for _ in 0..10 {
+                let flushes = flushes.clone();
+                pools.schedule(Priority::High, move || {
+                    flushes.fetch_add(1, Ordering::SeqCst);
+                });
+                let compactions = compactions.clone();
+                pools.schedule(Priority::Low, move || {
+                    compactions.fetch_add(1, Ordering::SeqCst);
+                });
+            }
+        }
+        assert_eq!(10, flushes.load(Ordering::SeqCst));
+        assert_eq!(10, compactions.load(Ordering::SeqCst));
+    }
+}
@@ -13,13 +13,19 @@
 use std::fmt::{Display, Formatter};
 use std::io;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[repr(u8)]
 pub enum Error {
     NotFound = 1,
     Corruption = 2,
     NotSupport = 3,
     InvalidArgument = 4,
-    IOError = 5
+    IOError = 5,
+    /// An insert into a cache with a strict capacity limit found no room
+    MemoryLimit = 6,
+    /// The block at `offset` of the table file `file_number` does not
+    /// match its checksum. A Corruption that tells which block is damaged.
+    BlockChecksumMismatch { file_number: u64, offset: u64 } = 7
 }
 
 impl From<io::Error> for Error {
@@ -46,6 +52,12 @@ impl Display for Error {
             Error::IOError => {
                 panic!("io error")
             },
+            Error::MemoryLimit => {
+                panic!("memory limit")
+            },
+            Error::BlockChecksumMismatch { file_number, offset } => {
+                panic!("block checksum mismatch in table {} at offset {}", file_number, offset)
+            },
             _ => {
                 panic!("unknown error")
             }
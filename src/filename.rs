@@ -10,9 +10,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use crate::Result;
 
-enum FileType {
+#[derive(Debug, PartialEq)]
+pub enum FileType {
     kLogFile,
     kDBLockFile,
     kTableFile,
@@ -22,22 +26,232 @@ enum FileType {
     kInfoLogFile
 }
 
-fn write_string_to_file_sync() -> Result<bool> {
-    Ok(true)
+/// Write `data` to the named file and sync it to stable storage. The file
+/// is removed again if any step fails.
+pub fn write_string_to_file_sync(data: &[u8], fname: impl AsRef<Path>) -> Result<()> {
+    let fname = fname.as_ref();
+    let result = (|| {
+        let mut file = File::create(fname)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(fname);
+    }
+    result
 }
 
-fn make_file_name(path: &str, number: u64, suffix: &str) -> Box<String> {
-    Box::new(format!("{}/{:06}.{}", path, number, suffix))
+fn make_file_name(path: &Path, number: u64, suffix: &str) -> PathBuf {
+    path.join(format!("{:06}.{}", number, suffix))
 }
 
-pub fn log_file_name(path: &str, number: u64) -> Box<String> {
+pub fn log_file_name(path: impl AsRef<Path>, number: u64) -> PathBuf {
     assert!(number > 0);
-    make_file_name(path, number, "log")
+    make_file_name(path.as_ref(), number, "log")
+}
+
+/// Return the name of the sstable with the specified number
+/// in the db named by "dbname".
+pub fn table_file_name(dbname: impl AsRef<Path>, number: u64) -> PathBuf {
+    assert!(number > 0);
+    make_file_name(dbname.as_ref(), number, "ldb")
+}
+
+/// Return the legacy file name for an sstable with the specified number
+/// in the db named by "dbname".
+pub fn sst_table_file_name(dbname: impl AsRef<Path>, number: u64) -> PathBuf {
+    assert!(number > 0);
+    make_file_name(dbname.as_ref(), number, "sst")
+}
+
+/// Return the name of the descriptor file for the db named by
+/// "dbname" and the specified incarnation number.
+pub fn descriptor_file_name(dbname: impl AsRef<Path>, number: u64) -> PathBuf {
+    assert!(number > 0);
+    dbname.as_ref().join(format!("MANIFEST-{:06}", number))
+}
+
+/// Return the name of the current file. This file contains the name
+/// of the current manifest file.
+pub fn current_file_name(dbname: impl AsRef<Path>) -> PathBuf {
+    dbname.as_ref().join("CURRENT")
+}
+
+/// Return the name of the lock file for the db named by "dbname".
+pub fn lock_file_name(dbname: impl AsRef<Path>) -> PathBuf {
+    dbname.as_ref().join("LOCK")
+}
+
+/// Return the name of the file holding the unique id of the db.
+pub fn identity_file_name(dbname: impl AsRef<Path>) -> PathBuf {
+    dbname.as_ref().join("IDENTITY")
+}
+
+/// Return the name of a temporary file owned by the db named "dbname".
+pub fn temp_file_name(dbname: impl AsRef<Path>, number: u64) -> PathBuf {
+    assert!(number > 0);
+    make_file_name(dbname.as_ref(), number, "dbtmp")
+}
+
+/// If filename is a revel file, return the number encoded in it and its
+/// type. The number is 0 for files that do not carry one. Returns None
+/// for names that are not revel files.
+pub fn parse_file_name(filename: &str) -> Option<(u64, FileType)> {
+    match filename {
+        "CURRENT" => Some((0, FileType::kCurrentFile)),
+        "LOCK" => Some((0, FileType::kDBLockFile)),
+        "LOG" | "LOG.old" => Some((0, FileType::kInfoLogFile)),
+        _ => {
+            if let Some(rest) = filename.strip_prefix("MANIFEST-") {
+                return Some((parse_number(rest)?, FileType::KDescriptorFile));
+            }
+            let (number, suffix) = filename.split_once('.')?;
+            let file_type = match suffix {
+                "log" => FileType::kLogFile,
+                "sst" | "ldb" => FileType::kTableFile,
+                "dbtmp" => FileType::kTempFile,
+                _ => return None
+            };
+            Some((parse_number(number)?, file_type))
+        }
+    }
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Make the CURRENT file point to the descriptor file with the
+/// specified number.
+///
+/// The new contents are written and synced to a temp file first, which
+/// then replaces CURRENT by a rename, so a crash at any point leaves
+/// CURRENT pointing to either the old or the new descriptor, never to a
+/// half written name. The directory is synced last to make the rename
+/// itself durable.
+pub fn set_current_file(dbname: impl AsRef<Path>, descriptor_number: u64) -> Result<()> {
+    let dbname = dbname.as_ref();
+    // Keep only the file name of the manifest and add a newline
+    let manifest = descriptor_file_name(dbname, descriptor_number);
+    let contents = manifest.file_name().expect("manifest file name").to_string_lossy();
+    let tmp = temp_file_name(dbname, descriptor_number);
+    write_string_to_file_sync(format!("{}\n", contents).as_bytes(), &tmp)?;
+    if let Err(e) = std::fs::rename(&tmp, current_file_name(dbname)) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    sync_dir(dbname)
+}
+
+/// Sync the directory entries of `dir`, e.g. after renaming a file in it.
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
 }
 
 #[test]
 fn test() {
-    assert_eq!("testdb/000192.log", make_file_name("testdb", 192, "log").as_str());
-    assert_eq!("testdb/192345.log", make_file_name("testdb", 192345, "log").as_str());
-    assert_eq!("testdb/1923457.log", make_file_name("testdb", 1923457, "log").as_str());
-}
\ No newline at end of file
+    assert_eq!(Path::new("testdb/000192.log"), make_file_name(Path::new("testdb"), 192, "log"));
+    assert_eq!(Path::new("testdb/192345.log"), make_file_name(Path::new("testdb"), 192345, "log"));
+    assert_eq!(Path::new("testdb/1923457.log"), make_file_name(Path::new("testdb"), 1923457, "log"));
+}
+
+#[test]
+fn test_construction() {
+    assert_eq!(Path::new("foo/CURRENT"), current_file_name("foo"));
+    assert_eq!(Path::new("foo/IDENTITY"), identity_file_name("foo"));
+    assert_eq!(Path::new("foo/MANIFEST-000999"), descriptor_file_name("foo", 999));
+    assert_eq!(Path::new("foo/000999.ldb"), table_file_name("foo", 999));
+    assert_eq!(Path::new("foo/000999.sst"), sst_table_file_name("foo", 999));
+    assert_eq!(Path::new("foo/LOCK"), lock_file_name("foo"));
+    assert_eq!(Path::new("foo/000999.dbtmp"), temp_file_name("foo", 999));
+    assert_eq!(Path::new("foo/000007.log"), log_file_name(PathBuf::from("foo"), 7));
+}
+
+#[test]
+fn test_non_utf8_path() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = std::env::temp_dir().join(OsStr::from_bytes(b"revel_filename_test_non_utf8_\xff"));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    assert_eq!(dir.join("000003.log"), log_file_name(&dir, 3));
+    set_current_file(&dir, 2).expect("set current failed");
+    assert_eq!("MANIFEST-000002\n", std::fs::read_to_string(current_file_name(&dir)).unwrap());
+}
+
+#[test]
+fn test_set_current_file() {
+    let dir = std::env::temp_dir().join("revel_filename_test_set_current_file");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    set_current_file(&dir, 5).expect("set current failed");
+    assert_eq!("MANIFEST-000005\n", std::fs::read_to_string(current_file_name(&dir)).unwrap());
+    set_current_file(&dir, 7).expect("set current failed");
+    assert_eq!("MANIFEST-000007\n", std::fs::read_to_string(current_file_name(&dir)).unwrap());
+    assert!(!temp_file_name(&dir, 7).exists());
+}
+
+#[test]
+fn test_set_current_file_crash() {
+    let dir = std::env::temp_dir().join("revel_filename_test_set_current_file_crash");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    set_current_file(&dir, 5).expect("set current failed");
+
+    // A crash while the temp file is written, or before it is renamed,
+    // leaves CURRENT as it was
+    std::fs::write(temp_file_name(&dir, 7), "MANIF").unwrap();
+    assert_eq!("MANIFEST-000005\n", std::fs::read_to_string(current_file_name(&dir)).unwrap());
+    write_string_to_file_sync(b"MANIFEST-000007\n", temp_file_name(&dir, 7)).unwrap();
+    assert_eq!("MANIFEST-000005\n", std::fs::read_to_string(current_file_name(&dir)).unwrap());
+
+    // The next update replaces the leftover temp file
+    set_current_file(&dir, 7).expect("set current failed");
+    assert_eq!("MANIFEST-000007\n", std::fs::read_to_string(current_file_name(&dir)).unwrap());
+    assert!(!temp_file_name(&dir, 7).exists());
+
+    // A failed rename keeps the old CURRENT and removes the temp file
+    let blocked = std::env::temp_dir().join("revel_filename_test_set_current_file_blocked");
+    let _ = std::fs::remove_dir_all(&blocked);
+    std::fs::create_dir_all(current_file_name(&blocked).join("x")).unwrap();
+    assert!(set_current_file(&blocked, 9).is_err());
+    assert!(current_file_name(&blocked).is_dir());
+    assert!(!temp_file_name(&blocked, 9).exists());
+}
+
+#[test]
+fn test_parse_file_name() {
+    let cases = [
+        ("100.log", 100, FileType::kLogFile),
+        ("0.log", 0, FileType::kLogFile),
+        ("0.sst", 0, FileType::kTableFile),
+        ("0.ldb", 0, FileType::kTableFile),
+        ("CURRENT", 0, FileType::kCurrentFile),
+        ("LOCK", 0, FileType::kDBLockFile),
+        ("MANIFEST-2", 2, FileType::KDescriptorFile),
+        ("MANIFEST-7", 7, FileType::KDescriptorFile),
+        ("LOG", 0, FileType::kInfoLogFile),
+        ("LOG.old", 0, FileType::kInfoLogFile),
+        ("18446744073709551615.log", 18446744073709551615, FileType::kLogFile),
+        ("000005.dbtmp", 5, FileType::kTempFile),
+    ];
+    for (name, number, file_type) in cases {
+        assert_eq!(Some((number, file_type)), parse_file_name(name), "{}", name);
+    }
+
+    let errors = [
+        "", "foo", "foo-dx-100.log", ".log", "manifest", "CURREN", "CURRENTX",
+        "MANIFES", "MANIFEST", "MANIFEST-", "XMANIFEST-3", "MANIFEST-3x", "LOC", "LOCKx",
+        "LO", "LOGx", "18446744073709551616.log", "184467440737095516150.log", "100",
+        "100.", "100.lop", "IDENTITY",
+    ];
+    for name in errors {
+        assert_eq!(None, parse_file_name(name), "{}", name);
+    }
+}
@@ -0,0 +1,229 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A database can be configured with a custom FilterPolicy object.
+//! This object is responsible for creating a small filter from a set
+//! of keys.  These filters are stored in revel and are consulted
+//! automatically by revel to decide whether or not to read some
+//! information from disk. In many cases, a filter can cut down the
+//! number of disk seeks form a handful to a single disk seek per
+//! DB::get() call.
+//!
+//! Most people will want to use the builtin bloom filter support (see
+//! BloomFilterPolicy below).
+
+use crate::util::hash::hash;
+
+pub trait FilterPolicy: Send + Sync {
+
+    /// Return the name of this policy.  Note that if the filter encoding
+    /// changes in an incompatible way, the name returned by this method
+    /// must be changed.  Otherwise, old incompatible filters may be
+    /// passed to methods of this type.
+    fn name(&self) -> &str;
+
+    /// keys[0,n-1] contains a list of keys (potentially with duplicates)
+    /// that are ordered according to the user supplied comparator.
+    /// Append a filter that summarizes keys[0,n-1] to dst.
+    fn create_filter(&self, keys: &[&[u8]], dst: &mut Vec<u8>);
+
+    /// "filter" contains the data appended by a preceding call to
+    /// create_filter() on this class.  This method must return true if
+    /// the key was in the list of keys passed to create_filter().
+    /// This method may return true or false if the key was not on the
+    /// list, but it should aim to return false with a high probability.
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool;
+}
+
+fn bloom_hash(key: &[u8]) -> u32 {
+    hash(key, 0xbc9f1d34)
+}
+
+/// A filter policy that uses a bloom filter with approximately the
+/// specified number of bits per key.  A good value for bits_per_key
+/// is 10, which yields a filter with ~ 1% false positive rate.
+///
+/// Note: if you are using a custom comparator that ignores some parts
+/// of the keys being compared, you must not use BloomFilterPolicy and
+/// must provide your own FilterPolicy that also ignores the
+/// corresponding parts of the keys.  For example, if the comparator
+/// ignores trailing spaces, it would be incorrect to use a FilterPolicy
+/// (like BloomFilterPolicy) that does not ignore trailing spaces in keys.
+pub struct BloomFilterPolicy {
+    bits_per_key: usize,
+    k: usize
+}
+
+impl BloomFilterPolicy {
+
+    pub fn new(bits_per_key: usize) -> Self {
+        // We intentionally round down to reduce probing cost a little bit
+        // 0.69 =~ ln(2)
+        let k = (bits_per_key as f64 * 0.69) as usize;
+        BloomFilterPolicy {
+            bits_per_key,
+            k: k.clamp(1, 30)
+        }
+    }
+}
+
+impl FilterPolicy for BloomFilterPolicy {
+
+    fn name(&self) -> &str {
+        "revel.BuiltinBloomFilter2"
+    }
+
+    fn create_filter(&self, keys: &[&[u8]], dst: &mut Vec<u8>) {
+        // Compute bloom filter size (in both bits and bytes)
+        // For small n, we can see a very high false positive rate.  Fix it
+        // by enforcing a minimum bloom filter length.
+        let bits = std::cmp::max(keys.len() * self.bits_per_key, 64);
+        let bytes = bits.div_ceil(8);
+        let bits = bytes * 8;
+
+        let init_size = dst.len();
+        dst.resize(init_size + bytes, 0);
+        // Remember # of probes in filter
+        dst.push(self.k as u8);
+        let array = &mut dst[init_size..init_size + bytes];
+        for key in keys {
+            // Use double-hashing to generate a sequence of hash values.
+            // See analysis in [Kirsch,Mitzenmacher 2006].
+            let mut h = bloom_hash(key);
+            // Rotate right 17 bits
+            let delta = h.rotate_right(17);
+            for _ in 0..self.k {
+                let bitpos = h as usize % bits;
+                array[bitpos / 8] |= 1 << (bitpos % 8);
+                h = h.wrapping_add(delta);
+            }
+        }
+    }
+
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        let len = filter.len();
+        if len < 2 {
+            return false;
+        }
+        let array = &filter[..len - 1];
+        let bits = (len - 1) * 8;
+
+        // Use the encoded k so that we can read filters generated by
+        // bloom filters created using different parameters.
+        let k = filter[len - 1];
+        if k > 30 {
+            // Reserved for potentially new encodings for short bloom filters.
+            // Consider it a match.
+            return true;
+        }
+
+        let mut h = bloom_hash(key);
+        // Rotate right 17 bits
+        let delta = h.rotate_right(17);
+        for _ in 0..k {
+            let bitpos = h as usize % bits;
+            if array[bitpos / 8] & (1 << (bitpos % 8)) == 0 {
+                return false;
+            }
+            h = h.wrapping_add(delta);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u32) -> [u8; 4] {
+        i.to_le_bytes()
+    }
+
+    fn build(policy: &BloomFilterPolicy, keys: &[Vec<u8>]) -> Vec<u8> {
+        let keys: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        let mut filter = Vec::new();
+        policy.create_filter(&keys, &mut filter);
+        filter
+    }
+
+    fn false_positive_rate(policy: &BloomFilterPolicy, filter: &[u8]) -> f64 {
+        let result = (0..10000).filter(|i| policy.key_may_match(&key(i + 1000000000), filter)).count();
+        result as f64 / 10000.0
+    }
+
+    #[test]
+    fn test_empty_filter() {
+        let policy = BloomFilterPolicy::new(10);
+        let filter = build(&policy, &[]);
+        assert!(!policy.key_may_match(b"hello", &filter));
+        assert!(!policy.key_may_match(b"world", &filter));
+    }
+
+    #[test]
+    fn test_small() {
+        let policy = BloomFilterPolicy::new(10);
+        let filter = build(&policy, &[b"hello".to_vec(), b"world".to_vec()]);
+        assert!(policy.key_may_match(b"hello", &filter));
+        assert!(policy.key_may_match(b"world", &filter));
+        assert!(!policy.key_may_match(b"x", &filter));
+        assert!(!policy.key_may_match(b"foo", &filter));
+    }
+
+    #[test]
+    fn test_varying_lengths() {
+        let policy = BloomFilterPolicy::new(10);
+        // Count number of filters that significantly exceed the false positive rate
+        let mut mediocre_filters = 0;
+        let mut good_filters = 0;
+
+        let mut length = 1;
+        while length <= 10000 {
+            let keys: Vec<Vec<u8>> = (0..length).map(|i| key(i).to_vec()).collect();
+            let filter = build(&policy, &keys);
+            assert!(filter.len() <= (length as usize * 10 / 8) + 40, "length {}", length);
+
+            // All added keys must match
+            for k in &keys {
+                assert!(policy.key_may_match(k, &filter), "length {}; key {:?}", length, k);
+            }
+
+            // Check false positive rate
+            let rate = false_positive_rate(&policy, &filter);
+            assert!(rate <= 0.02, "length {}; rate {}", length, rate); // Must not be over 2%
+            if rate > 0.0125 {
+                mediocre_filters += 1; // Allowed, but not too often
+            } else {
+                good_filters += 1;
+            }
+
+            length = if length < 10 {
+                length + 1
+            } else if length < 100 {
+                length + 10
+            } else if length < 1000 {
+                length + 100
+            } else {
+                length + 1000
+            };
+        }
+        assert!(mediocre_filters <= good_filters / 5);
+    }
+
+    #[test]
+    fn test_reserved_probe_counts() {
+        let policy = BloomFilterPolicy::new(10);
+        // Filters too short to hold a probe count match nothing, filters
+        // with a probe count of a newer encoding match everything
+        assert!(!policy.key_may_match(b"hello", &[0xff]));
+        assert!(policy.key_may_match(b"hello", &[0, 0, 31]));
+    }
+}
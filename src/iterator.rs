@@ -0,0 +1,108 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::Result;
+use crate::slice::Slice;
+
+/// An iterator yields a sequence of key/value pairs from a source. It is the
+/// internal counterpart of leveldb's Iterator: memtables, tables and the
+/// merged db view all implement it so that they can be stacked.
+pub trait InternalIterator {
+
+    /// An iterator is either positioned at a key/value pair, or
+    /// not valid. This method returns true iff the iterator is valid.
+    fn valid(&self) -> bool;
+
+    /// Position at the first key in the source. The iterator is valid()
+    /// after this call iff the source is not empty.
+    fn seek_to_first(&mut self);
+
+    /// Position at the last key in the source. The iterator is
+    /// valid() after this call iff the source is not empty.
+    fn seek_to_last(&mut self);
+
+    /// Position at the first key in the source that is at or past target.
+    /// The iterator is valid() after this call iff the source contains
+    /// an entry that comes at or past target.
+    fn seek(&mut self, target: &Slice);
+
+    /// Moves to the next entry in the source. After this call, valid() is
+    /// true iff the iterator was not positioned at the last entry in the source.
+    /// REQUIRES: valid()
+    fn next(&mut self);
+
+    /// Moves to the previous entry in the source. After this call, valid() is
+    /// true iff the iterator was not positioned at the first entry in source.
+    /// REQUIRES: valid()
+    fn prev(&mut self);
+
+    /// Return the key for the current entry. The underlying storage for
+    /// the returned slice is valid only until the next modification of
+    /// the iterator.
+    /// REQUIRES: valid()
+    fn key(&self) -> Slice<'_>;
+
+    /// Return the value for the current entry. The underlying storage for
+    /// the returned slice is valid only until the next modification of
+    /// the iterator.
+    /// REQUIRES: valid()
+    fn value(&self) -> Slice<'_>;
+
+    /// If an error has occurred, return it. Else return Ok. An iterator
+    /// that stops being valid() because of an error is told apart from
+    /// one that reached the end of its source by this method.
+    fn status(&self) -> Result<()>;
+}
+
+/// An iterator over nothing. It is never valid and reports `status`, so an
+/// error hit while creating an iterator can be returned as one.
+pub struct EmptyIterator {
+    status: Result<()>
+}
+
+impl EmptyIterator {
+    pub fn new(status: Result<()>) -> Self {
+        EmptyIterator { status }
+    }
+}
+
+impl InternalIterator for EmptyIterator {
+    fn valid(&self) -> bool {
+        false
+    }
+
+    fn seek_to_first(&mut self) {}
+
+    fn seek_to_last(&mut self) {}
+
+    fn seek(&mut self, _target: &Slice) {}
+
+    fn next(&mut self) {
+        panic!("next() on an empty iterator");
+    }
+
+    fn prev(&mut self) {
+        panic!("prev() on an empty iterator");
+    }
+
+    fn key(&self) -> Slice<'_> {
+        panic!("key() on an empty iterator");
+    }
+
+    fn value(&self) -> Slice<'_> {
+        panic!("value() on an empty iterator");
+    }
+
+    fn status(&self) -> Result<()> {
+        self.status.clone()
+    }
+}
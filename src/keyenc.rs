@@ -0,0 +1,252 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Encodings of numbers and tuples into keys whose bytewise order matches
+//! the order of the encoded values, for use with the default bytewise
+//! comparator.
+//!
+//! Numbers are stored in 8 big-endian bytes. Signed integers have their
+//! sign bit flipped so that negative values sort first; floats have their
+//! sign bit flipped when positive and all their bits flipped when negative.
+//! Under this encoding -0.0 sorts before 0.0 and NaNs sort after +inf (or
+//! before -inf for NaNs with the sign bit set).
+//!
+//! A tuple is a sequence of parts, each starting with a tag byte naming its
+//! kind. Byte strings end with 0x00 0x01, and a 0x00 byte within them is
+//! stored as 0x00 0xff, so a string sorts before any longer string it is a
+//! prefix of. Tuples sharing a schema compare part by part.
+
+use crate::Error::Corruption;
+use crate::Result;
+
+const TAG_BYTES: u8 = 0x01;
+const TAG_I64: u8 = 0x02;
+const TAG_U64: u8 = 0x03;
+const TAG_F64: u8 = 0x04;
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xff;
+const TERMINATOR: u8 = 0x01;
+
+const SIGN_BIT: u64 = 1 << 63;
+
+pub fn put_u64(dst: &mut Vec<u8>, value: u64) {
+    dst.extend_from_slice(&value.to_be_bytes());
+}
+
+/// Decode a u64 from the start of `input`, return it and the number of
+/// bytes it took.
+pub fn get_u64(input: &[u8]) -> Result<(u64, usize)> {
+    if input.len() < 8 {
+        return Err(Corruption);
+    }
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&input[..8]);
+    Ok((u64::from_be_bytes(buf), 8))
+}
+
+pub fn put_i64(dst: &mut Vec<u8>, value: i64) {
+    put_u64(dst, value as u64 ^ SIGN_BIT);
+}
+
+pub fn get_i64(input: &[u8]) -> Result<(i64, usize)> {
+    let (v, n) = get_u64(input)?;
+    Ok(((v ^ SIGN_BIT) as i64, n))
+}
+
+pub fn put_f64(dst: &mut Vec<u8>, value: f64) {
+    let bits = value.to_bits();
+    let encoded = if bits & SIGN_BIT != 0 { !bits } else { bits ^ SIGN_BIT };
+    put_u64(dst, encoded);
+}
+
+pub fn get_f64(input: &[u8]) -> Result<(f64, usize)> {
+    let (v, n) = get_u64(input)?;
+    let bits = if v & SIGN_BIT != 0 { v ^ SIGN_BIT } else { !v };
+    Ok((f64::from_bits(bits), n))
+}
+
+/// Append `value` escaped and terminated, see the module documentation.
+pub fn put_bytes(dst: &mut Vec<u8>, value: &[u8]) {
+    for &c in value {
+        dst.push(c);
+        if c == ESCAPE {
+            dst.push(ESCAPED_ZERO);
+        }
+    }
+    dst.push(ESCAPE);
+    dst.push(TERMINATOR);
+}
+
+/// Decode a byte string written by put_bytes from the start of `input`,
+/// return it and the number of bytes it took.
+pub fn get_bytes(input: &[u8]) -> Result<(Vec<u8>, usize)> {
+    let mut value = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        if input[i] != ESCAPE {
+            value.push(input[i]);
+            i += 1;
+            continue;
+        }
+        match input.get(i + 1) {
+            Some(&ESCAPED_ZERO) => value.push(ESCAPE),
+            Some(&TERMINATOR) => return Ok((value, i + 2)),
+            // Missing terminator or bad escape
+            _ => return Err(Corruption)
+        }
+        i += 2;
+    }
+    Err(Corruption)
+}
+
+/// One part of a tuple key.
+#[derive(Clone, Debug, PartialEq)]
+pub enum KeyPart {
+    Bytes(Vec<u8>),
+    I64(i64),
+    U64(u64),
+    F64(f64)
+}
+
+/// Append the encoding of the tuple `parts`.
+pub fn put_tuple(dst: &mut Vec<u8>, parts: &[KeyPart]) {
+    for part in parts {
+        match part {
+            KeyPart::Bytes(value) => {
+                dst.push(TAG_BYTES);
+                put_bytes(dst, value);
+            },
+            KeyPart::I64(value) => {
+                dst.push(TAG_I64);
+                put_i64(dst, *value);
+            },
+            KeyPart::U64(value) => {
+                dst.push(TAG_U64);
+                put_u64(dst, *value);
+            },
+            KeyPart::F64(value) => {
+                dst.push(TAG_F64);
+                put_f64(dst, *value);
+            }
+        }
+    }
+}
+
+/// Returns the encoding of the tuple `parts`.
+pub fn encode_tuple(parts: &[KeyPart]) -> Vec<u8> {
+    let mut dst = Vec::new();
+    put_tuple(&mut dst, parts);
+    dst
+}
+
+/// Decode a key made of tuple parts only, such as one built by
+/// encode_tuple.
+pub fn decode_tuple(mut input: &[u8]) -> Result<Vec<KeyPart>> {
+    let mut parts = Vec::new();
+    while let Some((&tag, rest)) = input.split_first() {
+        let (part, n) = match tag {
+            TAG_BYTES => get_bytes(rest).map(|(v, n)| (KeyPart::Bytes(v), n))?,
+            TAG_I64 => get_i64(rest).map(|(v, n)| (KeyPart::I64(v), n))?,
+            TAG_U64 => get_u64(rest).map(|(v, n)| (KeyPart::U64(v), n))?,
+            TAG_F64 => get_f64(rest).map(|(v, n)| (KeyPart::F64(v), n))?,
+            // Unknown tag
+            _ => return Err(Corruption)
+        };
+        parts.push(part);
+        input = &rest[n..];
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_sorted<T: Copy + std::fmt::Debug>(values: &[T], put: fn(&mut Vec<u8>, T)) {
+        let encoded = values.iter().map(|v| {
+            let mut dst = Vec::new();
+            put(&mut dst, *v);
+            dst
+        }).collect::<Vec<_>>();
+        for i in 1..encoded.len() {
+            assert!(encoded[i - 1] < encoded[i], "{:?} !< {:?}", values[i - 1], values[i]);
+        }
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_sorted(&[0, 1, 255, 256, u64::MAX - 1, u64::MAX], put_u64);
+        assert_sorted(&[i64::MIN, -256, -1, 0, 1, 255, i64::MAX], put_i64);
+        assert_sorted(&[f64::NEG_INFINITY, -1e300, -1.5, -f64::MIN_POSITIVE, -0.0, 0.0,
+            f64::MIN_POSITIVE, 1.5, 1e300, f64::INFINITY], put_f64);
+
+        for v in [0, 7, u64::MAX] {
+            let mut dst = Vec::new();
+            put_u64(&mut dst, v);
+            assert_eq!((v, 8), get_u64(&dst).unwrap());
+        }
+        for v in [i64::MIN, -3, 0, i64::MAX] {
+            let mut dst = Vec::new();
+            put_i64(&mut dst, v);
+            assert_eq!((v, 8), get_i64(&dst).unwrap());
+        }
+        for v in [f64::NEG_INFINITY, -2.5, -0.0, 0.0, 2.5, f64::MAX] {
+            let mut dst = Vec::new();
+            put_f64(&mut dst, v);
+            let (decoded, n) = get_f64(&dst).unwrap();
+            assert_eq!((v.to_bits(), 8), (decoded.to_bits(), n));
+        }
+        let mut dst = Vec::new();
+        put_f64(&mut dst, f64::NAN);
+        assert!(get_f64(&dst).unwrap().0.is_nan());
+        assert_eq!(Err(Corruption), get_u64(&[0; 7]));
+    }
+
+    #[test]
+    fn test_bytes() {
+        let values: [&[u8]; 6] = [b"", b"\x00", b"\x00\x00", b"\x00\x01", b"a", b"a\x00"];
+        let mut encoded = Vec::new();
+        for v in values {
+            let mut dst = Vec::new();
+            put_bytes(&mut dst, v);
+            dst.extend_from_slice(b"rest");
+            let (decoded, n) = get_bytes(&dst).unwrap();
+            assert_eq!(v, &decoded[..]);
+            assert_eq!(&dst[n..], b"rest");
+            encoded.push(dst[..n].to_vec());
+        }
+        for i in 1..encoded.len() {
+            assert!(encoded[i - 1] < encoded[i]);
+        }
+        assert_eq!(Err(Corruption), get_bytes(b"abc"));
+        assert_eq!(Err(Corruption), get_bytes(b"a\x00"));
+        assert_eq!(Err(Corruption), get_bytes(b"a\x00\x02"));
+    }
+
+    #[test]
+    fn test_tuple() {
+        let key = |series: &str, ts: i64| encode_tuple(&[KeyPart::Bytes(series.as_bytes().to_vec()), KeyPart::I64(ts)]);
+        // Composite keys sort by series, then by time
+        let keys = [key("cpu", -5), key("cpu", 0), key("cpu", 10), key("cpu0", -100), key("mem", -100)];
+        for i in 1..keys.len() {
+            assert!(keys[i - 1] < keys[i]);
+        }
+
+        let parts = vec![KeyPart::Bytes(b"a\x00b".to_vec()), KeyPart::I64(-1), KeyPart::U64(2), KeyPart::F64(-0.5)];
+        let encoded = encode_tuple(&parts);
+        assert_eq!(parts, decode_tuple(&encoded).unwrap());
+        assert_eq!(Vec::<KeyPart>::new(), decode_tuple(b"").unwrap());
+        assert_eq!(Err(Corruption), decode_tuple(&encoded[..encoded.len() - 1]));
+        assert_eq!(Err(Corruption), decode_tuple(b"\x09"));
+    }
+}
@@ -17,12 +17,27 @@ use crate::error::Error;
 pub type Result<T = (), E = Error> = std::result::Result<T, E>;
 
 pub mod db;
+pub mod db_ttl;
 pub mod error;
 pub mod slice;
 pub mod write_batch;
+pub mod write_batch_with_index;
 pub mod comparator;
 pub mod log_writer;
 pub mod options;
+pub mod db_iter;
+pub mod cache;
+pub mod keyenc;
+pub mod dump;
+pub mod write_buffer_manager;
+pub mod filter_policy;
+pub mod compaction_filter;
+pub mod table_properties;
+pub mod repair;
+pub mod snapshot;
+pub mod random;
+#[cfg(any(test, feature = "test_utils"))]
+pub mod test_utils;
 
 mod memtable;
 mod log;
@@ -31,9 +46,17 @@ mod filename;
 mod skiplist;
 mod dbformat;
 mod coding;
-mod random;
 mod env;
 mod util;
 mod log_format;
 mod log_reader;
-mod version_set;
\ No newline at end of file
+mod version_set;
+mod version_edit;
+mod statistics;
+mod compaction;
+mod iterator;
+mod readahead;
+mod table;
+mod builder;
+mod table_cache;
+mod range_del;
@@ -10,18 +10,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cell::RefCell;
-use std::fs::read;
-use std::io::Write;
 use crate::coding::decode_fix32;
 use crate::env::SequentialFile;
-use crate::Error::IOError;
 use crate::log_format::{kBlockSize, kHeaderSize, kMaxRecordType, RecordType};
-use crate::log_format::RecordType::{kLastType, kMiddleType, kZeroType};
-
 use crate::slice::Slice;
 use crate::util::crc;
-use crate::util::crc::extend;
+
+const K_ZERO_TYPE: u32 = RecordType::kZeroType as u32;
 
 const K_FULL_TYPE: u32 = RecordType::kFullType as u32;
 
@@ -31,212 +26,488 @@ const K_MIDDLE_TYPE: u32 = RecordType::kMiddleType as u32;
 
 const K_LAST_TYPE: u32 = RecordType::kLastType as u32;
 
-const kEof: u32 = (kMaxRecordType + 1) as u32;
+// Extend record types with the following special values
+
+const K_EOF: u32 = (kMaxRecordType + 1) as u32;
+
+// Returned whenever we find an invalid physical record.
+// Currently there are three situations in which this happens:
+// * The record has an invalid CRC (read_physical_record reports a drop)
+// * The record is a 0-length record (No drop is reported)
+// * The record is below constructor's initial_offset (No drop is reported)
+const K_BAD_RECORD: u32 = (kMaxRecordType + 2) as u32;
 
-const kBadRecord: u32 = (kMaxRecordType + 2) as u32;
+/// Interface for reporting errors.
+pub trait Reporter {
+
+    /// Some corruption was detected. "bytes" is the approximate number
+    /// of bytes dropped due to the corruption.
+    fn corruption(&mut self, bytes: usize, reason: &str);
+}
 
 pub struct Reader {
 
     file: Box<dyn SequentialFile>,
 
+    reporter: Option<Box<dyn Reporter>>,
+
     checksum: bool,
 
-    buffer: RefCell<Vec<u8>>,
+    backing_store: Vec<u8>,
 
-    eof: RefCell<bool>,
+    // Unread part of backing_store is [buffer_start, buffer_start + buffer_len)
+    buffer_start: usize,
 
-    last_record_offset: RefCell<u64>,
+    buffer_len: usize,
 
-    end_of_buffer_offset: RefCell<u64>,
+    // Last read() indicated EOF by returning < kBlockSize
+    eof: bool,
 
+    // Offset of the last record returned by read_record.
+    last_record_offset: u64,
+
+    // Offset of the first location past the end of buffer.
+    end_of_buffer_offset: u64,
+
+    // Offset at which to start looking for the first record to return
     initial_offset: u64,
 
+    // True if we are resynchronizing after a seek (initial_offset > 0). In
+    // particular, a run of kMiddleType and kLastType records can be silently
+    // skipped in this mode
     resyncing: bool,
 
-    skip_size: RefCell<u64>
-
+    // True if the file ended in the middle of a record
+    truncated_tail: bool
 }
 
 impl Reader {
 
-    pub fn new(file: Box<dyn SequentialFile>, checksum: bool, initial_offset: u64) -> Self {
+    /// Create a reader that will return log records from "file".
+    ///
+    /// If "reporter" is Some, it is notified whenever some data is
+    /// dropped due to a detected corruption.
+    ///
+    /// If "checksum" is true, verify checksums if available.
+    ///
+    /// The Reader will start reading at the first record located at physical
+    /// position >= initial_offset within the file.
+    pub fn new(file: Box<dyn SequentialFile>, reporter: Option<Box<dyn Reporter>>, checksum: bool, initial_offset: u64) -> Self {
         Reader {
             file,
+            reporter,
             checksum,
-            buffer: RefCell::new(vec![0; kBlockSize]),
-            eof: RefCell::new(false),
-            last_record_offset: RefCell::new(0),
-            end_of_buffer_offset: RefCell::new(0),
+            backing_store: vec![0; kBlockSize],
+            buffer_start: 0,
+            buffer_len: 0,
+            eof: false,
+            last_record_offset: 0,
+            end_of_buffer_offset: 0,
             initial_offset,
             resyncing: initial_offset > 0,
-            skip_size: RefCell::new(0)
+            truncated_tail: false
         }
     }
 
-    pub fn read_record<'a, 'b>(&'a mut self, scratch: &'b mut Vec<u8>) -> crate::Result<Slice<'b>> {
-        // todo!() skip to last record offset
-        scratch.clear();
+    /// Read the next record into scratch. Returns the record if read
+    /// successfully, None if we hit end of the input. The returned slice
+    /// is backed by scratch and valid until its next modification.
+    pub fn read_record<'b>(&mut self, scratch: &'b mut Vec<u8>) -> Option<Slice<'b>> {
+        if self.last_record_offset < self.initial_offset && !self.skip_to_initial_block() {
+            return None;
+        }
 
+        scratch.clear();
         let mut in_fragmented_record = false;
+        // Record offset of the logical record that we're reading
+        // 0 is a dummy value to make compilers happy
         let mut prospective_record_offset: u64 = 0;
+
         loop {
-            let physical_record_offset = 0; //*self.end_of_buffer_offset.borrow() - *self.skip_size.borrow() - kHeaderSize as u64 - fragment.size() as u64;
+            let (record_type, start, length) = self.read_physical_record();
 
-            /*if self.resyncing {
-                if record_type == kMiddleType as u32 {
+            // read_physical_record may have only had an empty trailer remaining in its
+            // internal buffer. Calculate the offset of the next physical record now
+            // that it has returned, properly accounting for its header size.
+            let physical_record_offset = (self.end_of_buffer_offset as i64 - self.buffer_len as i64
+                - kHeaderSize as i64 - length as i64).max(0) as u64;
+
+            if self.resyncing {
+                if record_type == K_MIDDLE_TYPE {
                     continue;
-                } else if record_type == kLastType as u32 {
+                } else if record_type == K_LAST_TYPE {
                     self.resyncing = false;
+                    continue;
                 } else {
                     self.resyncing = false;
                 }
-            }*/
-            //let buf = self.buffer.borrow();
-            match self.read_physical_record() {
-                Ok((record_type, length)) => {
-                    let buf = self.buffer.borrow();
-                    match record_type {
-                        K_FULL_TYPE => {
-                            self.last_record_offset.replace(physical_record_offset);
-                            scratch.clear();
-                            scratch.extend_from_slice(&buf[kHeaderSize..kHeaderSize+length]);
-                            return Ok(Slice::from_bytes(&scratch[..]));
-                        },
-                        K_FIRST_TYPE => {
-                            in_fragmented_record = true;
-                            prospective_record_offset = physical_record_offset;
-                            scratch.extend_from_slice(&buf[kHeaderSize..kHeaderSize+length]);
-                        },
-                        K_MIDDLE_TYPE => {
-                            if !in_fragmented_record {
-                                // todo!()
-                            } else {
-                                scratch.extend_from_slice(&buf[kHeaderSize..kHeaderSize+length]);
-                            }
-                        },
-                        K_LAST_TYPE => {
-                            if !in_fragmented_record {
-                                // todo!()
-                            } else {
-                                scratch.extend_from_slice(&buf[kHeaderSize..kHeaderSize+length]);
-                                self.last_record_offset.replace(prospective_record_offset);
-                                return Ok(Slice::from_bytes(scratch.as_slice()));
-                            }
-                        },
-                        _ => {
-                            break;
-                        }
+            }
+
+            match record_type {
+                K_FULL_TYPE => {
+                    if in_fragmented_record && !scratch.is_empty() {
+                        self.report_corruption(scratch.len(), "partial record without end(1)");
                     }
+                    prospective_record_offset = physical_record_offset;
+                    scratch.clear();
+                    scratch.extend_from_slice(&self.backing_store[start..start + length]);
+                    self.last_record_offset = prospective_record_offset;
+                    return Some(Slice::from_bytes(scratch));
                 },
-                Err(err_type) => {
-                    match err_type {
-                        kEof => {
-                            if in_fragmented_record {
-                                // This can be caused by the writer dying immediately after
-                                // writing a physical record but before completing the next; don't
-                                // treat it as a corruption, just ignore the entire logical record.
-                                scratch.clear();
-                            }
-                            return Ok(Slice::from_empty());
-                        }
-                        _ => {
-                            in_fragmented_record = false;
-                            scratch.clear();
-
-                            break;
-                        }
+                K_FIRST_TYPE => {
+                    if in_fragmented_record && !scratch.is_empty() {
+                        self.report_corruption(scratch.len(), "partial record without end(2)");
+                    }
+                    prospective_record_offset = physical_record_offset;
+                    scratch.clear();
+                    scratch.extend_from_slice(&self.backing_store[start..start + length]);
+                    in_fragmented_record = true;
+                },
+                K_MIDDLE_TYPE => {
+                    if !in_fragmented_record {
+                        self.report_corruption(length, "missing start of fragmented record(1)");
+                    } else {
+                        scratch.extend_from_slice(&self.backing_store[start..start + length]);
+                    }
+                },
+                K_LAST_TYPE => {
+                    if !in_fragmented_record {
+                        self.report_corruption(length, "missing start of fragmented record(2)");
+                    } else {
+                        scratch.extend_from_slice(&self.backing_store[start..start + length]);
+                        self.last_record_offset = prospective_record_offset;
+                        return Some(Slice::from_bytes(scratch));
+                    }
+                },
+                K_EOF => {
+                    if in_fragmented_record {
+                        // This can be caused by the writer dying immediately after
+                        // writing a physical record but before completing the next; don't
+                        // treat it as a corruption, just ignore the entire logical record.
+                        self.truncated_tail = true;
+                        scratch.clear();
                     }
+                    return None;
+                },
+                K_BAD_RECORD => {
+                    if in_fragmented_record {
+                        self.report_corruption(scratch.len(), "error in middle of record");
+                        in_fragmented_record = false;
+                        scratch.clear();
+                    }
+                },
+                _ => {
+                    let dropped = length + if in_fragmented_record { scratch.len() } else { 0 };
+                    self.report_corruption(dropped, &format!("unknown record type {}", record_type));
+                    in_fragmented_record = false;
+                    scratch.clear();
                 }
             }
         }
-        Err(IOError)
     }
 
-    fn read_physical_record(&self) -> Result<(u32, usize), u32> {
-        self.skip_size.replace(0);
-        if *self.eof.borrow() {
-            return Err(kEof);
+    /// Returns the offset just past the data consumed so far, i.e. the number
+    /// of bytes of the file processed.
+    pub fn position(&self) -> u64 {
+        self.end_of_buffer_offset - self.buffer_len as u64
+    }
+
+    /// Returns true if the input ended in the middle of a record, which is
+    /// what a writer dying half way through a write leaves behind. Such a
+    /// torn record is dropped without being reported as a corruption, it is
+    /// up to the caller whether to tolerate it.
+    pub fn truncated_tail(&self) -> bool {
+        self.truncated_tail
+    }
+
+    /// Skips all blocks that are completely before "initial_offset".
+    ///
+    /// Returns true on success. Handles reporting.
+    fn skip_to_initial_block(&mut self) -> bool {
+        let offset_in_block = (self.initial_offset % kBlockSize as u64) as usize;
+        let mut block_start_location = self.initial_offset - offset_in_block as u64;
+
+        // Don't search a block if we'd be in the trailer
+        if offset_in_block > kBlockSize - 6 {
+            block_start_location += kBlockSize as u64;
         }
 
-        let mut buf_len = 0;
-        {
-            let mut buf = self.buffer.borrow_mut();
-            let res = self.file.read(buf.as_mut_slice());
-            match res {
-                Ok(slice) => {
-                    buf_len = slice.size();
-                },
-                Err(_) => {
-                    self.eof.replace(true);
-                    return Err(kEof);
-                }
-            }
+        self.end_of_buffer_offset = block_start_location;
+
+        // Skip to start of first block that can contain the initial record
+        if block_start_location > 0 && self.file.skip(block_start_location).is_err() {
+            self.report_drop(block_start_location as usize, "skip error");
+            return false;
         }
+        true
+    }
 
-        let end_of_buffer_offset = self.end_of_buffer_offset.take();
-        self.end_of_buffer_offset.replace(end_of_buffer_offset + buf_len as u64);
-        {
-            let buf = self.buffer.borrow();
-            let size = buf.len();
-            if size < kBlockSize {
-                self.eof.replace(true);
+    fn clear_buffer(&mut self) {
+        self.buffer_start = 0;
+        self.buffer_len = 0;
+    }
+
+    /// Returns the record type and the position and length of its payload
+    /// in backing_store.
+    fn read_physical_record(&mut self) -> (u32, usize, usize) {
+        loop {
+            if self.buffer_len < kHeaderSize {
+                if !self.eof {
+                    // Last read was a full read, so this is a trailer to skip
+                    self.clear_buffer();
+                    match self.file.read(&mut self.backing_store) {
+                        Ok(slice) => {
+                            self.buffer_len = slice.size();
+                            self.end_of_buffer_offset += self.buffer_len as u64;
+                            if self.buffer_len < kBlockSize {
+                                self.eof = true;
+                            }
+                        },
+                        Err(_) => {
+                            self.report_drop(kBlockSize, "read error");
+                            self.eof = true;
+                            return (K_EOF, 0, 0);
+                        }
+                    }
+                    continue;
+                } else {
+                    // Note that if buffer is non-empty, we have a truncated header at the
+                    // end of the file, which can be caused by the writer crashing in the
+                    // middle of writing the header. Instead of considering this an error,
+                    // just report EOF.
+                    if self.buffer_len > 0 {
+                        self.truncated_tail = true;
+                    }
+                    self.clear_buffer();
+                    return (K_EOF, 0, 0);
+                }
             }
 
-            let header = &buf[..buf_len];
-            let a = (header[4] & 0xff) as u32;
-            let b = (header[5] & 0xff) as u32;
-            let type_ = header[6] as i32;
+            // Parse the header
+            let header = &self.backing_store[self.buffer_start..];
+            let a = header[4] as usize;
+            let b = header[5] as usize;
+            let record_type = header[6] as u32;
             let length = a | (b << 8);
-            if kHeaderSize + length as usize > size {
-                // todo!() error
-                return Err(kEof);
+            if kHeaderSize + length > self.buffer_len {
+                let drop_size = self.buffer_len;
+                self.clear_buffer();
+                if !self.eof {
+                    self.report_corruption(drop_size, "bad record length");
+                    return (K_BAD_RECORD, 0, 0);
+                }
+                // If the end of the file has been reached without reading |length| bytes
+                // of payload, assume the writer died in the middle of writing the record.
+                // Don't report a corruption.
+                self.truncated_tail = true;
+                return (K_EOF, 0, 0);
             }
 
-            if type_ == kZeroType as i32 && length == 0 {
-                // todo!() Skip zero length record without reporting any dorps ...
-                return Err(kBadRecord);
+            if record_type == K_ZERO_TYPE && length == 0 {
+                // Skip zero length record without reporting any drops since
+                // such records are produced by the mmap based writing code in
+                // env_posix.cc that preallocates file regions.
+                self.clear_buffer();
+                return (K_BAD_RECORD, 0, 0);
             }
 
+            // Check crc
             if self.checksum {
                 let expected_crc = crc::unmask(decode_fix32(&header[0..4]));
-                let actual_crc = crc::value(&header[6..]);
+                let actual_crc = crc::value(&header[6..kHeaderSize + length]);
                 if actual_crc != expected_crc {
-                    // todo!()
-                    return Err(kBadRecord);
+                    // Drop the rest of the buffer since "length" itself may have
+                    // been corrupted and if we trust it, we could find some
+                    // fragment of a real log record that just happens to look
+                    // like a valid log record.
+                    let drop_size = self.buffer_len;
+                    self.clear_buffer();
+                    self.report_corruption(drop_size, "checksum mismatch");
+                    return (K_BAD_RECORD, 0, 0);
                 }
             }
-            let prefix_removed = &header[(kHeaderSize + length as usize)..];
-            if (end_of_buffer_offset + buf_len as u64 - prefix_removed.len() as u64 - kHeaderSize as u64 - length as u64) < self.initial_offset {
-                self.skip_size.replace(size as u64);
-                return Err(kBadRecord);
+
+            let start = self.buffer_start + kHeaderSize;
+            self.buffer_start += kHeaderSize + length;
+            self.buffer_len -= kHeaderSize + length;
+
+            // Skip physical record that started before initial_offset
+            if self.end_of_buffer_offset - ((self.buffer_len + kHeaderSize + length) as u64) < self.initial_offset {
+                return (K_BAD_RECORD, 0, 0);
             }
 
-            return Ok((type_ as u32, length as usize));
+            return (record_type, start, length);
         }
     }
 
+    /// Reports dropped bytes to the reporter.
+    fn report_corruption(&mut self, bytes: usize, reason: &str) {
+        self.report_drop(bytes, reason);
+    }
 
+    fn report_drop(&mut self, bytes: usize, reason: &str) {
+        let dropped_start = self.end_of_buffer_offset as i64 - self.buffer_len as i64 - bytes as i64;
+        if dropped_start >= self.initial_offset as i64 {
+            if let Some(reporter) = self.reporter.as_mut() {
+                reporter.corruption(bytes, reason);
+            }
+        }
+    }
 }
 
-
-
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
     use std::rc::Rc;
-    use crate::env::MemorySequentialFile;
+    use crate::env::{MemorySequentialFile, WritableFile};
+    use crate::log_writer::Writer;
+    use crate::Result;
     use super::*;
 
+    /// A WritableFile whose contents stay reachable after it is handed to a
+    /// log writer
+    struct StringDest {
+        contents: Rc<RefCell<Vec<u8>>>
+    }
+
+    impl WritableFile for StringDest {
+        fn append(&mut self, data: &Slice) -> Result<()> {
+            self.contents.borrow_mut().extend_from_slice(data.data());
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn close(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Default)]
+    struct ReportCollector {
+        dropped_bytes: usize,
+        message: String
+    }
+
+    struct SharedReporter(Rc<RefCell<ReportCollector>>);
+
+    impl Reporter for SharedReporter {
+        fn corruption(&mut self, bytes: usize, reason: &str) {
+            let mut collector = self.0.borrow_mut();
+            collector.dropped_bytes += bytes;
+            collector.message.push_str(reason);
+        }
+    }
+
+    fn write_records(records: &[Vec<u8>]) -> Vec<u8> {
+        let contents = Rc::new(RefCell::new(Vec::new()));
+        let mut writer = Writer::new(Rc::new(RefCell::new(StringDest { contents: contents.clone() })));
+        for record in records {
+            writer.add_record(&Slice::from_bytes(record)).expect("write failed");
+        }
+        let data = contents.borrow().clone();
+        data
+    }
+
+    fn read_all(data: Vec<u8>) -> (Vec<Vec<u8>>, Rc<RefCell<ReportCollector>>, bool) {
+        let collector = Rc::new(RefCell::new(ReportCollector::default()));
+        let file = Box::new(MemorySequentialFile::new(Rc::new(data)));
+        let mut reader = Reader::new(file, Some(Box::new(SharedReporter(collector.clone()))), true, 0);
+        let mut records = Vec::new();
+        let mut scratch = Vec::new();
+        while let Some(record) = reader.read_record(&mut scratch) {
+            records.push(record.data().to_vec());
+        }
+        let truncated = reader.truncated_tail();
+        (records, collector, truncated)
+    }
+
+    fn big_string(partial: &str, n: usize) -> Vec<u8> {
+        partial.bytes().cycle().take(n).collect()
+    }
+
     #[test]
     fn test() {
         let memory = Rc::new(vec![129, 221, 1, 7, 11, 0, 1, 104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100]);
         let file = MemorySequentialFile::new(memory);
         let sequential_file = Box::new(file);
-        let mut reader = Reader::new(sequential_file, true, 0);
+        let mut reader = Reader::new(sequential_file, None, true, 0);
         let mut buf = vec![];
         let slice = reader.read_record(&mut buf).expect("error");
         unsafe {
             let str = String::from_utf8_unchecked(slice.data().to_vec());
             assert_eq!(str, "hello world");
         }
+        assert!(reader.read_record(&mut buf).is_none());
+    }
+
+    #[test]
+    fn test_read_write() {
+        let records = vec![b"foo".to_vec(), b"bar".to_vec(), Vec::new(), b"xxxx".to_vec(),
+                           big_string("medium", 50000), big_string("large", 100000)];
+        let (read, collector, truncated) = read_all(write_records(&records));
+        assert_eq!(records, read);
+        assert_eq!(0, collector.borrow().dropped_bytes);
+        assert!(!truncated);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let mut data = write_records(&[b"foo".to_vec(), b"bar".to_vec()]);
+        data[kHeaderSize] ^= 0x01;
+        let (read, collector, truncated) = read_all(data);
+        // The rest of the block is dropped with the corrupted record
+        assert!(read.is_empty());
+        assert_eq!(2 * kHeaderSize + 6, collector.borrow().dropped_bytes);
+        assert_eq!("checksum mismatch", collector.borrow().message);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn test_bad_length_in_last_block() {
+        let mut data = write_records(&[b"foo".to_vec()]);
+        data[4] = 0xff;
+        let (read, collector, truncated) = read_all(data);
+        assert!(read.is_empty());
+        // At the end of the file a bad length looks like a torn write
+        assert_eq!(0, collector.borrow().dropped_bytes);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn test_truncated_tail() {
+        let records = vec![b"foo".to_vec(), big_string("bar", 40000)];
+        let data = write_records(&records);
+
+        // Torn payload of the last record
+        let (read, collector, truncated) = read_all(data[..data.len() - 1].to_vec());
+        assert_eq!(vec![b"foo".to_vec()], read);
+        assert_eq!(0, collector.borrow().dropped_bytes);
+        assert!(truncated);
+
+        // Torn header
+        let (read, _, truncated) = read_all(data[..kHeaderSize + 3 + 2].to_vec());
+        assert_eq!(vec![b"foo".to_vec()], read);
+        assert!(truncated);
+
+        // A clean end of file is not a torn tail
+        let (read, _, truncated) = read_all(data[..kHeaderSize + 3].to_vec());
+        assert_eq!(vec![b"foo".to_vec()], read);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn test_missing_start() {
+        let data = write_records(&[big_string("bar", 2 * kBlockSize)]);
+        // Drop the first fragment
+        let (read, collector, _) = read_all(data[kBlockSize..].to_vec());
+        assert!(read.is_empty());
+        assert!(collector.borrow().message.contains("missing start of fragmented record"));
+    }
+}
@@ -13,12 +13,15 @@
 //! memtable
 use std::cmp::Ordering;
 use std::cmp::Ordering::Less;
-use std::rc::Rc;
-use crate::coding::{decode_fixed64, encode_fixed64, encode_varint32, get_varint32, varint_length};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use crate::coding::{decode_fixed64, encode_fixed64, encode_varint32, get_varint32, put_varint32, varint_length};
 use crate::comparator::Comparator;
 use crate::dbformat::{compare, InternalKeyComparator, LookupKey, SequenceNumber, ValueType};
-use crate::{comparator, Error};
+use crate::{Error, Result};
+use crate::iterator::InternalIterator;
 use crate::Error::NotFound;
+use crate::range_del::RangeTombstone;
 use crate::skiplist::{Cmp, Iter, SkipList};
 use crate::slice::Slice;
 
@@ -32,11 +35,11 @@ fn get_length_prefixed_slice(buf: &[u8], offset: usize) -> Slice {
 type Table = SkipList<Vec<u8>>;
 
 struct KeyComparator {
-    comparator: Rc<InternalKeyComparator>
+    comparator: Arc<InternalKeyComparator>
 }
 
 impl KeyComparator {
-    pub fn new(comparator: Rc<InternalKeyComparator>) -> Self {
+    pub fn new(comparator: Arc<InternalKeyComparator>) -> Self {
         KeyComparator {
             comparator
         }
@@ -55,20 +58,36 @@ pub struct MemTable {
     
     table: Box<Table>,
 
-    comparator: Rc<InternalKeyComparator>
+    // Range tombstones, keyed by the begin key with the end key as value.
+    // Kept apart from the point entries so that point lookups never have
+    // to step over them.
+    range_del_table: Box<Table>,
+
+    comparator: Arc<InternalKeyComparator>,
+
+    // Combined size of the encoded entries
+    memory_usage: AtomicUsize
 }
 
 impl MemTable {
     
     pub fn new(comparator: InternalKeyComparator) -> Self {
-        let cmp = Rc::new(comparator);
+        let cmp = Arc::new(comparator);
         let key_comparator = KeyComparator::new(cmp.clone());
         MemTable {
             table: Box::new(Table::new(Box::new(key_comparator))),
-            comparator: cmp.clone()
+            range_del_table: Box::new(Table::new(Box::new(KeyComparator::new(cmp.clone())))),
+            comparator: cmp.clone(),
+            memory_usage: AtomicUsize::new(0)
         }
     }
 
+    /// Returns an estimate of the number of bytes of data in use by this
+    /// data structure.
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.memory_usage.load(AtomicOrdering::Relaxed)
+    }
+
     /// Format of an entry is concatenation of:
     /// 
     ///  key_size     : varint32 of internal_key.size()
@@ -80,7 +99,10 @@ impl MemTable {
     ///  value_size   : varint32 of value.size()
     /// 
     ///  value bytes  : char[value.size()]
-    pub fn add(&mut self, seq: SequenceNumber, valueType: ValueType, key: &Slice, value: &Slice) {
+    ///
+    /// Iterators over the memtable may be live while entries are added, but
+    /// adds must not run concurrently with each other.
+    pub fn add(&self, seq: SequenceNumber, valueType: ValueType, key: &Slice, value: &Slice) {
         let key_size = key.size();
         let val_size = value.size();
         let internal_key_size = key_size + 8;
@@ -103,13 +125,110 @@ impl MemTable {
         }
         
         assert_eq!(offset + val_size, encoded_len);
-        self.table.insert(buf)
+        self.memory_usage.fetch_add(encoded_len, AtomicOrdering::Relaxed);
+        if valueType == ValueType::KTypeRangeDeletion {
+            self.range_del_table.insert(buf)
+        } else {
+            self.table.insert(buf)
+        }
+    }
+
+    /// Returns the largest sequence number, not greater than `read_seq`, of
+    /// the range tombstones covering `user_key`, or 0 if there is none.
+    pub fn max_covering_tombstone_seq(&self, user_key: &Slice, read_seq: SequenceNumber) -> SequenceNumber {
+        let user_comparator = self.comparator.user_comparator();
+        let mut max_seq = 0;
+        let mut iter = Iter::new(&self.range_del_table);
+        iter.seek_to_first();
+        while iter.valid() {
+            let entry = iter.key();
+            let internal_key = get_length_prefixed_slice(entry, 0);
+            let key_size = internal_key.size();
+            let begin = Slice::from_bytes(&internal_key.data()[..key_size - 8]);
+            if user_comparator(&begin, user_key) == Ordering::Greater {
+                // Tombstones are sorted by begin key, none of the rest covers user_key
+                break;
+            }
+            let seq = decode_fixed64(internal_key.data(), key_size - 8) >> 8;
+            let end = get_length_prefixed_slice(entry, varint_length(key_size as u64) + key_size);
+            if seq <= read_seq && seq > max_seq && user_comparator(user_key, &end) == Ordering::Less {
+                max_seq = seq;
+            }
+            iter.next();
+        }
+        max_seq
+    }
+
+    /// Returns an iterator over the point entries of the memtable. The keys
+    /// returned are internal keys encoded by append_internal_key.
+    pub fn iter(&self) -> MemTableIterator<'_> {
+        MemTableIterator {
+            iter: Iter::new(&self.table),
+            tmp: Vec::new()
+        }
+    }
+
+    /// Returns an iterator over the point entries of a shared memtable. The
+    /// iterator holds a reference to the memtable, so it stays valid after
+    /// the caller has released its own.
+    pub fn owned_iter(self: &Arc<Self>) -> OwnedMemTableIterator {
+        // SAFETY: the skiplist never frees a node before it is dropped
+        // itself, and `mem` keeps the list alive for as long as the
+        // iterator exists, so the borrow can be extended to 'static.
+        let iter = unsafe { std::mem::transmute::<MemTableIterator<'_>, MemTableIterator<'static>>(self.iter()) };
+        OwnedMemTableIterator { iter, _mem: self.clone() }
     }
 
     /// If memtable contains a value for key, return (true, Ok(Vec<u8)).
     /// If memtable contains a deletion for key, return (true, Err(NotFound))
     /// Else, return (false,Err(NotFound).
     pub fn get(&self, key: &LookupKey) -> (bool, Result<Vec<u8>, Error>) {
+        match self.find(key, |buf, value_offset| get_length_prefixed_slice(buf, value_offset).data().to_vec()) {
+            Some(Some(value)) => (true, Ok(value)),
+            Some(None) => (true, Err(NotFound)),
+            None => (false, Err(NotFound))
+        }
+    }
+
+    /// Like get(), but only tells whether the key has a value, without
+    /// copying it. Returns Some(true) for a value, Some(false) for a
+    /// deletion and None if the memtable holds neither.
+    pub fn contains_key(&self, key: &LookupKey) -> Option<bool> {
+        self.find(key, |_, _| ()).map(|value| value.is_some())
+    }
+
+    /// Returns the range tombstones of the memtable.
+    pub fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        let mut tombstones = Vec::new();
+        let mut iter = Iter::new(&self.range_del_table);
+        iter.seek_to_first();
+        while iter.valid() {
+            let entry = iter.key();
+            let internal_key = get_length_prefixed_slice(entry, 0);
+            let end_key = get_length_prefixed_slice(entry, varint_length(internal_key.size() as u64) + internal_key.size());
+            // Only added by add() as KTypeRangeDeletion
+            tombstones.push(RangeTombstone::decode(&internal_key, &end_key).expect("bad range tombstone in memtable"));
+            iter.next();
+        }
+        tombstones
+    }
+
+    /// Find the newest entry of the user key of `key` visible at its
+    /// sequence. Returns None if there is no such entry, Some(None) if it
+    /// is a deletion or is covered by a newer range tombstone, and
+    /// Some(read(entry, value_offset)) for a value.
+    fn find<R>(&self, key: &LookupKey, read: impl FnOnce(&[u8], usize) -> R) -> Option<Option<R>> {
+        let covering = self.max_covering_tombstone_seq(&key.user_key(), key.sequence());
+        match self.find_point(key, covering, read) {
+            // A range tombstone hides older entries of the tables as well
+            None if covering > 0 => Some(None),
+            found => found
+        }
+    }
+
+    /// Like find(), but only the range tombstones up to sequence `covering`
+    /// are taken into account.
+    fn find_point<R>(&self, key: &LookupKey, covering: SequenceNumber, read: impl FnOnce(&[u8], usize) -> R) -> Option<Option<R>> {
         let memkey = key.memtable_key();
         let mut iter = Iter::new(&self.table);
         let data = memkey.data();
@@ -128,30 +247,141 @@ impl MemTable {
             let buf = iter.key();
             let result = get_varint32(buf, 0, 5);
             return match result {
-                Ok((key_length, mut offset)) => {
+                Ok((key_length, offset)) => {
                     if (self.comparator.user_comparator())(&Slice::from_bytes(&buf[offset..=(key_length-8) as usize]), &key.user_key()) == Ordering::Equal {
                         let tag = decode_fixed64(buf, offset + key_length as usize - 8);
-                        return match ValueType::from((tag & 0xff) as u8) {
-                            ValueType::KTypeValue => {
-                                let slice = get_length_prefixed_slice(buf, offset + key_length as usize);
-                                (true, Ok(slice.data().to_vec()))
+                        if tag >> 8 < covering {
+                            // Deleted by a newer range tombstone
+                            return Some(None);
+                        }
+                        return match ValueType::try_from((tag & 0xff) as u8) {
+                            Ok(ValueType::KTypeValue) => Some(Some(read(buf, offset + key_length as usize))),
+                            Ok(ValueType::KTypeDeletion) => Some(None),
+                            Ok(ValueType::KTypeRangeDeletion) => {
+                                // Range tombstones are kept in range_del_table
+                                unreachable!("range tombstone in point table")
                             },
-                            ValueType::KTypeDeletion => {
-                                (true, Err(NotFound))
+                            Err(_) => {
+                                // Entries are only inserted through add, which
+                                // takes a known ValueType
+                                unreachable!("unknown record tag in memtable")
                             }
                         }
                     }
-                    return (false, Err(NotFound))
+                    None
                 },
-                Err(_) => (false, Err(NotFound))
+                Err(_) => None
             }
         }
-        (false, Err(NotFound))
+        None
+    }
+}
+
+pub struct MemTableIterator<'a> {
+
+    iter: Iter<'a, Vec<u8>>,
+
+    // For passing to encode_key
+    tmp: Vec<u8>
+}
+
+impl<'a> InternalIterator for MemTableIterator<'a> {
+
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last()
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        // Encode a suitable internal key target for "target"
+        self.tmp.clear();
+        put_varint32(&mut self.tmp, target.size() as u32);
+        self.tmp.extend_from_slice(target.data());
+        self.iter.seek(&self.tmp)
+    }
+
+    fn next(&mut self) {
+        self.iter.next()
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev()
+    }
+
+    fn key(&self) -> Slice<'_> {
+        get_length_prefixed_slice(self.iter.key(), 0)
+    }
+
+    fn value(&self) -> Slice<'_> {
+        let entry = self.iter.key();
+        let key = get_length_prefixed_slice(entry, 0);
+        get_length_prefixed_slice(entry, varint_length(key.size() as u64) + key.size())
+    }
+
+    fn status(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An iterator over the point entries of a memtable that it keeps alive.
+pub struct OwnedMemTableIterator {
+
+    // Declared before `_mem` so that it is dropped first
+    iter: MemTableIterator<'static>,
+
+    _mem: Arc<MemTable>
+}
+
+impl InternalIterator for OwnedMemTableIterator {
+
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last()
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.iter.seek(target)
+    }
+
+    fn next(&mut self) {
+        self.iter.next()
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev()
+    }
+
+    fn key(&self) -> Slice<'_> {
+        self.iter.key()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        self.iter.value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.iter.status()
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::comparator::bytewise_compare;
+    use crate::dbformat::{append_internal_key, parse_internal_key};
     use super::*;
 
     #[test]
@@ -160,7 +390,7 @@ mod tests {
             a.data().cmp(b.data())
         };
         let internalKeyComparator = InternalKeyComparator::new(user_comparator);
-        let mut mem = MemTable::new(internalKeyComparator);
+        let mem = MemTable::new(internalKeyComparator);
         let (key, value) = ("key", "value");
         mem.add(1, ValueType::KTypeValue, &Slice::from_str(key), &Slice::from_str(value));
         let result = mem.get(&LookupKey::new(&Slice::from_str(key), 1 as SequenceNumber));
@@ -170,5 +400,73 @@ mod tests {
         assert!(!result.0);
         let err = result.1.expect_err("unexpect");
         assert_eq!(NotFound, err);
+
+        mem.add(2, ValueType::KTypeDeletion, &Slice::from_str(key), &Slice::from_str(""));
+        assert_eq!(Some(true), mem.contains_key(&LookupKey::new(&Slice::from_str(key), 1)));
+        assert_eq!(Some(false), mem.contains_key(&LookupKey::new(&Slice::from_str(key), 2)));
+        assert_eq!(None, mem.contains_key(&LookupKey::new(&Slice::from_str("yek"), 2)));
+    }
+
+    #[test]
+    fn test_range_tombstones() {
+        let mem = MemTable::new(InternalKeyComparator::new(bytewise_compare));
+        mem.add(1, ValueType::KTypeValue, &Slice::from_str("b"), &Slice::from_str("v"));
+        mem.add(2, ValueType::KTypeRangeDeletion, &Slice::from_str("a"), &Slice::from_str("c"));
+        mem.add(3, ValueType::KTypeRangeDeletion, &Slice::from_str("b"), &Slice::from_str("d"));
+
+        // Range tombstones do not show up as point entries, but hide the
+        // older ones they cover
+        let result = mem.get(&LookupKey::new(&Slice::from_str("b"), 1));
+        assert!(result.0);
+        assert_eq!(b"v".to_vec(), result.1.unwrap());
+        assert_eq!((true, Err(NotFound)), mem.get(&LookupKey::new(&Slice::from_str("b"), 3)));
+        assert_eq!(Some(false), mem.contains_key(&LookupKey::new(&Slice::from_str("c"), 3)));
+        assert_eq!(None, mem.contains_key(&LookupKey::new(&Slice::from_str("c"), 1)));
+        assert_eq!(vec![RangeTombstone::new(b"a", b"c", 2), RangeTombstone::new(b"b", b"d", 3)], mem.range_tombstones());
+
+        let covering = |key: &str, seq| mem.max_covering_tombstone_seq(&Slice::from_str(key), seq);
+        assert_eq!(2, covering("a", 3));
+        assert_eq!(3, covering("b", 3));
+        assert_eq!(2, covering("b", 2));
+        assert_eq!(0, covering("b", 1));
+        assert_eq!(3, covering("c", 3));
+        assert_eq!(0, covering("d", 3));
+        assert_eq!(0, covering("0", 3));
+    }
+
+    #[test]
+    fn test_iterator() {
+        let mem = MemTable::new(InternalKeyComparator::new(bytewise_compare));
+        mem.add(1, ValueType::KTypeValue, &Slice::from_str("b"), &Slice::from_str("v1"));
+        mem.add(2, ValueType::KTypeValue, &Slice::from_str("a"), &Slice::from_str("v2"));
+        mem.add(3, ValueType::KTypeDeletion, &Slice::from_str("b"), &Slice::from_str(""));
+        mem.add(4, ValueType::KTypeRangeDeletion, &Slice::from_str("a"), &Slice::from_str("z"));
+
+        let entry = |iter: &MemTableIterator| {
+            let key = iter.key();
+            let parsed = parse_internal_key(&key).expect("bad internal key");
+            (String::from_utf8_lossy(parsed.user_key.data()).to_string(), parsed.sequence,
+             String::from_utf8_lossy(iter.value().data()).to_string())
+        };
+        let mut iter = mem.iter();
+        assert!(!iter.valid());
+        iter.seek_to_first();
+        assert_eq!(("a".to_string(), 2, "v2".to_string()), entry(&iter));
+        iter.next();
+        assert_eq!(("b".to_string(), 3, "".to_string()), entry(&iter));
+        iter.next();
+        assert_eq!(("b".to_string(), 1, "v1".to_string()), entry(&iter));
+        iter.next();
+        assert!(!iter.valid());
+
+        iter.seek_to_last();
+        assert_eq!(("b".to_string(), 1, "v1".to_string()), entry(&iter));
+        iter.prev();
+        assert_eq!(("b".to_string(), 3, "".to_string()), entry(&iter));
+
+        let mut target = Vec::new();
+        append_internal_key(&mut target, &Slice::from_str("b"), 2, ValueType::KTypeValue);
+        iter.seek(&Slice::from_bytes(&target));
+        assert_eq!(("b".to_string(), 1, "v1".to_string()), entry(&iter));
     }
 }
@@ -11,33 +11,537 @@
 // limitations under the License.
 
 use std::cmp::Ordering;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::cache::ShardedLRUCache;
+use crate::comparator::{bytewise_compare, BYTEWISE_COMPARATOR_NAME};
+use crate::compaction_filter::CompactionFilter;
+use crate::filter_policy::FilterPolicy;
 use crate::slice::Slice;
+use crate::snapshot::Snapshot;
+use crate::table_properties::TablePropertiesCollectorFactory;
+use crate::write_buffer_manager::WriteBufferManager;
 
+/// Newest on-disk format version this library reads and writes. The
+/// version is recorded in the MANIFEST, and a db written with a newer version
+/// is refused instead of being misread.
+pub const K_LATEST_FORMAT_VERSION: u32 = 2;
+
+/// On-disk encodings that change the format compared to version 1. Each can
+/// only be used once the format version in use is at least the version that
+/// introduced it, so files are never written in an encoding an older
+/// release of the same format version cannot read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatFeature {
+    /// Index blocks split into partitions with a top-level index.
+    PartitionedIndex,
+
+    /// Data blocks ending with a hash table over their keys.
+    DataBlockHashIndex,
+
+    /// Index blocks storing their restart array as group varint deltas.
+    DeltaEncodedRestarts
+}
+
+impl FormatFeature {
+
+    /// Returns the first format version that supports the feature.
+    pub fn min_format_version(&self) -> u32 {
+        match self {
+            FormatFeature::PartitionedIndex => 2,
+            FormatFeature::DataBlockHashIndex => 2,
+            FormatFeature::DeltaEncodedRestarts => 2
+        }
+    }
+}
+
+/// Strategy used to choose which file of a level is compacted next.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CompactionPri {
+    /// Rotate through the key space of the level, starting after the
+    /// largest key of the previous compaction (leveldb's compact pointer).
+    #[default]
+    RoundRobin,
+
+    /// Prefer the smallest file, which keeps the write amplification of a
+    /// single compaction low.
+    SmallestFirst,
+
+    /// Prefer the file holding the oldest data, i.e. the one with the
+    /// smallest largest sequence number. Suits time-series workloads where
+    /// new keys are appended and old ranges are rarely updated.
+    OldestDataFirst,
+
+    /// Prefer the file holding the most deletion entries, so space taken
+    /// by deleted data is reclaimed sooner.
+    MostTombstonesFirst
+}
+
+/// How the index block of a table file is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IndexType {
+    /// A single index block holding one entry per data block, searched
+    /// with a binary search. The whole index is loaded when a table opens.
+    #[default]
+    BinarySearch,
+
+    /// The index is split into partitions of about `metadata_block_size`
+    /// bytes, plus a small top-level index over the partitions. Only the
+    /// top-level index stays in memory, the partitions are read through the
+    /// block cache on demand, which bounds the memory used by huge tables.
+    /// Requires FormatFeature::PartitionedIndex.
+    TwoLevelIndexSearch
+}
+
+/// How a key is located within a data block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DataBlockIndexType {
+    /// Binary search over the restart points of the block.
+    #[default]
+    BinarySearch,
+
+    /// A hash table appended to every data block maps each user key to its
+    /// restart interval, so point lookups skip the binary search. Costs
+    /// about one byte per key divided by `data_block_hash_table_util_ratio`.
+    /// Requires FormatFeature::DataBlockHashIndex.
+    BinaryAndHash
+}
+
+/// DB contents are stored in a set of blocks, each of which holds a
+/// sequence of key,value pairs.  Each block may be compressed before
+/// being stored in a file.  The following enum describes which
+/// compression method (if any) is used to compress a block.
+/// The value of a variant is the type byte of the blocks it compresses, so
+/// variants must never be renumbered.
+///
+/// Lz4 and Zstd need the cargo features of the same name. Without them
+/// blocks are written uncompressed instead, and reading a block compressed
+/// by them fails with NotSupport.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionType {
+    NoCompression = 0x0,
+    #[default]
+    Snappy = 0x1,
+    /// Faster than Snappy with a similar ratio.
+    Lz4 = 0x4,
+    /// Slower, but compresses much better, which suits cold data.
+    Zstd = 0x7
+}
+
+impl TryFrom<u8> for CompressionType {
+    type Error = crate::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x0 => Ok(CompressionType::NoCompression),
+            0x1 => Ok(CompressionType::Snappy),
+            0x4 => Ok(CompressionType::Lz4),
+            0x7 => Ok(CompressionType::Zstd),
+            // "bad block type"
+            _ => Err(crate::Error::Corruption)
+        }
+    }
+}
+
+/// Progress of the recovery of a db while it is being opened.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RecoveryProgress<'a> {
+    /// Name of the file being replayed
+    pub file_name: &'a Path,
+
+    /// Size of that file in bytes
+    pub file_size: u64,
+
+    /// Bytes of the file processed so far
+    pub bytes_processed: u64,
+
+    /// Records of the file applied so far
+    pub records_applied: u64
+}
+
+/// Callback receiving the progress of recovery, see Options::recovery_progress.
+pub type RecoveryProgressCallback = Arc<dyn Fn(&RecoveryProgress) + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Options {
 
-    pub comparator: fn(a: &Slice, b: &Slice) -> Ordering
+    pub comparator: fn(a: &Slice, b: &Slice) -> Ordering,
+
+    /// Name of the ordering implemented by `comparator`. It is recorded in
+    /// the MANIFEST, and the db refuses to open with a comparator of
+    /// another name: keys sorted by one ordering cannot be read with
+    /// another. Change the name whenever the ordering changes.
+    ///
+    /// Default: "leveldb.BytewiseComparator"
+    pub comparator_name: &'static str,
+
+    /// If true, the implementation will do aggressive checking of the
+    /// data it is processing and will stop early if it detects any
+    /// errors. For example, a damaged MANIFEST fails the open instead of
+    /// being truncated to its last valid edit.
+    pub paranoid_checks: bool,
+
+    /// Format version of the files written by the db, between 1 and
+    /// K_LATEST_FORMAT_VERSION. Keeping an older version allows the db to be
+    /// opened by older releases, at the cost of the features introduced since.
+    /// Defaults to 1, so the features of newer versions are opt-in. Going
+    /// back to an older version only affects new files: the MANIFEST keeps
+    /// recording the highest version the db was written with, as the tables
+    /// written before may need it.
+    pub format_version: u32,
+
+    /// Amount of data to build up in memory (backed by an unsorted log
+    /// on disk) before converting to a sorted on-disk file.
+    ///
+    /// Larger values increase performance, especially during bulk loads.
+    /// Up to two write buffers may be held in memory at the same time,
+    /// so you may wish to adjust this parameter to control memory usage.
+    /// Also, a larger write buffer will result in a longer recovery time
+    /// the next time the database is opened.
+    pub write_buffer_size: usize,
+
+    /// How the next file within a level is picked for compaction.
+    pub compaction_pri: CompactionPri,
+
+    /// Maximum number of flush and compaction jobs run concurrently. The
+    /// jobs are split between a high priority flush pool and a low priority
+    /// compaction pool, see `background_job_limits`.
+    pub max_background_jobs: usize,
+
+    /// Maximum number of threads a single compaction is split across. The
+    /// key range of a large compaction is cut into up to this many
+    /// sub-compactions that run in parallel and whose outputs are installed
+    /// together. 1 runs every compaction on a single thread.
+    pub max_subcompactions: usize,
+
+    /// Target size of the tables compactions write into level-1. Output
+    /// files are cut once they reach the target size of their level.
+    pub target_file_size_base: u64,
+
+    /// The target file size of every level past level-1 is this many times
+    /// the one of the level above it.
+    pub target_file_size_multiplier: u64,
+
+    /// Upper bound on the total size of the input files of a compaction.
+    /// The files of the compacted level are not expanded past it. 0 means
+    /// 25 times `target_file_size_base`.
+    pub max_compaction_bytes: u64,
+
+    /// Soft limit on number of level-0 files. Writes are slowed down when
+    /// this many level-0 files exist.
+    pub level0_slowdown_writes_trigger: usize,
+
+    /// Maximum number of level-0 files. Writes are stopped at this point.
+    pub level0_stop_writes_trigger: usize,
+
+    /// Writes are slowed down when the estimated number of bytes compaction
+    /// needs to rewrite to bring every level under its target size exceeds
+    /// this. 0 disables the limit.
+    pub soft_pending_compaction_bytes_limit: u64,
+
+    /// Writes are stopped when the estimated pending compaction bytes exceed
+    /// this. 0 disables the limit.
+    pub hard_pending_compaction_bytes_limit: u64,
+
+    /// Once an iterator reads consecutive blocks of a table file, it starts
+    /// reading ahead this many bytes, doubling the amount on every further
+    /// prefetch up to `max_auto_readahead_size`.
+    pub initial_auto_readahead_size: usize,
+
+    /// Upper bound of the automatic readahead of iterators. 0 disables
+    /// readahead.
+    pub max_auto_readahead_size: usize,
+
+    /// Readahead used by compactions while reading their input files. Inputs
+    /// are read sequentially from start to end, so a large value turns many
+    /// small reads into few big ones, which matters most on spinning disks
+    /// and network storage. 0 disables readahead.
+    pub compaction_readahead_size: usize,
+
+    /// If true, the MANIFEST and table files are written through memory
+    /// mappings, with an msync on every sync, instead of buffered writes.
+    /// This helps on platforms where write calls are expensive.
+    pub allow_mmap_writes: bool,
+
+    /// Like `allow_mmap_writes`, but for the write ahead log.
+    pub allow_mmap_wal_writes: bool,
+
+    /// If set, a background thread syncs the write ahead log this often.
+    /// Writes without WriteOptions::sync then lose at most about one
+    /// interval of data on a machine crash, without paying for a sync on
+    /// every write. Must not be zero.
+    pub wal_sync_interval: Option<Duration>,
+
+    /// Approximate size of user data packed per block. Note that the
+    /// block size specified here corresponds to uncompressed data.
+    pub block_size: usize,
+
+    /// Number of keys between restart points for delta encoding of keys.
+    /// Most clients should leave this parameter alone.
+    pub block_restart_interval: usize,
+
+    /// Compress blocks using the specified compression algorithm. Blocks
+    /// that compress by less than 12.5% are stored uncompressed.
+    ///
+    /// Default: Snappy, which gives lightweight but fast compression.
+    /// Typical speeds of Snappy on an Intel(R) Core(TM)2 2.4GHz:
+    ///    ~200-500MB/s compression
+    ///    ~400-800MB/s decompression
+    /// Note that these speeds are significantly faster than most
+    /// persistent storage speeds, and therefore it is typically never
+    /// worth switching to NoCompression.  Even if the input data is
+    /// incompressible, the Snappy implementation will efficiently
+    /// detect that and will switch to uncompressed mode.
+    pub compression: CompressionType,
+
+    /// Layout of the index of table files.
+    pub index_type: IndexType,
+
+    /// Target size of a partition of a partitioned index.
+    pub metadata_block_size: usize,
+
+    /// How keys are located within data blocks.
+    pub data_block_index_type: DataBlockIndexType,
+
+    /// Ratio of keys to buckets of the data block hash index. Lower values
+    /// mean fewer collisions and a larger index.
+    pub data_block_hash_table_util_ratio: f64,
+
+    /// How long DB::open waits for the LOCK file of the db held by another
+    /// process or open to be released before failing. Smooths over
+    /// restarts racing with the previous process still closing the db.
+    /// Zero fails immediately.
+    pub lock_wait_timeout: Duration,
+
+    /// If set, called during DB::open after every record replayed from the
+    /// MANIFEST, so that services opening a large db can report startup
+    /// progress. The callback runs on the opening thread and should be cheap.
+    pub recovery_progress: Option<RecoveryProgressCallback>,
+
+    /// If non-null, use the specified cache for blocks. A cache can be
+    /// shared by several dbs to bound their combined memory.
+    /// If null, the db creates and uses an 8MB internal cache.
+    pub block_cache: Option<Arc<ShardedLRUCache<Vec<u8>>>>,
+
+    /// If true, the index and filter blocks of tables are read through the
+    /// block cache, with high priority, and are charged to it. Otherwise
+    /// every open table keeps them in memory, outside of the cache, until
+    /// it is closed. Without a block cache this option has no effect.
+    pub cache_index_and_filter_blocks: bool,
+
+    /// If non-null, use the specified filter policy to reduce disk reads.
+    /// Many applications will benefit from passing the result of
+    /// BloomFilterPolicy::new() here. Filters are built over user keys.
+    pub filter_policy: Option<Arc<dyn FilterPolicy>>,
+
+    /// Factories of the collectors recording custom properties of every
+    /// table built, see TableProperties::user_collected_properties.
+    pub table_properties_collectors: Vec<Arc<dyn TablePropertiesCollectorFactory>>,
+
+    /// If non-null, compactions ask the specified filter whether to remove
+    /// each value they write that no snapshot can read.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+
+    /// If non-null, the memtable memory of the db is accounted to the
+    /// specified manager, which can be shared by several dbs to bound their
+    /// combined memtable memory. The db holding the largest memtable is
+    /// asked to flush it when the budget of the manager is exceeded.
+    pub write_buffer_manager: Option<Arc<WriteBufferManager>>
+}
+
+/// Number of threads of each background pool.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackgroundJobLimits {
+    pub max_flushes: usize,
+    pub max_compactions: usize
+}
+
+impl Options {
+
+    /// Returns true if files written with these options may use `feature`.
+    pub fn supports(&self, feature: FormatFeature) -> bool {
+        self.format_version >= feature.min_format_version()
+    }
+
+    /// Returns the size at which compactions cut the files they write into
+    /// `level`. Level-0 and level-1 share `target_file_size_base`.
+    pub fn target_file_size(&self, level: usize) -> u64 {
+        let mut result = self.target_file_size_base;
+        for _ in 1..level {
+            result = result.saturating_mul(self.target_file_size_multiplier);
+        }
+        result
+    }
+
+    /// Returns `max_compaction_bytes`, or its default if it is not set.
+    pub fn compaction_bytes_limit(&self) -> u64 {
+        if self.max_compaction_bytes == 0 {
+            self.target_file_size_base.saturating_mul(25)
+        } else {
+            self.max_compaction_bytes
+        }
+    }
+
+    /// Split `max_background_jobs` between flushes and compactions. A quarter
+    /// of the jobs, and at least one, are reserved for flushes so that they
+    /// are never starved by long running compactions.
+    pub fn background_job_limits(&self) -> BackgroundJobLimits {
+        let max_flushes = std::cmp::max(1, self.max_background_jobs / 4);
+        let max_compactions = std::cmp::max(1, self.max_background_jobs.saturating_sub(max_flushes));
+        BackgroundJobLimits {
+            max_flushes,
+            max_compactions
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            comparator: bytewise_compare,
+            comparator_name: BYTEWISE_COMPARATOR_NAME,
+            paranoid_checks: false,
+            format_version: 1,
+            write_buffer_size: 4 << 20,
+            compaction_pri: CompactionPri::default(),
+            max_background_jobs: 2,
+            max_subcompactions: 1,
+            target_file_size_base: 2 << 20,
+            target_file_size_multiplier: 1,
+            max_compaction_bytes: 0,
+            level0_slowdown_writes_trigger: 8,
+            level0_stop_writes_trigger: 12,
+            soft_pending_compaction_bytes_limit: 64 << 30,
+            hard_pending_compaction_bytes_limit: 256 << 30,
+            initial_auto_readahead_size: 8 << 10,
+            max_auto_readahead_size: 256 << 10,
+            compaction_readahead_size: 2 << 20,
+            allow_mmap_writes: false,
+            allow_mmap_wal_writes: false,
+            wal_sync_interval: None,
+            block_size: 4 << 10,
+            block_restart_interval: 16,
+            compression: CompressionType::default(),
+            index_type: IndexType::default(),
+            metadata_block_size: 4096,
+            data_block_index_type: DataBlockIndexType::default(),
+            data_block_hash_table_util_ratio: 0.75,
+            lock_wait_timeout: Duration::ZERO,
+            recovery_progress: None,
+            block_cache: None,
+            cache_index_and_filter_blocks: false,
+            filter_policy: None,
+            table_properties_collectors: Vec::new(),
+            compaction_filter: None,
+            write_buffer_manager: None
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ReadOptions<'a> {
+
+    /// If true, all data read from underlying storage will be
+    /// verified against corresponding checksums, and a mismatch fails the
+    /// read with Corruption.
+    pub verify_checksums: bool,
+
+    /// Should the data read for this iteration be cached in memory?
+    /// Callers may wish to set this field to false for bulk scans.
+    pub fill_cache: bool,
+
+    /// If set, read as of the supplied snapshot, which must belong to the
+    /// DB that is being read. If None, use an implicit snapshot of the
+    /// state at the beginning of this read operation.
+    pub snapshot: Option<&'a Snapshot>
 }
 
-pub struct ReadOptions {
+impl ReadOptions<'_> {
 
+    /// Return these options without the snapshot, for readers kept past
+    /// the borrow of the snapshot. Tables hold no snapshot-dependent
+    /// state, their readers only need the other options.
+    pub(crate) fn without_snapshot(&self) -> ReadOptions<'static> {
+        ReadOptions { verify_checksums: self.verify_checksums, fill_cache: self.fill_cache, snapshot: None }
+    }
 }
 
 pub struct WriteOptions {
 
-    pub sync: bool
+    pub sync: bool,
+
+    /// If true, writes will not first go to the write ahead log, and the
+    /// write may get lost after a crash until the memtable holding it is
+    /// flushed. Can not be combined with sync.
+    pub disable_wal: bool
 
 }
 
-impl Default for ReadOptions {
+impl Default for ReadOptions<'_> {
     fn default() -> Self {
-        ReadOptions{}
+        ReadOptions {
+            verify_checksums: false,
+            fill_cache: true,
+            snapshot: None
+        }
     }
 }
 
 impl Default for WriteOptions {
     fn default() -> Self {
         WriteOptions {
-            sync: true
+            sync: true,
+            disable_wal: false
+        }
+    }
+}
+
+/// Options that control flush operations
+pub struct FlushOptions {
+
+    /// If true, the flush will wait until the flush is done.
+    pub wait: bool
+
+}
+
+impl Default for FlushOptions {
+    fn default() -> Self {
+        FlushOptions {
+            wait: true
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_background_job_limits() {
+        let mut options = Options::default();
+        assert_eq!(BackgroundJobLimits { max_flushes: 1, max_compactions: 1 }, options.background_job_limits());
+        options.max_background_jobs = 1;
+        assert_eq!(BackgroundJobLimits { max_flushes: 1, max_compactions: 1 }, options.background_job_limits());
+        options.max_background_jobs = 8;
+        assert_eq!(BackgroundJobLimits { max_flushes: 2, max_compactions: 6 }, options.background_job_limits());
+        options.max_background_jobs = 16;
+        assert_eq!(BackgroundJobLimits { max_flushes: 4, max_compactions: 12 }, options.background_job_limits());
+    }
+
+    #[test]
+    fn test_compaction_sizes() {
+        let mut options = Options::default();
+        assert_eq!(2 << 20, options.target_file_size(0));
+        assert_eq!(2 << 20, options.target_file_size(3));
+        assert_eq!(50 << 20, options.compaction_bytes_limit());
+        options.target_file_size_base = 1 << 20;
+        options.target_file_size_multiplier = 4;
+        assert_eq!(1 << 20, options.target_file_size(1));
+        assert_eq!(4 << 20, options.target_file_size(2));
+        assert_eq!(16 << 20, options.target_file_size(3));
+        assert_eq!(25 << 20, options.compaction_bytes_limit());
+        options.max_compaction_bytes = 8 << 20;
+        assert_eq!(8 << 20, options.compaction_bytes_limit());
+    }
+}
@@ -49,24 +49,118 @@ impl Random {
 
     /// Returns a uniformly distributed value in the range [0..n-1]
     /// REQUIRES: n > 0
-    fn uniform(&mut self, n: i32) -> u32{
+    pub fn uniform(&mut self, n: i32) -> u32{
         self.next() % n as u32
     }
 
     /// Randomly returns true ~"1/n" of the time, and false otherwise.
     /// REQUIRES: n > 0
-    pub(crate) fn one_in(&mut self, n: i32) -> bool {
+    pub fn one_in(&mut self, n: i32) -> bool {
         self.next() % n as u32 == 0
     }
 
     /// Skewed: pick "base" uniformly from range [0,max_log] and then
     /// return "base" random bits.  The effect is to pick a number in the
     /// range [0,2^max_log-1] with exponential bias towards smaller numbers.
-    fn skewed(&mut self, max_log: i32) -> u32 {
+    pub fn skewed(&mut self, max_log: i32) -> u32 {
         let v: u32;
         {
             v = self.uniform(max_log + 1)
         }
         self.uniform(1 << v)
     }
-}
\ No newline at end of file
+}
+
+/// A 64-bit generator (SplitMix64) for when the 31 bits of Random are not
+/// enough, e.g. to pick keys from a large key space. Every seed, including
+/// zero, gives a full period of 2^64 values.
+pub struct Random64 {
+    state: u64
+}
+
+impl Random64 {
+
+    pub fn new(seed: u64) -> Self {
+        Random64 { state: seed }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a uniformly distributed value in the range [0..n-1]
+    /// REQUIRES: n > 0
+    pub fn uniform(&mut self, n: u64) -> u64 {
+        assert!(n > 0);
+        // The high half of the 128-bit product is in [0, n) with a bias of
+        // at most n / 2^64, far less than that of a plain modulo
+        ((self.next() as u128 * n as u128) >> 64) as u64
+    }
+
+    /// Randomly returns true ~"1/n" of the time, and false otherwise.
+    /// REQUIRES: n > 0
+    pub fn one_in(&mut self, n: u64) -> bool {
+        self.uniform(n) == 0
+    }
+
+    /// Skewed: pick "base" uniformly from range [0,max_log] and then
+    /// return "base" random bits.  The effect is to pick a number in the
+    /// range [0,2^max_log-1] with exponential bias towards smaller numbers.
+    /// REQUIRES: max_log < 64
+    pub fn skewed(&mut self, max_log: u32) -> u64 {
+        assert!(max_log < 64);
+        let base = self.uniform(max_log as u64 + 1);
+        self.uniform(1 << base)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random() {
+        let mut rnd = Random::new(301);
+        for _ in 0..1000 {
+            assert!(rnd.uniform(10) < 10);
+            assert!(rnd.skewed(4) < 16);
+        }
+        assert!(rnd.one_in(1));
+    }
+
+    #[test]
+    fn test_random64() {
+        let mut a = Random64::new(0);
+        let mut b = Random64::new(0);
+        let values = (0..100).map(|_| a.next()).collect::<Vec<_>>();
+        assert_eq!(values, (0..100).map(|_| b.next()).collect::<Vec<_>>());
+        assert_ne!(values, (0..100).map(|_| Random64::new(1).next()).collect::<Vec<_>>());
+        // Values use the whole 64 bits
+        assert!(values.iter().any(|v| *v > u32::MAX as u64));
+
+        let mut rnd = Random64::new(301);
+        let mut counts = [0; 10];
+        for _ in 0..100_000 {
+            counts[rnd.uniform(10) as usize] += 1;
+        }
+        for count in counts {
+            assert!((9_000..11_000).contains(&count), "{}", count);
+        }
+        assert!(rnd.uniform(u64::MAX) > 0 || rnd.uniform(u64::MAX) > 0);
+        assert_eq!(0, rnd.uniform(1));
+        assert!(rnd.one_in(1));
+
+        let ones = (0..100_000).filter(|_| rnd.one_in(100)).count();
+        assert!((700..1300).contains(&ones), "{}", ones);
+
+        let skewed = (0..10_000).map(|_| rnd.skewed(40)).collect::<Vec<_>>();
+        assert!(skewed.iter().all(|v| *v < 1 << 40));
+        // About half the values have at most 20 bits
+        let small = skewed.iter().filter(|v| **v < 1 << 20).count();
+        assert!((4_000..6_500).contains(&small), "{}", small);
+    }
+}
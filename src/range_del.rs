@@ -0,0 +1,191 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Range tombstones delete every user key in [start_key, end_key) written
+//! before them. They are kept apart from the point entries: memtables keep
+//! them in a side table, and tables in a range deletion block, whose
+//! entries map the internal key (start_key, sequence, KTypeRangeDeletion)
+//! to end_key. Readers collect the tombstones of everything they read in a
+//! RangeDelAggregator and check every point entry against it.
+
+use std::cmp::Ordering;
+use crate::dbformat::{append_internal_key, parse_internal_key, SequenceNumber, ValueType, K_MAX_SEQUENCE_NUMBER};
+use crate::Error::Corruption;
+use crate::Result;
+use crate::slice::Slice;
+
+/// Name of the metaindex entry of the range deletion block.
+pub(crate) const K_RANGE_DEL_BLOCK: &[u8] = b"revel.range_del";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeTombstone {
+
+    pub start_key: Vec<u8>,
+
+    // Exclusive
+    pub end_key: Vec<u8>,
+
+    pub sequence: SequenceNumber
+}
+
+impl RangeTombstone {
+
+    pub fn new(start_key: &[u8], end_key: &[u8], sequence: SequenceNumber) -> Self {
+        RangeTombstone { start_key: start_key.to_vec(), end_key: end_key.to_vec(), sequence }
+    }
+
+    /// Decode the tombstone stored under `internal_key` with `end_key` as
+    /// value.
+    pub fn decode(internal_key: &Slice, end_key: &Slice) -> Result<Self> {
+        let parsed = parse_internal_key(internal_key)?;
+        if parsed.value_type != ValueType::KTypeRangeDeletion {
+            return Err(Corruption);
+        }
+        Ok(RangeTombstone::new(parsed.user_key.data(), end_key.data(), parsed.sequence))
+    }
+
+    /// Returns the internal key the tombstone is stored under, the smallest
+    /// key of a file holding it.
+    pub fn internal_key(&self) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_bytes(&self.start_key), self.sequence, ValueType::KTypeRangeDeletion);
+        key
+    }
+
+    /// Returns the largest key of a file holding the tombstone. The end key
+    /// is excluded, so this sorts before every entry of the end key.
+    pub fn end_internal_key(&self) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_bytes(&self.end_key), K_MAX_SEQUENCE_NUMBER, ValueType::KTypeRangeDeletion);
+        key
+    }
+
+    /// Returns true if `user_key` is in the range of the tombstone.
+    pub fn covers(&self, ucmp: fn(&Slice, &Slice) -> Ordering, user_key: &Slice) -> bool {
+        ucmp(&Slice::from_bytes(&self.start_key), user_key) != Ordering::Greater
+            && ucmp(user_key, &Slice::from_bytes(&self.end_key)) == Ordering::Less
+    }
+
+    /// Returns the part of the tombstone within the user key range
+    /// [lower, upper), a missing bound leaving that side open, or None if
+    /// they do not overlap.
+    pub fn truncate(&self, ucmp: fn(&Slice, &Slice) -> Ordering, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Option<RangeTombstone> {
+        let mut start_key = self.start_key.as_slice();
+        if let Some(lower) = lower {
+            if ucmp(&Slice::from_bytes(lower), &Slice::from_bytes(start_key)) == Ordering::Greater {
+                start_key = lower;
+            }
+        }
+        let mut end_key = self.end_key.as_slice();
+        if let Some(upper) = upper {
+            if ucmp(&Slice::from_bytes(upper), &Slice::from_bytes(end_key)) == Ordering::Less {
+                end_key = upper;
+            }
+        }
+        (ucmp(&Slice::from_bytes(start_key), &Slice::from_bytes(end_key)) == Ordering::Less)
+            .then(|| RangeTombstone::new(start_key, end_key, self.sequence))
+    }
+}
+
+/// The range tombstones visible to a read at some sequence number,
+/// gathered from the memtables and tables it reads.
+pub struct RangeDelAggregator {
+
+    user_comparator: fn(&Slice, &Slice) -> Ordering,
+
+    sequence: SequenceNumber,
+
+    // Sorted by start key
+    tombstones: Vec<RangeTombstone>
+}
+
+impl RangeDelAggregator {
+
+    /// Create an aggregator keeping the tombstones visible at `sequence`.
+    pub fn new(user_comparator: fn(&Slice, &Slice) -> Ordering, sequence: SequenceNumber) -> Self {
+        RangeDelAggregator { user_comparator, sequence, tombstones: Vec::new() }
+    }
+
+    pub fn add<I>(&mut self, tombstones: I) where I: IntoIterator<Item = RangeTombstone> {
+        let sequence = self.sequence;
+        self.tombstones.extend(tombstones.into_iter().filter(|t| t.sequence <= sequence));
+        let ucmp = self.user_comparator;
+        self.tombstones.sort_by(|a, b| ucmp(&Slice::from_bytes(&a.start_key), &Slice::from_bytes(&b.start_key)));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tombstones.is_empty()
+    }
+
+    /// Returns the largest sequence number of the tombstones covering
+    /// `user_key`, or 0 if there is none.
+    pub fn max_covering_seq(&self, user_key: &Slice) -> SequenceNumber {
+        let ucmp = self.user_comparator;
+        self.tombstones.iter()
+            .take_while(|t| ucmp(&Slice::from_bytes(&t.start_key), user_key) != Ordering::Greater)
+            .filter(|t| t.covers(ucmp, user_key))
+            .map(|t| t.sequence)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns true if the entry of `user_key` at `sequence` is deleted by
+    /// one of the tombstones.
+    pub fn should_delete(&self, user_key: &Slice, sequence: SequenceNumber) -> bool {
+        self.max_covering_seq(user_key) > sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::comparator::bytewise_compare;
+    use super::*;
+
+    #[test]
+    fn test_range_tombstone() {
+        let t = RangeTombstone::new(b"b", b"d", 5);
+        assert!(!t.covers(bytewise_compare, &Slice::from_str("a")));
+        assert!(t.covers(bytewise_compare, &Slice::from_str("b")));
+        assert!(t.covers(bytewise_compare, &Slice::from_str("c")));
+        assert!(!t.covers(bytewise_compare, &Slice::from_str("d")));
+        assert_eq!(Ok(t.clone()), RangeTombstone::decode(&Slice::from_bytes(&t.internal_key()), &Slice::from_str("d")));
+        let mut value_key = Vec::new();
+        append_internal_key(&mut value_key, &Slice::from_str("b"), 5, ValueType::KTypeValue);
+        assert_eq!(Err(Corruption), RangeTombstone::decode(&Slice::from_bytes(&value_key), &Slice::from_str("d")));
+
+        let truncate = |lower: Option<&str>, upper: Option<&str>| {
+            t.truncate(bytewise_compare, lower.map(str::as_bytes), upper.map(str::as_bytes))
+        };
+        assert_eq!(Some(t.clone()), truncate(None, None));
+        assert_eq!(Some(t.clone()), truncate(Some("a"), Some("e")));
+        assert_eq!(Some(RangeTombstone::new(b"c", b"d", 5)), truncate(Some("c"), None));
+        assert_eq!(Some(RangeTombstone::new(b"b", b"bb", 5)), truncate(None, Some("bb")));
+        assert_eq!(None, truncate(Some("d"), None));
+        assert_eq!(None, truncate(None, Some("b")));
+    }
+
+    #[test]
+    fn test_range_del_aggregator() {
+        let mut aggregator = RangeDelAggregator::new(bytewise_compare, 10);
+        assert!(aggregator.is_empty());
+        aggregator.add(vec![RangeTombstone::new(b"c", b"f", 4), RangeTombstone::new(b"a", b"d", 7)]);
+        // Not visible at 10
+        aggregator.add(vec![RangeTombstone::new(b"a", b"z", 11)]);
+        assert_eq!(7, aggregator.max_covering_seq(&Slice::from_str("a")));
+        assert_eq!(7, aggregator.max_covering_seq(&Slice::from_str("c")));
+        assert_eq!(4, aggregator.max_covering_seq(&Slice::from_str("d")));
+        assert_eq!(0, aggregator.max_covering_seq(&Slice::from_str("f")));
+        assert!(aggregator.should_delete(&Slice::from_str("e"), 3));
+        assert!(!aggregator.should_delete(&Slice::from_str("e"), 4));
+        assert!(!aggregator.should_delete(&Slice::from_str("x"), 1));
+    }
+}
@@ -0,0 +1,261 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::rc::Rc;
+use crate::env::RandomAccessFile;
+use crate::Result;
+use crate::slice::Slice;
+
+/// Number of back to back sequential reads after which readahead kicks in.
+/// A single read, e.g. a point lookup, never pays for prefetching.
+const K_MIN_SEQUENTIAL_READS: usize = 2;
+
+/// A RandomAccessFile wrapper for scans. Once reads start to hit consecutive
+/// ranges of the file, every read that misses the buffer also prefetches
+/// the bytes following it, starting with `initial_readahead_size` and
+/// doubling on each prefetch up to `max_readahead_size`. A read that is not
+/// sequential drops back to unbuffered reads at the initial size.
+///
+/// Each iterator should use its own wrapper, the underlying file can be
+/// shared.
+pub struct ReadaheadRandomAccessFile {
+
+    file: Rc<dyn RandomAccessFile>,
+
+    initial_readahead_size: usize,
+
+    max_readahead_size: usize,
+
+    readahead_size: Cell<usize>,
+
+    // Prefetched bytes, starting at buffer_offset in the file
+    buffer: RefCell<Vec<u8>>,
+
+    buffer_offset: Cell<u64>,
+
+    // True if the buffer was cut short by the end of the file
+    buffer_at_eof: Cell<bool>,
+
+    // End offset of the previous read, to detect sequential reads
+    prev_end: Cell<u64>,
+
+    num_sequential_reads: Cell<usize>,
+
+    // Sequential reads needed before prefetching starts
+    min_sequential_reads: usize
+}
+
+impl ReadaheadRandomAccessFile {
+
+    /// Wraps `file`. A `max_readahead_size` of 0 disables readahead.
+    pub fn new(file: Rc<dyn RandomAccessFile>, initial_readahead_size: usize, max_readahead_size: usize) -> Self {
+        let initial_readahead_size = min(initial_readahead_size, max_readahead_size);
+        ReadaheadRandomAccessFile {
+            file,
+            initial_readahead_size,
+            max_readahead_size,
+            readahead_size: Cell::new(initial_readahead_size),
+            buffer: RefCell::new(Vec::new()),
+            buffer_offset: Cell::new(0),
+            buffer_at_eof: Cell::new(false),
+            prev_end: Cell::new(0),
+            num_sequential_reads: Cell::new(0),
+            min_sequential_reads: K_MIN_SEQUENTIAL_READS
+        }
+    }
+
+    /// Wraps `file` for a compaction input. Compactions read every file
+    /// front to back, so each read that misses the buffer prefetches a fixed
+    /// `readahead_size` bytes, starting with the very first one. A
+    /// `readahead_size` of 0 disables readahead.
+    pub fn for_compaction(file: Rc<dyn RandomAccessFile>, readahead_size: usize) -> Self {
+        let mut f = Self::new(file, readahead_size, readahead_size);
+        f.min_sequential_reads = 1;
+        f
+    }
+
+    /// Size of the next prefetch.
+    pub fn readahead_size(&self) -> usize {
+        self.readahead_size.get()
+    }
+
+    fn update_read_pattern(&self, offset: u64, n: usize) {
+        if self.num_sequential_reads.get() > 0 && offset == self.prev_end.get() {
+            self.num_sequential_reads.set(self.num_sequential_reads.get() + 1);
+        } else {
+            self.num_sequential_reads.set(1);
+            self.readahead_size.set(self.initial_readahead_size);
+        }
+        self.prev_end.set(offset + n as u64);
+    }
+
+    /// Copies [offset, offset + scratch.len()) out of the buffer if it holds
+    /// the whole range. Returns the number of bytes copied.
+    fn try_read_from_buffer(&self, offset: u64, scratch: &mut [u8]) -> Option<usize> {
+        let buffer = self.buffer.borrow();
+        let start = self.buffer_offset.get();
+        if offset < start {
+            return None;
+        }
+        let begin = (offset - start) as usize;
+        // Only a buffer ending at the end of the file can serve a read that
+        // extends past it
+        if begin > buffer.len() || (begin + scratch.len() > buffer.len() && !self.buffer_at_eof.get()) {
+            return None;
+        }
+        let n = min(scratch.len(), buffer.len() - begin);
+        scratch[..n].copy_from_slice(&buffer[begin..begin + n]);
+        Some(n)
+    }
+}
+
+impl RandomAccessFile for ReadaheadRandomAccessFile {
+
+    fn read<'a>(&'a self, offset: u64, scratch: &'a mut [u8]) -> Result<Slice<'a>> {
+        let n = scratch.len();
+        self.update_read_pattern(offset, n);
+        if let Some(copied) = self.try_read_from_buffer(offset, scratch) {
+            return Ok(Slice::from_bytes(&scratch[..copied]));
+        }
+        if self.max_readahead_size == 0 || self.num_sequential_reads.get() < self.min_sequential_reads {
+            return self.file.read(offset, scratch);
+        }
+
+        // Prefetch the requested bytes together with the readahead
+        let readahead = self.readahead_size();
+        let mut buffer = self.buffer.borrow_mut();
+        let mut prefetch = vec![0; n + readahead];
+        let read = self.file.read(offset, &mut prefetch)?.size();
+        prefetch.truncate(read);
+        *buffer = prefetch;
+        self.buffer_offset.set(offset);
+        self.buffer_at_eof.set(read < n + readahead);
+        self.readahead_size.set(min(readahead * 2, self.max_readahead_size));
+
+        let copied = min(n, read);
+        scratch[..copied].copy_from_slice(&buffer[..copied]);
+        Ok(Slice::from_bytes(&scratch[..copied]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An in-memory file that records the length of every read
+    struct CountingFile {
+        data: Vec<u8>,
+        reads: RefCell<Vec<usize>>
+    }
+
+    impl RandomAccessFile for CountingFile {
+        fn read<'a>(&'a self, offset: u64, scratch: &'a mut [u8]) -> Result<Slice<'a>> {
+            self.reads.borrow_mut().push(scratch.len());
+            let begin = min(offset as usize, self.data.len());
+            let n = min(scratch.len(), self.data.len() - begin);
+            scratch[..n].copy_from_slice(&self.data[begin..begin + n]);
+            Ok(Slice::from_bytes(&scratch[..n]))
+        }
+    }
+
+    fn new_file(size: usize) -> Rc<CountingFile> {
+        Rc::new(CountingFile {
+            data: (0..size).map(|i| i as u8).collect(),
+            reads: RefCell::new(Vec::new())
+        })
+    }
+
+    fn read(file: &ReadaheadRandomAccessFile, offset: u64, n: usize) -> Vec<u8> {
+        let mut scratch = vec![0; n];
+        file.read(offset, &mut scratch).expect("read error").data().to_vec()
+    }
+
+    fn expected(offset: usize, n: usize) -> Vec<u8> {
+        (offset..offset + n).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_sequential_reads_escalate() {
+        let base = new_file(1 << 16);
+        let file = ReadaheadRandomAccessFile::new(base.clone(), 1024, 4096);
+        let mut offset = 0;
+        for _ in 0..40 {
+            assert_eq!(expected(offset, 100), read(&file, offset as u64, 100));
+            offset += 100;
+        }
+        // The first read goes straight to the file, the second starts
+        // prefetching and every prefetch doubles up to the maximum
+        let reads = base.reads.borrow();
+        assert_eq!(vec![100, 1124, 2148, 4196], reads[..4].to_vec());
+        assert!(reads[4..].iter().all(|n| *n == 4196));
+        assert!(reads.len() < 40 / 2);
+        assert_eq!(4096, file.readahead_size());
+    }
+
+    #[test]
+    fn test_random_reads_reset() {
+        let base = new_file(1 << 16);
+        let file = ReadaheadRandomAccessFile::new(base.clone(), 1024, 4096);
+        let mut offset = 0;
+        while offset < 1324 {
+            assert_eq!(expected(offset, 100), read(&file, offset as u64, 100));
+            offset += 100;
+        }
+        assert_eq!(4096, file.readahead_size());
+        // A jump elsewhere is not sequential
+        assert_eq!(expected(10000, 10), read(&file, 10000, 10));
+        assert_eq!(1024, file.readahead_size());
+        assert_eq!(vec![100, 1124, 2148, 10], *base.reads.borrow());
+
+        // Readahead can be turned off
+        let base = new_file(1 << 16);
+        let file = ReadaheadRandomAccessFile::new(base.clone(), 1024, 0);
+        for i in 0..5 {
+            assert_eq!(expected(i * 10, 10), read(&file, (i * 10) as u64, 10));
+        }
+        assert_eq!(vec![10; 5], *base.reads.borrow());
+    }
+
+    #[test]
+    fn test_end_of_file() {
+        let base = new_file(1000);
+        let file = ReadaheadRandomAccessFile::new(base.clone(), 4096, 8192);
+        read(&file, 0, 100);
+        assert_eq!(expected(100, 100), read(&file, 100, 100));
+        // Served from the short prefetch, including a read past the end
+        assert_eq!(expected(200, 800), read(&file, 200, 800));
+        assert!(read(&file, 1000, 100).is_empty());
+        assert_eq!(vec![100, 4196], *base.reads.borrow());
+    }
+
+    #[test]
+    fn test_compaction_readahead() {
+        let base = new_file(10000);
+        let file = ReadaheadRandomAccessFile::for_compaction(base.clone(), 4096);
+        let mut offset = 0;
+        while offset < 10000 {
+            assert_eq!(expected(offset, 100), read(&file, offset as u64, 100));
+            offset += 100;
+        }
+        // Prefetching starts right away and never grows
+        assert_eq!(vec![4196, 4196, 4196], *base.reads.borrow());
+        assert_eq!(4096, file.readahead_size());
+
+        let base = new_file(1000);
+        let file = ReadaheadRandomAccessFile::for_compaction(base.clone(), 0);
+        read(&file, 0, 100);
+        read(&file, 100, 100);
+        assert_eq!(vec![100, 100], *base.reads.borrow());
+    }
+}
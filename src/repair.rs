@@ -0,0 +1,436 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! We recover the contents of the descriptor from the other files we find.
+//!
+//! (1) Any log files are first converted to tables
+//!
+//! (2) We scan every table to compute
+//!     (a) smallest/largest for the table
+//!     (b) largest sequence number in the table
+//!
+//! (3) We generate descriptor contents:
+//!      - log number is set to zero
+//!      - next-file-number is set to 1 + largest file number we found
+//!      - last-sequence-number is set to largest sequence# found across
+//!        all tables (see 2c)
+//!      - compaction pointers are cleared
+//!      - every table file is added at level 0
+//!
+//! Possible optimization 1:
+//!   (a) Compute total size and use to pick appropriate max-level M
+//!   (b) Sort tables by largest sequence# in the table
+//!   (c) For each table: if it overlaps earlier table, place in level-0,
+//!       else place in level-M.
+//!
+//! Possible optimization 2:
+//!   Store per-table metadata (smallest, largest, largest-seq#, ...)
+//!   in the table's meta section to speed up ScanTable.
+use std::cell::Cell;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::builder::build_table;
+use crate::dbformat::{parse_internal_key, InternalKeyComparator, SequenceNumber};
+use crate::env::{new_random_access_file, new_sequential_file, new_writable_file, try_lock_file};
+use crate::Error::{IOError, NotFound};
+use crate::iterator::InternalIterator;
+use crate::filename::{descriptor_file_name, lock_file_name, log_file_name, parse_file_name, set_current_file,
+                      sst_table_file_name, table_file_name, temp_file_name, FileType};
+use crate::log_reader::Reader;
+use crate::log_writer;
+use crate::memtable::MemTable;
+use crate::options::{Options, ReadOptions};
+use crate::Result;
+use crate::slice::Slice;
+use crate::table::Table;
+use crate::version_edit::{FileMetaData, VersionEdit};
+use crate::version_set::CorruptionReporter;
+use crate::write_batch::{insert_into, GroupReplay, WriteBatch};
+
+/// If a DB cannot be opened, you may attempt to call this method to
+/// resurrect as much of the contents of the database as possible.
+/// Some data may be lost, so be careful when calling this function
+/// on a database that contains important information.
+///
+/// The descriptor is rebuilt from the log and table files found in the
+/// db directory: logs are converted to tables, and every readable table
+/// is added to level-0 of a fresh MANIFEST. Files that cannot be used are
+/// moved to the "lost" subdirectory rather than deleted.
+pub fn repair_db(dbname: impl AsRef<Path>, options: &Options) -> Result<()> {
+    let dbname = dbname.as_ref();
+    let Some(_lock) = try_lock_file(lock_file_name(dbname))? else {
+        // "lock <dbname>/LOCK: already held by process"
+        return Err(IOError);
+    };
+    Repairer::new(dbname, options).run()
+}
+
+struct TableInfo {
+
+    meta: FileMetaData,
+
+    max_sequence: SequenceNumber
+}
+
+struct Repairer {
+
+    dbname: PathBuf,
+
+    options: Options,
+
+    manifests: Vec<PathBuf>,
+
+    table_numbers: Vec<u64>,
+
+    logs: Vec<u64>,
+
+    tables: Vec<TableInfo>,
+
+    next_file_number: u64
+}
+
+impl Repairer {
+
+    fn new(dbname: &Path, options: &Options) -> Self {
+        Repairer {
+            dbname: dbname.to_path_buf(),
+            options: options.clone(),
+            manifests: Vec::new(),
+            table_numbers: Vec::new(),
+            logs: Vec::new(),
+            tables: Vec::new(),
+            // The new descriptor is number 1
+            next_file_number: 2
+        }
+    }
+
+    fn run(mut self) -> Result<()> {
+        self.find_files()?;
+        self.convert_log_files_to_tables();
+        self.extract_meta_data();
+        self.write_descriptor()
+    }
+
+    fn find_files(&mut self) -> Result<()> {
+        let mut found = false;
+        for entry in std::fs::read_dir(&self.dbname)? {
+            let name = entry?.file_name();
+            let Some((number, file_type)) = name.to_str().and_then(parse_file_name) else {
+                continue;
+            };
+            if file_type == FileType::KDescriptorFile {
+                self.manifests.push(self.dbname.join(&name));
+            } else {
+                self.next_file_number = self.next_file_number.max(number + 1);
+                match file_type {
+                    FileType::kLogFile => self.logs.push(number),
+                    FileType::kTableFile => self.table_numbers.push(number),
+                    // Ignore other files, including the LOCK we hold
+                    _ => continue
+                }
+            }
+            found = true;
+        }
+        if !found {
+            // "repair found no files"
+            return Err(NotFound);
+        }
+        Ok(())
+    }
+
+    fn convert_log_files_to_tables(&mut self) {
+        self.logs.sort_unstable();
+        for log in std::mem::take(&mut self.logs) {
+            // A log that cannot be converted is dropped, like the records
+            // of a log that cannot be read
+            let _ = self.convert_log_to_table(log);
+            self.archive_file(&log_file_name(&self.dbname, log));
+        }
+    }
+
+    fn convert_log_to_table(&mut self, log: u64) -> Result<()> {
+        let file = new_sequential_file(log_file_name(&self.dbname, log))?;
+        // We intentionally make the reader do checksumming so that
+        // corruptions cause entire commits to be skipped instead of
+        // propagating bad information (like overly large sequence
+        // numbers).
+        let corrupted = Rc::new(Cell::new(false));
+        let reporter = CorruptionReporter { corrupted: corrupted.clone() };
+        let mut reader = Reader::new(file, Some(Box::new(reporter)), true, 0);
+
+        // Read all the records and add to a memtable
+        let mem = MemTable::new(InternalKeyComparator::new(self.options.comparator));
+        let mut replay = GroupReplay::new();
+        let mut scratch = Vec::new();
+        while let Some(record) = reader.read_record(&mut scratch) {
+            // The records dropped before this one may belong to the open group
+            if corrupted.replace(false) {
+                replay.abort();
+            }
+            let Ok(batches) = WriteBatch::from_data(record.data().to_vec()).and_then(|batch| replay.add(batch)) else {
+                // "log record too small" or malformed group, and batches
+                // that cannot be applied, are skipped with their group
+                replay.abort();
+                continue;
+            };
+            for batch in batches {
+                insert_into(&batch, &mem)?;
+            }
+        }
+
+        // Do not record a version edit for this conversion to a Table
+        // since extract_meta_data() will also generate edits.
+        let mut meta = FileMetaData { number: self.next_file_number, ..FileMetaData::default() };
+        self.next_file_number += 1;
+        build_table(&self.dbname, &self.options, &mut mem.iter(), &mem.range_tombstones(), &mut meta)?;
+        if meta.file_size > 0 {
+            self.table_numbers.push(meta.number);
+        }
+        Ok(())
+    }
+
+    fn extract_meta_data(&mut self) {
+        for number in std::mem::take(&mut self.table_numbers) {
+            match self.scan_table(number) {
+                Ok(Some(table)) => self.tables.push(table),
+                // Empty and unreadable tables are set aside
+                _ => {
+                    self.archive_file(&table_file_name(&self.dbname, number));
+                    self.archive_file(&sst_table_file_name(&self.dbname, number));
+                }
+            }
+        }
+    }
+
+    /// Read every entry of table `number` to find its smallest and largest
+    /// keys and sequence numbers. Returns None for a table without entries.
+    fn scan_table(&self, number: u64) -> Result<Option<TableInfo>> {
+        let fname = table_file_name(&self.dbname, number);
+        if !fname.exists() {
+            // Tables written by older releases use the legacy name, which
+            // the db no longer opens
+            std::fs::rename(sst_table_file_name(&self.dbname, number), &fname)?;
+        }
+        let file_size = std::fs::metadata(&fname)?.len();
+        let table = Rc::new(Table::open(&self.options, new_random_access_file(&fname)?, number, file_size)?);
+        let mut iter = table.iter(&ReadOptions { verify_checksums: true, fill_cache: false, snapshot: None });
+
+        let mut meta = FileMetaData {
+            number,
+            file_size,
+            smallest_seqno: SequenceNumber::MAX,
+            ..FileMetaData::default()
+        };
+        let mut max_sequence = 0;
+        let mut empty = true;
+        iter.seek_to_first();
+        while iter.valid() {
+            let key = iter.key();
+            // Entries with unparsable keys are kept, but do not count
+            if let Ok(parsed) = parse_internal_key(&key) {
+                if empty {
+                    empty = false;
+                    meta.smallest = key.data().to_vec();
+                }
+                meta.largest = key.data().to_vec();
+                meta.smallest_seqno = meta.smallest_seqno.min(parsed.sequence);
+                meta.largest_seqno = meta.largest_seqno.max(parsed.sequence);
+                max_sequence = max_sequence.max(parsed.sequence);
+            }
+            iter.next();
+        }
+        iter.status()?;
+        let icmp = InternalKeyComparator::new(self.options.comparator);
+        for tombstone in table.range_tombstones() {
+            empty = false;
+            meta.add_range_tombstone(&icmp, tombstone);
+            max_sequence = max_sequence.max(tombstone.sequence);
+        }
+        if empty {
+            return Ok(None);
+        }
+        Ok(Some(TableInfo { meta, max_sequence }))
+    }
+
+    fn write_descriptor(&mut self) -> Result<()> {
+        let tmp = temp_file_name(&self.dbname, 1);
+        let max_sequence = self.tables.iter().map(|t| t.max_sequence).max().unwrap_or(0);
+
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(self.options.comparator_name);
+        edit.set_format_version(self.options.format_version);
+        edit.set_log_number(0);
+        edit.set_next_file(self.next_file_number);
+        edit.set_last_sequence(max_sequence);
+        for t in &self.tables {
+            // TODO(opt): separate out into multiple levels
+            edit.add_file(0, t.meta.clone());
+        }
+
+        let result = (|| {
+            let file = new_writable_file(&tmp, self.options.allow_mmap_writes)?;
+            let mut log = log_writer::Writer::new(file.clone());
+            let mut record = Vec::new();
+            edit.encode_to(&mut record);
+            log.add_record(&Slice::from_bytes(&record))?;
+            file.borrow().sync()?;
+            file.borrow().close()?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e);
+        }
+
+        // Discard older manifests
+        for manifest in std::mem::take(&mut self.manifests) {
+            self.archive_file(&manifest);
+        }
+
+        // Install new manifest
+        std::fs::rename(&tmp, descriptor_file_name(&self.dbname, 1))?;
+        set_current_file(&self.dbname, 1)
+    }
+
+    fn archive_file(&self, fname: &Path) {
+        if !fname.exists() {
+            return;
+        }
+        // Move into another directory.  E.g., for
+        //    dir/foo
+        // rename to
+        //    dir/lost/foo
+        let lost = self.dbname.join("lost");
+        // Ignore error
+        let _ = create_dir_all(&lost);
+        if let Some(name) = fname.file_name() {
+            let _ = std::fs::rename(fname, lost.join(name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::DB;
+    use crate::Error::{IOError, NotFound};
+    use crate::options::WriteOptions;
+    use crate::version_set::VersionSet;
+    use super::*;
+
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("revel_repair_test_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn key(i: usize) -> String {
+        format!("key{:04}", i)
+    }
+
+    /// Fill the db with tables holding keys [0, 1000) and a log holding
+    /// keys [1000, 1100), then remove its descriptor.
+    fn build_and_lose_descriptor(dbname: &Path) {
+        let mut db = DB::open(&Options::default(), dbname).unwrap();
+        let value = "v".repeat(100);
+        for i in 0..1000 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&key(i)), &Slice::from_str(&value)).unwrap();
+        }
+        drop(db);
+        // Recovery flushes the log into several tables
+        let options = Options { write_buffer_size: 16 << 10, ..Options::default() };
+        let mut db = DB::open(&options, dbname).unwrap();
+        for i in 1000..1100 {
+            db.put(&WriteOptions::default(), &Slice::from_str(&key(i)), &Slice::from_str(&value)).unwrap();
+        }
+        drop(db);
+
+        for entry in std::fs::read_dir(dbname).unwrap() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            if name == "CURRENT" || name.starts_with("MANIFEST") {
+                std::fs::remove_file(&path).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_repair_lost_descriptor() {
+        let dbname = db_path("lost_descriptor");
+        build_and_lose_descriptor(&dbname);
+
+        repair_db(&dbname, &Options::default()).unwrap();
+        // The converted log is set aside
+        assert!(dbname.join("lost").read_dir().unwrap().any(|e| e.unwrap().path().extension().unwrap() == "log"));
+
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        let read_options = ReadOptions::default();
+        for i in 0..1100 {
+            assert!(db.get(&read_options, &Slice::from_str(&key(i))).is_ok());
+        }
+        assert_eq!(1100, db.scan(&read_options, None, None).count());
+        assert_eq!(1100, db.latest_sequence_number());
+    }
+
+    #[test]
+    fn test_repair_legacy_table_name() {
+        let dbname = db_path("legacy_table_name");
+        build_and_lose_descriptor(&dbname);
+        let mut renamed = 0;
+        for entry in std::fs::read_dir(&dbname).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "ldb") {
+                std::fs::rename(&path, path.with_extension("sst")).unwrap();
+                renamed += 1;
+            }
+        }
+        assert!(renamed > 0);
+
+        repair_db(&dbname, &Options::default()).unwrap();
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(1100, db.scan(&ReadOptions::default(), None, None).count());
+    }
+
+    #[test]
+    fn test_repair_corrupted_table() {
+        let dbname = db_path("corrupted_table");
+        build_and_lose_descriptor(&dbname);
+        std::fs::write(table_file_name(&dbname, 100), b"not a table").unwrap();
+
+        repair_db(&dbname, &Options::default()).unwrap();
+        assert!(!table_file_name(&dbname, 100).exists());
+        assert!(dbname.join("lost").join("000100.ldb").exists());
+
+        // The damaged file's number is never handed out again
+        let mut versions = VersionSet::new(&dbname, &Options::default());
+        versions.recover().unwrap();
+        assert!(versions.new_file_number() > 100);
+        drop(versions);
+
+        let db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(1100, db.scan(&ReadOptions::default(), None, None).count());
+    }
+
+    #[test]
+    fn test_repair_no_files() {
+        let dbname = db_path("no_files");
+        std::fs::create_dir_all(&dbname).unwrap();
+        assert_eq!(Err(NotFound), repair_db(&dbname, &Options::default()));
+    }
+
+    #[test]
+    fn test_repair_open_db() {
+        let dbname = db_path("open_db");
+        let _db = DB::open(&Options::default(), &dbname).unwrap();
+        assert_eq!(Err(IOError), repair_db(&dbname, &Options::default()));
+    }
+}
@@ -10,8 +10,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::cell::RefCell;
 use std::iter::Iterator;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use crate::random::Random;
 
@@ -37,9 +37,9 @@ pub struct SkipList<K> where K: Default {
     
     max_height: AtomicUsize,
     
-    rand: RefCell<Random>,
+    rand: Mutex<Random>,
     
-    comparator: Box<dyn Cmp<K>>
+    comparator: Box<dyn Cmp<K> + Send + Sync>
     
 }
 
@@ -59,7 +59,7 @@ impl <K> Node<K> {
         }
     }
     
-    fn no_barrier_set_next(&mut self, n: usize, node: *const Node<K>) {
+    fn no_barrier_set_next(&self, n: usize, node: *const Node<K>) {
         self.next[n].store(node as *mut Node<K>, Ordering::Relaxed);
     }
     
@@ -79,15 +79,18 @@ impl <K> Node<K> {
 
 impl<K> SkipList<K> where K: Default {
     
-    pub fn new(comparator: Box<dyn Cmp<K>>) -> Self {
+    pub fn new(comparator: Box<dyn Cmp<K> + Send + Sync>) -> Self {
         SkipList {
             comparator,
             max_height: AtomicUsize::new(1),
             head: Node::new_node(K::default(), MAX_HEIGHT),
-            rand: RefCell::new(Random::new(0xdeadbeef))
+            rand: Mutex::new(Random::new(0xdeadbeef))
         }
     }
     
+    /// Insert key into the list. Readers may iterate the list while keys
+    /// are inserted, but inserts must not run concurrently with each other.
+    /// REQUIRES: nothing that compares equal to key is currently in the list.
     pub fn insert(&self, key: K) {
         let (_, mut prev) = self.find_greater_or_equal(&key, true);
         let height = self.random_height();
@@ -103,7 +106,9 @@ impl<K> SkipList<K> where K: Default {
             unsafe {
                 let pre_next = (*prev[i]).no_barrier_next(i);
                 (*new_node_ptr).no_barrier_set_next(i, pre_next);
-                (&mut *(prev[i] as *mut Node<K>)).no_barrier_set_next(i, new_node_ptr);
+                // Publish the node only after its own links are in place, a
+                // reader following this link must see them.
+                (*prev[i]).set_next(i, new_node_ptr);
             }
         }
     }
@@ -129,7 +134,7 @@ impl<K> SkipList<K> where K: Default {
                     prev[level] = x;
                 }
                 if level == 0 {
-                    return if x.is_null() {
+                    return if next.is_null() {
                         (None, Box::new(prev))
                     } else {
                         unsafe { (Some(&*next), Box::new(prev)) }
@@ -143,7 +148,8 @@ impl<K> SkipList<K> where K: Default {
     fn random_height(&self) -> usize {
         const kBranching: usize = 4;
         let mut height: usize = 1;
-        while height < MAX_HEIGHT && self.rand.borrow_mut().one_in(kBranching as i32) {
+        let mut rand = self.rand.lock().unwrap();
+        while height < MAX_HEIGHT && rand.one_in(kBranching as i32) {
             height += 1;
         }
         assert!(height > 0);
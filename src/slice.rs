@@ -11,6 +11,8 @@
 // limitations under the License.
 
 use std::cmp::Ordering;
+use std::fmt;
+use crate::util::logging::{escape_string, to_hex};
 
 #[derive(Debug, PartialEq, Eq, Ord, PartialOrd)]
 pub struct Slice<'a> {
@@ -39,7 +41,7 @@ impl<'a> Slice<'a> {
         self.data.len()
     }
     
-    pub fn data(&self) -> &[u8]{
+    pub fn data(&self) -> &'a [u8] {
         self.data
     }
 
@@ -52,6 +54,24 @@ impl<'a> Slice<'a> {
     pub fn empty(&self) -> bool {
         self.size == 0
     }
+
+    /// Return a human-readable version of the slice, with the bytes that
+    /// are not printable ASCII escaped, e.g. "foo\x00\x01".
+    pub fn escaped_string(&self) -> String {
+        escape_string(self.data)
+    }
+
+    /// Return the slice as upper case hex, two digits per byte.
+    pub fn to_hex(&self) -> String {
+        to_hex(self.data)
+    }
+}
+
+impl<'a> fmt::Display for Slice<'a> {
+    /// Formats the slice as escaped_string().
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.escaped_string())
+    }
 }
 
 #[test]
@@ -65,4 +85,13 @@ fn test() {
     assert_eq!(slice3, slice4);
     let slice5 = Slice::from_bytes("124".as_bytes());
     assert_eq!(slice3.cmp(&slice5), Ordering::Less);
+}
+
+#[test]
+fn test_formatting() {
+    let slice = Slice::from_bytes(b"foo\x00\x01");
+    assert_eq!("foo\\x00\\x01", slice.escaped_string());
+    assert_eq!("foo\\x00\\x01", slice.to_string());
+    assert_eq!("666F6F0001", slice.to_hex());
+    assert_eq!("", Slice::from_empty().to_hex());
 }
\ No newline at end of file
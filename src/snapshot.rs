@@ -0,0 +1,193 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use crate::dbformat::SequenceNumber;
+
+/// Abstract handle to particular state of a DB. Reads through a snapshot,
+/// see ReadOptions::snapshot, see the db as it was when the snapshot was
+/// taken, whatever was written since.
+///
+/// The snapshot is released when dropped, after which compactions are
+/// free to drop the old entries it kept alive. Holding on to a snapshot
+/// for long makes the db keep every overwritten entry since.
+#[derive(Debug)]
+pub struct Snapshot {
+
+    // List of the snapshots of the db, the snapshot is removed from it when
+    // released. The snapshot does not keep the db alive.
+    list: Weak<RefCell<SnapshotList>>,
+
+    // Node of the snapshot in the list
+    node: usize,
+
+    sequence_number: SequenceNumber
+}
+
+impl Snapshot {
+
+    /// Create a snapshot at `sequence_number`, registered in `list` until
+    /// it is released.
+    pub(crate) fn new(list: &Rc<RefCell<SnapshotList>>, sequence_number: SequenceNumber) -> Self {
+        let node = list.borrow_mut().insert(sequence_number);
+        Snapshot { list: Rc::downgrade(list), node, sequence_number }
+    }
+
+    /// Return the sequence number of the last update visible through this
+    /// snapshot.
+    pub fn sequence_number(&self) -> SequenceNumber {
+        self.sequence_number
+    }
+
+    /// Release the snapshot. Same as dropping it, spelled out.
+    pub fn release(self) {}
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Some(list) = self.list.upgrade() {
+            list.borrow_mut().delete(self.node, self.sequence_number);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+
+    sequence_number: SequenceNumber,
+
+    prev: usize,
+
+    next: usize
+}
+
+/// The snapshots of a db, as a circular doubly-linked list ordered by
+/// sequence number: a new snapshot is never older than those already in
+/// the list, so it is appended at the end. The nodes live in a vector and
+/// link to each other by index; released nodes are reused.
+#[derive(Debug)]
+pub(crate) struct SnapshotList {
+
+    // nodes[0] is the dummy head of the list
+    nodes: Vec<Node>,
+
+    // Released nodes, available for new snapshots
+    free: Vec<usize>,
+
+    len: usize
+}
+
+impl SnapshotList {
+
+    pub fn new() -> Self {
+        SnapshotList {
+            nodes: vec![Node { sequence_number: 0, prev: 0, next: 0 }],
+            free: Vec::new(),
+            len: 0
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return the sequence number of the oldest snapshot.
+    /// REQUIRES: !is_empty()
+    pub fn oldest(&self) -> SequenceNumber {
+        assert!(!self.is_empty());
+        self.nodes[self.nodes[0].next].sequence_number
+    }
+
+    /// Return the sequence number of the newest snapshot.
+    /// REQUIRES: !is_empty()
+    pub fn newest(&self) -> SequenceNumber {
+        assert!(!self.is_empty());
+        self.nodes[self.nodes[0].prev].sequence_number
+    }
+
+    /// Append a snapshot at `sequence_number` to the list, and return its
+    /// node.
+    /// REQUIRES: the list is empty or sequence_number >= newest()
+    fn insert(&mut self, sequence_number: SequenceNumber) -> usize {
+        assert!(self.is_empty() || self.newest() <= sequence_number);
+        let last = self.nodes[0].prev;
+        let node = Node { sequence_number, prev: last, next: 0 };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            },
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        };
+        self.nodes[last].next = index;
+        self.nodes[0].prev = index;
+        self.len += 1;
+        index
+    }
+
+    /// Remove the snapshot of `node` from the list.
+    fn delete(&mut self, index: usize, sequence_number: SequenceNumber) {
+        assert!(index != 0 && self.nodes[index].sequence_number == sequence_number);
+        let (prev, next) = (self.nodes[index].prev, self.nodes[index].next);
+        self.nodes[prev].next = next;
+        self.nodes[next].prev = prev;
+        self.free.push(index);
+        self.len -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_list() {
+        let list = Rc::new(RefCell::new(SnapshotList::new()));
+        let state = || {
+            let list = list.borrow();
+            if list.is_empty() { (0, 0, 0) } else { (list.len(), list.oldest(), list.newest()) }
+        };
+        assert_eq!((0, 0, 0), state());
+        let s1 = Snapshot::new(&list, 10);
+        let s2 = Snapshot::new(&list, 20);
+        let s3 = Snapshot::new(&list, 20);
+        assert_eq!((3, 10, 20), state());
+        assert_eq!(20, s3.sequence_number());
+
+        // Releasing from the middle keeps the order
+        s2.release();
+        assert_eq!((2, 10, 20), state());
+        drop(s1);
+        assert_eq!((1, 20, 20), state());
+
+        // Released nodes are reused
+        let s4 = Snapshot::new(&list, 30);
+        let s5 = Snapshot::new(&list, 40);
+        assert_eq!(4, list.borrow().nodes.len());
+        assert_eq!((3, 20, 40), state());
+        drop(s3);
+        drop(s5);
+        assert_eq!((1, 30, 30), state());
+
+        // A snapshot outliving its list has nothing left to release
+        drop(list);
+        drop(s4);
+    }
+}
@@ -0,0 +1,145 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! statistics collected while the db is running
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::util::histogram::Histogram;
+
+/// Operations whose latencies are recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramType {
+    Get = 0,
+    Write = 1,
+    Seek = 2,
+    Flush = 3,
+    Compaction = 4
+}
+
+impl HistogramType {
+
+    pub const ALL: [HistogramType; 5] = [
+        HistogramType::Get,
+        HistogramType::Write,
+        HistogramType::Seek,
+        HistogramType::Flush,
+        HistogramType::Compaction
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HistogramType::Get => "get",
+            HistogramType::Write => "write",
+            HistogramType::Seek => "seek",
+            HistogramType::Flush => "flush",
+            HistogramType::Compaction => "compaction"
+        }
+    }
+}
+
+/// Events whose occurrences are counted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ticker {
+    BlockCacheHit = 0,
+    BlockCacheMiss = 1,
+    BlockCacheAdd = 2,
+    BlockCacheAddFailures = 3,
+    NumberKeysWritten = 4,
+    BytesWritten = 5,
+    WalFileSynced = 6
+}
+
+impl Ticker {
+
+    pub const ALL: [Ticker; 7] = [
+        Ticker::BlockCacheHit,
+        Ticker::BlockCacheMiss,
+        Ticker::BlockCacheAdd,
+        Ticker::BlockCacheAddFailures,
+        Ticker::NumberKeysWritten,
+        Ticker::BytesWritten,
+        Ticker::WalFileSynced
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Ticker::BlockCacheHit => "block.cache.hit",
+            Ticker::BlockCacheMiss => "block.cache.miss",
+            Ticker::BlockCacheAdd => "block.cache.add",
+            Ticker::BlockCacheAddFailures => "block.cache.add.failures",
+            Ticker::NumberKeysWritten => "number.keys.written",
+            Ticker::BytesWritten => "bytes.written",
+            Ticker::WalFileSynced => "wal.synced"
+        }
+    }
+}
+
+pub struct Statistics {
+    tickers: Vec<AtomicU64>,
+    histograms: Vec<Mutex<Histogram>>
+}
+
+impl Default for Statistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Statistics {
+
+    pub fn new() -> Self {
+        Statistics {
+            tickers: Ticker::ALL.iter().map(|_| AtomicU64::new(0)).collect(),
+            histograms: HistogramType::ALL.iter().map(|_| Mutex::new(Histogram::new())).collect()
+        }
+    }
+
+    /// Adds `count` occurrences of `t`.
+    pub fn record_tick(&self, t: Ticker, count: u64) {
+        self.tickers[t as usize].fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns the number of occurrences of `t` recorded so far.
+    pub fn ticker_count(&self, t: Ticker) -> u64 {
+        self.tickers[t as usize].load(Ordering::Relaxed)
+    }
+
+    /// Formats the count of every ticker, one per line.
+    pub fn ticker_string(&self) -> String {
+        let mut value = String::new();
+        for t in Ticker::ALL {
+            let _ = writeln!(value, "{} COUNT : {}", t.name(), self.ticker_count(t));
+        }
+        value
+    }
+
+    /// Records the latency of one operation of type `t`, in microseconds.
+    pub fn measure_time(&self, t: HistogramType, elapsed: Duration) {
+        self.histograms[t as usize].lock().unwrap().add(elapsed.as_micros() as f64);
+    }
+
+    /// Formats the histogram of every operation type that has been recorded
+    /// at least once.
+    pub fn histogram_string(&self) -> String {
+        let mut value = String::new();
+        for t in HistogramType::ALL {
+            let histogram = self.histograms[t as usize].lock().unwrap();
+            if histogram.count() == 0 {
+                continue;
+            }
+            let _ = write!(value, "** {} (micros) **\n{}\n", t.name(), histogram);
+        }
+        value
+    }
+}
@@ -0,0 +1,796 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod block;
+pub mod block_builder;
+pub mod data_block_hash_index;
+pub mod filter_block;
+pub mod format;
+pub mod index_builder;
+pub mod merger;
+pub mod table_builder;
+pub mod two_level_iterator;
+
+use std::rc::Rc;
+use std::sync::Arc;
+use crate::cache::CachePriority;
+use crate::coding::put_varint64;
+use crate::dbformat::{extract_user_key, InternalKeyComparator};
+use crate::env::RandomAccessFile;
+use crate::Error::Corruption;
+use crate::iterator::{EmptyIterator, InternalIterator};
+use crate::options::{IndexType, Options, ReadOptions};
+use crate::range_del::{RangeTombstone, K_RANGE_DEL_BLOCK};
+use crate::readahead::ReadaheadRandomAccessFile;
+use crate::Result;
+use crate::slice::Slice;
+use crate::table::block::Block;
+use crate::table::filter_block::FilterBlockReader;
+use crate::table::format::{decode_index_type, read_block, BlockHandle, Footer, K_INDEX_TYPE_META_KEY};
+use crate::table::table_builder::filter_meta_key;
+use crate::table::two_level_iterator::TwoLevelIterator;
+use crate::table_properties::{TableProperties, K_PROPERTIES_BLOCK};
+
+/// A Table is a sorted map from internal keys to values, read from a file
+/// written by TableBuilder. Tables are immutable and persistent.
+pub struct Table {
+
+    options: Options,
+
+    icmp: Rc<InternalKeyComparator>,
+
+    file: Rc<dyn RandomAccessFile>,
+
+    // Number of the table file, to tell which file a damaged block is in
+    file_number: u64,
+
+    footer: Footer,
+
+    index_type: IndexType,
+
+    // Prefix of the block cache keys of the blocks of the table
+    cache_id: u64,
+
+    // The index block, the top-level index of a partitioned index. Kept
+    // in memory while the table is open, unless it is read through the
+    // block cache (options.cache_index_and_filter_blocks).
+    index_block: Option<Rc<Block>>,
+
+    index_handle: BlockHandle,
+
+    // Filter block of the data blocks, if the table has one built by
+    // options.filter_policy. Kept in memory like the index block.
+    filter: Option<Rc<FilterBlockReader>>,
+
+    filter_handle: Option<BlockHandle>,
+
+    // Range tombstones of the table, read when it is opened
+    range_tombstones: Vec<RangeTombstone>,
+
+    properties: TableProperties
+}
+
+impl Table {
+
+    /// Attempt to open the table that is stored in bytes [0..file_size)
+    /// of `file`, the table file numbered `file_number`, and read the
+    /// metadata entries necessary to allow retrieving data from the table.
+    ///
+    /// Returns Corruption if the file is not a table, and NotSupport if it
+    /// was written by a newer release in a format this one cannot read.
+    /// The checksums of the index and meta blocks are verified if
+    /// options.paranoid_checks is set.
+    pub fn open(options: &Options, file: Rc<dyn RandomAccessFile>, file_number: u64, size: u64) -> Result<Table> {
+        if size < Footer::K_ENCODED_LENGTH as u64 {
+            // "file is too short to be an sstable"
+            return Err(Corruption);
+        }
+        let mut footer_space = [0; Footer::K_ENCODED_LENGTH];
+        let footer_input = file.read(size - Footer::K_ENCODED_LENGTH as u64, &mut footer_space)?;
+        let footer = Footer::decode_from(footer_input.data())?;
+        let icmp = Rc::new(InternalKeyComparator::new(options.comparator));
+
+        let mut index_type = IndexType::BinarySearch;
+        let mut filter_handle = None;
+        let mut properties_handle = None;
+        let mut range_del_handle = None;
+        let filter_key = options.filter_policy.as_ref().map(|policy| filter_meta_key(policy.as_ref()));
+        let meta_index_block = Rc::new(Block::new(read_block(file.as_ref(), file_number, &footer.metaindex_handle, options.paranoid_checks)?)?);
+        // Meta entries are only iterated, never searched by internal key
+        let mut iter = meta_index_block.iter(icmp.clone());
+        iter.seek_to_first();
+        while iter.valid() {
+            if iter.key().data() == K_INDEX_TYPE_META_KEY {
+                index_type = decode_index_type(iter.value().data())?;
+            } else if iter.key().data() == K_PROPERTIES_BLOCK {
+                properties_handle = Some(BlockHandle::decode_from(iter.value().data())?.0);
+            } else if iter.key().data() == K_RANGE_DEL_BLOCK {
+                range_del_handle = Some(BlockHandle::decode_from(iter.value().data())?.0);
+            } else if filter_key.as_deref() == Some(iter.key().data()) {
+                filter_handle = BlockHandle::decode_from(iter.value().data()).ok().map(|(handle, _)| handle);
+            }
+            iter.next();
+        }
+        iter.status()?;
+
+        // Tables written before properties were recorded have none
+        let mut properties = TableProperties::default();
+        if let Some(handle) = properties_handle {
+            let properties_block = Rc::new(Block::new(read_block(file.as_ref(), file_number, &handle, options.paranoid_checks)?)?);
+            let mut iter = properties_block.iter(icmp.clone());
+            iter.seek_to_first();
+            while iter.valid() {
+                properties.decode_property(iter.key().data(), iter.value().data())?;
+                iter.next();
+            }
+            iter.status()?;
+        }
+
+        let mut range_tombstones = Vec::new();
+        if let Some(handle) = range_del_handle {
+            let range_del_block = Rc::new(Block::new(read_block(file.as_ref(), file_number, &handle, options.paranoid_checks)?)?);
+            let mut iter = range_del_block.iter(icmp.clone());
+            iter.seek_to_first();
+            while iter.valid() {
+                range_tombstones.push(RangeTombstone::decode(&iter.key(), &iter.value())?);
+                iter.next();
+            }
+            iter.status()?;
+        }
+
+        let mut table = Table {
+            options: options.clone(),
+            icmp,
+            file,
+            file_number,
+            footer,
+            index_type,
+            cache_id: options.block_cache.as_ref().map_or(0, |cache| cache.new_id()),
+            index_block: None,
+            index_handle: footer.index_handle,
+            filter: None,
+            filter_handle,
+            range_tombstones,
+            properties
+        };
+        if !table.cache_index_and_filter_blocks() {
+            let read_options = ReadOptions { verify_checksums: options.paranoid_checks, ..ReadOptions::default() };
+            table.index_block = Some(table.read_index_block(&read_options)?);
+            table.filter = table.read_filter(&read_options);
+        }
+        Ok(table)
+    }
+
+    /// Range tombstones of the table, ordered by start key.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Properties recorded in the table when it was built.
+    pub fn properties(&self) -> &TableProperties {
+        &self.properties
+    }
+
+    /// Returns the first entry of the table at or after the internal key
+    /// `key`, or None if every entry is before it. The filter and the hash
+    /// index of data blocks may also return None when the table holds no
+    /// entry for the user key of `key`, so the caller checks the user key
+    /// of the entry.
+    pub fn get(&self, options: &ReadOptions, key: &Slice) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let Some(handle) = self.find_data_block(options, key)? else {
+            return Ok(None);
+        };
+        let filter = match self.filter.as_ref() {
+            Some(filter) => Some(filter.clone()),
+            None if self.cache_index_and_filter_blocks() => self.read_filter(options),
+            None => None
+        };
+        if let Some(filter) = filter {
+            if !filter.key_may_match(handle.offset, extract_user_key(key).data()) {
+                // Not found
+                return Ok(None);
+            }
+        }
+        let contents = self.read_block_cached(self.file.as_ref(), &handle, options, CachePriority::Low)?;
+        let block = Rc::new(Block::new(contents)?);
+        let mut iter = block.iter(self.icmp.clone());
+        iter.seek_for_get(key);
+        iter.status()?;
+        Ok(iter.valid().then(|| (iter.key().data().to_vec(), iter.value().data().to_vec())))
+    }
+
+    /// Given a key, return an approximate byte offset in the file where
+    /// the data for that key begins (or would begin if the key were
+    /// present in the file). The returned value is in terms of file
+    /// bytes, and so includes effects like compression of the underlying
+    /// data. E.g., the approximate offset of the last key in the table
+    /// will be close to the file length.
+    pub fn approximate_offset_of(&self, key: &Slice) -> u64 {
+        match self.find_data_block(&ReadOptions::default(), key) {
+            Ok(Some(handle)) => handle.offset,
+            // key is past the last key in the file (or the index cannot
+            // be read). Approximate the offset by returning the offset of
+            // the metaindex block (which is right near the end of the
+            // file).
+            _ => self.footer.metaindex_handle.offset
+        }
+    }
+
+    /// Returns an iterator over the contents of the table. The result of
+    /// iter() is initially invalid (caller must call one of the seek
+    /// methods on the iterator before using it). If the
+    /// index block cannot be read, the iterator reports the error. The
+    /// iterator holds a reference to the table, keeping it open.
+    pub fn iter(self: &Rc<Self>, options: &ReadOptions) -> TwoLevelIterator<'static> {
+        // Reads of a scan are sequential, so they go through readahead
+        let file: Rc<dyn RandomAccessFile> = Rc::new(ReadaheadRandomAccessFile::new(
+            self.file.clone(), self.options.initial_auto_readahead_size, self.options.max_auto_readahead_size));
+        self.iter_over(file, options)
+    }
+
+    /// Like iter(), for a compaction reading the whole table front to back:
+    /// every read prefetches options.compaction_readahead_size bytes.
+    pub fn compaction_iter(self: &Rc<Self>, options: &ReadOptions) -> TwoLevelIterator<'static> {
+        let file: Rc<dyn RandomAccessFile> = Rc::new(ReadaheadRandomAccessFile::for_compaction(
+            self.file.clone(), self.options.compaction_readahead_size));
+        self.iter_over(file, options)
+    }
+
+    fn iter_over(self: &Rc<Self>, file: Rc<dyn RandomAccessFile>, options: &ReadOptions) -> TwoLevelIterator<'static> {
+        let index_block = match self.index_block.as_ref() {
+            Some(index_block) => Ok(index_block.clone()),
+            None => self.read_index_block(options)
+        };
+        let index_iter: Box<dyn InternalIterator> = match (index_block, self.index_type) {
+            (Err(e), _) => Box::new(EmptyIterator::new(Err(e))),
+            (Ok(index_block), IndexType::BinarySearch) => Box::new(index_block.iter(self.icmp.clone())),
+            (Ok(index_block), IndexType::TwoLevelIndexSearch) => {
+                // The top-level index yields the partitions, which yield
+                // the data blocks
+                let (table, file, options) = (self.clone(), file.clone(), options.without_snapshot());
+                Box::new(TwoLevelIterator::new(
+                    Box::new(index_block.iter(self.icmp.clone())),
+                    Box::new(move |index_value| table.block_reader(file.as_ref(), index_value, &options, CachePriority::High))))
+            }
+        };
+        let (table, options) = (self.clone(), options.without_snapshot());
+        TwoLevelIterator::new(index_iter, Box::new(move |index_value| table.block_reader(file.as_ref(), index_value, &options, CachePriority::Low)))
+    }
+
+    /// Convert an index iterator value (i.e., an encoded BlockHandle)
+    /// into an iterator over the contents of the corresponding block.
+    fn block_reader(&self, file: &dyn RandomAccessFile, index_value: &[u8], options: &ReadOptions, priority: CachePriority) -> Result<Box<dyn InternalIterator>> {
+        let (handle, _) = BlockHandle::decode_from(index_value)?;
+        let block = Rc::new(Block::new(self.read_block_cached(file, &handle, options, priority)?)?);
+        Ok(Box::new(block.iter(self.icmp.clone())))
+    }
+
+    /// Whether the index and filter blocks are read through the block
+    /// cache rather than kept by the table.
+    fn cache_index_and_filter_blocks(&self) -> bool {
+        self.options.cache_index_and_filter_blocks && self.options.block_cache.is_some()
+    }
+
+    fn read_index_block(&self, options: &ReadOptions) -> Result<Rc<Block>> {
+        let contents = self.read_block_cached(self.file.as_ref(), &self.index_handle, options, CachePriority::High)?;
+        Ok(Rc::new(Block::new(contents)?))
+    }
+
+    /// A filter that cannot be read only costs extra block reads, so
+    /// errors are not propagated.
+    fn read_filter(&self, options: &ReadOptions) -> Option<Rc<FilterBlockReader>> {
+        let (policy, handle) = (self.options.filter_policy.as_ref()?, self.filter_handle.as_ref()?);
+        let contents = self.read_block_cached(self.file.as_ref(), handle, options, CachePriority::High).ok()?;
+        Some(Rc::new(FilterBlockReader::new(policy.clone(), contents)))
+    }
+
+    /// Reads the block of `handle`, through the block cache if there is
+    /// one. Blocks read while opening the table, and index and filter
+    /// blocks unless options.cache_index_and_filter_blocks is set, are
+    /// kept by the table and never go through the cache. Fails with
+    /// MemoryLimit if the cache has a strict capacity limit and the block
+    /// does not fit. Blocks missing from the cache are only inserted if
+    /// options.fill_cache is set.
+    fn read_block_cached(&self, file: &dyn RandomAccessFile, handle: &BlockHandle, options: &ReadOptions, priority: CachePriority) -> Result<Arc<Vec<u8>>> {
+        let cache = match self.options.block_cache.as_ref() {
+            Some(cache) if priority == CachePriority::Low || self.cache_index_and_filter_blocks() => cache,
+            _ => return Ok(Arc::new(read_block(file, self.file_number, handle, options.verify_checksums)?))
+        };
+        let mut key = self.cache_id.to_le_bytes().to_vec();
+        put_varint64(&mut key, handle.offset);
+        if let Some(contents) = cache.lookup(&key) {
+            return Ok(contents);
+        }
+        let contents = read_block(file, self.file_number, handle, options.verify_checksums)?;
+        if !options.fill_cache {
+            return Ok(Arc::new(contents));
+        }
+        let charge = contents.len();
+        cache.insert(&key, contents, charge, priority)
+    }
+
+    /// Returns the handle of the first data block whose last key is at or
+    /// after `key`, going through the partition of a partitioned index.
+    fn find_data_block(&self, options: &ReadOptions, key: &Slice) -> Result<Option<BlockHandle>> {
+        let index_block = match self.index_block.as_ref() {
+            Some(index_block) => index_block.clone(),
+            None => self.read_index_block(options)?
+        };
+        let Some(handle) = self.seek_index(&index_block, key)? else {
+            return Ok(None);
+        };
+        if self.index_type != IndexType::TwoLevelIndexSearch {
+            return Ok(Some(handle));
+        }
+        let contents = self.read_block_cached(self.file.as_ref(), &handle, options, CachePriority::High)?;
+        let partition = Rc::new(Block::new(contents)?);
+        self.seek_index(&partition, key)
+    }
+
+    /// Returns the handle of the first block of `index` whose last key is
+    /// at or after `key`.
+    fn seek_index(&self, index: &Rc<Block>, key: &Slice) -> Result<Option<BlockHandle>> {
+        let mut iter = index.iter(self.icmp.clone());
+        iter.seek(key);
+        iter.status()?;
+        if !iter.valid() {
+            return Ok(None);
+        }
+        Ok(Some(BlockHandle::decode_from(iter.value().data())?.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use crate::cache::{LRUCacheOptions, ShardedLRUCache};
+    use crate::dbformat::{append_internal_key, ValueType, K_MAX_SEQUENCE_NUMBER};
+    use crate::env::{MemoryRandomAccessFile, MemoryWritableFile};
+    use crate::Error::{BlockChecksumMismatch, MemoryLimit, NotSupport};
+    use crate::filter_policy::{BloomFilterPolicy, FilterPolicy};
+    use crate::options::{CompressionType, DataBlockIndexType, K_LATEST_FORMAT_VERSION};
+    use crate::table::format::compress_block;
+    use crate::table::table_builder::TableBuilder;
+    use super::*;
+
+    fn ikey(user_key: &str, sequence: u64) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str(user_key), sequence, ValueType::KTypeValue);
+        key
+    }
+
+    fn build_table(options: &Options, entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let file = Rc::new(RefCell::new(MemoryWritableFile::new(Vec::new())));
+        let mut builder = TableBuilder::new(options, file.clone());
+        for (key, value) in entries {
+            builder.add(key, value).unwrap();
+        }
+        builder.finish().unwrap();
+        assert_eq!(file.borrow().contents().len() as u64, builder.file_size());
+        let contents = file.borrow().contents().to_vec();
+        contents
+    }
+
+    fn open_table(options: &Options, contents: Vec<u8>) -> Result<Rc<Table>> {
+        let size = contents.len() as u64;
+        Table::open(options, Rc::new(MemoryRandomAccessFile::new(Rc::new(contents))), 0, size).map(Rc::new)
+    }
+
+    fn collect(iter: &mut dyn InternalIterator, forward: bool) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = Vec::new();
+        while iter.valid() {
+            entries.push((iter.key().data().to_vec(), iter.value().data().to_vec()));
+            if forward {
+                iter.next();
+            } else {
+                iter.prev();
+            }
+        }
+        assert_eq!(Ok(()), iter.status());
+        entries
+    }
+
+    fn check_table(options: &Options) {
+        let entries: Vec<_> = (0..1000)
+            .map(|i| (ikey(&format!("key{:06}", i * 2), i), format!("value{}", i).repeat(i as usize % 7).into_bytes()))
+            .collect();
+        let table = open_table(options, build_table(options, &entries)).unwrap();
+        assert_eq!(options.format_version, table.footer.format_version);
+        assert_eq!(entries.len() as u64, table.properties().num_entries);
+        let read_options = ReadOptions::default();
+
+        for (key, value) in &entries {
+            assert_eq!(Some((key.clone(), value.clone())), table.get(&read_options, &Slice::from_bytes(key)).unwrap());
+        }
+        // Lookups land on the first entry at or after the key
+        let found = table.get(&read_options, &Slice::from_bytes(&ikey("key000501", 0))).unwrap();
+        if options.data_block_index_type == DataBlockIndexType::BinarySearch && options.filter_policy.is_none() {
+            assert_eq!(Some(entries[251].clone()), found);
+        } else {
+            // The hash index or the filter knows the table has no entry
+            // for the user key
+            assert!(found.is_none() || found == Some(entries[251].clone()));
+        }
+        assert_eq!(None, table.get(&read_options, &Slice::from_bytes(&ikey("key999999", 0))).unwrap());
+
+        let mut iter = table.iter(&read_options);
+        assert!(!iter.valid());
+        iter.seek_to_first();
+        assert_eq!(entries, collect(&mut iter, true));
+        iter.seek_to_last();
+        assert_eq!(entries.iter().rev().cloned().collect::<Vec<_>>(), collect(&mut iter, false));
+        iter.seek(&Slice::from_bytes(&ikey("key000501", 0)));
+        assert_eq!(entries[251..], collect(&mut iter, true)[..]);
+        iter.seek(&Slice::from_bytes(&ikey("key000501", 0)));
+        iter.prev();
+        assert_eq!(entries[..251].iter().rev().cloned().collect::<Vec<_>>(), collect(&mut iter, false));
+        iter.seek(&Slice::from_bytes(&ikey("key999999", 0)));
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn test_table() {
+        check_table(&Options { block_size: 256, ..Options::default() });
+        // A single data block
+        check_table(&Options { block_size: 1 << 20, ..Options::default() });
+    }
+
+    #[test]
+    fn test_table_compression() {
+        check_table(&Options { block_size: 256, compression: CompressionType::NoCompression, ..Options::default() });
+
+        let entries: Vec<_> = (0..100).map(|i| (ikey(&format!("key{:06}", i), 1), vec![b'x'; 1000])).collect();
+        let uncompressed = build_table(&Options { compression: CompressionType::NoCompression, ..Options::default() }, &entries);
+        for compression in [CompressionType::Snappy, CompressionType::Lz4, CompressionType::Zstd] {
+            let options = Options { compression, ..Options::default() };
+            let compressed = build_table(&options, &entries);
+            // Backends that are not compiled in store blocks uncompressed
+            if compress_block(compression, b"").is_some() {
+                assert!(compressed.len() * 10 < uncompressed.len(), "{:?}", compression);
+            } else {
+                assert_eq!(uncompressed.len(), compressed.len());
+            }
+            let table = open_table(&options, compressed).unwrap();
+            let mut iter = table.iter(&ReadOptions::default());
+            iter.seek_to_first();
+            assert_eq!(entries, collect(&mut iter, true));
+        }
+
+        // Incompressible data blocks are stored as they are, only the index
+        // shrinks
+        let mut rnd = crate::random::Random::new(301);
+        let entries: Vec<_> = (0..100)
+            .map(|i| (ikey(&format!("key{:06}", i), 1), (0..1000).map(|_| rnd.next() as u8).collect::<Vec<u8>>()))
+            .collect();
+        let compressed = build_table(&Options::default(), &entries);
+        let uncompressed = build_table(&Options { compression: CompressionType::NoCompression, ..Options::default() }, &entries);
+        assert!(compressed.len() > uncompressed.len() - uncompressed.len() / 100);
+        let table = open_table(&Options::default(), compressed).unwrap();
+        let mut iter = table.iter(&ReadOptions::default());
+        iter.seek_to_first();
+        assert_eq!(entries, collect(&mut iter, true));
+    }
+
+    #[test]
+    fn test_table_index_layouts() {
+        check_table(&Options {
+            format_version: K_LATEST_FORMAT_VERSION,
+            block_size: 256,
+            data_block_index_type: DataBlockIndexType::BinaryAndHash,
+            ..Options::default()
+        });
+        check_table(&Options {
+            format_version: K_LATEST_FORMAT_VERSION,
+            block_size: 256,
+            index_type: IndexType::TwoLevelIndexSearch,
+            metadata_block_size: 128,
+            ..Options::default()
+        });
+    }
+
+    /// Builds empty filters that match no key.
+    struct MatchNothing;
+
+    impl FilterPolicy for MatchNothing {
+        fn name(&self) -> &str {
+            "MatchNothing"
+        }
+
+        fn create_filter(&self, _keys: &[&[u8]], _dst: &mut Vec<u8>) {}
+
+        fn key_may_match(&self, _key: &[u8], _filter: &[u8]) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_table_filter() {
+        check_table(&Options {
+            block_size: 256,
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            ..Options::default()
+        });
+
+        let options = Options { filter_policy: Some(Arc::new(MatchNothing)), ..Options::default() };
+        let entry = (ikey("a", 1), b"v".to_vec());
+        let contents = build_table(&options, std::slice::from_ref(&entry));
+        let key = Slice::from_bytes(&entry.0);
+        // The filter is consulted only by readers using the same policy
+        let table = open_table(&options, contents.clone()).unwrap();
+        assert_eq!(None, table.get(&ReadOptions::default(), &key).unwrap());
+        let table = open_table(&Options::default(), contents.clone()).unwrap();
+        assert_eq!(Some(entry.clone()), table.get(&ReadOptions::default(), &key).unwrap());
+        let bloom = Options { filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))), ..Options::default() };
+        let table = open_table(&bloom, contents).unwrap();
+        assert_eq!(Some(entry.clone()), table.get(&ReadOptions::default(), &key).unwrap());
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let options = Options { compression: CompressionType::NoCompression, ..Options::default() };
+        let entry = (ikey("key", 1), b"value".to_vec());
+        let mut contents = build_table(&options, std::slice::from_ref(&entry));
+        // Damage the value in the data block at the start of the file
+        let pos = contents.windows(5).position(|w| w == b"value").unwrap();
+        contents[pos] = b'V';
+        let table = open_table(&options, contents).unwrap();
+        let key = Slice::from_bytes(&entry.0);
+
+        let unverified = table.get(&ReadOptions::default(), &key).unwrap();
+        assert_eq!(Some((entry.0.clone(), b"Value".to_vec())), unverified);
+
+        let verify = ReadOptions { verify_checksums: true, ..ReadOptions::default() };
+        // The error tells the damaged block, the data block at the start
+        // of the file
+        let damaged = BlockChecksumMismatch { file_number: 0, offset: 0 };
+        assert_eq!(Err(damaged.clone()), table.get(&verify, &key));
+        let mut iter = table.iter(&verify);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert_eq!(Err(damaged), iter.status());
+
+        // paranoid_checks verifies the index and meta blocks when the table
+        // is opened. The meta index block is the last block of the table.
+        let mut contents = build_table(&options, std::slice::from_ref(&entry));
+        let meta_index_pos = contents.len() - Footer::K_ENCODED_LENGTH - 10;
+        contents[meta_index_pos] ^= 0x1;
+        let paranoid = Options { paranoid_checks: true, ..options.clone() };
+        let meta_index_offset = Footer::decode_from(&contents[contents.len() - Footer::K_ENCODED_LENGTH..]).unwrap().metaindex_handle.offset;
+        assert_eq!(Err(BlockChecksumMismatch { file_number: 0, offset: meta_index_offset }), open_table(&paranoid, contents).map(|_| ()));
+    }
+
+    #[test]
+    fn test_empty_table() {
+        let options = Options::default();
+        let table = open_table(&options, build_table(&options, &[])).unwrap();
+        assert_eq!(None, table.get(&ReadOptions::default(), &Slice::from_bytes(&ikey("a", 0))).unwrap());
+        let mut iter = table.iter(&ReadOptions::default());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        assert_eq!(Ok(()), iter.status());
+    }
+
+    #[test]
+    fn test_approximate_offset_of() {
+        let options = Options { block_size: 1024, compression: CompressionType::NoCompression, ..Options::default() };
+        let entries = vec![
+            (ikey("k01", 1), b"hello".to_vec()),
+            (ikey("k02", 1), b"hello2".to_vec()),
+            (ikey("k03", 1), vec![b'x'; 10000]),
+            (ikey("k04", 1), vec![b'x'; 200000]),
+            (ikey("k05", 1), vec![b'x'; 300000]),
+            (ikey("k06", 1), b"hello3".to_vec()),
+            (ikey("k07", 1), vec![b'x'; 100000]),
+        ];
+        for index_type in [IndexType::BinarySearch, IndexType::TwoLevelIndexSearch] {
+            let options = Options { index_type, metadata_block_size: 64, ..options.clone() };
+            let table = open_table(&options, build_table(&options, &entries)).unwrap();
+            let offset = |key: &str| table.approximate_offset_of(&Slice::from_bytes(&ikey(key, K_MAX_SEQUENCE_NUMBER)));
+            let between = |key: &str, low: u64, high: u64| {
+                let offset = offset(key);
+                assert!(low <= offset && offset <= high, "{} at {} not in [{}, {}]", key, offset, low, high);
+            };
+            between("abc", 0, 0);
+            between("k01", 0, 0);
+            between("k01a", 0, 0);
+            between("k02", 0, 0);
+            between("k03", 0, 0);
+            between("k04", 10000, 11000);
+            between("k04a", 210000, 211000);
+            between("k05", 210000, 211000);
+            between("k06", 510000, 511000);
+            between("k07", 510000, 511000);
+            between("xyz", 610000, 612000);
+        }
+    }
+
+    #[test]
+    fn test_bad_table() {
+        let options = Options::default();
+        let contents = build_table(&options, &[(ikey("a", 1), b"v".to_vec())]);
+        assert_eq!(Err(Corruption), open_table(&options, contents[..Footer::K_ENCODED_LENGTH - 1].to_vec()).map(|_| ()));
+        assert_eq!(Err(Corruption), open_table(&options, contents[1..].to_vec()).map(|_| ()));
+
+        let newer = Options { format_version: K_LATEST_FORMAT_VERSION + 1, ..Options::default() };
+        let contents = build_table(&newer, &[(ikey("a", 1), b"v".to_vec())]);
+        assert_eq!(Err(NotSupport), open_table(&options, contents).map(|_| ()));
+    }
+
+    struct CountCollector {
+        count: u64
+    }
+
+    impl crate::table_properties::TablePropertiesCollector for CountCollector {
+        fn add(&mut self, user_key: &[u8], _value: &[u8]) {
+            if user_key.ends_with(b"0") {
+                self.count += 1;
+            }
+        }
+
+        fn finish(&mut self) -> Vec<(String, Vec<u8>)> {
+            vec![("test.count".to_string(), self.count.to_le_bytes().to_vec())]
+        }
+    }
+
+    struct CountCollectorFactory;
+
+    impl crate::table_properties::TablePropertiesCollectorFactory for CountCollectorFactory {
+        fn name(&self) -> &str {
+            "CountCollectorFactory"
+        }
+
+        fn create_table_properties_collector(&self) -> Box<dyn crate::table_properties::TablePropertiesCollector> {
+            Box::new(CountCollector { count: 0 })
+        }
+    }
+
+    #[test]
+    fn test_table_properties() {
+        let options = Options {
+            block_size: 256,
+            filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+            table_properties_collectors: vec![Arc::new(CountCollectorFactory)],
+            ..Options::default()
+        };
+        let entries: Vec<_> = (0..100).map(|i| (ikey(&format!("key{:06}", i), 1), format!("value{}", i).into_bytes())).collect();
+        let file = Rc::new(RefCell::new(MemoryWritableFile::new(Vec::new())));
+        let mut builder = TableBuilder::new(&options, file.clone());
+        for (key, value) in &entries {
+            builder.add(key, value).unwrap();
+        }
+        builder.finish().unwrap();
+        let contents = file.borrow().contents().to_vec();
+        let built = open_table(&options, contents.clone()).unwrap().properties().clone();
+
+        assert_eq!(100, built.num_entries);
+        assert!(built.num_data_blocks > 1);
+        assert_eq!(entries.iter().map(|(key, _)| key.len() as u64).sum::<u64>(), built.raw_key_size);
+        assert_eq!(entries.iter().map(|(_, value)| value.len() as u64).sum::<u64>(), built.raw_value_size);
+        assert!(built.data_size > 0 && built.index_size > 0 && built.filter_size > 0);
+        assert!(built.data_size + built.index_size + built.filter_size < contents.len() as u64);
+        assert!(built.creation_time > 0);
+        assert_eq!(Some(&10u64.to_le_bytes().to_vec()), built.user_collected_properties.get("test.count"));
+
+        // Properties do not depend on the options the table is read with
+        let table = open_table(&Options::default(), build_table(&options, &entries)).unwrap();
+        assert_eq!(100, table.properties().num_entries);
+        assert_eq!(1, table.properties().user_collected_properties.len());
+    }
+
+    #[test]
+    fn test_table_block_cache() {
+        let entries: Vec<_> = (0..1000).map(|i| (ikey(&format!("key{:06}", i), 1), format!("value{}", i).into_bytes())).collect();
+        for cache_index_and_filter_blocks in [false, true] {
+            let cache = Arc::new(ShardedLRUCache::new(1 << 20));
+            let options = Options {
+                block_size: 256,
+                filter_policy: Some(Arc::new(BloomFilterPolicy::new(10))),
+                block_cache: Some(cache.clone()),
+                cache_index_and_filter_blocks,
+                ..Options::default()
+            };
+            check_table(&options);
+            cache.prune();
+            assert_eq!(0, cache.entry_count());
+
+            let table = open_table(&options, build_table(&options, &entries)).unwrap();
+            assert_eq!(0, cache.entry_count());
+            let read_options = ReadOptions::default();
+            let (key, value) = &entries[500];
+            assert_eq!(Some((key.clone(), value.clone())), table.get(&read_options, &Slice::from_bytes(key)).unwrap());
+            // The data block, plus the index and filter blocks if they are
+            // cached
+            let cached = if cache_index_and_filter_blocks { 3 } else { 1 };
+            assert_eq!(cached, cache.entry_count());
+            // Lookups of the same block hit the cache
+            let hits = cache.hits();
+            assert_eq!(Some((key.clone(), value.clone())), table.get(&read_options, &Slice::from_bytes(key)).unwrap());
+            assert_eq!(hits + cached as u64, cache.hits());
+            assert_eq!(cached, cache.entry_count());
+
+            let mut iter = table.iter(&read_options);
+            iter.seek_to_first();
+            assert_eq!(entries, collect(&mut iter, true));
+            assert!(cache.entry_count() > cached);
+            drop(iter);
+
+            // Blocks are read again once evicted
+            cache.prune();
+            assert_eq!(0, cache.entry_count());
+            assert_eq!(Some((key.clone(), value.clone())), table.get(&read_options, &Slice::from_bytes(key)).unwrap());
+        }
+
+        // The index block can be missing from the cache when it has a strict
+        // capacity limit
+        let cache = Arc::new(ShardedLRUCache::with_options(LRUCacheOptions { strict_capacity_limit: true, ..LRUCacheOptions::new(0) }));
+        let options = Options { block_cache: Some(cache.clone()), cache_index_and_filter_blocks: true, ..Options::default() };
+        let table = open_table(&options, build_table(&options, &entries)).unwrap();
+        let (key, _) = &entries[500];
+        assert_eq!(Err(MemoryLimit), table.get(&ReadOptions::default(), &Slice::from_bytes(key)));
+        let mut iter = table.iter(&ReadOptions::default());
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert_eq!(Err(MemoryLimit), iter.status());
+    }
+
+    #[test]
+    fn test_tables_share_block_cache() {
+        // Tables with blocks at the same offsets get distinct cache keys
+        // from ShardedLRUCache::new_id
+        let cache = Arc::new(ShardedLRUCache::new(1 << 20));
+        let options = Options { block_cache: Some(cache.clone()), ..Options::default() };
+        let key = ikey("key", 1);
+        let tables: Vec<_> = [b"a", b"b"].iter()
+            .map(|value| open_table(&options, build_table(&options, &[(key.clone(), value.to_vec())])).unwrap())
+            .collect();
+        assert_ne!(tables[0].cache_id, tables[1].cache_id);
+        for _ in 0..2 {
+            assert_eq!(Some((key.clone(), b"a".to_vec())), tables[0].get(&ReadOptions::default(), &Slice::from_bytes(&key)).unwrap());
+            assert_eq!(Some((key.clone(), b"b".to_vec())), tables[1].get(&ReadOptions::default(), &Slice::from_bytes(&key)).unwrap());
+        }
+        assert_eq!(2, cache.entry_count());
+    }
+
+    #[test]
+    fn test_fill_cache() {
+        let cache = Arc::new(ShardedLRUCache::new(1 << 20));
+        let options = Options {
+            block_size: 256,
+            block_cache: Some(cache.clone()),
+            cache_index_and_filter_blocks: true,
+            ..Options::default()
+        };
+        let entries: Vec<_> = (0..1000).map(|i| (ikey(&format!("key{:06}", i), 1), format!("value{}", i).into_bytes())).collect();
+        let table = open_table(&options, build_table(&options, &entries)).unwrap();
+
+        // A scan that does not fill the cache leaves it untouched
+        let scan = ReadOptions { fill_cache: false, ..ReadOptions::default() };
+        let mut iter = table.iter(&scan);
+        iter.seek_to_first();
+        assert_eq!(entries, collect(&mut iter, true));
+        drop(iter);
+        let (key, value) = &entries[500];
+        assert_eq!(Some((key.clone(), value.clone())), table.get(&scan, &Slice::from_bytes(key)).unwrap());
+        assert_eq!(0, cache.entry_count());
+
+        // but still uses the blocks that are cached
+        table.get(&ReadOptions::default(), &Slice::from_bytes(key)).unwrap();
+        assert_eq!(2, cache.entry_count());
+        let hits = cache.hits();
+        table.get(&scan, &Slice::from_bytes(key)).unwrap();
+        assert_eq!(hits + 2, cache.hits());
+        assert_eq!(2, cache.entry_count());
+    }
+}
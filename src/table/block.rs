@@ -0,0 +1,441 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reader of the blocks written by BlockBuilder, see block_builder for
+//! their layout.
+
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::sync::Arc;
+use crate::coding::{decode_fix32, get_varint32};
+use crate::comparator::Comparator;
+use crate::Error::Corruption;
+use crate::iterator::InternalIterator;
+use crate::options::DataBlockIndexType;
+use crate::Result;
+use crate::slice::Slice;
+use crate::table::block_builder::decode_restart_array;
+use crate::table::data_block_hash_index::{decode_block_footer, DataBlockHashIndex, K_COLLISION, K_NO_ENTRY};
+
+pub struct Block {
+
+    // Shared with the block cache when the block was read through it, so
+    // that the cache entry stays pinned while the block is in use
+    data: Arc<Vec<u8>>,
+
+    // End of the entries, where the restart array starts
+    data_end: usize,
+
+    // Offsets of the restart points
+    restarts: Vec<u32>,
+
+    // Whether the block ends with a hash index over its user keys
+    has_hash_index: bool
+}
+
+impl Block {
+
+    /// Parses the trailer of `contents`, the contents of a block without
+    /// its block trailer.
+    pub fn new(contents: impl Into<Arc<Vec<u8>>>) -> Result<Block> {
+        let contents = contents.into();
+        if contents.len() < 4 {
+            // "bad block contents"
+            return Err(Corruption);
+        }
+        let (restarts, data_end, has_hash_index) = match decode_block_footer(&contents) {
+            (DataBlockIndexType::BinaryAndHash, num_restarts) => {
+                let num_restarts = num_restarts as usize;
+                let (_, restarts_end) = DataBlockHashIndex::new(&contents[..contents.len() - 4]).ok_or(Corruption)?;
+                let restarts_start = restarts_end.checked_sub(num_restarts * 4).ok_or(Corruption)?;
+                let restarts = (0..num_restarts).map(|i| decode_fix32(&contents[restarts_start + i * 4..])).collect();
+                (restarts, restarts_start, true)
+            }
+            (DataBlockIndexType::BinarySearch, _) => {
+                let (restarts, data_end) = decode_restart_array(&contents)?;
+                (restarts, data_end, false)
+            }
+        };
+        if restarts.iter().any(|r| *r as usize > data_end) {
+            // "restart point past the entries"
+            return Err(Corruption);
+        }
+        Ok(Block { data: contents, data_end, restarts, has_hash_index })
+    }
+
+    /// Returns an iterator over the entries of the block, ordered by
+    /// `comparator`.
+    pub fn iter(self: &Rc<Self>, comparator: Rc<dyn Comparator>) -> BlockIter {
+        BlockIter {
+            block: self.clone(),
+            comparator,
+            current: self.data_end,
+            restart_index: self.restarts.len(),
+            key: Vec::new(),
+            value_offset: 0,
+            value_len: 0,
+            status: Ok(())
+        }
+    }
+}
+
+/// Decodes the header of the entry at `offset`. Returns the number of
+/// shared key bytes, of non shared key bytes, the value length and the
+/// length of the header.
+fn decode_entry(data: &[u8], offset: usize, limit: usize) -> Option<(usize, usize, usize, usize)> {
+    let mut p = offset;
+    let mut next = || {
+        let (value, n) = get_varint32(data, p, limit).ok()?;
+        p += n;
+        Some(value as usize)
+    };
+    let shared = next()?;
+    let non_shared = next()?;
+    let value_length = next()?;
+    let header_len = p - offset;
+    if limit - p < non_shared + value_length {
+        return None;
+    }
+    Some((shared, non_shared, value_length, header_len))
+}
+
+pub struct BlockIter {
+
+    block: Rc<Block>,
+
+    comparator: Rc<dyn Comparator>,
+
+    // Offset in data of current entry.  >= data_end if !valid
+    current: usize,
+
+    // Index of restart block in which current falls
+    restart_index: usize,
+
+    key: Vec<u8>,
+
+    value_offset: usize,
+
+    value_len: usize,
+
+    status: Result<()>
+}
+
+impl BlockIter {
+
+    fn num_restarts(&self) -> usize {
+        self.block.restarts.len()
+    }
+
+    /// Return the offset in data just past the end of the current entry.
+    fn next_entry_offset(&self) -> usize {
+        self.value_offset + self.value_len
+    }
+
+    fn get_restart_point(&self, index: usize) -> usize {
+        self.block.restarts[index] as usize
+    }
+
+    fn seek_to_restart_point(&mut self, index: usize) {
+        self.key.clear();
+        self.restart_index = index;
+        // current will be fixed by parse_next_key();
+
+        // parse_next_key() starts at the end of value, so set value accordingly
+        self.value_offset = self.get_restart_point(index);
+        self.value_len = 0;
+    }
+
+    fn invalidate(&mut self) {
+        self.current = self.block.data_end;
+        self.restart_index = self.num_restarts();
+    }
+
+    fn corruption_error(&mut self) {
+        self.invalidate();
+        self.status = Err(Corruption);
+        self.key.clear();
+        self.value_offset = 0;
+        self.value_len = 0;
+    }
+
+    fn parse_next_key(&mut self) -> bool {
+        self.current = self.next_entry_offset();
+        let limit = self.block.data_end;
+        if self.current >= limit {
+            // No more entries to return.  Mark as invalid.
+            self.invalidate();
+            return false;
+        }
+
+        // Decode next entry
+        match decode_entry(&self.block.data, self.current, limit) {
+            Some((shared, non_shared, value_len, header_len)) if shared <= self.key.len() => {
+                let p = self.current + header_len;
+                self.key.truncate(shared);
+                self.key.extend_from_slice(&self.block.data[p..p + non_shared]);
+                self.value_offset = p + non_shared;
+                self.value_len = value_len;
+                while self.restart_index + 1 < self.num_restarts()
+                    && self.get_restart_point(self.restart_index + 1) < self.current {
+                    self.restart_index += 1;
+                }
+                true
+            }
+            _ => {
+                self.corruption_error();
+                false
+            }
+        }
+    }
+
+    fn compare(&self, a: &[u8], b: &Slice) -> Ordering {
+        self.comparator.compare(&Slice::from_bytes(a), b)
+    }
+
+    /// Position at the first entry at or past `target` for a point lookup.
+    /// Blocks with a hash index use it to skip the binary search, and leave
+    /// the iterator invalid instead when that entry is not for the user
+    /// key of `target`, an internal key.
+    pub fn seek_for_get(&mut self, target: &Slice) {
+        if !self.block.has_hash_index || target.size() < 8 {
+            return self.seek(target);
+        }
+        let user_key = &target.data()[..target.size() - 8];
+        let data = &self.block.data;
+        let restart = match DataBlockHashIndex::new(&data[..data.len() - 4]) {
+            Some((index, _)) => index.lookup(user_key),
+            None => K_COLLISION
+        };
+        match restart {
+            K_NO_ENTRY => return self.invalidate(),
+            K_COLLISION => self.seek(target),
+            r if r as usize >= self.num_restarts() => return self.corruption_error(),
+            r => {
+                self.seek_to_restart_point(r as usize);
+                // Linear search within the restart interval for first key >= target
+                while self.parse_next_key() && self.compare(&self.key, target) == Ordering::Less {}
+            }
+        }
+        if self.valid() && self.key.get(..self.key.len().saturating_sub(8)) != Some(user_key) {
+            // The key is not in this block
+            self.invalidate();
+        }
+    }
+}
+
+impl InternalIterator for BlockIter {
+
+    fn valid(&self) -> bool {
+        self.current < self.block.data_end
+    }
+
+    fn seek_to_first(&mut self) {
+        if self.num_restarts() == 0 {
+            return self.invalidate();
+        }
+        self.seek_to_restart_point(0);
+        self.parse_next_key();
+    }
+
+    fn seek_to_last(&mut self) {
+        if self.num_restarts() == 0 {
+            return self.invalidate();
+        }
+        self.seek_to_restart_point(self.num_restarts() - 1);
+        while self.parse_next_key() && self.next_entry_offset() < self.block.data_end {
+            // Keep skipping
+        }
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        if self.num_restarts() == 0 {
+            return self.invalidate();
+        }
+        // Binary search in restart array to find the last restart point
+        // with a key < target
+        let mut left = 0;
+        let mut right = self.num_restarts() - 1;
+        while left < right {
+            let mid = (left + right).div_ceil(2);
+            let region_offset = self.get_restart_point(mid);
+            let (shared, non_shared, _, header_len) = match decode_entry(&self.block.data, region_offset, self.block.data_end) {
+                Some(entry) => entry,
+                None => return self.corruption_error()
+            };
+            if shared != 0 {
+                return self.corruption_error();
+            }
+            let p = region_offset + header_len;
+            if self.compare(&self.block.data[p..p + non_shared], target) == Ordering::Less {
+                // Key at "mid" is smaller than "target".  Therefore all
+                // blocks before "mid" are uninteresting.
+                left = mid;
+            } else {
+                // Key at "mid" is >= "target".  Therefore all blocks at or
+                // after "mid" are uninteresting.
+                right = mid - 1;
+            }
+        }
+
+        // Linear search (within restart block) for first key >= target
+        self.seek_to_restart_point(left);
+        while self.parse_next_key() && self.compare(&self.key, target) == Ordering::Less {}
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        self.parse_next_key();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+
+        // Scan backwards to a restart point before current
+        let original = self.current;
+        while self.get_restart_point(self.restart_index) >= original {
+            if self.restart_index == 0 {
+                // No more entries
+                return self.invalidate();
+            }
+            self.restart_index -= 1;
+        }
+
+        self.seek_to_restart_point(self.restart_index);
+        // Loop until end of current entry hits the start of original entry
+        while self.parse_next_key() && self.next_entry_offset() < original {}
+    }
+
+    fn key(&self) -> Slice<'_> {
+        assert!(self.valid());
+        Slice::from_bytes(&self.key)
+    }
+
+    fn value(&self) -> Slice<'_> {
+        assert!(self.valid());
+        Slice::from_bytes(&self.block.data[self.value_offset..self.value_offset + self.value_len])
+    }
+
+    fn status(&self) -> Result<()> {
+        self.status.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dbformat::{append_internal_key, InternalKeyComparator, ValueType};
+    use crate::comparator::bytewise_compare;
+    use crate::table::block_builder::BlockBuilder;
+    use super::*;
+
+    fn ikey(user_key: &str, sequence: u64) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str(user_key), sequence, ValueType::KTypeValue);
+        key
+    }
+
+    fn entries(n: usize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..n).map(|i| (ikey(&format!("key{:05}", i * 2), 1), format!("v{}", i).repeat(i % 3).into_bytes())).collect()
+    }
+
+    fn new_iter(builder: &mut BlockBuilder, entries: &[(Vec<u8>, Vec<u8>)]) -> BlockIter {
+        for (key, value) in entries {
+            builder.add(key, value);
+        }
+        let block = Rc::new(Block::new(builder.finish().to_vec()).unwrap());
+        block.iter(Rc::new(InternalKeyComparator::new(bytewise_compare)))
+    }
+
+    fn current(iter: &BlockIter) -> Option<(Vec<u8>, Vec<u8>)> {
+        iter.valid().then(|| (iter.key().data().to_vec(), iter.value().data().to_vec()))
+    }
+
+    fn check_iter(iter: &mut BlockIter, entries: &[(Vec<u8>, Vec<u8>)]) {
+        let mut forward = Vec::new();
+        iter.seek_to_first();
+        while let Some(entry) = current(iter) {
+            forward.push(entry);
+            iter.next();
+        }
+        assert_eq!(entries, &forward[..]);
+
+        let mut backward = Vec::new();
+        iter.seek_to_last();
+        while let Some(entry) = current(iter) {
+            backward.push(entry);
+            iter.prev();
+        }
+        backward.reverse();
+        assert_eq!(entries, &backward[..]);
+
+        for (i, (key, _)) in entries.iter().enumerate() {
+            iter.seek(&Slice::from_bytes(key));
+            assert_eq!(Some(entries[i].clone()), current(iter));
+            // Keys between two entries land on the second one
+            let user_key = String::from_utf8(key[..key.len() - 8].to_vec()).unwrap();
+            iter.seek(&Slice::from_bytes(&ikey(&user_key, 0)));
+            assert_eq!(entries.get(i + 1).cloned(), current(iter));
+            iter.seek_for_get(&Slice::from_bytes(key));
+            assert_eq!(Some(entries[i].clone()), current(iter));
+            if i > 0 {
+                iter.seek(&Slice::from_bytes(key));
+                iter.prev();
+                assert_eq!(Some(entries[i - 1].clone()), current(iter));
+            }
+        }
+        iter.seek(&Slice::from_bytes(&ikey("zzz", 1)));
+        assert!(!iter.valid());
+        assert_eq!(Ok(()), iter.status());
+    }
+
+    #[test]
+    fn test_block_iter() {
+        for n in [0, 1, 2, 15, 16, 17, 100] {
+            for interval in [1, 2, 16] {
+                let entries = entries(n);
+                check_iter(&mut new_iter(&mut BlockBuilder::new(interval), &entries), &entries);
+                check_iter(&mut new_iter(&mut BlockBuilder::with_delta_encoded_restarts(interval), &entries), &entries);
+                check_iter(&mut new_iter(&mut BlockBuilder::with_hash_index(interval, 0.75), &entries), &entries);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seek_for_get() {
+        let entries = entries(100);
+        let mut iter = new_iter(&mut BlockBuilder::with_hash_index(4, 0.75), &entries);
+        // A user key missing from the block leaves the iterator invalid
+        for i in 0..100 {
+            iter.seek_for_get(&Slice::from_bytes(&ikey(&format!("key{:05}", i * 2 + 1), 1)));
+            assert!(!iter.valid());
+        }
+        // So does a key whose entries all precede the target
+        iter.seek_for_get(&Slice::from_bytes(&ikey("key00198", 0)));
+        assert!(!iter.valid());
+        assert_eq!(Ok(()), iter.status());
+    }
+
+    #[test]
+    fn test_corrupt_block() {
+        assert_eq!(Err(Corruption), Block::new(vec![0; 3]).map(|_| ()));
+        // More restarts than the block can hold
+        assert_eq!(Err(Corruption), Block::new(vec![0, 0, 0, 0, 9, 0, 0, 0]).map(|_| ()));
+
+        // An entry whose value runs past the restart array
+        let mut contents = vec![0, 1, 100, b'k'];
+        contents.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0]);
+        let block = Rc::new(Block::new(contents).unwrap());
+        let mut iter = block.iter(Rc::new(InternalKeyComparator::new(bytewise_compare)));
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert_eq!(Err(Corruption), iter.status());
+    }
+}
@@ -0,0 +1,401 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! BlockBuilder generates blocks where keys are prefix-compressed:
+//!
+//! When we store a key, we drop the prefix shared with the previous
+//! string.  This helps reduce the space requirement significantly.
+//! Furthermore, once every K keys, we do not apply the prefix
+//! compression and store the entire key.  We call this a "restart
+//! point".  The tail end of the block stores the offsets of all of the
+//! restart points, and can be used to do a binary search when looking
+//! for a particular key.  Values are stored as-is (without compression)
+//! immediately following the corresponding key.
+//!
+//! An entry for a particular key-value pair has the form:
+//!     shared_bytes: varint32
+//!     unshared_bytes: varint32
+//!     value_length: varint32
+//!     key_delta: char[unshared_bytes]
+//!     value: char[value_length]
+//! shared_bytes == 0 for restart points.
+//!
+//! The trailer of the block has the form:
+//!     restarts: uint32[num_restarts]
+//!     num_restarts: uint32
+//! restarts[i] contains the offset within the block of the ith restart point.
+//! Data blocks built with a hash index carry it between the restart array
+//! and num_restarts, see data_block_hash_index.
+//!
+//! Blocks built with delta encoded restarts (format version 2 index blocks)
+//! replace the restart array by
+//!     restart_deltas: group varint[num_restarts]
+//!     restart_deltas_size: uint32
+//! and set K_DELTA_ENCODED_RESTARTS_BIT in num_restarts. restart_deltas[i]
+//! is the distance of the ith restart point from the previous one. Index
+//! blocks restart at every entry, so this shrinks their trailer from four
+//! bytes to little more than one byte per entry.
+
+use crate::coding::{decode_fix32, get_group_varint32, group_varint32_value_length, put_fixed32, put_group_varint32, put_varint32};
+use crate::Error::Corruption;
+use crate::options::DataBlockIndexType;
+use crate::Result;
+use crate::table::data_block_hash_index::{DataBlockHashIndexBuilder, K_MAX_BLOCK_SIZE_SUPPORTED_BY_HASH_INDEX, pack_index_type_and_num_restarts, unpack_index_type_and_num_restarts};
+
+/// Set in num_restarts of blocks whose restart array is delta encoded.
+pub const K_DELTA_ENCODED_RESTARTS_BIT: u32 = 1 << 30;
+
+pub struct BlockBuilder {
+
+    block_restart_interval: usize,
+
+    // Destination buffer
+    buffer: Vec<u8>,
+
+    // Restart points
+    restarts: Vec<u32>,
+
+    // Number of entries emitted since restart
+    counter: usize,
+
+    // Has finish() been called?
+    finished: bool,
+
+    last_key: Vec<u8>,
+
+    // Set for data blocks with a hash index over their user keys
+    hash_index: Option<DataBlockHashIndexBuilder>,
+
+    // Whether the restart array is stored as group varint deltas
+    delta_encoded_restarts: bool,
+
+    // Size of the group varint encoded restart deltas
+    restart_deltas_size: usize
+}
+
+impl BlockBuilder {
+
+    pub fn new(block_restart_interval: usize) -> Self {
+        assert!(block_restart_interval >= 1);
+        BlockBuilder {
+            block_restart_interval,
+            buffer: Vec::new(),
+            // First restart point is at offset 0
+            restarts: vec![0],
+            counter: 0,
+            finished: false,
+            last_key: Vec::new(),
+            hash_index: None,
+            delta_encoded_restarts: false,
+            // Tag byte and a single byte for the first restart point
+            restart_deltas_size: 2
+        }
+    }
+
+    /// Create a builder of blocks whose restart array is delta encoded.
+    /// Requires FormatFeature::DeltaEncodedRestarts.
+    pub fn with_delta_encoded_restarts(block_restart_interval: usize) -> Self {
+        let mut builder = Self::new(block_restart_interval);
+        builder.delta_encoded_restarts = true;
+        builder
+    }
+
+    /// Create a builder of data blocks that end with a hash index mapping
+    /// the user key of every entry to its restart interval. Keys must be
+    /// internal keys.
+    pub fn with_hash_index(block_restart_interval: usize, util_ratio: f64) -> Self {
+        let mut builder = Self::new(block_restart_interval);
+        builder.hash_index = Some(DataBlockHashIndexBuilder::new(util_ratio));
+        builder
+    }
+
+    /// Reset the contents as if the BlockBuilder was just constructed.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.restarts.clear();
+        self.restarts.push(0);
+        self.counter = 0;
+        self.finished = false;
+        self.last_key.clear();
+        self.restart_deltas_size = 2;
+        if let Some(hash_index) = self.hash_index.as_mut() {
+            hash_index.reset();
+        }
+    }
+
+    /// REQUIRES: finish() has not been called since the last call to reset().
+    /// REQUIRES: key is larger than any previously added key
+    pub fn add(&mut self, key: &[u8], value: &[u8]) {
+        assert!(!self.finished);
+        assert!(self.counter <= self.block_restart_interval);
+        let mut shared = 0;
+        if self.counter < self.block_restart_interval {
+            // See how much sharing to do with previous string
+            shared = self.last_key.iter().zip(key).take_while(|(a, b)| a == b).count();
+        } else {
+            // Restart compression
+            let delta = self.buffer.len() as u32 - self.restarts[self.restarts.len() - 1];
+            if self.restarts.len().is_multiple_of(4) {
+                // Starts a new group
+                self.restart_deltas_size += 1;
+            }
+            self.restart_deltas_size += group_varint32_value_length(delta);
+            self.restarts.push(self.buffer.len() as u32);
+            self.counter = 0;
+        }
+        let non_shared = key.len() - shared;
+
+        // Add "<shared><non_shared><value_size>" to buffer
+        put_varint32(&mut self.buffer, shared as u32);
+        put_varint32(&mut self.buffer, non_shared as u32);
+        put_varint32(&mut self.buffer, value.len() as u32);
+
+        // Add string delta to buffer followed by value
+        self.buffer.extend_from_slice(&key[shared..]);
+        self.buffer.extend_from_slice(value);
+
+        if let Some(hash_index) = self.hash_index.as_mut() {
+            assert!(key.len() >= 8);
+            hash_index.add(&key[..key.len() - 8], self.restarts.len() - 1);
+        }
+
+        // Update state
+        self.last_key.truncate(shared);
+        self.last_key.extend_from_slice(&key[shared..]);
+        self.counter += 1;
+    }
+
+    /// Finish building the block and return a slice that refers to the
+    /// block contents.  The returned slice will remain valid for the
+    /// lifetime of this builder or until reset() is called.
+    pub fn finish(&mut self) -> &[u8] {
+        if self.delta_encoded_restarts {
+            assert!(self.hash_index.is_none());
+            let mut prev = 0;
+            let deltas: Vec<u32> = self.restarts.iter().map(|r| {
+                let delta = r - prev;
+                prev = *r;
+                delta
+            }).collect();
+            put_group_varint32(&mut self.buffer, &deltas);
+            put_fixed32(&mut self.buffer, self.restart_deltas_size as u32);
+            put_fixed32(&mut self.buffer, self.restarts.len() as u32 | K_DELTA_ENCODED_RESTARTS_BIT);
+            self.finished = true;
+            return &self.buffer;
+        }
+
+        // Append restart array
+        for i in 0..self.restarts.len() {
+            put_fixed32(&mut self.buffer, self.restarts[i]);
+        }
+        let mut index_type = DataBlockIndexType::BinarySearch;
+        if let Some(hash_index) = self.hash_index.as_ref() {
+            // Blocks the hash index cannot address are left without one
+            if hash_index.valid() && self.current_size_estimate() <= K_MAX_BLOCK_SIZE_SUPPORTED_BY_HASH_INDEX {
+                hash_index.finish(&mut self.buffer);
+                index_type = DataBlockIndexType::BinaryAndHash;
+            }
+        }
+        put_fixed32(&mut self.buffer, pack_index_type_and_num_restarts(index_type, self.restarts.len() as u32));
+        self.finished = true;
+        &self.buffer
+    }
+
+    /// Returns an estimate of the current (uncompressed) size of the block
+    /// we are building.
+    pub fn current_size_estimate(&self) -> usize {
+        let hash_index_size = self.hash_index.as_ref().map_or(0, |h| h.estimate_size());
+        let restarts_size = if self.delta_encoded_restarts {
+            self.restart_deltas_size + 4
+        } else {
+            self.restarts.len() * 4
+        };
+        self.buffer.len() +                     // Raw data buffer
+            restarts_size +                     // Restart array
+            hash_index_size +                   // Hash index
+            4                                   // Restart array length
+    }
+
+    /// Return true iff no entries have been added since the last reset()
+    pub fn empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+}
+
+/// Decode the restart array of a block without a hash index. Returns the
+/// restart offsets and the end of the entries of the block.
+pub fn decode_restart_array(block: &[u8]) -> Result<(Vec<u32>, usize)> {
+    if block.len() < 4 {
+        // "bad block contents"
+        return Err(Corruption);
+    }
+    let footer = decode_fix32(&block[block.len() - 4..]);
+    let (index_type, num_restarts) = unpack_index_type_and_num_restarts(footer);
+    if index_type != DataBlockIndexType::BinarySearch {
+        return Err(Corruption);
+    }
+    let num_restarts = num_restarts as usize;
+    let trailer_end = block.len() - 4;
+    if footer & K_DELTA_ENCODED_RESTARTS_BIT == 0 {
+        if num_restarts * 4 > trailer_end {
+            return Err(Corruption);
+        }
+        let restarts_start = trailer_end - num_restarts * 4;
+        let restarts = (0..num_restarts).map(|i| decode_fix32(&block[restarts_start + i * 4..])).collect();
+        return Ok((restarts, restarts_start));
+    }
+    if trailer_end < 4 {
+        return Err(Corruption);
+    }
+    let deltas_size = decode_fix32(&block[trailer_end - 4..]) as usize;
+    if deltas_size > trailer_end - 4 {
+        return Err(Corruption);
+    }
+    let deltas_start = trailer_end - 4 - deltas_size;
+    let (deltas, len) = get_group_varint32(&block[deltas_start..trailer_end - 4], num_restarts)?;
+    if len != deltas_size {
+        // "restart deltas size mismatch"
+        return Err(Corruption);
+    }
+    let mut restarts = Vec::with_capacity(num_restarts);
+    let mut offset = 0u32;
+    for delta in deltas {
+        offset = offset.checked_add(delta).ok_or(Corruption)?;
+        if offset as usize > deltas_start {
+            // "restart point past the entries"
+            return Err(Corruption);
+        }
+        restarts.push(offset);
+    }
+    Ok((restarts, deltas_start))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dbformat::{append_internal_key, ValueType};
+    use crate::slice::Slice;
+    use crate::table::data_block_hash_index::{decode_block_footer, DataBlockHashIndex, K_COLLISION};
+    use super::*;
+
+    #[test]
+    fn test_prefix_compression() {
+        let mut builder = BlockBuilder::new(2);
+        assert!(builder.empty());
+        builder.add(b"apple", b"1");
+        builder.add(b"apricot", b"2");
+        builder.add(b"banana", b"3");
+        assert!(!builder.empty());
+        let estimate = builder.current_size_estimate();
+        let contents = builder.finish().to_vec();
+        assert_eq!(estimate, contents.len());
+
+        let expected_data = [
+            &[0, 5, 1][..], b"apple", b"1",
+            &[2, 5, 1][..], b"ricot", b"2",
+            // Restart point: the whole key is stored
+            &[0, 6, 1][..], b"banana", b"3",
+        ].concat();
+        assert_eq!(expected_data[..], contents[..expected_data.len()]);
+        let trailer = &contents[expected_data.len()..];
+        assert_eq!(3 * 4, trailer.len());
+        assert_eq!(0, decode_fix32(&trailer[0..]));
+        assert_eq!(18, decode_fix32(&trailer[4..]));
+        assert_eq!(2, decode_fix32(&trailer[8..]));
+
+        builder.reset();
+        assert!(builder.empty());
+        assert_eq!(8, builder.finish().len());
+    }
+
+    #[test]
+    fn test_delta_encoded_restarts() {
+        let key = |i: u32| format!("key{:06}", i).into_bytes();
+        let mut plain = BlockBuilder::new(1);
+        let mut delta = BlockBuilder::with_delta_encoded_restarts(1);
+        for i in 0..1000 {
+            plain.add(&key(i), &[7u8; 10]);
+            delta.add(&key(i), &[7u8; 10]);
+        }
+        let estimate = delta.current_size_estimate();
+        let plain_contents = plain.finish().to_vec();
+        let contents = delta.finish().to_vec();
+        assert_eq!(estimate, contents.len());
+        let (plain_restarts, plain_end) = decode_restart_array(&plain_contents).unwrap();
+        let (restarts, end) = decode_restart_array(&contents).unwrap();
+        assert_eq!(plain_restarts, restarts);
+        assert_eq!(1000, restarts.len());
+        assert_eq!(plain_end, end);
+        assert_eq!(plain_contents[..plain_end], contents[..end]);
+        // One byte per delta plus a tag byte per four deltas
+        assert_eq!(1000 + 250 + 8, contents.len() - end);
+
+        delta.reset();
+        let contents = delta.finish().to_vec();
+        assert_eq!((vec![0], 0), decode_restart_array(&contents).unwrap());
+
+        // Damaged trailers are detected
+        let mut delta = BlockBuilder::with_delta_encoded_restarts(2);
+        delta.add(b"a", b"1");
+        delta.add(b"b", b"2");
+        delta.add(b"c", b"3");
+        let contents = delta.finish().to_vec();
+        assert_eq!(vec![0, 10], decode_restart_array(&contents).unwrap().0);
+        let mut bad_size = contents.clone();
+        let len = bad_size.len();
+        bad_size[len - 8] = 100;
+        assert!(decode_restart_array(&bad_size).is_err());
+        let mut bad_delta = contents.clone();
+        bad_delta[len - 9] = 200;
+        assert!(decode_restart_array(&bad_delta).is_err());
+        assert!(decode_restart_array(&contents[..3]).is_err());
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let ikey = |i: u32| {
+            let mut key = Vec::new();
+            append_internal_key(&mut key, &Slice::from_str(&format!("key{:04}", i)), i as u64, ValueType::KTypeValue);
+            key
+        };
+        let mut builder = BlockBuilder::with_hash_index(16, 0.75);
+        for i in 0..1000 {
+            builder.add(&ikey(i), b"value");
+        }
+        let estimate = builder.current_size_estimate();
+        let contents = builder.finish().to_vec();
+        assert_eq!(estimate, contents.len());
+        let (index_type, num_restarts) = decode_block_footer(&contents);
+        assert_eq!(DataBlockIndexType::BinaryAndHash, index_type);
+        assert_eq!(1000 / 16 + 1, num_restarts);
+
+        let (index, restarts_end) = DataBlockHashIndex::new(&contents[..contents.len() - 4]).unwrap();
+        let restart_offset = |r: usize| decode_fix32(&contents[restarts_end - (num_restarts as usize - r) * 4..]) as usize;
+        for i in 0..1000 {
+            match index.lookup(format!("key{:04}", i).as_bytes()) {
+                K_COLLISION => {},
+                r => {
+                    // The restart point the index names is the one holding the key
+                    assert_eq!(i as usize / 16, r as usize);
+                    assert_eq!(&ikey(i / 16 * 16)[..], &contents[restart_offset(r as usize) + 3..][..15]);
+                }
+            }
+        }
+
+        // Past 253 restart intervals the block has no hash index
+        let mut builder = BlockBuilder::with_hash_index(1, 0.75);
+        for i in 0..300 {
+            builder.add(&ikey(i), b"");
+        }
+        let contents = builder.finish().to_vec();
+        assert_eq!((DataBlockIndexType::BinarySearch, 300), decode_block_footer(&contents));
+    }
+}
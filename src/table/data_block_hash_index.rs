@@ -0,0 +1,216 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A hash table appended to a data block that maps the user keys of the
+//! block to the restart interval holding them, so a point lookup can go
+//! straight to the right interval instead of binary searching the restart
+//! array.
+//!
+//! The table follows the restart array:
+//!     buckets: uint8[num_buckets]
+//!     num_buckets: uint16
+//! Each bucket holds the index of a restart interval, K_NO_ENTRY if no key
+//! hashes to it, or K_COLLISION if keys of different intervals do. A lookup
+//! that finds K_COLLISION falls back to the binary search.
+//!
+//! Blocks carrying the table have the top bit of their num_restarts footer
+//! set, see pack_index_type_and_num_restarts.
+
+use crate::coding::decode_fix32;
+use crate::options::DataBlockIndexType;
+use crate::util::hash::hash;
+
+pub const K_NO_ENTRY: u8 = 255;
+pub const K_COLLISION: u8 = 254;
+pub const K_MAX_RESTART_SUPPORTED_BY_HASH_INDEX: usize = 253;
+
+/// Restart offsets are found through the hash table only in blocks
+/// smaller than this.
+pub const K_MAX_BLOCK_SIZE_SUPPORTED_BY_HASH_INDEX: usize = 1 << 16;
+
+const K_DATA_BLOCK_INDEX_TYPE_BIT_SHIFT: u32 = 31;
+// Bit 30 flags a delta encoded restart array, see block_builder
+const K_NUM_RESTARTS_MASK: u32 = (1 << (K_DATA_BLOCK_INDEX_TYPE_BIT_SHIFT - 1)) - 1;
+const K_HASH_SEED: u32 = 0x9e3779b9;
+
+pub fn pack_index_type_and_num_restarts(index_type: DataBlockIndexType, num_restarts: u32) -> u32 {
+    assert!(num_restarts <= K_NUM_RESTARTS_MASK);
+    match index_type {
+        DataBlockIndexType::BinarySearch => num_restarts,
+        DataBlockIndexType::BinaryAndHash => num_restarts | (1 << K_DATA_BLOCK_INDEX_TYPE_BIT_SHIFT)
+    }
+}
+
+pub fn unpack_index_type_and_num_restarts(block_footer: u32) -> (DataBlockIndexType, u32) {
+    let index_type = if block_footer & (1 << K_DATA_BLOCK_INDEX_TYPE_BIT_SHIFT) != 0 {
+        DataBlockIndexType::BinaryAndHash
+    } else {
+        DataBlockIndexType::BinarySearch
+    };
+    (index_type, block_footer & K_NUM_RESTARTS_MASK)
+}
+
+pub struct DataBlockHashIndexBuilder {
+
+    // Ratio of keys to buckets; lower means fewer collisions
+    util_ratio: f64,
+
+    estimated_num_buckets: f64,
+
+    // False once a restart index too large for a bucket was added
+    valid: bool,
+
+    hash_and_restart_pairs: Vec<(u32, u8)>
+}
+
+impl DataBlockHashIndexBuilder {
+
+    pub fn new(util_ratio: f64) -> Self {
+        assert!(util_ratio > 0.0);
+        DataBlockHashIndexBuilder {
+            util_ratio,
+            estimated_num_buckets: 0.0,
+            valid: true,
+            hash_and_restart_pairs: Vec::new()
+        }
+    }
+
+    pub fn add(&mut self, user_key: &[u8], restart_index: usize) {
+        if restart_index > K_MAX_RESTART_SUPPORTED_BY_HASH_INDEX {
+            self.valid = false;
+            return;
+        }
+        self.hash_and_restart_pairs.push((hash(user_key, K_HASH_SEED), restart_index as u8));
+        self.estimated_num_buckets += 1.0 / self.util_ratio;
+    }
+
+    /// True if every restart index added fits in a bucket.
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    /// Estimate of the bytes finish() appends.
+    pub fn estimate_size(&self) -> usize {
+        let estimated_num_buckets = (self.estimated_num_buckets as usize) | 1;
+        estimated_num_buckets + 2
+    }
+
+    /// Append the buckets and their count to `buffer`.
+    pub fn finish(&self, buffer: &mut Vec<u8>) {
+        // An odd number of buckets spreads the hashes better
+        let num_buckets = (self.estimated_num_buckets as usize).clamp(1, u16::MAX as usize) | 1;
+        let mut buckets = vec![K_NO_ENTRY; num_buckets];
+        for (hash_value, restart_index) in &self.hash_and_restart_pairs {
+            let bucket = &mut buckets[*hash_value as usize % num_buckets];
+            if *bucket == K_NO_ENTRY {
+                *bucket = *restart_index;
+            } else if *bucket != *restart_index {
+                *bucket = K_COLLISION;
+            }
+        }
+        buffer.extend_from_slice(&buckets);
+        buffer.extend_from_slice(&(num_buckets as u16).to_le_bytes());
+    }
+
+    pub fn reset(&mut self) {
+        self.estimated_num_buckets = 0.0;
+        self.valid = true;
+        self.hash_and_restart_pairs.clear();
+    }
+}
+
+/// Read side of the hash table at the end of a block.
+pub struct DataBlockHashIndex<'a> {
+    buckets: &'a [u8]
+}
+
+impl<'a> DataBlockHashIndex<'a> {
+
+    /// `data` is the block up to, but excluding, its num_restarts footer.
+    /// Returns the index and the offset where the table starts, which is
+    /// the end of the restart array.
+    pub fn new(data: &'a [u8]) -> Option<(Self, usize)> {
+        if data.len() < 2 {
+            return None;
+        }
+        let num_buckets = u16::from_le_bytes([data[data.len() - 2], data[data.len() - 1]]) as usize;
+        let start = (data.len() - 2).checked_sub(num_buckets)?;
+        Some((DataBlockHashIndex { buckets: &data[start..data.len() - 2] }, start))
+    }
+
+    /// Returns the restart interval that may hold `user_key`, K_NO_ENTRY if
+    /// the key is not in the block, or K_COLLISION if the table cannot tell.
+    pub fn lookup(&self, user_key: &[u8]) -> u8 {
+        if self.buckets.is_empty() {
+            return K_COLLISION;
+        }
+        self.buckets[hash(user_key, K_HASH_SEED) as usize % self.buckets.len()]
+    }
+}
+
+/// Decodes the footer of a finished block. Returns the data block index type
+/// and the number of restarts.
+pub fn decode_block_footer(block: &[u8]) -> (DataBlockIndexType, u32) {
+    unpack_index_type_and_num_restarts(decode_fix32(&block[block.len() - 4..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_num_restarts() {
+        for num_restarts in [0, 1, 253, K_NUM_RESTARTS_MASK] {
+            for index_type in [DataBlockIndexType::BinarySearch, DataBlockIndexType::BinaryAndHash] {
+                let footer = pack_index_type_and_num_restarts(index_type, num_restarts);
+                assert_eq!((index_type, num_restarts), unpack_index_type_and_num_restarts(footer));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hash_index() {
+        let mut builder = DataBlockHashIndexBuilder::new(0.75);
+        let keys = (0..200).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            builder.add(key.as_bytes(), i / 4);
+        }
+        assert!(builder.valid());
+        let mut buffer = b"restarts".to_vec();
+        builder.finish(&mut buffer);
+        assert_eq!(8 + builder.estimate_size(), buffer.len());
+
+        let (index, start) = DataBlockHashIndex::new(&buffer).unwrap();
+        assert_eq!(8, start);
+        let mut collisions = 0;
+        for (i, key) in keys.iter().enumerate() {
+            match index.lookup(key.as_bytes()) {
+                K_COLLISION => collisions += 1,
+                restart => assert_eq!(i / 4, restart as usize)
+            }
+        }
+        assert!(collisions < keys.len() / 2);
+        let missing = (0..200).filter(|i| index.lookup(format!("missing{}", i).as_bytes()) == K_NO_ENTRY).count();
+        assert!(missing > 0);
+    }
+
+    #[test]
+    fn test_too_many_restarts() {
+        let mut builder = DataBlockHashIndexBuilder::new(0.75);
+        builder.add(b"a", K_MAX_RESTART_SUPPORTED_BY_HASH_INDEX);
+        assert!(builder.valid());
+        builder.add(b"b", K_MAX_RESTART_SUPPORTED_BY_HASH_INDEX + 1);
+        assert!(!builder.valid());
+        builder.reset();
+        assert!(builder.valid());
+    }
+}
@@ -0,0 +1,278 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A filter block is stored near the end of a Table file.  It contains
+//! filters (e.g., bloom filters) for all data blocks in the table combined
+//! into a single filter block.
+//!
+//! The filter block has the form:
+//!     filter[i]: byte[]               (one per 2KB of data block offsets)
+//!     offset_of_filter[i]: fixed32
+//!     offset_of_offset_array: fixed32
+//!     base_lg: u8                     (K_FILTER_BASE_LG)
+//! filter[i] summarizes the keys of the data blocks starting in the i-th
+//! 2KB range of the file.
+
+use std::sync::Arc;
+use crate::coding::{decode_fix32, put_fixed32};
+use crate::filter_policy::FilterPolicy;
+
+// Generate new filter every 2KB of data
+const K_FILTER_BASE_LG: u8 = 11;
+const K_FILTER_BASE: u64 = 1 << K_FILTER_BASE_LG;
+
+/// A FilterBlockBuilder is used to construct all of the filters for a
+/// particular Table.  It generates a single string which is stored as
+/// a special block in the Table.
+///
+/// The sequence of calls to FilterBlockBuilder must match the regexp:
+///      (start_block add_key*)* finish
+pub struct FilterBlockBuilder {
+
+    policy: Arc<dyn FilterPolicy>,
+
+    // Flattened key contents
+    keys: Vec<u8>,
+
+    // Starting index in keys of each key
+    start: Vec<usize>,
+
+    // Filter data computed so far
+    result: Vec<u8>,
+
+    filter_offsets: Vec<u32>
+}
+
+impl FilterBlockBuilder {
+
+    pub fn new(policy: Arc<dyn FilterPolicy>) -> Self {
+        FilterBlockBuilder {
+            policy,
+            keys: Vec::new(),
+            start: Vec::new(),
+            result: Vec::new(),
+            filter_offsets: Vec::new()
+        }
+    }
+
+    pub fn start_block(&mut self, block_offset: u64) {
+        let filter_index = (block_offset / K_FILTER_BASE) as usize;
+        assert!(filter_index >= self.filter_offsets.len());
+        while filter_index > self.filter_offsets.len() {
+            self.generate_filter();
+        }
+    }
+
+    pub fn add_key(&mut self, key: &[u8]) {
+        self.start.push(self.keys.len());
+        self.keys.extend_from_slice(key);
+    }
+
+    pub fn finish(&mut self) -> &[u8] {
+        if !self.start.is_empty() {
+            self.generate_filter();
+        }
+
+        // Append array of per-filter offsets
+        let array_offset = self.result.len() as u32;
+        for offset in &self.filter_offsets {
+            put_fixed32(&mut self.result, *offset);
+        }
+        put_fixed32(&mut self.result, array_offset);
+        // Save encoding parameter in result
+        self.result.push(K_FILTER_BASE_LG);
+        &self.result
+    }
+
+    fn generate_filter(&mut self) {
+        self.filter_offsets.push(self.result.len() as u32);
+        if self.start.is_empty() {
+            // Fast path if there are no keys for this filter
+            return;
+        }
+
+        // Make list of keys from flattened key structure
+        // Simplify length computation
+        self.start.push(self.keys.len());
+        let keys: Vec<&[u8]> = self.start.windows(2).map(|w| &self.keys[w[0]..w[1]]).collect();
+
+        // Generate filter for current set of keys and append to result.
+        self.policy.create_filter(&keys, &mut self.result);
+
+        self.keys.clear();
+        self.start.clear();
+    }
+}
+
+pub struct FilterBlockReader {
+
+    policy: Arc<dyn FilterPolicy>,
+
+    data: Arc<Vec<u8>>,
+
+    // Offset of the beginning of the offset array (at block-end)
+    offset: usize,
+
+    // Number of entries in offset array
+    num: usize,
+
+    // Encoding parameter (see K_FILTER_BASE_LG)
+    base_lg: u8
+}
+
+impl FilterBlockReader {
+
+    /// REQUIRES: `contents` is the contents of a filter block built by
+    /// FilterBlockBuilder with the same policy.
+    pub fn new(policy: Arc<dyn FilterPolicy>, contents: impl Into<Arc<Vec<u8>>>) -> Self {
+        let mut reader = FilterBlockReader { policy, data: contents.into(), offset: 0, num: 0, base_lg: 0 };
+        let n = reader.data.len();
+        if n < 5 {
+            // 1 byte for base_lg and 4 for start of offset array
+            return reader;
+        }
+        let last_word = decode_fix32(&reader.data[n - 5..]) as usize;
+        if last_word > n - 5 {
+            return reader;
+        }
+        reader.base_lg = reader.data[n - 1];
+        reader.offset = last_word;
+        reader.num = (n - 5 - last_word) / 4;
+        reader
+    }
+
+    pub fn key_may_match(&self, block_offset: u64, key: &[u8]) -> bool {
+        let index = block_offset.checked_shr(self.base_lg as u32).unwrap_or(0) as usize;
+        if index < self.num {
+            let start = decode_fix32(&self.data[self.offset + index * 4..]) as usize;
+            let limit = decode_fix32(&self.data[self.offset + index * 4 + 4..]) as usize;
+            if start <= limit && limit <= self.offset {
+                return self.policy.key_may_match(key, &self.data[start..limit]);
+            } else if start == limit {
+                // Empty filters do not match any keys
+                return false;
+            }
+        }
+        // Errors are treated as potential matches
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // For testing: emit an array with one hash value per key
+    struct TestHashFilter;
+
+    impl FilterPolicy for TestHashFilter {
+        fn name(&self) -> &str {
+            "TestHashFilter"
+        }
+
+        fn create_filter(&self, keys: &[&[u8]], dst: &mut Vec<u8>) {
+            for key in keys {
+                put_fixed32(dst, crate::util::hash::hash(key, 1));
+            }
+        }
+
+        fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+            let h = crate::util::hash::hash(key, 1);
+            filter.chunks_exact(4).any(|chunk| decode_fix32(chunk) == h)
+        }
+    }
+
+    fn policy() -> Arc<dyn FilterPolicy> {
+        Arc::new(TestHashFilter)
+    }
+
+    #[test]
+    fn test_empty_builder() {
+        let mut builder = FilterBlockBuilder::new(policy());
+        let block = builder.finish().to_vec();
+        assert_eq!(vec![0, 0, 0, 0, 11], block);
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(100000, b"foo"));
+    }
+
+    #[test]
+    fn test_single_chunk() {
+        let mut builder = FilterBlockBuilder::new(policy());
+        builder.start_block(100);
+        builder.add_key(b"foo");
+        builder.add_key(b"bar");
+        builder.add_key(b"box");
+        builder.start_block(200);
+        builder.add_key(b"box");
+        builder.start_block(300);
+        builder.add_key(b"hello");
+        let block = builder.finish().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+        assert!(reader.key_may_match(100, b"foo"));
+        assert!(reader.key_may_match(100, b"bar"));
+        assert!(reader.key_may_match(100, b"box"));
+        assert!(reader.key_may_match(100, b"hello"));
+        assert!(reader.key_may_match(100, b"foo"));
+        assert!(!reader.key_may_match(100, b"missing"));
+        assert!(!reader.key_may_match(100, b"other"));
+    }
+
+    #[test]
+    fn test_multi_chunk() {
+        let mut builder = FilterBlockBuilder::new(policy());
+
+        // First filter
+        builder.start_block(0);
+        builder.add_key(b"foo");
+        builder.start_block(2000);
+        builder.add_key(b"bar");
+
+        // Second filter
+        builder.start_block(3100);
+        builder.add_key(b"box");
+
+        // Third filter is empty
+
+        // Last filter
+        builder.start_block(9000);
+        builder.add_key(b"box");
+        builder.add_key(b"hello");
+
+        let block = builder.finish().to_vec();
+        let reader = FilterBlockReader::new(policy(), block);
+
+        // Check first filter
+        assert!(reader.key_may_match(0, b"foo"));
+        assert!(reader.key_may_match(2000, b"bar"));
+        assert!(!reader.key_may_match(0, b"box"));
+        assert!(!reader.key_may_match(0, b"hello"));
+
+        // Check second filter
+        assert!(reader.key_may_match(3100, b"box"));
+        assert!(!reader.key_may_match(3100, b"foo"));
+        assert!(!reader.key_may_match(3100, b"bar"));
+        assert!(!reader.key_may_match(3100, b"hello"));
+
+        // Check third filter (empty)
+        assert!(!reader.key_may_match(4100, b"foo"));
+        assert!(!reader.key_may_match(4100, b"bar"));
+        assert!(!reader.key_may_match(4100, b"box"));
+        assert!(!reader.key_may_match(4100, b"hello"));
+
+        // Check last filter
+        assert!(reader.key_may_match(9000, b"box"));
+        assert!(reader.key_may_match(9000, b"hello"));
+        assert!(!reader.key_may_match(9000, b"foo"));
+        assert!(!reader.key_may_match(9000, b"bar"));
+    }
+}
@@ -0,0 +1,309 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::coding::{decode_fix32, decode_fixed64, get_varint64, put_fixed32, put_varint64};
+use crate::env::RandomAccessFile;
+use crate::Error::{BlockChecksumMismatch, Corruption, NotSupport};
+use crate::options::{CompressionType, IndexType, K_LATEST_FORMAT_VERSION};
+use crate::Result;
+use crate::util::crc;
+
+/// kTableMagicNumber was picked by running
+///    echo http://code.google.com/p/leveldb/ | sha1sum
+/// and taking the leading 64 bits.
+pub const K_TABLE_MAGIC_NUMBER: u64 = 0xdb4775248b80fb57;
+
+/// 1-byte type + 32-bit crc
+pub const K_BLOCK_TRAILER_SIZE: usize = 5;
+
+/// Name of the metaindex entry recording the index type of a table. Its
+/// value is a single byte, see encode_index_type. Tables without the entry
+/// have a single index block.
+pub const K_INDEX_TYPE_META_KEY: &[u8] = b"revel.index.type";
+
+/// BlockHandle is a pointer to the extent of a file that stores a data
+/// block or a meta block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockHandle {
+
+    /// The offset of the block in the file.
+    pub offset: u64,
+
+    /// The size of the stored block
+    pub size: u64
+}
+
+impl BlockHandle {
+
+    /// Maximum encoding length of a BlockHandle
+    pub const K_MAX_ENCODED_LENGTH: usize = 10 + 10;
+
+    pub fn new(offset: u64, size: u64) -> Self {
+        BlockHandle { offset, size }
+    }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        put_varint64(dst, self.offset);
+        put_varint64(dst, self.size);
+    }
+
+    /// Decodes a handle from the front of `input`. Returns the handle and
+    /// the number of bytes it took.
+    pub fn decode_from(input: &[u8]) -> Result<(BlockHandle, usize)> {
+        let (offset, n1) = get_varint64(input, 0, input.len()).map_err(|_| Corruption)?;
+        let (size, n2) = get_varint64(input, n1, input.len()).map_err(|_| Corruption)?;
+        Ok((BlockHandle { offset, size }, n1 + n2))
+    }
+}
+
+/// Footer encapsulates the fixed information stored at the tail
+/// end of every table file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Footer {
+    pub metaindex_handle: BlockHandle,
+    pub index_handle: BlockHandle,
+
+    /// Format version the table was written with, see
+    /// options::FormatFeature.
+    pub format_version: u32
+}
+
+impl Footer {
+
+    /// Encoded length of a Footer.  Note that the serialization of a
+    /// Footer will always occupy exactly this many bytes.  It consists
+    /// of two block handles, padding, the format version and a magic
+    /// number.
+    pub const K_ENCODED_LENGTH: usize = 2 * BlockHandle::K_MAX_ENCODED_LENGTH + 4 + 8;
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        let original_size = dst.len();
+        self.metaindex_handle.encode_to(dst);
+        self.index_handle.encode_to(dst);
+        // Padding
+        dst.resize(original_size + 2 * BlockHandle::K_MAX_ENCODED_LENGTH, 0);
+        put_fixed32(dst, self.format_version);
+        dst.extend_from_slice(&K_TABLE_MAGIC_NUMBER.to_le_bytes());
+        assert_eq!(original_size + Self::K_ENCODED_LENGTH, dst.len());
+    }
+
+    /// Decodes the footer from the last K_ENCODED_LENGTH bytes of `input`.
+    /// Tables written in a newer format version are refused.
+    pub fn decode_from(input: &[u8]) -> Result<Footer> {
+        if input.len() < Self::K_ENCODED_LENGTH {
+            // "file is too short to be an sstable"
+            return Err(Corruption);
+        }
+        let input = &input[input.len() - Self::K_ENCODED_LENGTH..];
+        let magic_offset = Self::K_ENCODED_LENGTH - 8;
+        if decode_fixed64(input, magic_offset) != K_TABLE_MAGIC_NUMBER {
+            // "not an sstable (bad magic number)"
+            return Err(Corruption);
+        }
+        let format_version = decode_fix32(&input[magic_offset - 4..]);
+        if format_version > K_LATEST_FORMAT_VERSION {
+            // Written by a newer release in a format we do not understand
+            return Err(NotSupport);
+        }
+        let (metaindex_handle, n) = BlockHandle::decode_from(input)?;
+        let (index_handle, _) = BlockHandle::decode_from(&input[n..])?;
+        Ok(Footer { metaindex_handle, index_handle, format_version })
+    }
+}
+
+/// Returns the masked crc stored in the trailer of a block with the given
+/// contents and type byte.
+pub fn block_checksum(contents: &[u8], block_type: u8) -> u32 {
+    let mut digest = crc::CASTAGNOLI.digest();
+    digest.update(contents);
+    // Extend crc to cover block type
+    digest.update(&[block_type]);
+    crc::mask(digest.finalize())
+}
+
+/// Read the block identified by `handle` from `file`, the table numbered
+/// `file_number`, and return its contents, uncompressed and without the
+/// trailer. The checksum of the block is checked if `verify_checksums` is
+/// set, a mismatch is reported as BlockChecksumMismatch.
+pub fn read_block(file: &dyn RandomAccessFile, file_number: u64, handle: &BlockHandle, verify_checksums: bool) -> Result<Vec<u8>> {
+    // Read the block contents as well as the type/crc footer.
+    let n = handle.size as usize;
+    let mut buf = vec![0; n + K_BLOCK_TRAILER_SIZE];
+    let read = file.read(handle.offset, &mut buf)?.size();
+    if read != n + K_BLOCK_TRAILER_SIZE {
+        // "truncated block read"
+        return Err(Corruption);
+    }
+
+    // Check the crc of the type and the block contents
+    if verify_checksums && decode_fix32(&buf[n + 1..]) != block_checksum(&buf[..n], buf[n]) {
+        return Err(BlockChecksumMismatch { file_number, offset: handle.offset });
+    }
+    match CompressionType::try_from(buf[n])? {
+        CompressionType::NoCompression => {
+            buf.truncate(n);
+            Ok(buf)
+        }
+        compression_type => uncompress_block(compression_type, &buf[..n])
+    }
+}
+
+/// Compresses `raw` with `compression_type`. Returns None if the backend
+/// is not compiled in.
+pub fn compress_block(compression_type: CompressionType, raw: &[u8]) -> Option<Vec<u8>> {
+    match compression_type {
+        CompressionType::NoCompression => Some(raw.to_vec()),
+        CompressionType::Snappy => snap::raw::Encoder::new().compress_vec(raw).ok(),
+        #[cfg(feature = "lz4")]
+        CompressionType::Lz4 => Some(lz4_flex::block::compress_prepend_size(raw)),
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => zstd::bulk::compress(raw, zstd::DEFAULT_COMPRESSION_LEVEL).ok(),
+        #[cfg(not(feature = "lz4"))]
+        CompressionType::Lz4 => None,
+        #[cfg(not(feature = "zstd"))]
+        CompressionType::Zstd => None
+    }
+}
+
+/// Returns the contents of a block compressed with `compression_type`.
+pub fn uncompress_block(compression_type: CompressionType, data: &[u8]) -> Result<Vec<u8>> {
+    match compression_type {
+        CompressionType::NoCompression => Ok(data.to_vec()),
+        // "corrupted compressed block contents"
+        CompressionType::Snappy => snap::raw::Decoder::new().decompress_vec(data).map_err(|_| Corruption),
+        #[cfg(feature = "lz4")]
+        CompressionType::Lz4 => lz4_flex::block::decompress_size_prepended(data).map_err(|_| Corruption),
+        #[cfg(feature = "zstd")]
+        CompressionType::Zstd => zstd::stream::decode_all(data).map_err(|_| Corruption),
+        // Written by a build with a backend this one lacks
+        #[cfg(not(feature = "lz4"))]
+        CompressionType::Lz4 => Err(NotSupport),
+        #[cfg(not(feature = "zstd"))]
+        CompressionType::Zstd => Err(NotSupport)
+    }
+}
+
+pub fn encode_index_type(index_type: IndexType) -> u8 {
+    match index_type {
+        IndexType::BinarySearch => 0x0,
+        IndexType::TwoLevelIndexSearch => 0x1
+    }
+}
+
+pub fn decode_index_type(value: &[u8]) -> Result<IndexType> {
+    match value {
+        [0x0] => Ok(IndexType::BinarySearch),
+        [0x1] => Ok(IndexType::TwoLevelIndexSearch),
+        // An index layout of a newer release
+        _ => Err(NotSupport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::env::MemoryRandomAccessFile;
+    use super::*;
+
+    #[test]
+    fn test_block_handle() {
+        for handle in [BlockHandle::new(0, 0), BlockHandle::new(1 << 40, 4096), BlockHandle::new(u64::MAX, u64::MAX)] {
+            let mut encoded = Vec::new();
+            handle.encode_to(&mut encoded);
+            assert!(encoded.len() <= BlockHandle::K_MAX_ENCODED_LENGTH);
+            encoded.extend_from_slice(b"rest");
+            assert_eq!(Ok((handle, encoded.len() - 4)), BlockHandle::decode_from(&encoded));
+            assert_eq!(Err(Corruption), BlockHandle::decode_from(&encoded[..encoded.len() - 5]).map(|_| ()));
+        }
+    }
+
+    #[test]
+    fn test_compression() {
+        let raw = b"compressible ".repeat(100);
+        for compression_type in [CompressionType::NoCompression, CompressionType::Snappy, CompressionType::Lz4, CompressionType::Zstd] {
+            match compress_block(compression_type, &raw) {
+                Some(compressed) => assert_eq!(Ok(raw.clone()), uncompress_block(compression_type, &compressed)),
+                // Blocks of a backend that is not compiled in cannot be read
+                None => assert_eq!(Err(NotSupport), uncompress_block(compression_type, &raw))
+            }
+        }
+        assert_eq!(Err(Corruption), uncompress_block(CompressionType::Snappy, &[0xff, 0xff]));
+    }
+
+    #[test]
+    fn test_footer() {
+        let footer = Footer {
+            metaindex_handle: BlockHandle::new(1 << 20, 37),
+            index_handle: BlockHandle::new(3, 1 << 33),
+            format_version: K_LATEST_FORMAT_VERSION
+        };
+        let mut encoded = b"table contents".to_vec();
+        footer.encode_to(&mut encoded);
+        assert_eq!(14 + Footer::K_ENCODED_LENGTH, encoded.len());
+        assert_eq!(Ok(footer), Footer::decode_from(&encoded));
+
+        assert_eq!(Err(Corruption), Footer::decode_from(&encoded[..Footer::K_ENCODED_LENGTH - 1]));
+        let mut bad_magic = encoded.clone();
+        *bad_magic.last_mut().unwrap() ^= 1;
+        assert_eq!(Err(Corruption), Footer::decode_from(&bad_magic));
+
+        let mut newer = Vec::new();
+        Footer { format_version: K_LATEST_FORMAT_VERSION + 1, ..footer }.encode_to(&mut newer);
+        assert_eq!(Err(NotSupport), Footer::decode_from(&newer));
+    }
+
+    #[test]
+    fn test_read_block() {
+        let mut file = b"block".to_vec();
+        file.push(CompressionType::NoCompression as u8);
+        file.extend_from_slice(&block_checksum(b"block", CompressionType::NoCompression as u8).to_le_bytes());
+        let compressed = snap::raw::Encoder::new().compress_vec(&[b'x'; 100]).unwrap();
+        file.extend_from_slice(&compressed);
+        file.push(CompressionType::Snappy as u8);
+        file.extend_from_slice(&block_checksum(&compressed, CompressionType::Snappy as u8).to_le_bytes());
+        let file = MemoryRandomAccessFile::new(Rc::new(file));
+        assert_eq!(Ok(b"block".to_vec()), read_block(&file, 0, &BlockHandle::new(0, 5), true));
+        assert_eq!(Ok(vec![b'x'; 100]), read_block(&file, 0, &BlockHandle::new(10, compressed.len() as u64), true));
+        // Reads past the end of the file, unknown block types and bad
+        // compressed contents are corruption
+        assert_eq!(Err(Corruption), read_block(&file, 0, &BlockHandle::new(6, 5 + compressed.len() as u64), true));
+        assert_eq!(Err(BlockChecksumMismatch { file_number: 7, offset: 0 }), read_block(&file, 7, &BlockHandle::new(0, 4), true));
+        let mut bad_snappy = vec![0xff, 0xff, CompressionType::Snappy as u8];
+        bad_snappy.extend_from_slice(&block_checksum(&[0xff, 0xff], CompressionType::Snappy as u8).to_le_bytes());
+        let file = MemoryRandomAccessFile::new(Rc::new(bad_snappy));
+        assert_eq!(Err(Corruption), read_block(&file, 0, &BlockHandle::new(0, 2), true));
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let mut contents = b"block".to_vec();
+        contents.push(CompressionType::NoCompression as u8);
+        contents.extend_from_slice(&block_checksum(b"block", CompressionType::NoCompression as u8).to_le_bytes());
+        let handle = BlockHandle::new(0, 5);
+        for i in 0..contents.len() {
+            let mut corrupted = contents.clone();
+            corrupted[i] ^= 0x10;
+            let file = MemoryRandomAccessFile::new(Rc::new(corrupted));
+            assert_eq!(Err(BlockChecksumMismatch { file_number: 7, offset: 0 }), read_block(&file, 7, &handle, true));
+            // Without verification only a damaged type byte is noticed, damaged
+            // contents are returned as they are
+            let unverified = read_block(&file, 0, &handle, false);
+            if i == 5 {
+                assert_eq!(Err(Corruption), unverified);
+            } else if i < 5 {
+                assert_ne!(Ok(b"block".to_vec()), unverified);
+            } else {
+                assert_eq!(Ok(b"block".to_vec()), unverified);
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Builders of the index of a table file. The index maps the last key of
+//! every data block to the handle of that block.
+
+use crate::options::{FormatFeature, IndexType, Options};
+use crate::Result;
+use crate::table::block_builder::BlockBuilder;
+use crate::table::format::BlockHandle;
+
+pub trait IndexBuilder {
+
+    /// Add an entry for a finished data block whose last key is `last_key`.
+    fn add_index_entry(&mut self, last_key: &[u8], handle: &BlockHandle);
+
+    /// Finish building the index and return the contents of the index
+    /// block. Any additional block the index needs is handed to
+    /// `write_block`, which stores it in the file and returns its handle.
+    fn finish(&mut self, write_block: &mut dyn FnMut(&[u8]) -> Result<BlockHandle>) -> Result<Vec<u8>>;
+}
+
+/// Returns the index builder for the index type configured in `options`.
+/// Index blocks get delta encoded restart arrays when the format version
+/// supports them.
+pub fn new_index_builder(options: &Options) -> Box<dyn IndexBuilder> {
+    let delta_encoded_restarts = options.supports(FormatFeature::DeltaEncodedRestarts);
+    match options.index_type {
+        IndexType::BinarySearch => Box::new(BinarySearchIndexBuilder::new(delta_encoded_restarts)),
+        IndexType::TwoLevelIndexSearch => {
+            Box::new(PartitionedIndexBuilder::new(options.metadata_block_size, delta_encoded_restarts))
+        }
+    }
+}
+
+/// Builds a single index block.
+pub struct BinarySearchIndexBuilder {
+    index_block: BlockBuilder
+}
+
+impl BinarySearchIndexBuilder {
+    pub fn new(delta_encoded_restarts: bool) -> Self {
+        // Every index entry is a restart point, so a lookup is a plain
+        // binary search
+        let index_block = if delta_encoded_restarts {
+            BlockBuilder::with_delta_encoded_restarts(1)
+        } else {
+            BlockBuilder::new(1)
+        };
+        BinarySearchIndexBuilder { index_block }
+    }
+}
+
+impl IndexBuilder for BinarySearchIndexBuilder {
+
+    fn add_index_entry(&mut self, last_key: &[u8], handle: &BlockHandle) {
+        let mut handle_encoding = Vec::new();
+        handle.encode_to(&mut handle_encoding);
+        self.index_block.add(last_key, &handle_encoding);
+    }
+
+    fn finish(&mut self, _write_block: &mut dyn FnMut(&[u8]) -> Result<BlockHandle>) -> Result<Vec<u8>> {
+        Ok(self.index_block.finish().to_vec())
+    }
+}
+
+/// Builds an index split into partitions of about `metadata_block_size`
+/// bytes. Each partition is written as a block of its own, and the
+/// returned top-level index maps the last key of every partition to its
+/// handle.
+pub struct PartitionedIndexBuilder {
+
+    metadata_block_size: usize,
+
+    sub_index: BinarySearchIndexBuilder,
+
+    // Last key added to sub_index
+    sub_index_last_key: Vec<u8>,
+
+    // Finished partitions and their last keys
+    partitions: Vec<(Vec<u8>, Vec<u8>)>,
+
+    delta_encoded_restarts: bool
+}
+
+impl PartitionedIndexBuilder {
+
+    pub fn new(metadata_block_size: usize, delta_encoded_restarts: bool) -> Self {
+        PartitionedIndexBuilder {
+            metadata_block_size,
+            sub_index: BinarySearchIndexBuilder::new(delta_encoded_restarts),
+            sub_index_last_key: Vec::new(),
+            partitions: Vec::new(),
+            delta_encoded_restarts
+        }
+    }
+
+    fn cut_partition(&mut self) {
+        if self.sub_index.index_block.empty() {
+            return;
+        }
+        let contents = self.sub_index.index_block.finish().to_vec();
+        self.sub_index.index_block.reset();
+        self.partitions.push((std::mem::take(&mut self.sub_index_last_key), contents));
+    }
+}
+
+impl IndexBuilder for PartitionedIndexBuilder {
+
+    fn add_index_entry(&mut self, last_key: &[u8], handle: &BlockHandle) {
+        self.sub_index.add_index_entry(last_key, handle);
+        self.sub_index_last_key.clear();
+        self.sub_index_last_key.extend_from_slice(last_key);
+        if self.sub_index.index_block.current_size_estimate() >= self.metadata_block_size {
+            self.cut_partition();
+        }
+    }
+
+    fn finish(&mut self, write_block: &mut dyn FnMut(&[u8]) -> Result<BlockHandle>) -> Result<Vec<u8>> {
+        self.cut_partition();
+        let mut top_level_index = BinarySearchIndexBuilder::new(self.delta_encoded_restarts);
+        for (last_key, contents) in self.partitions.drain(..) {
+            let handle = write_block(&contents)?;
+            top_level_index.add_index_entry(&last_key, &handle);
+        }
+        top_level_index.finish(write_block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::coding::get_varint32;
+    use crate::table::block_builder::decode_restart_array;
+    use super::*;
+
+    /// Decodes every entry of a block built with a restart interval of 1.
+    fn decode_block(contents: &[u8]) -> Vec<(Vec<u8>, BlockHandle)> {
+        let (_, limit) = decode_restart_array(contents).unwrap();
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset < limit {
+            let mut field = || {
+                let (v, n) = get_varint32(contents, offset, limit).unwrap();
+                offset += n;
+                v as usize
+            };
+            let (shared, non_shared, value_length) = (field(), field(), field());
+            assert_eq!(0, shared);
+            let key = contents[offset..offset + non_shared].to_vec();
+            offset += non_shared;
+            let (handle, n) = BlockHandle::decode_from(&contents[offset..offset + value_length]).unwrap();
+            assert_eq!(n, value_length);
+            offset += value_length;
+            entries.push((key, handle));
+        }
+        entries
+    }
+
+    fn build(builder: &mut dyn IndexBuilder, n: u64) -> (Vec<u8>, Vec<Vec<u8>>) {
+        for i in 0..n {
+            builder.add_index_entry(format!("key{:04}", i).as_bytes(), &BlockHandle::new(i * 100, 100));
+        }
+        let mut blocks = Vec::new();
+        let index = builder.finish(&mut |contents| {
+            blocks.push(contents.to_vec());
+            Ok(BlockHandle::new(1_000_000 + blocks.len() as u64 - 1, contents.len() as u64))
+        }).unwrap();
+        (index, blocks)
+    }
+
+    #[test]
+    fn test_binary_search_index() {
+        let (index, blocks) = build(&mut BinarySearchIndexBuilder::new(false), 10);
+        assert!(blocks.is_empty());
+        let entries = decode_block(&index);
+        assert_eq!(10, entries.len());
+        assert_eq!((b"key0003".to_vec(), BlockHandle::new(300, 100)), entries[3]);
+
+        // Delta encoded restarts hold the same entries in less space
+        let (delta_index, _) = build(&mut BinarySearchIndexBuilder::new(true), 10);
+        assert_eq!(entries, decode_block(&delta_index));
+        assert_eq!(index.len() - 40 + 13 + 4, delta_index.len());
+    }
+
+    #[test]
+    fn test_partitioned_index() {
+        let (index, partitions) = build(&mut PartitionedIndexBuilder::new(256, true), 100);
+        assert!(partitions.len() > 1);
+        let top_level = decode_block(&index);
+        assert_eq!(partitions.len(), top_level.len());
+
+        // The partitions hold every entry in order, and each is indexed by
+        // its last key
+        let mut all = Vec::new();
+        for (i, partition) in partitions.iter().enumerate() {
+            assert!(partition.len() < 256 + 64);
+            let entries = decode_block(partition);
+            assert_eq!(top_level[i].0, entries.last().unwrap().0);
+            assert_eq!(BlockHandle::new(1_000_000 + i as u64, partition.len() as u64), top_level[i].1);
+            all.extend(entries);
+        }
+        let expected = (0..100u64)
+            .map(|i| (format!("key{:04}", i).into_bytes(), BlockHandle::new(i * 100, 100)))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, all);
+    }
+
+    #[test]
+    fn test_new_index_builder() {
+        let options = Options { index_type: IndexType::TwoLevelIndexSearch, metadata_block_size: 64, ..Options::default() };
+        let (_, partitions) = build(new_index_builder(&options).as_mut(), 20);
+        assert!(partitions.len() > 1);
+        let (_, partitions) = build(new_index_builder(&Options::default()).as_mut(), 20);
+        assert!(partitions.is_empty());
+    }
+}
@@ -0,0 +1,414 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An iterator over the union of the entries of several iterators, such
+//! as the memtables and the table files of the db.
+
+use std::cmp::Ordering;
+use std::rc::Rc;
+use crate::comparator::Comparator;
+use crate::iterator::InternalIterator;
+use crate::Result;
+use crate::slice::Slice;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Forward,
+    Reverse
+}
+
+/// Return an iterator that provides the union of the data in
+/// children. For example, an iterator over the memtable and all the
+/// tables of the db. The result does no duplicate suppression: if a key
+/// is present in K child iterators, it will be yielded K times.
+pub struct MergingIterator<'a> {
+
+    comparator: Rc<dyn Comparator>,
+
+    children: Vec<Box<dyn InternalIterator + 'a>>,
+
+    // Index of the child the iterator is positioned at, if any
+    current: Option<usize>,
+
+    // Which direction is the iterator moving?
+    direction: Direction
+}
+
+impl<'a> MergingIterator<'a> {
+
+    pub fn new(comparator: Rc<dyn Comparator>, children: Vec<Box<dyn InternalIterator + 'a>>) -> Self {
+        MergingIterator {
+            comparator,
+            children,
+            current: None,
+            direction: Direction::Forward
+        }
+    }
+
+    fn find_smallest(&mut self) {
+        let mut smallest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate() {
+            if !child.valid() {
+                continue;
+            }
+            if let Some(s) = smallest {
+                if self.comparator.compare(&child.key(), &self.children[s].key()) != Ordering::Less {
+                    continue;
+                }
+            }
+            smallest = Some(i);
+        }
+        self.current = smallest;
+    }
+
+    fn find_largest(&mut self) {
+        let mut largest: Option<usize> = None;
+        for (i, child) in self.children.iter().enumerate().rev() {
+            if !child.valid() {
+                continue;
+            }
+            if let Some(l) = largest {
+                if self.comparator.compare(&child.key(), &self.children[l].key()) != Ordering::Greater {
+                    continue;
+                }
+            }
+            largest = Some(i);
+        }
+        self.current = largest;
+    }
+}
+
+impl<'a> InternalIterator for MergingIterator<'a> {
+
+    fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn seek_to_first(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_first();
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn seek_to_last(&mut self) {
+        for child in self.children.iter_mut() {
+            child.seek_to_last();
+        }
+        self.find_largest();
+        self.direction = Direction::Reverse;
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        for child in self.children.iter_mut() {
+            child.seek(target);
+        }
+        self.find_smallest();
+        self.direction = Direction::Forward;
+    }
+
+    fn next(&mut self) {
+        let current = self.current.expect("next() on an invalid iterator");
+
+        // Ensure that all children are positioned after key().
+        // If we are moving in the forward direction, it is already
+        // true for all of the non-current children since current is
+        // the smallest child and key() == current.key(). Otherwise,
+        // we explicitly position the non-current children.
+        if self.direction != Direction::Forward {
+            let key = self.key().data().to_vec();
+            let key = Slice::from_bytes(&key);
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.valid() && self.comparator.compare(&key, &child.key()) == Ordering::Equal {
+                    child.next();
+                }
+            }
+            self.direction = Direction::Forward;
+        }
+
+        self.children[current].next();
+        self.find_smallest();
+    }
+
+    fn prev(&mut self) {
+        let current = self.current.expect("prev() on an invalid iterator");
+
+        // Ensure that all children are positioned before key().
+        // If we are moving in the reverse direction, it is already
+        // true for all of the non-current children since current is
+        // the largest child and key() == current.key(). Otherwise,
+        // we explicitly position the non-current children.
+        if self.direction != Direction::Reverse {
+            let key = self.key().data().to_vec();
+            let key = Slice::from_bytes(&key);
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if i == current {
+                    continue;
+                }
+                child.seek(&key);
+                if child.valid() {
+                    // Child is at first entry >= key(). Step back one to be < key()
+                    child.prev();
+                } else {
+                    // Child has no entries >= key(). Position at last entry.
+                    child.seek_to_last();
+                }
+            }
+            self.direction = Direction::Reverse;
+        }
+
+        self.children[current].prev();
+        self.find_largest();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        self.children[self.current.expect("key() on an invalid iterator")].key()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        self.children[self.current.expect("value() on an invalid iterator")].value()
+    }
+
+    fn status(&self) -> Result<()> {
+        for child in &self.children {
+            child.status()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::build_table;
+    use crate::comparator::bytewise_compare;
+    use crate::dbformat::{append_internal_key, InternalKeyComparator, ValueType};
+    use crate::Error::IOError;
+    use crate::memtable::MemTable;
+    use crate::options::{Options, ReadOptions};
+    use crate::random::Random;
+    use crate::table_cache::TableCache;
+    use crate::version_edit::FileMetaData;
+    use super::*;
+
+    fn icmp() -> InternalKeyComparator {
+        InternalKeyComparator::new(bytewise_compare)
+    }
+
+    struct VecIter {
+        entries: Vec<(Vec<u8>, Vec<u8>)>,
+        pos: usize
+    }
+
+    impl InternalIterator for VecIter {
+        fn valid(&self) -> bool {
+            self.pos < self.entries.len()
+        }
+
+        fn seek_to_first(&mut self) {
+            self.pos = 0;
+        }
+
+        fn seek_to_last(&mut self) {
+            self.pos = if self.entries.is_empty() { 0 } else { self.entries.len() - 1 };
+        }
+
+        fn seek(&mut self, target: &Slice) {
+            self.pos = self.entries.iter()
+                .position(|(key, _)| icmp().compare(&Slice::from_bytes(key), target) != Ordering::Less)
+                .unwrap_or(self.entries.len());
+        }
+
+        fn next(&mut self) {
+            self.pos += 1;
+        }
+
+        fn prev(&mut self) {
+            // Stepping back from the first entry invalidates the iterator
+            self.pos = self.pos.checked_sub(1).unwrap_or(self.entries.len());
+        }
+
+        fn key(&self) -> Slice<'_> {
+            Slice::from_bytes(&self.entries[self.pos].0)
+        }
+
+        fn value(&self) -> Slice<'_> {
+            Slice::from_bytes(&self.entries[self.pos].1)
+        }
+
+        fn status(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_merging_iterator() {
+        let mut rnd = Random::new(301);
+        let mut all = Vec::new();
+        let mut children: Vec<Box<dyn InternalIterator>> = Vec::new();
+        for child in 0..5u64 {
+            let mut entries = Vec::new();
+            for i in 0..(child * 37) {
+                if rnd.one_in(2) {
+                    // Children share user keys, with distinct sequences
+                    let mut key = Vec::new();
+                    append_internal_key(&mut key, &Slice::from_str(&format!("{:05}", i)), child, ValueType::KTypeValue);
+                    entries.push((key, format!("v{}", i).into_bytes()));
+                }
+            }
+            all.extend(entries.iter().cloned());
+            children.push(Box::new(VecIter { entries, pos: 0 }));
+        }
+        all.sort_by(|(a, _), (b, _)| icmp().compare(&Slice::from_bytes(a), &Slice::from_bytes(b)));
+        let mut iter = MergingIterator::new(Rc::new(icmp()), children);
+
+        iter.seek_to_first();
+        let mut forward = Vec::new();
+        while iter.valid() {
+            forward.push((iter.key().data().to_vec(), iter.value().data().to_vec()));
+            iter.next();
+        }
+        assert_eq!(all, forward);
+
+        iter.seek_to_last();
+        let mut backward = Vec::new();
+        while iter.valid() {
+            backward.push((iter.key().data().to_vec(), iter.value().data().to_vec()));
+            iter.prev();
+        }
+        backward.reverse();
+        assert_eq!(all, backward);
+
+        // Switch directions in the middle
+        let middle = all.len() / 2;
+        iter.seek(&Slice::from_bytes(&all[middle].0));
+        assert_eq!(all[middle].0, iter.key().data());
+        iter.prev();
+        assert_eq!(all[middle - 1].0, iter.key().data());
+        iter.next();
+        iter.next();
+        assert_eq!(all[middle + 1].0, iter.key().data());
+        iter.prev();
+        assert_eq!(all[middle].0, iter.key().data());
+
+        let mut empty = MergingIterator::new(Rc::new(icmp()), Vec::new());
+        empty.seek_to_first();
+        assert!(!empty.valid());
+        assert_eq!(Ok(()), empty.status());
+    }
+
+    fn ikey(user_key: &str, seq: u64) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str(user_key), seq, ValueType::KTypeValue);
+        key
+    }
+
+    #[test]
+    fn test_merging_heterogeneous_children() {
+        let dbname = std::env::temp_dir().join("revel_merger_test");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let options = Options::default();
+
+        // A table, a memtable and a plain iterator, the user key "c" in all
+        // three of them
+        let flushed = MemTable::new(icmp());
+        for key in ["a", "c", "e"] {
+            flushed.add(1, ValueType::KTypeValue, &Slice::from_str(key), &Slice::from_str("table"));
+        }
+        let mut meta = FileMetaData { number: 7, ..FileMetaData::default() };
+        build_table(&dbname, &options, &mut flushed.iter(), &[], &mut meta).unwrap();
+        let table_cache = TableCache::new(&dbname, &options, 10);
+
+        let mem = MemTable::new(icmp());
+        mem.add(5, ValueType::KTypeValue, &Slice::from_str("b"), &Slice::from_str("mem"));
+        mem.add(6, ValueType::KTypeValue, &Slice::from_str("c"), &Slice::from_str("mem"));
+        mem.add(7, ValueType::KTypeValue, &Slice::from_str("f"), &Slice::from_str("mem"));
+
+        let vec_iter = VecIter {
+            entries: vec![(ikey("c", 2), b"vec".to_vec()), (ikey("d", 3), b"vec".to_vec())],
+            pos: 0
+        };
+
+        let children: Vec<Box<dyn InternalIterator>> = vec![
+            table_cache.iter(&ReadOptions::default(), 7, meta.file_size),
+            Box::new(mem.iter()),
+            Box::new(vec_iter)
+        ];
+        let mut iter = MergingIterator::new(Rc::new(icmp()), children);
+        let entry = |iter: &MergingIterator| (iter.key().data().to_vec(), String::from_utf8_lossy(iter.value().data()).to_string());
+        let expected: Vec<(Vec<u8>, String)> = [("a", 1, "table"), ("b", 5, "mem"), ("c", 6, "mem"), ("c", 2, "vec"),
+                                                ("c", 1, "table"), ("d", 3, "vec"), ("e", 1, "table"), ("f", 7, "mem")]
+            .into_iter()
+            .map(|(key, seq, value)| (ikey(key, seq), value.to_string()))
+            .collect();
+
+        let mut forward = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            forward.push(entry(&iter));
+            iter.next();
+        }
+        assert_eq!(expected, forward);
+
+        let mut backward = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            backward.push(entry(&iter));
+            iter.prev();
+        }
+        backward.reverse();
+        assert_eq!(expected, backward);
+
+        // Turning around among the entries of one user key spread over the
+        // children
+        iter.seek(&Slice::from_bytes(&ikey("c", 2)));
+        assert_eq!(expected[3], entry(&iter));
+        iter.prev();
+        assert_eq!(expected[2], entry(&iter));
+        iter.prev();
+        assert_eq!(expected[1], entry(&iter));
+        iter.next();
+        iter.next();
+        assert_eq!(expected[3], entry(&iter));
+        iter.next();
+        assert_eq!(expected[4], entry(&iter));
+        iter.prev();
+        assert_eq!(expected[3], entry(&iter));
+        assert_eq!(Ok(()), iter.status());
+    }
+
+    #[test]
+    fn test_merging_child_error() {
+        let dbname = std::env::temp_dir().join("revel_merger_error_test");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let table_cache = TableCache::new(&dbname, &Options::default(), 10);
+
+        // The entries of the healthy children are still yielded, and the
+        // failure of the missing table shows in the status
+        let children: Vec<Box<dyn InternalIterator>> = vec![
+            Box::new(VecIter { entries: vec![(ikey("a", 1), b"v".to_vec())], pos: 0 }),
+            table_cache.iter(&ReadOptions::default(), 9, 1000)
+        ];
+        let mut iter = MergingIterator::new(Rc::new(icmp()), children);
+        iter.seek_to_first();
+        assert_eq!(ikey("a", 1), iter.key().data());
+        iter.next();
+        assert!(!iter.valid());
+        assert_eq!(Err(IOError), iter.status());
+    }
+}
@@ -0,0 +1,319 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! TableBuilder provides the interface used to build a Table
+//! (an immutable and sorted map from keys to values).
+//!
+//! A table file has the form:
+//!     data blocks
+//!     filter block (with a filter policy only)
+//!     index partitions (partitioned indexes only)
+//!     index block
+//!     range deletion block (with range tombstones only)
+//!     properties block
+//!     metaindex block
+//!     footer
+//! Every block is followed by a trailer holding its type byte and the
+//! masked crc of its contents and type. The metaindex maps the names of
+//! meta entries to their values: "filter.<policy name>" to the handle of
+//! the filter block, format::K_INDEX_TYPE_META_KEY to the index type,
+//! "revel.range_del" to the handle of the range deletion block, and
+//! "revel.properties" to the handle of the properties block.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::comparator::Comparator;
+use crate::dbformat::{extract_user_key, InternalKeyComparator, ValueType};
+use crate::env::WritableFile;
+use crate::filter_policy::FilterPolicy;
+use crate::options::{CompressionType, DataBlockIndexType, IndexType, Options};
+use crate::range_del::{RangeTombstone, K_RANGE_DEL_BLOCK};
+use crate::Result;
+use crate::slice::Slice;
+use crate::table::block_builder::BlockBuilder;
+use crate::table::filter_block::FilterBlockBuilder;
+use crate::table::format::{block_checksum, compress_block, encode_index_type, BlockHandle, Footer, K_INDEX_TYPE_META_KEY};
+use crate::table::index_builder::{new_index_builder, IndexBuilder};
+use crate::table_properties::{TableProperties, TablePropertiesCollector, K_PROPERTIES_BLOCK};
+
+pub struct TableBuilder {
+
+    options: Options,
+
+    icmp: InternalKeyComparator,
+
+    file: Rc<RefCell<dyn WritableFile>>,
+
+    // Offset in the file where the next block is written
+    offset: u64,
+
+    data_block: BlockBuilder,
+
+    index_builder: Box<dyn IndexBuilder>,
+
+    filter_block: Option<FilterBlockBuilder>,
+
+    last_key: Vec<u8>,
+
+    num_entries: u64,
+
+    range_tombstones: Vec<RangeTombstone>,
+
+    properties: TableProperties,
+
+    collectors: Vec<Box<dyn TablePropertiesCollector>>,
+
+    // Either finish() or abandon() has been called.
+    closed: bool
+}
+
+impl TableBuilder {
+
+    /// Create a builder that will store the contents of the table it is
+    /// building in `file`. Keys are internal keys ordered by the
+    /// comparator of `options`.
+    pub fn new(options: &Options, file: Rc<RefCell<dyn WritableFile>>) -> Self {
+        let data_block = match options.data_block_index_type {
+            DataBlockIndexType::BinarySearch => BlockBuilder::new(options.block_restart_interval),
+            DataBlockIndexType::BinaryAndHash => {
+                BlockBuilder::with_hash_index(options.block_restart_interval, options.data_block_hash_table_util_ratio)
+            }
+        };
+        let mut filter_block = options.filter_policy.clone().map(FilterBlockBuilder::new);
+        if let Some(filter_block) = filter_block.as_mut() {
+            filter_block.start_block(0);
+        }
+        TableBuilder {
+            options: options.clone(),
+            icmp: InternalKeyComparator::new(options.comparator),
+            file,
+            offset: 0,
+            data_block,
+            index_builder: new_index_builder(options),
+            filter_block,
+            last_key: Vec::new(),
+            num_entries: 0,
+            range_tombstones: Vec::new(),
+            properties: TableProperties::default(),
+            collectors: options.table_properties_collectors.iter()
+                .map(|factory| factory.create_table_properties_collector())
+                .collect(),
+            closed: false
+        }
+    }
+
+    /// Add key,value to the table being constructed.
+    /// REQUIRES: key is after any previously added key according to comparator.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result {
+        assert!(!self.closed);
+        if self.num_entries > 0 {
+            assert_eq!(Ordering::Greater, self.icmp.compare(&Slice::from_bytes(key), &Slice::from_bytes(&self.last_key)));
+        }
+        let user_key = extract_user_key(&Slice::from_bytes(key));
+        if let Some(filter_block) = self.filter_block.as_mut() {
+            filter_block.add_key(user_key.data());
+        }
+        for collector in self.collectors.iter_mut() {
+            collector.add(user_key.data(), value);
+        }
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.num_entries += 1;
+        if key.len() >= 8 && key[key.len() - 8] == ValueType::KTypeDeletion as u8 {
+            self.properties.num_deletions += 1;
+        }
+        self.properties.raw_key_size += key.len() as u64;
+        self.properties.raw_value_size += value.len() as u64;
+        self.data_block.add(key, value);
+
+        if self.data_block.current_size_estimate() >= self.options.block_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Add a range tombstone to the table being constructed. Tombstones
+    /// are stored apart from the entries, in any order.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn add_range_tombstone(&mut self, tombstone: &RangeTombstone) {
+        assert!(!self.closed);
+        self.range_tombstones.push(tombstone.clone());
+    }
+
+    /// Advanced operation: flush any buffered key/value pairs to file.
+    /// Can be used to ensure that two adjacent entries never live in
+    /// the same data block.  Most clients should not need to use this method.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn flush(&mut self) -> Result {
+        assert!(!self.closed);
+        if self.data_block.empty() {
+            return Ok(());
+        }
+        let handle = write_block(&self.file, &mut self.offset, self.data_block.finish(), self.options.compression)?;
+        self.data_block.reset();
+        self.properties.num_data_blocks += 1;
+        self.index_builder.add_index_entry(&self.last_key, &handle);
+        if let Some(filter_block) = self.filter_block.as_mut() {
+            filter_block.start_block(self.offset);
+        }
+        self.file.borrow_mut().flush()
+    }
+
+    /// Finish building the table.  Stops using the file passed to the
+    /// constructor after this function returns.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn finish(&mut self) -> Result {
+        let flushed = self.flush();
+        self.closed = true;
+        flushed?;
+
+        self.properties.data_size = self.offset;
+
+        // Write filter block
+        let mut filter_block_handle = None;
+        if let Some(filter_block) = self.filter_block.as_mut() {
+            filter_block_handle = Some(write_raw_block(&self.file, &mut self.offset, filter_block.finish(), CompressionType::NoCompression)?);
+        }
+        self.properties.filter_size = self.offset - self.properties.data_size;
+
+        // Write index block
+        let index_offset = self.offset;
+        let file = &self.file;
+        let offset = &mut self.offset;
+        let compression = self.options.compression;
+        let index_contents = self.index_builder.finish(&mut |contents| write_block(file, offset, contents, compression))?;
+        let index_handle = write_block(&self.file, &mut self.offset, &index_contents, compression)?;
+        self.properties.index_size = self.offset - index_offset;
+
+        // Write range deletion block
+        let mut range_del_handle = None;
+        if !self.range_tombstones.is_empty() {
+            let mut entries: Vec<(Vec<u8>, &[u8])> = self.range_tombstones.iter()
+                .map(|t| (t.internal_key(), t.end_key.as_slice()))
+                .collect();
+            let icmp = &self.icmp;
+            entries.sort_by(|a, b| icmp.compare(&Slice::from_bytes(&a.0), &Slice::from_bytes(&b.0)));
+            let mut range_del_block = BlockBuilder::new(self.options.block_restart_interval);
+            for (key, end_key) in entries {
+                range_del_block.add(&key, end_key);
+            }
+            range_del_handle = Some(write_block(&self.file, &mut self.offset, range_del_block.finish(), compression)?);
+            self.properties.num_range_deletions = self.range_tombstones.len() as u64;
+        }
+
+        // Write properties block
+        self.properties.num_entries = self.num_entries;
+        self.properties.creation_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        for collector in self.collectors.iter_mut() {
+            self.properties.user_collected_properties.extend(collector.finish());
+        }
+        let mut properties_block = BlockBuilder::new(self.options.block_restart_interval);
+        for (name, value) in self.properties.encode() {
+            properties_block.add(&name, &value);
+        }
+        let properties_handle = write_block(&self.file, &mut self.offset, properties_block.finish(), compression)?;
+
+        // Write metaindex block, whose entries must be added in order
+        let mut meta_entries = BTreeMap::new();
+        if let (Some(policy), Some(handle)) = (self.options.filter_policy.as_ref(), filter_block_handle) {
+            // Add mapping from "filter.Name" to location of filter data
+            let mut handle_encoding = Vec::new();
+            handle.encode_to(&mut handle_encoding);
+            meta_entries.insert(filter_meta_key(policy.as_ref()), handle_encoding);
+        }
+        if self.options.index_type != IndexType::BinarySearch {
+            meta_entries.insert(K_INDEX_TYPE_META_KEY.to_vec(), vec![encode_index_type(self.options.index_type)]);
+        }
+        if let Some(handle) = range_del_handle {
+            let mut handle_encoding = Vec::new();
+            handle.encode_to(&mut handle_encoding);
+            meta_entries.insert(K_RANGE_DEL_BLOCK.to_vec(), handle_encoding);
+        }
+        let mut handle_encoding = Vec::new();
+        properties_handle.encode_to(&mut handle_encoding);
+        meta_entries.insert(K_PROPERTIES_BLOCK.to_vec(), handle_encoding);
+        let mut meta_index_block = BlockBuilder::new(self.options.block_restart_interval);
+        for (name, value) in meta_entries {
+            meta_index_block.add(&name, &value);
+        }
+        let metaindex_handle = write_block(&self.file, &mut self.offset, meta_index_block.finish(), compression)?;
+
+        // Write footer
+        let footer = Footer { metaindex_handle, index_handle, format_version: self.options.format_version };
+        let mut footer_encoding = Vec::new();
+        footer.encode_to(&mut footer_encoding);
+        self.file.borrow_mut().append(&Slice::from_bytes(&footer_encoding))?;
+        self.offset += footer_encoding.len() as u64;
+        self.file.borrow_mut().flush()
+    }
+
+    /// Indicate that the contents of this builder should be abandoned.  Stops
+    /// using the file passed to the constructor after this function returns.
+    /// If the caller is not going to call finish(), it must call abandon()
+    /// before destroying this builder.
+    /// REQUIRES: finish(), abandon() have not been called
+    pub fn abandon(&mut self) {
+        assert!(!self.closed);
+        self.closed = true;
+    }
+
+    /// Size of the file generated so far.  If invoked after a successful
+    /// finish() call, returns the size of the final generated file.
+    pub fn file_size(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl Drop for TableBuilder {
+    fn drop(&mut self) {
+        // Catch errors where caller forgot to call finish()
+        debug_assert!(self.closed || std::thread::panicking());
+    }
+}
+
+/// Returns the name of the metaindex entry of the filter block built by
+/// `policy`.
+pub fn filter_meta_key(policy: &dyn FilterPolicy) -> Vec<u8> {
+    format!("filter.{}", policy.name()).into_bytes()
+}
+
+/// Writes `raw` compressed with `compression`, or uncompressed if the
+/// compression saves less than 12.5%, and returns the handle of the block.
+fn write_block(file: &RefCell<dyn WritableFile>, offset: &mut u64, raw: &[u8], compression: CompressionType) -> Result<BlockHandle> {
+    if compression != CompressionType::NoCompression {
+        if let Some(compressed) = compress_block(compression, raw) {
+            if compressed.len() < raw.len() - raw.len() / 8 {
+                return write_raw_block(file, offset, &compressed, compression);
+            }
+        }
+    }
+    // Compression not supported, or compressed less than 12.5%, so just
+    // store uncompressed form
+    write_raw_block(file, offset, raw, CompressionType::NoCompression)
+}
+
+/// Writes `contents` followed by the block trailer at `offset` and returns
+/// the handle of the block.
+fn write_raw_block(file: &RefCell<dyn WritableFile>, offset: &mut u64, contents: &[u8], block_type: CompressionType) -> Result<BlockHandle> {
+    let handle = BlockHandle::new(*offset, contents.len() as u64);
+    let mut trailer = vec![block_type as u8];
+    trailer.extend_from_slice(&block_checksum(contents, block_type as u8).to_le_bytes());
+    let mut file = file.borrow_mut();
+    file.append(&Slice::from_bytes(contents))?;
+    file.append(&Slice::from_bytes(&trailer))?;
+    *offset += (contents.len() + trailer.len()) as u64;
+    Ok(handle)
+}
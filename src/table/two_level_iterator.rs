@@ -0,0 +1,278 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An iterator over a two level structure such as a table file: an index
+//! iterator yields one value per block, and the entries are read from the
+//! iterator of the current block.
+
+use crate::iterator::InternalIterator;
+use crate::Result;
+use crate::slice::Slice;
+
+/// Converts an index value into an iterator over the contents of the
+/// corresponding block.
+pub type BlockFunction<'a> = Box<dyn FnMut(&[u8]) -> Result<Box<dyn InternalIterator + 'a>> + 'a>;
+
+/// Return a new two level iterator.  A two-level iterator contains an
+/// index iterator whose values point to a sequence of blocks where
+/// each block is itself a sequence of key,value pairs.  The returned
+/// two-level iterator yields the concatenation of all key/value pairs
+/// in the sequence of blocks. Blocks are only opened once the iterator
+/// reaches them, by `block_function`.
+pub struct TwoLevelIterator<'a> {
+
+    block_function: BlockFunction<'a>,
+
+    index_iter: Box<dyn InternalIterator + 'a>,
+
+    // May be None
+    data_iter: Option<Box<dyn InternalIterator + 'a>>,
+
+    // If data_iter is set, then data_block_handle holds the
+    // "index_value" passed to block_function to create the data_iter.
+    data_block_handle: Vec<u8>,
+
+    status: Result<()>
+}
+
+impl<'a> TwoLevelIterator<'a> {
+
+    pub fn new(index_iter: Box<dyn InternalIterator + 'a>, block_function: BlockFunction<'a>) -> Self {
+        TwoLevelIterator {
+            block_function,
+            index_iter,
+            data_iter: None,
+            data_block_handle: Vec::new(),
+            status: Ok(())
+        }
+    }
+
+    fn save_error(&mut self, s: Result<()>) {
+        if self.status.is_ok() && s.is_err() {
+            self.status = s;
+        }
+    }
+
+    fn skip_empty_data_blocks_forward(&mut self) {
+        while !self.data_iter.as_ref().is_some_and(|iter| iter.valid()) {
+            // Move to next block
+            if !self.index_iter.valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            self.index_iter.next();
+            self.init_data_block();
+            if let Some(iter) = self.data_iter.as_mut() {
+                iter.seek_to_first();
+            }
+        }
+    }
+
+    fn skip_empty_data_blocks_backward(&mut self) {
+        while !self.data_iter.as_ref().is_some_and(|iter| iter.valid()) {
+            // Move to previous block
+            if !self.index_iter.valid() {
+                self.set_data_iterator(None);
+                return;
+            }
+            self.index_iter.prev();
+            self.init_data_block();
+            if let Some(iter) = self.data_iter.as_mut() {
+                iter.seek_to_last();
+            }
+        }
+    }
+
+    fn set_data_iterator(&mut self, data_iter: Option<Box<dyn InternalIterator + 'a>>) {
+        if let Some(iter) = self.data_iter.as_ref() {
+            let s = iter.status();
+            self.save_error(s);
+        }
+        self.data_iter = data_iter;
+    }
+
+    fn init_data_block(&mut self) {
+        if !self.index_iter.valid() {
+            self.set_data_iterator(None);
+            return;
+        }
+        let handle = self.index_iter.value();
+        if self.data_iter.is_some() && handle.data() == self.data_block_handle.as_slice() {
+            // data_iter is already constructed with this iterator, so
+            // no need to change anything
+            return;
+        }
+        let handle = handle.data().to_vec();
+        match (self.block_function)(&handle) {
+            Ok(iter) => {
+                self.data_block_handle = handle;
+                self.set_data_iterator(Some(iter));
+            }
+            Err(e) => {
+                self.set_data_iterator(None);
+                self.save_error(Err(e));
+            }
+        }
+    }
+}
+
+impl<'a> InternalIterator for TwoLevelIterator<'a> {
+
+    fn valid(&self) -> bool {
+        self.data_iter.as_ref().is_some_and(|iter| iter.valid())
+    }
+
+    fn seek_to_first(&mut self) {
+        self.index_iter.seek_to_first();
+        self.init_data_block();
+        if let Some(iter) = self.data_iter.as_mut() {
+            iter.seek_to_first();
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.index_iter.seek_to_last();
+        self.init_data_block();
+        if let Some(iter) = self.data_iter.as_mut() {
+            iter.seek_to_last();
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.index_iter.seek(target);
+        self.init_data_block();
+        if let Some(iter) = self.data_iter.as_mut() {
+            iter.seek(target);
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn next(&mut self) {
+        assert!(self.valid());
+        if let Some(iter) = self.data_iter.as_mut() {
+            iter.next();
+        }
+        self.skip_empty_data_blocks_forward();
+    }
+
+    fn prev(&mut self) {
+        assert!(self.valid());
+        if let Some(iter) = self.data_iter.as_mut() {
+            iter.prev();
+        }
+        self.skip_empty_data_blocks_backward();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        assert!(self.valid());
+        self.data_iter.as_ref().unwrap().key()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        assert!(self.valid());
+        self.data_iter.as_ref().unwrap().value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.index_iter.status()?;
+        if let Some(iter) = self.data_iter.as_ref() {
+            iter.status()?;
+        }
+        self.status.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+    use crate::comparator::bytewise_compare;
+    use crate::dbformat::{append_internal_key, InternalKeyComparator, ValueType};
+    use crate::Error::Corruption;
+    use crate::table::block::Block;
+    use crate::table::block_builder::BlockBuilder;
+    use super::*;
+
+    fn ikey(user_key: &str) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str(user_key), 1, ValueType::KTypeValue);
+        key
+    }
+
+    fn block(entries: &[(&str, &str)]) -> Rc<Block> {
+        let mut builder = BlockBuilder::new(16);
+        for (key, value) in entries {
+            builder.add(&ikey(key), value.as_bytes());
+        }
+        Rc::new(Block::new(builder.finish().to_vec()).unwrap())
+    }
+
+    fn keys(iter: &mut TwoLevelIterator, forward: bool) -> Vec<String> {
+        let mut keys = Vec::new();
+        while iter.valid() {
+            let key = iter.key().data();
+            keys.push(String::from_utf8(key[..key.len() - 8].to_vec()).unwrap());
+            if forward {
+                iter.next();
+            } else {
+                iter.prev();
+            }
+        }
+        keys
+    }
+
+    #[test]
+    fn test_two_level_iterator() {
+        let icmp = Rc::new(InternalKeyComparator::new(bytewise_compare));
+        // The index maps the last key of every block to its name. Block
+        // "1" is empty and block "2" cannot be read.
+        let index = block(&[("b", "0"), ("c", "1"), ("d", "2"), ("f", "3")]);
+        let blocks = [block(&[("a", ""), ("b", "")]), block(&[]), block(&[("e", ""), ("f", "")])];
+        let opened = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let new_iter = || {
+            let icmp = icmp.clone();
+            let blocks = blocks.clone();
+            let opened = opened.clone();
+            TwoLevelIterator::new(Box::new(index.iter(icmp.clone())), Box::new(move |index_value| {
+                opened.borrow_mut().push(index_value.to_vec());
+                let block = match index_value {
+                    b"0" => &blocks[0],
+                    b"1" => &blocks[1],
+                    b"3" => &blocks[2],
+                    _ => return Err(Corruption)
+                };
+                Ok(Box::new(block.iter(icmp.clone())) as Box<dyn InternalIterator>)
+            }))
+        };
+
+        let mut iter = new_iter();
+        assert!(!iter.valid());
+        // Blocks are opened lazily
+        iter.seek(&Slice::from_bytes(&ikey("a")));
+        assert_eq!(vec![b"0".to_vec()], *opened.borrow());
+        assert_eq!(Ok(()), iter.status());
+        assert_eq!(vec!["a", "b", "e", "f"], keys(&mut iter, true));
+        assert_eq!(Err(Corruption), iter.status());
+
+        let mut iter = new_iter();
+        iter.seek_to_last();
+        assert_eq!(vec!["f", "e", "b", "a"], keys(&mut iter, false));
+        assert_eq!(Err(Corruption), iter.status());
+
+        let mut iter = new_iter();
+        iter.seek(&Slice::from_bytes(&ikey("bb")));
+        assert_eq!(vec!["e", "f"], keys(&mut iter, true));
+        iter.seek(&Slice::from_bytes(&ikey("g")));
+        assert!(!iter.valid());
+    }
+}
@@ -0,0 +1,145 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Thread-compatible cache of the open tables of a db, keyed by file
+//! number.
+
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::cache::{CachePriority, ShardedLRUCache};
+use crate::env::new_random_access_file;
+use crate::filename::table_file_name;
+use crate::iterator::{EmptyIterator, InternalIterator};
+use crate::options::{Options, ReadOptions};
+use crate::range_del::RangeTombstone;
+use crate::Result;
+use crate::slice::Slice;
+use crate::table::Table;
+use crate::table_properties::TableProperties;
+
+pub struct TableCache {
+
+    dbname: PathBuf,
+
+    options: Options,
+
+    // Every open table is charged 1, so the capacity bounds the number of
+    // open table files
+    cache: ShardedLRUCache<Rc<Table>>
+}
+
+impl TableCache {
+
+    /// Create a cache keeping up to `entries` tables of the db open.
+    pub fn new(dbname: &Path, options: &Options, entries: usize) -> Self {
+        TableCache {
+            dbname: dbname.to_path_buf(),
+            options: options.clone(),
+            cache: ShardedLRUCache::new(entries)
+        }
+    }
+
+    fn find_table(&self, file_number: u64, file_size: u64) -> Result<Rc<Table>> {
+        let key = file_number.to_le_bytes();
+        if let Some(table) = self.cache.lookup(&key) {
+            return Ok(table.as_ref().clone());
+        }
+        let file = new_random_access_file(table_file_name(&self.dbname, file_number))?;
+        // We do not cache error results so that if the error is transient,
+        // or somebody repairs the file, we recover automatically.
+        let table = Rc::new(Table::open(&self.options, file, file_number, file_size)?);
+        self.cache.insert(&key, table.clone(), 1, CachePriority::Low)?;
+        Ok(table)
+    }
+
+    /// Return the properties of the specified table.
+    pub fn get_properties(&self, file_number: u64, file_size: u64) -> Result<TableProperties> {
+        Ok(self.find_table(file_number, file_size)?.properties().clone())
+    }
+
+    /// Return an iterator for the specified file number (the corresponding
+    /// file length must be exactly `file_size` bytes). An error opening the
+    /// table is reported by the status of the iterator.
+    pub fn iter(&self, options: &ReadOptions, file_number: u64, file_size: u64) -> Box<dyn InternalIterator> {
+        match self.find_table(file_number, file_size) {
+            Ok(table) => Box::new(table.iter(options)),
+            Err(e) => Box::new(EmptyIterator::new(Err(e)))
+        }
+    }
+
+    /// Returns the first entry of the specified file at or after the
+    /// internal key `key`, see Table::get.
+    pub fn get(&self, options: &ReadOptions, file_number: u64, file_size: u64, key: &Slice) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        self.find_table(file_number, file_size)?.get(options, key)
+    }
+
+    /// Return the range tombstones of the specified table.
+    pub fn range_tombstones(&self, file_number: u64, file_size: u64) -> Result<Vec<RangeTombstone>> {
+        Ok(self.find_table(file_number, file_size)?.range_tombstones().to_vec())
+    }
+
+    /// Returns the approximate offset of the internal key `key` in the
+    /// specified file, see Table::approximate_offset_of. A table that
+    /// cannot be opened counts as empty.
+    pub fn approximate_offset_of(&self, file_number: u64, file_size: u64, key: &Slice) -> u64 {
+        self.find_table(file_number, file_size).map_or(0, |table| table.approximate_offset_of(key))
+    }
+
+    /// Evict any entry for the specified file number.
+    pub fn evict(&self, file_number: u64) {
+        self.cache.erase(&file_number.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::build_table;
+    use crate::dbformat::{append_internal_key, InternalKeyComparator, ValueType};
+    use crate::Error::IOError;
+    use crate::memtable::MemTable;
+    use crate::version_edit::FileMetaData;
+    use super::*;
+
+    #[test]
+    fn test_table_cache() {
+        let dbname = std::env::temp_dir().join("revel_table_cache_test");
+        let _ = std::fs::remove_dir_all(&dbname);
+        std::fs::create_dir_all(&dbname).unwrap();
+        let options = Options::default();
+        let mem = MemTable::new(InternalKeyComparator::new(options.comparator));
+        mem.add(1, ValueType::KTypeValue, &Slice::from_str("key"), &Slice::from_str("value"));
+        let mut meta = FileMetaData { number: 7, ..FileMetaData::default() };
+        build_table(&dbname, &options, &mut mem.iter(), &[], &mut meta).unwrap();
+
+        let table_cache = TableCache::new(&dbname, &options, 10);
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str("key"), 1, ValueType::KTypeValue);
+        let entry = (key.clone(), b"value".to_vec());
+        assert_eq!(Some(entry.clone()), table_cache.get(&ReadOptions::default(), 7, meta.file_size, &Slice::from_bytes(&key)).unwrap());
+        assert_eq!(1, table_cache.cache.entry_count());
+        let mut iter = table_cache.iter(&ReadOptions::default(), 7, meta.file_size);
+        iter.seek_to_first();
+        assert_eq!(entry.0, iter.key().data());
+        drop(iter);
+        assert_eq!(1, table_cache.cache.entry_count());
+
+        // Open tables stay usable after their file is removed, until evicted
+        std::fs::remove_file(table_file_name(&dbname, 7)).unwrap();
+        assert!(table_cache.get(&ReadOptions::default(), 7, meta.file_size, &Slice::from_bytes(&key)).is_ok());
+        table_cache.evict(7);
+        assert_eq!(Err(IOError), table_cache.get(&ReadOptions::default(), 7, meta.file_size, &Slice::from_bytes(&key)));
+        let mut iter = table_cache.iter(&ReadOptions::default(), 7, meta.file_size);
+        iter.seek_to_first();
+        assert!(!iter.valid());
+        assert_eq!(Err(IOError), iter.status());
+    }
+}
@@ -0,0 +1,181 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Statistics of a table file, stored in its properties block. Besides
+//! the builtin properties, TablePropertiesCollectors registered in
+//! Options::table_properties_collectors record custom ones.
+
+use std::collections::BTreeMap;
+use crate::coding::{get_varint64, put_varint64};
+use crate::Error::Corruption;
+use crate::Result;
+
+/// Name of the metaindex entry of the properties block.
+pub(crate) const K_PROPERTIES_BLOCK: &[u8] = b"revel.properties";
+
+const K_NUM_ENTRIES: &str = "revel.num.entries";
+const K_NUM_DELETIONS: &str = "revel.num.deletions";
+const K_NUM_RANGE_DELETIONS: &str = "revel.num.range-deletions";
+const K_NUM_DATA_BLOCKS: &str = "revel.num.data.blocks";
+const K_RAW_KEY_SIZE: &str = "revel.raw.key.size";
+const K_RAW_VALUE_SIZE: &str = "revel.raw.value.size";
+const K_DATA_SIZE: &str = "revel.data.size";
+const K_INDEX_SIZE: &str = "revel.index.size";
+const K_FILTER_SIZE: &str = "revel.filter.size";
+const K_CREATION_TIME: &str = "revel.creation.time";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableProperties {
+
+    /// Number of entries in the table.
+    pub num_entries: u64,
+
+    /// Number of deletion markers among the entries.
+    pub num_deletions: u64,
+
+    /// Number of range tombstones, which are not counted as entries.
+    pub num_range_deletions: u64,
+
+    /// Number of data blocks in the table.
+    pub num_data_blocks: u64,
+
+    /// Total size of the keys, as internal keys.
+    pub raw_key_size: u64,
+
+    /// Total size of the values.
+    pub raw_value_size: u64,
+
+    /// Bytes taken by the data blocks, as stored.
+    pub data_size: u64,
+
+    /// Bytes taken by the index, including its partitions.
+    pub index_size: u64,
+
+    /// Bytes taken by the filter block.
+    pub filter_size: u64,
+
+    /// When the table was built, in seconds since the unix epoch.
+    pub creation_time: u64,
+
+    /// Properties recorded by the TablePropertiesCollectors of the options
+    /// the table was built with.
+    pub user_collected_properties: BTreeMap<String, Vec<u8>>
+}
+
+impl TableProperties {
+
+    /// Returns the contents of a properties block: the properties sorted by
+    /// name. User collected properties must not use the "revel." prefix.
+    pub(crate) fn encode(&self) -> BTreeMap<Vec<u8>, Vec<u8>> {
+        let mut properties: BTreeMap<Vec<u8>, Vec<u8>> = self.user_collected_properties.iter()
+            .map(|(name, value)| (name.as_bytes().to_vec(), value.clone()))
+            .collect();
+        for (name, value) in [
+            (K_NUM_ENTRIES, self.num_entries),
+            (K_NUM_DELETIONS, self.num_deletions),
+            (K_NUM_RANGE_DELETIONS, self.num_range_deletions),
+            (K_NUM_DATA_BLOCKS, self.num_data_blocks),
+            (K_RAW_KEY_SIZE, self.raw_key_size),
+            (K_RAW_VALUE_SIZE, self.raw_value_size),
+            (K_DATA_SIZE, self.data_size),
+            (K_INDEX_SIZE, self.index_size),
+            (K_FILTER_SIZE, self.filter_size),
+            (K_CREATION_TIME, self.creation_time)
+        ] {
+            let mut encoded = Vec::new();
+            put_varint64(&mut encoded, value);
+            properties.insert(name.as_bytes().to_vec(), encoded);
+        }
+        properties
+    }
+
+    /// Decodes one entry of a properties block into self.
+    pub(crate) fn decode_property(&mut self, name: &[u8], value: &[u8]) -> Result<()> {
+        let name = String::from_utf8(name.to_vec()).map_err(|_| Corruption)?;
+        let field = match name.as_str() {
+            K_NUM_ENTRIES => &mut self.num_entries,
+            K_NUM_DELETIONS => &mut self.num_deletions,
+            K_NUM_RANGE_DELETIONS => &mut self.num_range_deletions,
+            K_NUM_DATA_BLOCKS => &mut self.num_data_blocks,
+            K_RAW_KEY_SIZE => &mut self.raw_key_size,
+            K_RAW_VALUE_SIZE => &mut self.raw_value_size,
+            K_DATA_SIZE => &mut self.data_size,
+            K_INDEX_SIZE => &mut self.index_size,
+            K_FILTER_SIZE => &mut self.filter_size,
+            K_CREATION_TIME => &mut self.creation_time,
+            // Builtin properties of newer releases are skipped
+            _ if name.starts_with("revel.") => return Ok(()),
+            _ => {
+                self.user_collected_properties.insert(name, value.to_vec());
+                return Ok(());
+            }
+        };
+        *field = get_varint64(value, 0, value.len()).map_err(|_| Corruption)?.0;
+        Ok(())
+    }
+}
+
+/// Records custom properties of a table while it is built. A new collector
+/// is created for every table, see TablePropertiesCollectorFactory.
+pub trait TablePropertiesCollector {
+
+    /// Called for every entry added to the table, in key order.
+    fn add(&mut self, user_key: &[u8], value: &[u8]);
+
+    /// Called once the table is complete. Returns the properties to record
+    /// in the table. Names must not start with "revel.".
+    fn finish(&mut self) -> Vec<(String, Vec<u8>)>;
+}
+
+/// Creates the TablePropertiesCollector of every table built.
+pub trait TablePropertiesCollectorFactory: Send + Sync {
+
+    /// The name of the collectors created, for debugging.
+    fn name(&self) -> &str;
+
+    fn create_table_properties_collector(&self) -> Box<dyn TablePropertiesCollector>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut properties = TableProperties {
+            num_entries: 1,
+            num_deletions: 8,
+            num_range_deletions: 9,
+            num_data_blocks: 2,
+            raw_key_size: 3,
+            raw_value_size: 4,
+            data_size: 5,
+            index_size: 6,
+            filter_size: 7,
+            creation_time: 1 << 40,
+            user_collected_properties: BTreeMap::new()
+        };
+        properties.user_collected_properties.insert("app.version".to_string(), b"\x00\x01".to_vec());
+        let encoded = properties.encode();
+
+        let mut decoded = TableProperties::default();
+        for (name, value) in &encoded {
+            decoded.decode_property(name, value).unwrap();
+        }
+        assert_eq!(properties, decoded);
+
+        // Unknown builtin properties are ignored, bad values are corruption
+        decoded.decode_property(b"revel.newer.property", b"").unwrap();
+        assert_eq!(properties, decoded);
+        assert_eq!(Err(Corruption), decoded.decode_property(K_NUM_ENTRIES.as_bytes(), b"\x80"));
+    }
+}
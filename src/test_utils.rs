@@ -0,0 +1,412 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Utilities for testing code built on revel, enabled by the `test_utils`
+//! feature.
+//!
+//! ModelDB is a reference implementation of the db on top of a BTreeMap,
+//! and Harness runs random sequences of operations against both a DB and
+//! a ModelDB, checking after every operation that they agree. The
+//! in-memory files of the env are exported as well, for tests of the log
+//! and table formats that should not touch the disk.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use crate::db::DB;
+use crate::Error::NotFound;
+use crate::options::{Options, ReadOptions, WriteOptions};
+use crate::random::Random;
+use crate::Result;
+use crate::slice::Slice;
+use crate::write_batch::{Handler, WriteBatch};
+
+pub use crate::env::{MemoryRandomAccessFile, MemorySequentialFile, MemoryWritableFile, RandomAccessFile, SequentialFile, WritableFile};
+
+/// A db reduced to its specification: a sorted map from keys to values.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModelDB {
+
+    map: BTreeMap<Vec<u8>, Vec<u8>>
+}
+
+impl ModelDB {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) {
+        self.map.insert(key.to_vec(), value.to_vec());
+    }
+
+    pub fn delete(&mut self, key: &[u8]) {
+        self.map.remove(key);
+    }
+
+    /// Remove the entries with keys in [begin_key, end_key).
+    pub fn delete_range(&mut self, begin_key: &[u8], end_key: &[u8]) {
+        if begin_key >= end_key {
+            return;
+        }
+        let doomed = self.map.range::<[u8], _>((Bound::Included(begin_key), Bound::Excluded(end_key)))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in doomed {
+            self.map.remove(&key);
+        }
+    }
+
+    /// Apply every record of `batch`, in order.
+    pub fn write(&mut self, batch: &WriteBatch) -> Result<()> {
+        batch.iterate(self)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.map.get(key).cloned().ok_or(NotFound)
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Returns the entries with keys in [lower, upper) in key order; a
+    /// missing bound leaves that side of the range open.
+    pub fn scan(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let lower = lower.map_or(Bound::Unbounded, Bound::Included);
+        let upper = upper.map_or(Bound::Unbounded, Bound::Excluded);
+        if let (Bound::Included(l), Bound::Excluded(u)) = (lower, upper) {
+            if l >= u {
+                return Vec::new();
+            }
+        }
+        self.map.range::<[u8], _>((lower, upper))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl Handler for ModelDB {
+    fn put(&mut self, key: &Slice, value: &Slice) {
+        ModelDB::put(self, key.data(), value.data());
+    }
+
+    fn delete(&mut self, key: &Slice) {
+        ModelDB::delete(self, key.data());
+    }
+
+    fn delete_range(&mut self, begin_key: &Slice, end_key: &Slice) -> Result<()> {
+        ModelDB::delete_range(self, begin_key.data(), end_key.data());
+        Ok(())
+    }
+}
+
+/// Options of a Harness run.
+#[derive(Clone)]
+pub struct HarnessOptions {
+
+    /// Seed of the random operation sequence. A failing run is reproduced
+    /// by running again with the same seed.
+    pub seed: u32,
+
+    /// Number of operations to run.
+    pub num_ops: usize,
+
+    /// Number of distinct keys the operations pick from. A small key space
+    /// makes overwrites and deletions of existing keys frequent.
+    pub num_keys: u32,
+
+    /// Close and reopen the db on average once every `reopen_one_in`
+    /// operations, checking that it recovers the state of the model.
+    /// 0 never reopens.
+    pub reopen_one_in: u32,
+
+    /// Options the db is opened with.
+    pub options: Options
+}
+
+impl Default for HarnessOptions {
+    fn default() -> Self {
+        HarnessOptions {
+            seed: 301,
+            num_ops: 1000,
+            num_keys: 100,
+            reopen_one_in: 0,
+            options: Options::default()
+        }
+    }
+}
+
+/// Runs random operations against a DB and a ModelDB and panics, with the
+/// seed and the index of the operation, as soon as their results differ.
+pub struct Harness {
+
+    dbname: PathBuf,
+
+    options: HarnessOptions,
+
+    db: Option<DB>,
+
+    model: ModelDB,
+
+    rnd: Random,
+
+    // Index of the operation being run
+    step: usize
+}
+
+impl Harness {
+
+    /// Create a harness running on a new db at `dbname`. Anything at that
+    /// path is removed first.
+    pub fn new(dbname: impl AsRef<Path>, options: HarnessOptions) -> Result<Self> {
+        let dbname = dbname.as_ref().to_path_buf();
+        if dbname.exists() {
+            std::fs::remove_dir_all(&dbname)?;
+        }
+        let db = DB::open(&options.options, &dbname)?;
+        Ok(Harness {
+            dbname,
+            rnd: Random::new(options.seed),
+            options,
+            db: Some(db),
+            model: ModelDB::new(),
+            step: 0
+        })
+    }
+
+    /// Returns the db under test.
+    pub fn db(&mut self) -> &mut DB {
+        self.db.as_mut().unwrap()
+    }
+
+    /// Returns the model the db is checked against.
+    pub fn model(&self) -> &ModelDB {
+        &self.model
+    }
+
+    /// Run options.num_ops random operations, then compare the whole
+    /// contents of the db with the model.
+    pub fn run(&mut self) {
+        for _ in 0..self.options.num_ops {
+            self.step();
+        }
+        self.check_contents();
+    }
+
+    /// Run a single random operation.
+    pub fn step(&mut self) {
+        self.step += 1;
+        if self.options.reopen_one_in > 0 && self.rnd.one_in(self.options.reopen_one_in as i32) {
+            self.reopen();
+            self.check_contents();
+            return;
+        }
+        match self.rnd.uniform(100) {
+            0..=34 => {
+                let (key, value) = (self.random_key(), self.random_value());
+                self.db().put(&WriteOptions { sync: false, ..WriteOptions::default() }, &Slice::from_bytes(&key), &Slice::from_bytes(&value))
+                    .unwrap_or_else(|e| self.fail(&format!("put failed: {:?}", e)));
+                self.model.put(&key, &value);
+            },
+            35..=49 => {
+                let key = self.random_key();
+                self.db().delete(&WriteOptions { sync: false, ..WriteOptions::default() }, &Slice::from_bytes(&key))
+                    .unwrap_or_else(|e| self.fail(&format!("delete failed: {:?}", e)));
+                self.model.delete(&key);
+            },
+            50..=59 => {
+                let batch = self.random_batch();
+                self.model.write(&batch).unwrap();
+                self.db().write(&WriteOptions { sync: false, ..WriteOptions::default() }, batch)
+                    .unwrap_or_else(|e| self.fail(&format!("write failed: {:?}", e)));
+            },
+            60..=79 => {
+                let key = self.random_key();
+                let actual = self.db().get(&ReadOptions::default(), &Slice::from_bytes(&key));
+                let expected = self.model.get(&key);
+                if actual != expected {
+                    self.fail(&format!("get({:?}) returned {:?}, expected {:?}", Slice::from_bytes(&key), actual, expected));
+                }
+            },
+            80..=89 => {
+                let key = self.random_key();
+                let actual = self.db().contains_key(&ReadOptions::default(), &Slice::from_bytes(&key));
+                let expected = Ok(self.model.contains_key(&key));
+                if actual != expected {
+                    self.fail(&format!("contains_key({:?}) returned {:?}, expected {:?}", Slice::from_bytes(&key), actual, expected));
+                }
+            },
+            _ => {
+                let mut bounds = [self.random_key(), self.random_key()];
+                bounds.sort();
+                let lower = (!self.rnd.one_in(4)).then(|| bounds[0].clone());
+                let upper = (!self.rnd.one_in(4)).then(|| bounds[1].clone());
+                self.check_scan(lower.as_deref(), upper.as_deref());
+            }
+        }
+    }
+
+    /// Close and reopen the db.
+    pub fn reopen(&mut self) {
+        self.db = None;
+        let db = DB::open(&self.options.options, &self.dbname)
+            .unwrap_or_else(|e| self.fail(&format!("reopen failed: {:?}", e)));
+        self.db = Some(db);
+    }
+
+    /// Compare the whole contents of the db with the model.
+    pub fn check_contents(&mut self) {
+        self.check_scan(None, None);
+    }
+
+    /// Compare a scan of [lower, upper) of the db, in both directions, with
+    /// the model.
+    fn check_scan(&mut self, lower: Option<&[u8]>, upper: Option<&[u8]>) {
+        let expected = self.model.scan(lower, upper);
+        let db = self.db.as_ref().unwrap();
+        let forward = db.scan(&ReadOptions::default(), lower, upper).collect::<Result<Vec<_>>>();
+        let mut backward = db.scan(&ReadOptions::default(), lower, upper).rev().collect::<Result<Vec<_>>>();
+        if let Ok(entries) = &mut backward {
+            entries.reverse();
+        }
+        for (direction, actual) in [("forward", forward), ("backward", backward)] {
+            if actual.as_ref() != Ok(&expected) {
+                self.fail(&format!("{} scan of [{:?}, {:?}) returned {:?}, expected {:?}",
+                                   direction, lower.map(Slice::from_bytes), upper.map(Slice::from_bytes), actual, expected));
+            }
+        }
+    }
+
+    fn random_key(&mut self) -> Vec<u8> {
+        format!("key{:06}", self.rnd.uniform(self.options.num_keys as i32)).into_bytes()
+    }
+
+    fn random_value(&mut self) -> Vec<u8> {
+        // Mostly small values, with the occasional large one
+        let len = if self.rnd.one_in(20) { self.rnd.uniform(10000) } else { self.rnd.uniform(100) };
+        let mut value = format!("v{}-", self.step).into_bytes();
+        value.extend((0..len).map(|_| b'a' + self.rnd.uniform(26) as u8));
+        value
+    }
+
+    fn random_batch(&mut self) -> WriteBatch {
+        let mut batch = WriteBatch::new();
+        for _ in 0..=self.rnd.uniform(10) {
+            let key = self.random_key();
+            if self.rnd.one_in(20) {
+                let mut bounds = [key, self.random_key()];
+                bounds.sort();
+                batch.delete_range(&Slice::from_bytes(&bounds[0]), &Slice::from_bytes(&bounds[1]));
+            } else if self.rnd.one_in(3) {
+                batch.delete(&Slice::from_bytes(&key));
+            } else {
+                batch.put(&Slice::from_bytes(&key), &Slice::from_bytes(&self.random_value()));
+            }
+        }
+        batch
+    }
+
+    fn fail(&self, message: &str) -> ! {
+        panic!("seed {}, operation {}: {}", self.options.seed, self.step, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("revel_test_utils_{}", name))
+    }
+
+    #[test]
+    fn test_model() {
+        let mut model = ModelDB::new();
+        model.put(b"a", b"1");
+        model.put(b"b", b"2");
+        model.put(b"c", b"3");
+        model.delete(b"b");
+        assert_eq!(Ok(b"1".to_vec()), model.get(b"a"));
+        assert_eq!(Err(NotFound), model.get(b"b"));
+        assert!(model.contains_key(b"c"));
+        assert_eq!(2, model.len());
+
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("d"), &Slice::from_str("4"));
+        batch.delete_range(&Slice::from_str("a"), &Slice::from_str("c"));
+        model.write(&batch).unwrap();
+        let keys = |entries: Vec<(Vec<u8>, Vec<u8>)>| entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(vec![b"c".to_vec(), b"d".to_vec()], keys(model.scan(None, None)));
+        assert_eq!(vec![b"d".to_vec()], keys(model.scan(Some(b"c\0"), None)));
+        assert_eq!(vec![b"c".to_vec()], keys(model.scan(None, Some(b"d"))));
+        assert!(model.scan(Some(b"d"), Some(b"c")).is_empty());
+    }
+
+    #[test]
+    fn test_memory_files() {
+        let mut file = MemoryWritableFile::new(Vec::new());
+        file.append(&Slice::from_str("hello ")).unwrap();
+        file.append(&Slice::from_str("world")).unwrap();
+        file.sync().unwrap();
+        assert_eq!(b"hello world", file.contents());
+
+        let file = MemorySequentialFile::new(std::rc::Rc::new(file.contents().to_vec()));
+        let mut scratch = [0; 5];
+        assert_eq!(b"hello", file.read(&mut scratch).unwrap().data());
+        file.skip(1).unwrap();
+        assert_eq!(b"world", file.read(&mut scratch).unwrap().data());
+        assert!(file.read(&mut scratch).unwrap().empty());
+    }
+
+    #[test]
+    fn test_harness() {
+        for seed in [301, 302, 303] {
+            let options = HarnessOptions { seed, num_ops: 500, num_keys: 50, ..HarnessOptions::default() };
+            let mut harness = Harness::new(db_path(&format!("harness_{}", seed)), options).unwrap();
+            harness.run();
+            assert!(!harness.model().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_harness_with_flushes() {
+        // Small memtables and reopens write the range tombstones to tables
+        // and compact them
+        let options = HarnessOptions {
+            num_ops: 2000,
+            num_keys: 200,
+            reopen_one_in: 200,
+            options: Options { write_buffer_size: 16 * 1024, target_file_size_base: 8 * 1024, ..Options::default() },
+            ..HarnessOptions::default()
+        };
+        let mut harness = Harness::new(db_path("harness_flushes"), options).unwrap();
+        harness.run();
+    }
+
+    #[test]
+    #[should_panic(expected = "seed 301, operation 1")]
+    fn test_harness_detects_divergence() {
+        let mut harness = Harness::new(db_path("harness_divergence"), HarnessOptions::default()).unwrap();
+        harness.db().put(&WriteOptions { sync: false, ..WriteOptions::default() }, &Slice::from_str("unknown"), &Slice::from_str("v")).unwrap();
+        harness.step();
+        harness.check_contents();
+    }
+}
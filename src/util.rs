@@ -10,4 +10,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-pub mod crc;
\ No newline at end of file
+pub mod crc;
+pub mod hash;
+pub mod histogram;
+pub mod logging;
\ No newline at end of file
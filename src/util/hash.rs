@@ -0,0 +1,66 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::coding::decode_fix32;
+
+/// Simple hash function used for internal data structures.
+pub fn hash(data: &[u8], seed: u32) -> u32 {
+    // Similar to murmur hash
+    const M: u32 = 0xc6a4a793;
+    const R: u32 = 24;
+    let mut h = seed ^ (data.len() as u32).wrapping_mul(M);
+
+    // Pick up four bytes at a time
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        h = h.wrapping_add(decode_fix32(chunk));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+
+    // Pick up remaining bytes
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, b) in rest.iter().enumerate().rev() {
+            h = h.wrapping_add((*b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> R;
+    }
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_unsigned_issue() {
+        let data1 = [0x62];
+        let data2 = [0xc3, 0x97];
+        let data3 = [0xe2, 0x99, 0xa5];
+        let data4 = [0xe1, 0x80, 0xb9, 0x32];
+        let data5 = [
+            0x01, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00,
+            0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x18, 0x28, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+
+        assert_eq!(0xbc9f1d34, hash(&[], 0xbc9f1d34));
+        assert_eq!(0xef1345c4, hash(&data1, 0xbc9f1d34));
+        assert_eq!(0x5b663814, hash(&data2, 0xbc9f1d34));
+        assert_eq!(0x323c078f, hash(&data3, 0xbc9f1d34));
+        assert_eq!(0xed21633a, hash(&data4, 0xbc9f1d34));
+        assert_eq!(0xf333dabb, hash(&data5, 0x12345678));
+    }
+}
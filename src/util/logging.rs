@@ -0,0 +1,55 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Write;
+
+/// Return a human-readable version of "value".
+/// Escapes any non-printable characters found in "value".
+pub fn escape_string(value: &[u8]) -> String {
+    let mut r = String::with_capacity(value.len());
+    for &c in value {
+        if (b' '..=b'~').contains(&c) {
+            r.push(c as char);
+        } else {
+            let _ = write!(r, "\\x{:02x}", c);
+        }
+    }
+    r
+}
+
+/// Return "value" as upper case hex, two digits per byte.
+pub fn to_hex(value: &[u8]) -> String {
+    let mut r = String::with_capacity(value.len() * 2);
+    for &c in value {
+        let _ = write!(r, "{:02X}", c);
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_string() {
+        assert_eq!("", escape_string(b""));
+        assert_eq!("key~ 1", escape_string(b"key~ 1"));
+        assert_eq!("a\\x00b\\x7f\\xff", escape_string(b"a\x00b\x7f\xff"));
+    }
+
+    #[test]
+    fn test_to_hex() {
+        assert_eq!("", to_hex(b""));
+        assert_eq!("00017F80FF", to_hex(b"\x00\x01\x7f\x80\xff"));
+        assert_eq!("666F6F", to_hex(b"foo"));
+    }
+}
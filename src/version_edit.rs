@@ -0,0 +1,482 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use crate::coding::{decode_fixed64, get_length_prefixed_slice, get_varint32, get_varint64, put_length_prefixed_slice, put_varint32, put_varint64};
+use crate::comparator::Comparator;
+use crate::dbformat::{internal_key_debug_string, InternalKeyComparator, SequenceNumber};
+use crate::Error::Corruption;
+use crate::range_del::RangeTombstone;
+use crate::Result;
+use crate::slice::Slice;
+
+// Tag numbers for serialized VersionEdit. These numbers are written to
+// disk and should not be changed.
+const K_COMPARATOR: u32 = 1;
+const K_LOG_NUMBER: u32 = 2;
+const K_NEXT_FILE_NUMBER: u32 = 3;
+const K_LAST_SEQUENCE: u32 = 4;
+const K_COMPACT_POINTER: u32 = 5;
+const K_DELETED_FILE: u32 = 6;
+const K_NEW_FILE: u32 = 7;
+// 8 was used for large value refs
+const K_PREV_LOG_NUMBER: u32 = 9;
+const K_FORMAT_VERSION: u32 = 10;
+// A new file together with the range of its sequence numbers
+const K_NEW_FILE2: u32 = 11;
+
+/// Number of levels of the LSM tree.
+pub const K_NUM_LEVELS: usize = 7;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileMetaData {
+
+    pub number: u64,
+
+    /// File size in bytes
+    pub file_size: u64,
+
+    /// Smallest internal key served by table
+    pub smallest: Vec<u8>,
+
+    /// Largest internal key served by table
+    pub largest: Vec<u8>,
+
+    /// Smallest sequence number of the entries in the table
+    pub smallest_seqno: SequenceNumber,
+
+    /// Largest sequence number of the entries in the table
+    pub largest_seqno: SequenceNumber,
+
+    /// Set when the file was suggested for compaction. Only kept in memory,
+    /// it is not recorded in the MANIFEST.
+    pub marked_for_compaction: Cell<bool>,
+
+    /// Seeks allowed until the file is compacted. Only kept in memory, it
+    /// is set when the file joins a version.
+    pub allowed_seeks: Cell<i64>
+}
+
+impl FileMetaData {
+
+    /// Widen the key and sequence ranges of the file to cover a range
+    /// tombstone stored in it.
+    pub fn add_range_tombstone(&mut self, icmp: &InternalKeyComparator, tombstone: &RangeTombstone) {
+        let start = tombstone.internal_key();
+        if self.smallest.is_empty() || icmp.compare(&Slice::from_bytes(&start), &Slice::from_bytes(&self.smallest)) == Ordering::Less {
+            self.smallest = start;
+        }
+        let end = tombstone.end_internal_key();
+        if self.largest.is_empty() || icmp.compare(&Slice::from_bytes(&end), &Slice::from_bytes(&self.largest)) == Ordering::Greater {
+            self.largest = end;
+        }
+        self.smallest_seqno = self.smallest_seqno.min(tombstone.sequence);
+        self.largest_seqno = self.largest_seqno.max(tombstone.sequence);
+    }
+}
+
+/// A change to the set of files of the db and to its counters, as recorded
+/// in the MANIFEST. Replaying the edits of a MANIFEST in order rebuilds
+/// the current version.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VersionEdit {
+
+    pub(crate) comparator: Option<String>,
+
+    pub(crate) log_number: Option<u64>,
+
+    pub(crate) prev_log_number: Option<u64>,
+
+    pub(crate) format_version: Option<u32>,
+
+    pub(crate) next_file_number: Option<u64>,
+
+    pub(crate) last_sequence: Option<SequenceNumber>,
+
+    pub(crate) compact_pointers: Vec<(usize, Vec<u8>)>,
+
+    pub(crate) deleted_files: BTreeSet<(usize, u64)>,
+
+    pub(crate) new_files: Vec<(usize, FileMetaData)>
+}
+
+impl VersionEdit {
+
+    pub fn new() -> Self {
+        VersionEdit::default()
+    }
+
+    pub fn set_comparator_name(&mut self, name: &str) {
+        self.comparator = Some(name.to_string());
+    }
+
+    pub fn set_log_number(&mut self, num: u64) {
+        self.log_number = Some(num);
+    }
+
+    pub fn set_prev_log_number(&mut self, num: u64) {
+        self.prev_log_number = Some(num);
+    }
+
+    pub fn set_format_version(&mut self, version: u32) {
+        self.format_version = Some(version);
+    }
+
+    pub fn set_next_file(&mut self, num: u64) {
+        self.next_file_number = Some(num);
+    }
+
+    pub fn set_last_sequence(&mut self, seq: SequenceNumber) {
+        self.last_sequence = Some(seq);
+    }
+
+    /// Set the key at which the next compaction of `level` starts.
+    pub fn set_compact_pointer(&mut self, level: usize, key: &[u8]) {
+        self.compact_pointers.push((level, key.to_vec()));
+    }
+
+    /// Add the specified file at the specified level.
+    /// REQUIRES: This version has not been saved (see VersionSet::save_to)
+    /// REQUIRES: "smallest" and "largest" are smallest and largest keys in file
+    pub fn add_file(&mut self, level: usize, file: FileMetaData) {
+        self.new_files.push((level, file));
+    }
+
+    /// Delete the specified "file" from the specified "level".
+    pub fn remove_file(&mut self, level: usize, file: u64) {
+        self.deleted_files.insert((level, file));
+    }
+
+    pub fn encode_to(&self, dst: &mut Vec<u8>) {
+        if let Some(comparator) = &self.comparator {
+            put_varint32(dst, K_COMPARATOR);
+            put_length_prefixed_slice(dst, &Slice::from_str(comparator));
+        }
+        if let Some(log_number) = self.log_number {
+            put_varint32(dst, K_LOG_NUMBER);
+            put_varint64(dst, log_number);
+        }
+        if let Some(prev_log_number) = self.prev_log_number {
+            put_varint32(dst, K_PREV_LOG_NUMBER);
+            put_varint64(dst, prev_log_number);
+        }
+        if let Some(format_version) = self.format_version {
+            put_varint32(dst, K_FORMAT_VERSION);
+            put_varint32(dst, format_version);
+        }
+        if let Some(next_file_number) = self.next_file_number {
+            put_varint32(dst, K_NEXT_FILE_NUMBER);
+            put_varint64(dst, next_file_number);
+        }
+        if let Some(last_sequence) = self.last_sequence {
+            put_varint32(dst, K_LAST_SEQUENCE);
+            put_varint64(dst, last_sequence);
+        }
+
+        for (level, key) in &self.compact_pointers {
+            put_varint32(dst, K_COMPACT_POINTER);
+            put_varint32(dst, *level as u32);
+            put_length_prefixed_slice(dst, &Slice::from_bytes(key));
+        }
+
+        for (level, number) in &self.deleted_files {
+            put_varint32(dst, K_DELETED_FILE);
+            put_varint32(dst, *level as u32);
+            put_varint64(dst, *number);
+        }
+
+        for (level, f) in &self.new_files {
+            put_varint32(dst, K_NEW_FILE2);
+            put_varint32(dst, *level as u32);
+            put_varint64(dst, f.number);
+            put_varint64(dst, f.file_size);
+            put_length_prefixed_slice(dst, &Slice::from_bytes(&f.smallest));
+            put_length_prefixed_slice(dst, &Slice::from_bytes(&f.largest));
+            put_varint64(dst, f.smallest_seqno);
+            put_varint64(dst, f.largest_seqno);
+        }
+    }
+
+    /// Return a human readable string that lists every field set in this
+    /// edit, one per line.
+    pub fn debug_string(&self) -> String {
+        let mut r = String::from("VersionEdit {");
+        if let Some(comparator) = &self.comparator {
+            let _ = write!(r, "\n  Comparator: {}", comparator);
+        }
+        if let Some(log_number) = self.log_number {
+            let _ = write!(r, "\n  LogNumber: {}", log_number);
+        }
+        if let Some(prev_log_number) = self.prev_log_number {
+            let _ = write!(r, "\n  PrevLogNumber: {}", prev_log_number);
+        }
+        if let Some(format_version) = self.format_version {
+            let _ = write!(r, "\n  FormatVersion: {}", format_version);
+        }
+        if let Some(next_file_number) = self.next_file_number {
+            let _ = write!(r, "\n  NextFile: {}", next_file_number);
+        }
+        if let Some(last_sequence) = self.last_sequence {
+            let _ = write!(r, "\n  LastSeq: {}", last_sequence);
+        }
+        for (level, key) in &self.compact_pointers {
+            let _ = write!(r, "\n  CompactPointer: {} {}", level, internal_key_debug_string(key));
+        }
+        for (level, number) in &self.deleted_files {
+            let _ = write!(r, "\n  RemoveFile: {} {}", level, number);
+        }
+        for (level, f) in &self.new_files {
+            let _ = write!(r, "\n  AddFile: {} {} {} {} .. {}", level, f.number, f.file_size,
+                           internal_key_debug_string(&f.smallest), internal_key_debug_string(&f.largest));
+        }
+        r.push_str("\n}\n");
+        r
+    }
+
+    pub fn decode_from(src: &Slice) -> Result<VersionEdit> {
+        let mut edit = VersionEdit::new();
+        let mut input = Decoder { data: src.data() };
+        while !input.data.is_empty() {
+            let tag = input.varint32()?;
+            match tag {
+                K_COMPARATOR => {
+                    let name = input.length_prefixed()?;
+                    edit.comparator = Some(String::from_utf8(name.to_vec()).map_err(|_| Corruption)?);
+                },
+                K_LOG_NUMBER => edit.log_number = Some(input.varint64()?),
+                K_PREV_LOG_NUMBER => edit.prev_log_number = Some(input.varint64()?),
+                K_FORMAT_VERSION => edit.format_version = Some(input.varint32()?),
+                K_NEXT_FILE_NUMBER => edit.next_file_number = Some(input.varint64()?),
+                K_LAST_SEQUENCE => edit.last_sequence = Some(input.varint64()?),
+                K_COMPACT_POINTER => {
+                    let level = input.level()?;
+                    let key = input.internal_key()?;
+                    edit.compact_pointers.push((level, key));
+                },
+                K_DELETED_FILE => {
+                    let level = input.level()?;
+                    let number = input.varint64()?;
+                    edit.deleted_files.insert((level, number));
+                },
+                K_NEW_FILE | K_NEW_FILE2 => {
+                    let level = input.level()?;
+                    let mut f = FileMetaData {
+                        number: input.varint64()?,
+                        file_size: input.varint64()?,
+                        smallest: input.internal_key()?,
+                        largest: input.internal_key()?,
+                        ..FileMetaData::default()
+                    };
+                    if tag == K_NEW_FILE2 {
+                        f.smallest_seqno = input.varint64()?;
+                        f.largest_seqno = input.varint64()?;
+                    } else {
+                        // Only the sequence numbers of the boundary keys are known
+                        let a = sequence_of(&f.smallest);
+                        let b = sequence_of(&f.largest);
+                        f.smallest_seqno = a.min(b);
+                        f.largest_seqno = a.max(b);
+                    }
+                    edit.new_files.push((level, f));
+                },
+                _ => return Err(Corruption)
+            }
+        }
+        Ok(edit)
+    }
+}
+
+/// Returns the sequence number of an internal key.
+fn sequence_of(internal_key: &[u8]) -> SequenceNumber {
+    decode_fixed64(internal_key, internal_key.len() - 8) >> 8
+}
+
+/// Consumes the fields of an encoded VersionEdit, failing with Corruption
+/// on truncated or malformed input.
+struct Decoder<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+
+    fn varint32(&mut self) -> Result<u32> {
+        let (value, len) = get_varint32(self.data, 0, self.data.len()).map_err(|_| Corruption)?;
+        self.data = &self.data[len..];
+        Ok(value)
+    }
+
+    fn varint64(&mut self) -> Result<u64> {
+        let (value, len) = get_varint64(self.data, 0, self.data.len()).map_err(|_| Corruption)?;
+        self.data = &self.data[len..];
+        Ok(value)
+    }
+
+    fn length_prefixed(&mut self) -> Result<&'a [u8]> {
+        let data = self.data;
+        let (slice, skip) = get_length_prefixed_slice(data)?;
+        self.data = &data[skip + slice.size()..];
+        Ok(slice.data())
+    }
+
+    fn level(&mut self) -> Result<usize> {
+        let level = self.varint32()? as usize;
+        if level >= K_NUM_LEVELS {
+            return Err(Corruption);
+        }
+        Ok(level)
+    }
+
+    fn internal_key(&mut self) -> Result<Vec<u8>> {
+        let key = self.length_prefixed()?;
+        // An internal key is at least the 8 byte sequence and type tag
+        if key.len() < 8 {
+            return Err(Corruption);
+        }
+        Ok(key.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dbformat::{append_internal_key, ValueType};
+    use super::*;
+
+    fn ikey(user_key: &str, seq: SequenceNumber, t: ValueType) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str(user_key), seq, t);
+        key
+    }
+
+    fn test_encode_decode(edit: &VersionEdit) {
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+        let parsed = VersionEdit::decode_from(&Slice::from_bytes(&encoded)).expect("decode failed");
+        assert_eq!(*edit, parsed);
+        let mut encoded2 = Vec::new();
+        parsed.encode_to(&mut encoded2);
+        assert_eq!(encoded, encoded2);
+    }
+
+    #[test]
+    fn test_encode_decode_edit() {
+        const K_BIG: u64 = 1u64 << 50;
+
+        let mut edit = VersionEdit::new();
+        test_encode_decode(&edit);
+        for i in 0..4 {
+            edit.add_file(3, FileMetaData {
+                number: K_BIG + 300 + i,
+                file_size: K_BIG + 400 + i,
+                smallest: ikey("foo", K_BIG + 500 + i, ValueType::KTypeValue),
+                largest: ikey("zoo", K_BIG + 600 + i, ValueType::KTypeDeletion),
+                smallest_seqno: K_BIG + 500 + i,
+                largest_seqno: K_BIG + 600 + i,
+                ..FileMetaData::default()
+            });
+            edit.remove_file(4, K_BIG + 700 + i);
+            edit.compact_pointers.push((i as usize, ikey("x", K_BIG + 900 + i, ValueType::KTypeValue)));
+            test_encode_decode(&edit);
+        }
+
+        edit.set_comparator_name("foo");
+        edit.set_log_number(K_BIG + 100);
+        edit.set_next_file(K_BIG + 200);
+        edit.set_last_sequence(K_BIG + 1000);
+        edit.set_prev_log_number(K_BIG + 50);
+        edit.set_format_version(3);
+        test_encode_decode(&edit);
+    }
+
+    #[test]
+    fn test_debug_string() {
+        assert_eq!("VersionEdit {\n}\n", VersionEdit::new().debug_string());
+
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name("foo");
+        edit.set_log_number(5);
+        edit.set_next_file(9);
+        edit.set_last_sequence(100);
+        edit.set_compact_pointer(2, &ikey("m", 7, ValueType::KTypeValue));
+        edit.remove_file(1, 3);
+        edit.add_file(1, FileMetaData {
+            number: 8,
+            file_size: 1234,
+            smallest: ikey("a", 10, ValueType::KTypeValue),
+            largest: ikey("z", 20, ValueType::KTypeDeletion),
+            ..FileMetaData::default()
+        });
+        let expected = concat!(
+            "VersionEdit {\n",
+            "  Comparator: foo\n",
+            "  LogNumber: 5\n",
+            "  NextFile: 9\n",
+            "  LastSeq: 100\n",
+            "  CompactPointer: 2 'm' @ 7 : 1\n",
+            "  RemoveFile: 1 3\n",
+            "  AddFile: 1 8 1234 'a' @ 10 : 1 .. 'z' @ 20 : 0\n",
+            "}\n");
+        assert_eq!(expected, edit.debug_string());
+    }
+
+    #[test]
+    fn test_decode_legacy_new_file() {
+        let mut encoded = Vec::new();
+        put_varint32(&mut encoded, K_NEW_FILE);
+        put_varint32(&mut encoded, 2);
+        put_varint64(&mut encoded, 9);
+        put_varint64(&mut encoded, 100);
+        put_length_prefixed_slice(&mut encoded, &Slice::from_bytes(&ikey("a", 30, ValueType::KTypeValue)));
+        put_length_prefixed_slice(&mut encoded, &Slice::from_bytes(&ikey("b", 20, ValueType::KTypeValue)));
+        let edit = VersionEdit::decode_from(&Slice::from_bytes(&encoded)).unwrap();
+        let (level, f) = &edit.new_files[0];
+        assert_eq!(2, *level);
+        assert_eq!((9, 100, 20, 30), (f.number, f.file_size, f.smallest_seqno, f.largest_seqno));
+    }
+
+    #[test]
+    fn test_decode_corruption() {
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name("foo");
+        edit.add_file(1, FileMetaData {
+            number: 7,
+            file_size: 100,
+            smallest: ikey("a", 1, ValueType::KTypeValue),
+            largest: ikey("b", 2, ValueType::KTypeValue),
+            smallest_seqno: 1,
+            largest_seqno: 2,
+            ..FileMetaData::default()
+        });
+        let mut encoded = Vec::new();
+        edit.encode_to(&mut encoded);
+
+        // Every truncation inside a field is detected. The comparator field
+        // takes 5 bytes, cutting the record right after it leaves a valid edit.
+        for len in (1..encoded.len()).filter(|len| *len != 5) {
+            assert_eq!(Err(Corruption), VersionEdit::decode_from(&Slice::from_bytes(&encoded[..len])), "len {}", len);
+        }
+
+        // Unknown tag
+        let mut unknown = encoded.clone();
+        put_varint32(&mut unknown, 8);
+        assert_eq!(Err(Corruption), VersionEdit::decode_from(&Slice::from_bytes(&unknown)));
+
+        // Level out of range
+        let mut bad_level = Vec::new();
+        put_varint32(&mut bad_level, K_DELETED_FILE);
+        put_varint32(&mut bad_level, K_NUM_LEVELS as u32);
+        put_varint64(&mut bad_level, 1);
+        assert_eq!(Err(Corruption), VersionEdit::decode_from(&Slice::from_bytes(&bad_level)));
+    }
+}
@@ -10,25 +10,651 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::options::Options;
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+use crate::compaction::Compaction;
+use crate::comparator::Comparator;
+use crate::dbformat::{append_internal_key, extract_user_key, kValueTypeForSeek, parse_internal_key, InternalKeyComparator, LookupKey, ValueType, K_MAX_SEQUENCE_NUMBER};
+use crate::env::{new_sequential_file, new_writable_file, WritableFile};
+use crate::Error::{Corruption, InvalidArgument, NotFound, NotSupport};
+use crate::filename::{current_file_name, descriptor_file_name, parse_file_name, set_current_file};
+use crate::log_reader::{Reader, Reporter};
+use crate::log_writer;
+use crate::iterator::InternalIterator;
+use crate::options::{CompactionPri, K_LATEST_FORMAT_VERSION, Options, ReadOptions, RecoveryProgress};
+use crate::range_del::{RangeDelAggregator, RangeTombstone};
+use crate::Result;
+use crate::slice::Slice;
+use crate::table_cache::TableCache;
+use crate::util::logging::escape_string;
+use crate::version_edit::{FileMetaData, K_NUM_LEVELS, VersionEdit};
+
+/// Level-0 compaction is started when we hit this many files.
+pub const K_L0_COMPACTION_TRIGGER: usize = 4;
+
+/// Maximum number of bytes in all files of `level`, above level-0: 10MB
+/// for level-1, and ten times more for every level below.
+fn max_bytes_for_level(level: usize) -> f64 {
+    // Note: the result for level zero is not really used since we set
+    // the level-0 compaction threshold based on number of files.
+    let mut result = 10.0 * 1048576.0;
+    for _ in 1..level {
+        result *= 10.0;
+    }
+    result
+}
+
+/// Return the smallest index i such that files[i].largest >= key, or
+/// files.len() if there is no such file.
+/// REQUIRES: "files" contains a sorted list of non-overlapping files.
+pub fn find_file(icmp: &InternalKeyComparator, files: &[Rc<FileMetaData>], key: &Slice) -> usize {
+    files.partition_point(|f| icmp.compare(&Slice::from_bytes(&f.largest), key) == Ordering::Less)
+}
+
+/// Returns true iff some file in "files" overlaps the user key range
+/// [smallest_user_key, largest_user_key]. A missing smallest_user_key
+/// represents a key smaller than all keys, a missing largest_user_key a key
+/// larger than all keys.
+/// REQUIRES: If disjoint_sorted_files, files[] contains disjoint ranges
+///           in sorted order.
+pub fn some_file_overlaps_range(icmp: &InternalKeyComparator, disjoint_sorted_files: bool, files: &[Rc<FileMetaData>],
+                                smallest_user_key: Option<&Slice>, largest_user_key: Option<&Slice>) -> bool {
+    let ucmp = icmp.user_comparator();
+    let after_file = |user_key: Option<&Slice>, f: &FileMetaData| {
+        // A missing user_key occurs before all keys and is therefore never after "f"
+        user_key.is_some_and(|user_key| ucmp(user_key, &extract_user_key(&Slice::from_bytes(&f.largest))) == Ordering::Greater)
+    };
+    let before_file = |user_key: Option<&Slice>, f: &FileMetaData| {
+        // A missing user_key occurs after all keys and is therefore never before "f"
+        user_key.is_some_and(|user_key| ucmp(user_key, &extract_user_key(&Slice::from_bytes(&f.smallest))) == Ordering::Less)
+    };
+    if !disjoint_sorted_files {
+        // Need to check against all files
+        return files.iter().any(|f| !after_file(smallest_user_key, f) && !before_file(largest_user_key, f));
+    }
+
+    // Binary search over file list
+    let index = match smallest_user_key {
+        Some(smallest_user_key) => {
+            // Find the earliest possible internal key for smallest_user_key
+            let mut small_key = Vec::new();
+            append_internal_key(&mut small_key, smallest_user_key, K_MAX_SEQUENCE_NUMBER, kValueTypeForSeek);
+            find_file(icmp, files, &Slice::from_bytes(&small_key))
+        },
+        None => 0
+    };
+    match files.get(index) {
+        // beginning of range is after all files, so no overlap.
+        None => false,
+        Some(f) => !before_file(largest_user_key, f)
+    }
+}
+
+fn total_file_size(files: &[Rc<FileMetaData>]) -> u64 {
+    files.iter().map(|f| f.file_size).sum()
+}
+
+/// The set of table files of every level at some point in time.
+pub struct Version {
+
+    files: Vec<Vec<Rc<FileMetaData>>>,
+
+    // Next file to compact based on seek stats.
+    file_to_compact: RefCell<Option<(usize, Rc<FileMetaData>)>>,
+
+    // Level that should be compacted next and its compaction score.
+    // Score < 1 means compaction is not strictly needed.  These fields
+    // are initialized by finalize().
+    compaction_score: f64,
+
+    compaction_level: usize
+}
+
+impl Version {
+
+    fn new() -> Self {
+        Version {
+            files: vec![Vec::new(); K_NUM_LEVELS],
+            file_to_compact: RefCell::new(None),
+            compaction_score: -1.0,
+            compaction_level: 0
+        }
+    }
+
+    /// Return the files of the specified level, ordered by smallest key.
+    pub fn files(&self, level: usize) -> &[Rc<FileMetaData>] {
+        &self.files[level]
+    }
+
+    /// Return the files of "level" whose user key range overlaps
+    /// [begin, end]. A missing begin means before all keys, a missing end
+    /// means after all keys. Level-0 files may overlap each other, so the
+    /// range is grown to cover every level-0 file picked, and the files
+    /// overlapping the grown range are picked as well.
+    pub fn get_overlapping_inputs(&self, ucmp: fn(&Slice, &Slice) -> Ordering, level: usize,
+                                  begin: Option<&Slice>, end: Option<&Slice>) -> Vec<Rc<FileMetaData>> {
+        assert!(level < K_NUM_LEVELS);
+        let mut user_begin = begin.map(|begin| begin.data().to_vec());
+        let mut user_end = end.map(|end| end.data().to_vec());
+        let mut inputs = Vec::new();
+        let mut i = 0;
+        while i < self.files[level].len() {
+            let f = &self.files[level][i];
+            i += 1;
+            let file_start = extract_user_key(&Slice::from_bytes(&f.smallest));
+            let file_limit = extract_user_key(&Slice::from_bytes(&f.largest));
+            if user_begin.as_ref().is_some_and(|begin| ucmp(&file_limit, &Slice::from_bytes(begin)) == Ordering::Less) {
+                // "f" is completely before specified range; skip it
+            } else if user_end.as_ref().is_some_and(|end| ucmp(&file_start, &Slice::from_bytes(end)) == Ordering::Greater) {
+                // "f" is completely after specified range; skip it
+            } else {
+                inputs.push(f.clone());
+                if level == 0 {
+                    // Level-0 files may overlap each other.  So check if the newly
+                    // added file has expanded the range.  If so, restart search.
+                    if user_begin.as_ref().is_some_and(|begin| ucmp(&file_start, &Slice::from_bytes(begin)) == Ordering::Less) {
+                        user_begin = Some(file_start.data().to_vec());
+                        inputs.clear();
+                        i = 0;
+                    } else if user_end.as_ref().is_some_and(|end| ucmp(&file_limit, &Slice::from_bytes(end)) == Ordering::Greater) {
+                        user_end = Some(file_limit.data().to_vec());
+                        inputs.clear();
+                        i = 0;
+                    }
+                }
+            }
+        }
+        inputs
+    }
+
+    /// Returns true iff some file in "level" overlaps some part of
+    /// [smallest_user_key, largest_user_key]. A missing smallest_user_key
+    /// represents a key smaller than all the DB's keys, a missing
+    /// largest_user_key represents a key largest than all the DB's keys.
+    pub fn overlap_in_level(&self, ucmp: fn(&Slice, &Slice) -> Ordering, level: usize,
+                            smallest_user_key: Option<&Slice>, largest_user_key: Option<&Slice>) -> bool {
+        some_file_overlaps_range(&InternalKeyComparator::new(ucmp), level > 0, &self.files[level],
+                                 smallest_user_key, largest_user_key)
+    }
+
+    /// Lookup the value for `key` in the tables of this version. Level-0
+    /// files may overlap each other, so every one overlapping the key is
+    /// searched, newest first, before the higher levels. The files of the
+    /// other levels do not overlap, so a binary search finds the only one
+    /// that may hold the key. Returns NotFound if the newest entry of the
+    /// key is a deletion or there is none.
+    ///
+    /// When more than one file has to be read, the first one is charged a
+    /// seek, see update_stats.
+    pub fn get(&self, options: &ReadOptions, key: &LookupKey, table_cache: &TableCache,
+               ucmp: fn(&Slice, &Slice) -> Ordering) -> Result<Vec<u8>> {
+        let mut seek_file = None;
+        let result = self.get_charging(options, key, table_cache, ucmp, &mut seek_file);
+        if let Some((level, f)) = seek_file {
+            self.update_stats(level, f);
+        }
+        result
+    }
+
+    fn get_charging(&self, options: &ReadOptions, key: &LookupKey, table_cache: &TableCache,
+                    ucmp: fn(&Slice, &Slice) -> Ordering, seek_file: &mut Option<(usize, Rc<FileMetaData>)>) -> Result<Vec<u8>> {
+        let user_key = key.user_key();
+        let ikey = key.internal_key();
+        let mut last_file_read: Option<(usize, Rc<FileMetaData>)> = None;
+        // Tombstones of the files searched so far, which are newer than the
+        // entries of the files left
+        let mut range_del = RangeDelAggregator::new(ucmp, key.sequence());
+        for (level, f) in self.files_for_key(ucmp, &ikey) {
+            if seek_file.is_none() && last_file_read.is_some() {
+                // We have had more than one seek for this read.  Charge the 1st file.
+                *seek_file = last_file_read.take();
+            }
+            last_file_read = Some((level, f.clone()));
+
+            range_del.add(table_cache.range_tombstones(f.number, f.file_size)?);
+            let Some((found_key, value)) = table_cache.get(options, f.number, f.file_size, &ikey)? else {
+                continue;
+            };
+            let parsed = parse_internal_key(&Slice::from_bytes(&found_key))?;
+            if ucmp(&parsed.user_key, &user_key) == Ordering::Equal {
+                return match parsed.value_type {
+                    ValueType::KTypeValue if !range_del.should_delete(&user_key, parsed.sequence) => Ok(value),
+                    _ => Err(NotFound)
+                };
+            }
+        }
+        Err(NotFound)
+    }
+
+    /// Returns the files that may hold entries of the user key of
+    /// `internal_key`, with their levels, in the order they must be
+    /// searched: the level-0 files newest first, then at most one file of
+    /// every other level.
+    fn files_for_key(&self, ucmp: fn(&Slice, &Slice) -> Ordering, internal_key: &Slice) -> Vec<(usize, Rc<FileMetaData>)> {
+        let icmp = InternalKeyComparator::new(ucmp);
+        let user_key = extract_user_key(internal_key);
+        let mut level0: Vec<Rc<FileMetaData>> = self.files[0].iter()
+            .filter(|f| ucmp(&user_key, &extract_user_key(&Slice::from_bytes(&f.smallest))) != Ordering::Less
+                && ucmp(&user_key, &extract_user_key(&Slice::from_bytes(&f.largest))) != Ordering::Greater)
+            .cloned()
+            .collect();
+        level0.sort_by_key(|f| std::cmp::Reverse(f.number));
+        let mut files: Vec<(usize, Rc<FileMetaData>)> = level0.into_iter().map(|f| (0, f)).collect();
+        for level in 1..K_NUM_LEVELS {
+            let level_files = &self.files[level];
+            if let Some(f) = level_files.get(find_file(&icmp, level_files, internal_key)) {
+                // Unless all of "f" is past any data for user_key
+                if ucmp(&user_key, &extract_user_key(&Slice::from_bytes(&f.smallest))) != Ordering::Less {
+                    files.push((level, f.clone()));
+                }
+            }
+        }
+        files
+    }
+
+    /// Charge a seek to `f` of `level`. Once the file runs out of allowed
+    /// seeks, it becomes the next file to compact, unless another file
+    /// already is. Returns true if a new compaction may need to be
+    /// triggered.
+    pub fn update_stats(&self, level: usize, f: Rc<FileMetaData>) -> bool {
+        f.allowed_seeks.set(f.allowed_seeks.get() - 1);
+        let mut file_to_compact = self.file_to_compact.borrow_mut();
+        if f.allowed_seeks.get() <= 0 && file_to_compact.is_none() {
+            *file_to_compact = Some((level, f));
+            return true;
+        }
+        false
+    }
+
+    /// Record a sample of bytes read at the specified internal key, as
+    /// iterators do every so many bytes they read. Charges a seek to the
+    /// first file holding the key if more than one file does. Returns true
+    /// if a new compaction may need to be triggered.
+    pub fn record_read_sample(&self, ucmp: fn(&Slice, &Slice) -> Ordering, internal_key: &Slice) -> bool {
+        if parse_internal_key(internal_key).is_err() {
+            return false;
+        }
+        // Must have at least two matches since we want to merge across
+        // files. But what if we have a single file that contains many
+        // overwrites and deletions?  Should we have another mechanism for
+        // finding such files?
+        let mut files = self.files_for_key(ucmp, internal_key).into_iter();
+        match (files.next(), files.next()) {
+            (Some((level, f)), Some(_)) => self.update_stats(level, f),
+            _ => false
+        }
+    }
+
+    /// Return the file picked for compaction because it ran out of allowed
+    /// seeks, with its level.
+    pub fn file_to_compact(&self) -> Option<(usize, Rc<FileMetaData>)> {
+        self.file_to_compact.borrow().clone()
+    }
+
+    /// Append to `iters` an iterator over the contents of every table of
+    /// this version. Merged with the memtable iterators, they yield the
+    /// contents of the db.
+    pub fn add_iterators<'a>(&self, options: &ReadOptions, table_cache: &TableCache, iters: &mut Vec<Box<dyn InternalIterator + 'a>>) {
+        for files in &self.files {
+            for f in files {
+                iters.push(table_cache.iter(options, f.number, f.file_size));
+            }
+        }
+    }
+
+    /// Append the range tombstones of every table of this version to
+    /// `tombstones`. The tombstones of a table that cannot be read are left
+    /// out; iterators over that table report the error.
+    pub fn add_range_tombstones(&self, table_cache: &TableCache, tombstones: &mut Vec<RangeTombstone>) {
+        for files in &self.files {
+            for f in files {
+                if let Ok(table_tombstones) = table_cache.range_tombstones(f.number, f.file_size) {
+                    tombstones.extend(table_tombstones);
+                }
+            }
+        }
+    }
+
+    /// Return the approximate offset in the database of the data for the
+    /// internal key `ikey`, i.e. the combined size of the data before it.
+    pub fn approximate_offset_of(&self, ucmp: fn(&Slice, &Slice) -> Ordering, table_cache: &TableCache, ikey: &Slice) -> u64 {
+        let icmp = InternalKeyComparator::new(ucmp);
+        let mut result = 0;
+        for (level, files) in self.files.iter().enumerate() {
+            for f in files {
+                if icmp.compare(&Slice::from_bytes(&f.largest), ikey) != Ordering::Greater {
+                    // Entire file is before "ikey", so just add the file size
+                    result += f.file_size;
+                } else if icmp.compare(&Slice::from_bytes(&f.smallest), ikey) == Ordering::Greater {
+                    // Entire file is after "ikey", so ignore
+                    if level > 0 {
+                        // Files other than level 0 are sorted by smallest, so
+                        // no further files in this level will contain data
+                        // for "ikey".
+                        break;
+                    }
+                } else {
+                    // "ikey" falls in the range for this table. Add the
+                    // approximate offset of "ikey" within the table.
+                    result += table_cache.approximate_offset_of(f.number, f.file_size, ikey);
+                }
+            }
+        }
+        result
+    }
+
+    /// Return the files that were marked for compaction, with their levels.
+    pub fn files_marked_for_compaction(&self) -> Vec<(usize, Rc<FileMetaData>)> {
+        let mut marked = Vec::new();
+        for (level, files) in self.files.iter().enumerate() {
+            for f in files.iter().filter(|f| f.marked_for_compaction.get()) {
+                marked.push((level, f.clone()));
+            }
+        }
+        marked
+    }
+
+    /// Return the number of files at the specified level.
+    pub fn num_files(&self, level: usize) -> usize {
+        self.files[level].len()
+    }
+
+    /// Return the combined file size of all files at the specified level.
+    pub fn num_level_bytes(&self, level: usize) -> u64 {
+        total_file_size(&self.files[level])
+    }
+
+    /// Return the score of the level most in need of compaction. A score
+    /// of 1 or more means the level exceeds its limit.
+    pub fn compaction_score(&self) -> f64 {
+        self.compaction_score
+    }
+
+    /// Estimate the number of bytes compactions need to rewrite to bring
+    /// every level under its limit: the excess of each level over its
+    /// limit, plus level-0 as a whole once it must be compacted.
+    pub fn estimated_compaction_needed_bytes(&self) -> u64 {
+        let mut bytes = 0;
+        if self.num_files(0) >= K_L0_COMPACTION_TRIGGER {
+            bytes += self.num_level_bytes(0);
+        }
+        for level in 1..K_NUM_LEVELS - 1 {
+            bytes += self.num_level_bytes(level).saturating_sub(max_bytes_for_level(level) as u64);
+        }
+        bytes
+    }
+
+    /// Compute the best level for the next compaction.
+    fn finalize(&mut self) {
+        let mut best_level = 0;
+        let mut best_score = -1.0;
+        for level in 0..K_NUM_LEVELS - 1 {
+            let score = if level == 0 {
+                // We treat level-0 specially by bounding the number of files
+                // instead of number of bytes for two reasons:
+                //
+                // (1) With larger write-buffer sizes, it is nice not to do too
+                // many level-0 compactions.
+                //
+                // (2) The files in level-0 are merged on every read and
+                // therefore we wish to avoid too many files when the individual
+                // file size is small (perhaps because of a small write-buffer
+                // setting, or very high compression ratios, or lots of
+                // overwrites/deletions).
+                self.files[level].len() as f64 / K_L0_COMPACTION_TRIGGER as f64
+            } else {
+                // Compute the ratio of current size to size limit.
+                self.num_level_bytes(level) as f64 / max_bytes_for_level(level)
+            };
+            if score > best_score {
+                best_level = level;
+                best_score = score;
+            }
+        }
+        self.compaction_level = best_level;
+        self.compaction_score = best_score;
+    }
+
+    /// Return a human readable string that describes this version's contents.
+    pub fn debug_string(&self) -> String {
+        let mut r = String::new();
+        for (level, files) in self.files.iter().enumerate() {
+            // E.g.,
+            //   --- level 1 ---
+            //   17:123['a' .. 'd']
+            //   20:43['e' .. 'g']
+            let _ = writeln!(r, "--- level {} ---", level);
+            for f in files {
+                let _ = writeln!(r, " {}:{}['{}' .. '{}']", f.number, f.file_size,
+                                 escape_string(extract_user_key(&Slice::from_bytes(&f.smallest)).data()),
+                                 escape_string(extract_user_key(&Slice::from_bytes(&f.largest)).data()));
+            }
+        }
+        r
+    }
+
+    /// Verify the invariants every version must hold: each file's smallest
+    /// key is not after its largest, files above level 0 are sorted and do
+    /// not overlap, and no file appears twice. Returns Corruption on the
+    /// first violation found.
+    fn check_consistency(&self, icmp: &InternalKeyComparator) -> Result<()> {
+        let mut numbers = HashSet::new();
+        for (level, files) in self.files.iter().enumerate() {
+            for (i, f) in files.iter().enumerate() {
+                if !numbers.insert(f.number) {
+                    // "file N appears more than once"
+                    return Err(Corruption);
+                }
+                if icmp.compare(&Slice::from_bytes(&f.smallest), &Slice::from_bytes(&f.largest)) == Ordering::Greater {
+                    // "file N has smallest key after its largest key"
+                    return Err(Corruption);
+                }
+                if level > 0 && i > 0 {
+                    let prev = &files[i - 1];
+                    if icmp.compare(&Slice::from_bytes(&prev.largest), &Slice::from_bytes(&f.smallest)) != Ordering::Less {
+                        // "files N and M overlap at level L"
+                        return Err(Corruption);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Files added to and deleted from one level by the applied edits
+#[derive(Default)]
+struct LevelState {
+    deleted_files: HashSet<u64>,
+    added_files: HashMap<u64, Rc<FileMetaData>>
+}
+
+/// A helper so we can efficiently apply a whole sequence of edits to a
+/// particular version without creating intermediate versions that
+/// contain full copies of the intermediate state.
+struct Builder<'a> {
+
+    icmp: &'a InternalKeyComparator,
+
+    base: Rc<Version>,
+
+    levels: Vec<LevelState>,
+
+    // Latest compaction pointer of every level set by the edits
+    compact_pointers: Vec<Option<Vec<u8>>>
+}
+
+impl<'a> Builder<'a> {
+
+    fn new(icmp: &'a InternalKeyComparator, base: Rc<Version>) -> Self {
+        Builder {
+            icmp,
+            base,
+            levels: (0..K_NUM_LEVELS).map(|_| LevelState::default()).collect(),
+            compact_pointers: vec![None; K_NUM_LEVELS]
+        }
+    }
+
+    /// Apply all of the edits in *edit to the current state.
+    fn apply(&mut self, edit: &VersionEdit) {
+        for (level, key) in &edit.compact_pointers {
+            self.compact_pointers[*level] = Some(key.clone());
+        }
+        for &(level, number) in &edit.deleted_files {
+            self.levels[level].deleted_files.insert(number);
+            self.levels[level].added_files.remove(&number);
+        }
+        for (level, f) in &edit.new_files {
+            let f = Rc::new(f.clone());
+            // We arrange to automatically compact this file after
+            // a certain number of seeks.  Let's assume:
+            //   (1) One seek costs 10ms
+            //   (2) Writing or reading 1MB costs 10ms (100MB/s)
+            //   (3) A compaction of 1MB does 25MB of IO:
+            //         1MB read from this level
+            //         10-12MB read from next level (boundaries may be misaligned)
+            //         10-12MB written to next level
+            // This implies that 25 seeks cost the same as the compaction
+            // of 1MB of data.  I.e., one seek costs approximately the
+            // same as the compaction of 40KB of data.  We are a little
+            // conservative and allow approximately one seek for every 16KB
+            // of data before triggering a compaction.
+            f.allowed_seeks.set(std::cmp::max(100, (f.file_size / 16384) as i64));
+            self.levels[*level].deleted_files.remove(&f.number);
+            self.levels[*level].added_files.insert(f.number, f);
+        }
+    }
+
+    /// Returns the compaction pointers set by the applied edits, as
+    /// (level, key).
+    fn compact_pointers(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.compact_pointers.iter()
+            .enumerate()
+            .filter_map(|(level, key)| key.as_ref().map(|key| (level, key.as_slice())))
+    }
+
+    /// Save the current state in a new version, with the files of every
+    /// level sorted by their smallest key. Returns Corruption if the
+    /// version breaks the invariants checked by Version::check_consistency.
+    fn save_to(&self) -> Result<Version> {
+        let mut v = Version::new();
+        for (level, state) in self.levels.iter().enumerate() {
+            let files = &mut v.files[level];
+            files.extend(self.base.files[level].iter()
+                .filter(|f| !state.deleted_files.contains(&f.number) && !state.added_files.contains_key(&f.number))
+                .cloned());
+            files.extend(state.added_files.values().cloned());
+            files.sort_by(|a, b| {
+                self.icmp.compare(&Slice::from_bytes(&a.smallest), &Slice::from_bytes(&b.smallest))
+                    .then(a.number.cmp(&b.number))
+            });
+        }
+        v.check_consistency(self.icmp)?;
+        Ok(v)
+    }
+}
+
+/// Remembers that a corruption was reported while reading a log.
+pub(crate) struct CorruptionReporter {
+    pub(crate) corrupted: Rc<Cell<bool>>
+}
+
+impl Reporter for CorruptionReporter {
+    fn corruption(&mut self, _bytes: usize, _reason: &str) {
+        self.corrupted.set(true);
+    }
+}
 
 pub struct VersionSet {
 
-    dbname: String,
+    dbname: PathBuf,
+
+    options: Options,
+
+    icmp: InternalKeyComparator,
+
+    // Shared with the compaction jobs, which take the numbers of their
+    // output files from it off the db thread
+    next_file_number: Arc<AtomicU64>,
+
+    manifest_file_number: u64,
 
     last_sequence: u64,
 
+    log_number: u64,
+
+    // 0 or backing store for memtable being compacted
+    prev_log_number: u64,
+
+    // Format version of the current MANIFEST: the highest version the db
+    // has been written with
+    format_version: u32,
+
+    // Opened lazily
+    descriptor_log: Option<log_writer::Writer>,
+
+    descriptor_file: Option<Rc<RefCell<dyn WritableFile>>>,
+
+    current: Rc<Version>,
+
+    // Per-level key at which the next compaction at that level should start.
+    // Either an empty string, or a valid InternalKey.
+    compact_pointer: Vec<Vec<u8>>,
+
+    // Versions that were replaced as current, and that readers such as
+    // iterators may still hold. Their files stay live until the last
+    // reference is dropped.
+    old_versions: Vec<Weak<Version>>
 }
 
 impl VersionSet {
 
-    pub fn new(db_name: &str) -> Self {
+    pub fn new(db_name: impl AsRef<Path>, options: &Options) -> Self {
         VersionSet {
-            dbname: db_name.to_string(),
-            last_sequence: 0
+            dbname: db_name.as_ref().to_path_buf(),
+            options: options.clone(),
+            icmp: InternalKeyComparator::new(options.comparator),
+            next_file_number: Arc::new(AtomicU64::new(2)),
+            manifest_file_number: 0,  // Filled by recover()
+            last_sequence: 0,
+            log_number: 0,
+            prev_log_number: 0,
+            format_version: options.format_version,
+            descriptor_log: None,
+            descriptor_file: None,
+            current: Rc::new(Version::new()),
+            compact_pointer: vec![Vec::new(); K_NUM_LEVELS],
+            old_versions: Vec::new()
         }
     }
 
+    /// Allocate and return a new file number
+    pub fn new_file_number(&mut self) -> u64 {
+        self.next_file_number.fetch_add(1, AtomicOrdering::Relaxed)
+    }
+
+    /// Return the number the next call to new_file_number() will allocate.
+    pub fn next_file_number(&self) -> u64 {
+        self.next_file_number.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Mark the specified file number as used.
+    pub fn mark_file_number_used(&mut self, number: u64) {
+        self.next_file_number.fetch_max(number + 1, AtomicOrdering::Relaxed);
+    }
+
+    /// Return the counter file numbers are allocated from, for jobs that
+    /// create files on other threads.
+    pub fn file_numbers(&self) -> Arc<AtomicU64> {
+        self.next_file_number.clone()
+    }
+
     pub fn last_sequence(&self) -> u64 {
         self.last_sequence
     }
@@ -37,4 +663,1065 @@ impl VersionSet {
         assert!(s >= self.last_sequence);
         self.last_sequence = s;
     }
-}
\ No newline at end of file
+
+    /// Return the current log file number.
+    pub fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    /// Return the log file number of the log file that was being compacted
+    /// when the MANIFEST was written, or zero if there is no such log.
+    pub fn prev_log_number(&self) -> u64 {
+        self.prev_log_number
+    }
+
+    /// Return the current manifest file number
+    pub fn manifest_file_number(&self) -> u64 {
+        self.manifest_file_number
+    }
+
+    /// Return the number of Table files at the specified level.
+    pub fn num_level_files(&self, level: usize) -> usize {
+        assert!(level < K_NUM_LEVELS);
+        self.current.num_files(level)
+    }
+
+    /// Return the combined file size of all files at the specified level.
+    pub fn num_level_bytes(&self, level: usize) -> u64 {
+        assert!(level < K_NUM_LEVELS);
+        self.current.num_level_bytes(level)
+    }
+
+    /// Return a human-readable short (single-line) summary of the number
+    /// of files per level, e.g. "files[ 2 4 8 0 0 0 0 ]". Exposed as the
+    /// "revel.levelsummary" property, to follow the shape of the tree as
+    /// flushes and compactions go.
+    pub fn level_summary(&self) -> String {
+        let mut r = String::from("files[");
+        for level in 0..K_NUM_LEVELS {
+            let _ = write!(r, " {}", self.current.num_files(level));
+        }
+        r.push_str(" ]");
+        r
+    }
+
+    /// Return the current version.
+    pub fn current(&self) -> Rc<Version> {
+        self.current.clone()
+    }
+
+    /// Install `v` as the current version.
+    fn append_version(&mut self, mut v: Version) {
+        v.finalize();
+        let old = std::mem::replace(&mut self.current, Rc::new(v));
+        self.old_versions.retain(|version| version.strong_count() > 0);
+        self.old_versions.push(Rc::downgrade(&old));
+    }
+
+    /// Return the number of versions still in use: the current one and the
+    /// older ones pinned by readers.
+    pub fn num_live_versions(&self) -> usize {
+        1 + self.old_versions.iter().filter(|version| version.strong_count() > 0).count()
+    }
+
+    /// Add all files listed in any live version to *live. Obsolete file
+    /// deletion must spare them, as readers of older versions may still
+    /// access them.
+    pub fn add_live_files(&self, live: &mut HashSet<u64>) {
+        let versions = self.old_versions.iter()
+            .filter_map(Weak::upgrade)
+            .chain(std::iter::once(self.current.clone()));
+        for version in versions {
+            for files in &version.files {
+                live.extend(files.iter().map(|f| f.number));
+            }
+        }
+    }
+
+    /// Pick level and inputs for a new compaction.
+    /// Returns None if there is no compaction to be done.
+    ///
+    /// We prefer compactions triggered by too much data in a level over
+    /// the compactions triggered by seeks, and both over the files marked
+    /// by DB::suggest_compact_range. Level-0 files may overlap each other,
+    /// so the picked level-0 file is compacted with all the level-0 files
+    /// overlapping it, which keeps the newer entries of a key above the
+    /// older ones. `table_cache` gives access to the
+    /// table properties options.compaction_pri may need.
+    pub fn pick_compaction(&self, table_cache: &TableCache) -> Option<Compaction> {
+        let current = &self.current;
+        let (level, f) = if current.compaction_score >= 1.0 {
+            let level = current.compaction_level;
+            (level, self.pick_file(level, table_cache))
+        } else if let Some((level, f)) = current.file_to_compact.borrow().clone() {
+            (level, f)
+        } else {
+            current.files_marked_for_compaction().into_iter()
+                .find(|(level, _)| level + 1 < K_NUM_LEVELS)?
+        };
+        let inputs = if level == 0 {
+            // Files in level 0 may overlap each other, so pick up all overlapping ones
+            let (smallest, largest) = self.get_range(&[f]);
+            current.get_overlapping_inputs(self.options.comparator, 0,
+                                           Some(&extract_user_key(&Slice::from_bytes(&smallest))),
+                                           Some(&extract_user_key(&Slice::from_bytes(&largest))))
+        } else {
+            vec![f]
+        };
+        Some(self.setup_other_inputs(level, inputs))
+    }
+
+    /// Pick the file of `level` to compact next, as options.compaction_pri
+    /// says. REQUIRES: level has files
+    fn pick_file(&self, level: usize, table_cache: &TableCache) -> Rc<FileMetaData> {
+        let files = &self.current.files[level];
+        let f = match self.options.compaction_pri {
+            CompactionPri::RoundRobin => {
+                // Pick the first file that comes after compact_pointer[level]
+                let pointer = &self.compact_pointer[level];
+                files.iter()
+                    .find(|f| pointer.is_empty()
+                        || self.icmp.compare(&Slice::from_bytes(&f.largest), &Slice::from_bytes(pointer)) == Ordering::Greater)
+                    // Wrap-around to the beginning of the key space
+                    .or(files.first())
+            },
+            CompactionPri::SmallestFirst => files.iter().min_by_key(|f| f.file_size),
+            CompactionPri::OldestDataFirst => files.iter().min_by_key(|f| f.largest_seqno),
+            CompactionPri::MostTombstonesFirst => files.iter().min_by_key(|f| {
+                // A table that cannot be read is left to the other files
+                let num_deletions = table_cache.get_properties(f.number, f.file_size)
+                    .map_or(0, |properties| properties.num_deletions);
+                std::cmp::Reverse(num_deletions)
+            })
+        };
+        f.expect("no file to compact").clone()
+    }
+
+    /// Complete a compaction of `inputs` of `level` with the files of
+    /// level+1 they overlap. More files of `level` are added when they do
+    /// not pull in more files of level+1, as long as the compaction stays
+    /// under options.max_compaction_bytes.
+    fn setup_other_inputs(&self, level: usize, mut inputs: Vec<Rc<FileMetaData>>) -> Compaction {
+        let overlapping = |level: usize, smallest: &[u8], largest: &[u8]| {
+            self.current.get_overlapping_inputs(self.options.comparator, level,
+                                           Some(&extract_user_key(&Slice::from_bytes(smallest))),
+                                           Some(&extract_user_key(&Slice::from_bytes(largest))))
+        };
+        let (smallest, mut largest) = self.get_range(&inputs);
+        let mut parents = overlapping(level + 1, &smallest, &largest);
+
+        // Get entire range covered by compaction
+        let (mut all_start, mut all_limit) = self.get_range(&[inputs.as_slice(), &parents].concat());
+
+        // See if we can grow the number of inputs in "level" without
+        // changing the number of "level+1" files we pick up.
+        if !parents.is_empty() {
+            let expanded0 = overlapping(level, &all_start, &all_limit);
+            let inputs1_size = total_file_size(&parents);
+            let expanded0_size = total_file_size(&expanded0);
+            if expanded0.len() > inputs.len() && inputs1_size + expanded0_size < self.options.compaction_bytes_limit() {
+                let (new_start, new_limit) = self.get_range(&expanded0);
+                let expanded1 = overlapping(level + 1, &new_start, &new_limit);
+                if expanded1.len() == parents.len() {
+                    largest = new_limit;
+                    inputs = expanded0;
+                    parents = expanded1;
+                    (all_start, all_limit) = self.get_range(&[inputs.as_slice(), &parents].concat());
+                }
+            }
+        }
+
+        // Compute the set of grandparent files that overlap this compaction
+        // (parent == level+1; grandparent == level+2)
+        let grandparents = if level + 2 < K_NUM_LEVELS {
+            overlapping(level + 2, &all_start, &all_limit)
+        } else {
+            Vec::new()
+        };
+
+        let mut c = Compaction::new(level, self.current.clone(), [inputs, parents], grandparents,
+                                     self.options.target_file_size(level + 1));
+
+        // Update the place where we will do the next compaction for this
+        // level, once the compaction is installed.
+        c.edit().set_compact_pointer(level, &largest);
+        c
+    }
+
+    /// Return the smallest and largest internal keys of `inputs`.
+    /// REQUIRES: inputs is not empty
+    fn get_range(&self, inputs: &[Rc<FileMetaData>]) -> (Vec<u8>, Vec<u8>) {
+        assert!(!inputs.is_empty());
+        let mut smallest = &inputs[0].smallest;
+        let mut largest = &inputs[0].largest;
+        for f in &inputs[1..] {
+            if self.icmp.compare(&Slice::from_bytes(&f.smallest), &Slice::from_bytes(smallest)) == Ordering::Less {
+                smallest = &f.smallest;
+            }
+            if self.icmp.compare(&Slice::from_bytes(&f.largest), &Slice::from_bytes(largest)) == Ordering::Greater {
+                largest = &f.largest;
+            }
+        }
+        (smallest.clone(), largest.clone())
+    }
+
+    /// Apply *edit to the current version to form a new descriptor that
+    /// is both saved to persistent state and installed as the new
+    /// current version.
+    pub fn log_and_apply(&mut self, edit: &mut VersionEdit) -> Result<()> {
+        match edit.log_number {
+            Some(log_number) => {
+                assert!(log_number >= self.log_number);
+                assert!(log_number < self.next_file_number.load(AtomicOrdering::Relaxed));
+            },
+            None => edit.set_log_number(self.log_number)
+        }
+        if edit.prev_log_number.is_none() {
+            edit.set_prev_log_number(self.prev_log_number);
+        }
+        edit.set_next_file(self.next_file_number.load(AtomicOrdering::Relaxed));
+        edit.set_last_sequence(self.last_sequence);
+
+        // Refuse to install a version that breaks the invariants, which
+        // would otherwise make reads return wrong results
+        let v = {
+            let mut builder = Builder::new(&self.icmp, self.current.clone());
+            builder.apply(edit);
+            builder.save_to()?
+        };
+
+        // Initialize new descriptor log file if necessary by creating
+        // a temporary file that contains a snapshot of the current version.
+        let mut new_manifest_file = None;
+        if self.descriptor_log.is_none() {
+            let name = descriptor_file_name(&self.dbname, self.manifest_file_number);
+            let file = new_writable_file(&name, self.options.allow_mmap_writes)?;
+            self.descriptor_log = Some(log_writer::Writer::new(file.clone()));
+            self.descriptor_file = Some(file);
+            new_manifest_file = Some(name);
+            if let Err(e) = self.write_snapshot() {
+                self.drop_new_manifest(new_manifest_file);
+                return Err(e);
+            }
+        }
+
+        // Write new record to MANIFEST log
+        let mut record = Vec::new();
+        edit.encode_to(&mut record);
+        let mut result = self.descriptor_log.as_mut().unwrap().add_record(&Slice::from_bytes(&record));
+        if result.is_ok() {
+            result = self.descriptor_file.as_ref().unwrap().borrow().sync();
+        }
+
+        // If we just created a new descriptor file, install it by writing a
+        // new CURRENT file that points to it.
+        if result.is_ok() && new_manifest_file.is_some() {
+            result = set_current_file(&self.dbname, self.manifest_file_number);
+        }
+
+        if let Err(e) = result {
+            self.drop_new_manifest(new_manifest_file);
+            return Err(e);
+        }
+        if new_manifest_file.is_some() {
+            self.format_version = self.format_version.max(self.options.format_version);
+        }
+
+        // Install the new version
+        for (level, key) in &edit.compact_pointers {
+            self.compact_pointer[*level] = key.clone();
+        }
+        self.append_version(v);
+        self.log_number = edit.log_number.unwrap();
+        self.prev_log_number = edit.prev_log_number.unwrap();
+        Ok(())
+    }
+
+    /// Recover the last saved descriptor from persistent storage.
+    ///
+    /// Every record of the MANIFEST is checksummed. If a record turns out to
+    /// be corrupted, or the MANIFEST ends in a half written record, the
+    /// recovery either fails with Corruption when paranoid_checks is set, or
+    /// truncates the MANIFEST to the last valid edit before the damage and
+    /// recovers the state as of that edit.
+    pub fn recover(&mut self) -> Result<()> {
+        // Read "CURRENT" file, which contains a pointer to the current manifest file
+        let current = std::fs::read_to_string(current_file_name(&self.dbname))?;
+        if current.is_empty() || !current.ends_with('\n') {
+            return Err(Corruption);
+        }
+        let dscname = self.dbname.join(&current[..current.len() - 1]);
+        let file = match new_sequential_file(&dscname) {
+            Ok(file) => file,
+            // CURRENT points to a non-existent file
+            Err(_) => return Err(Corruption)
+        };
+
+        let file_size = std::fs::metadata(&dscname)?.len();
+        let mut records_applied = 0;
+
+        let corrupted = Rc::new(Cell::new(false));
+        let reporter = CorruptionReporter { corrupted: corrupted.clone() };
+        let mut reader = Reader::new(file, Some(Box::new(reporter)), true, 0);
+        let icmp = InternalKeyComparator::new(self.options.comparator);
+        let mut builder = Builder::new(&icmp, Rc::new(Version::new()));
+        let mut log_number = None;
+        let mut prev_log_number = None;
+        let mut next_file = None;
+        let mut last_sequence = None;
+        // Manifests written before the version was recorded are version 1
+        let mut format_version = 1;
+        let mut scratch = Vec::new();
+        while let Some(record) = reader.read_record(&mut scratch) {
+            if corrupted.get() {
+                // Edits past a damaged record cannot be trusted
+                break;
+            }
+            let edit = match VersionEdit::decode_from(&record) {
+                Ok(edit) => edit,
+                Err(_) => {
+                    corrupted.set(true);
+                    break;
+                }
+            };
+            if let Some(comparator) = &edit.comparator {
+                if comparator != self.options.comparator_name {
+                    // "<options.comparator_name> does not match existing
+                    // comparator <comparator>"
+                    return Err(InvalidArgument);
+                }
+            }
+            if let Some(version) = edit.format_version {
+                if version > K_LATEST_FORMAT_VERSION {
+                    // Written by a newer release in a format we do not understand
+                    return Err(NotSupport);
+                }
+                format_version = version;
+            }
+            builder.apply(&edit);
+            log_number = edit.log_number.or(log_number);
+            prev_log_number = edit.prev_log_number.or(prev_log_number);
+            next_file = edit.next_file_number.or(next_file);
+            last_sequence = edit.last_sequence.or(last_sequence);
+            records_applied += 1;
+            if let Some(callback) = self.options.recovery_progress.as_ref() {
+                callback(&RecoveryProgress {
+                    file_name: &dscname,
+                    file_size,
+                    bytes_processed: reader.position(),
+                    records_applied
+                });
+            }
+        }
+        let truncated = corrupted.get() || reader.truncated_tail();
+        if truncated && self.options.paranoid_checks {
+            return Err(Corruption);
+        }
+
+        // "no meta-nextfile entry", "no meta-lognumber entry" or
+        // "no last-sequence-number entry" in descriptor
+        let (Some(next_file), Some(log_number), Some(last_sequence)) = (next_file, log_number, last_sequence) else {
+            return Err(Corruption);
+        };
+        let prev_log_number = prev_log_number.unwrap_or(0);
+
+        self.mark_file_number_used(prev_log_number);
+        self.mark_file_number_used(log_number);
+
+        let v = builder.save_to()?;
+        for (level, key) in builder.compact_pointers() {
+            self.compact_pointer[level] = key.to_vec();
+        }
+        self.append_version(v);
+        self.manifest_file_number = next_file;
+        self.next_file_number.store(next_file + 1, AtomicOrdering::Relaxed);
+        self.last_sequence = last_sequence;
+        self.log_number = log_number;
+        self.prev_log_number = prev_log_number;
+        self.format_version = format_version;
+
+        if truncated {
+            self.write_recovered_manifest()?;
+        }
+        Ok(())
+    }
+
+    /// Start a fresh MANIFEST holding the version recovered from the
+    /// valid prefix of a damaged one, so the damaged tail is never
+    /// appended to. The damaged MANIFEST is left on disk.
+    fn write_recovered_manifest(&mut self) -> Result<()> {
+        // The lost edits may have allocated file numbers past the recovered
+        // next file number, so skip over every numbered file in the directory.
+        for entry in std::fs::read_dir(&self.dbname)? {
+            let name = entry?.file_name();
+            if let Some((number, _)) = name.to_str().and_then(parse_file_name) {
+                self.mark_file_number_used(number);
+            }
+        }
+        self.manifest_file_number = self.new_file_number();
+        self.log_and_apply(&mut VersionEdit::new())
+    }
+
+    /// Save current contents to the descriptor log
+    fn write_snapshot(&mut self) -> Result<()> {
+        let mut edit = VersionEdit::new();
+        edit.set_comparator_name(self.options.comparator_name);
+        // Tables written with a newer version than the options ask for may
+        // still be live, so the recorded version never goes down
+        edit.set_format_version(self.format_version.max(self.options.format_version));
+        // Save compaction pointers
+        for (level, key) in self.compact_pointer.iter().enumerate() {
+            if !key.is_empty() {
+                edit.set_compact_pointer(level, key);
+            }
+        }
+        // Save files
+        for level in 0..K_NUM_LEVELS {
+            for f in &self.current.files[level] {
+                edit.add_file(level, (**f).clone());
+            }
+        }
+        let mut record = Vec::new();
+        edit.encode_to(&mut record);
+        self.descriptor_log.as_mut().unwrap().add_record(&Slice::from_bytes(&record))
+    }
+
+    /// Forget a MANIFEST created by a failed log_and_apply and remove it.
+    fn drop_new_manifest(&mut self, name: Option<PathBuf>) {
+        if let Some(name) = name {
+            self.descriptor_log = None;
+            self.descriptor_file = None;
+            let _ = std::fs::remove_file(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::sync::{Arc, Mutex};
+    use crate::comparator::bytewise_compare;
+    use crate::dbformat::{append_internal_key, ValueType};
+    use crate::env::PosixWritableFile;
+    use crate::log_format::kHeaderSize;
+    use super::*;
+
+    fn db_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("revel_version_set_test_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn ikey(user_key: &str, seq: u64) -> Vec<u8> {
+        let mut key = Vec::new();
+        append_internal_key(&mut key, &Slice::from_str(user_key), seq, ValueType::KTypeValue);
+        key
+    }
+
+    /// Returns the sizes of MANIFEST-000001 after each of `n` edits adding
+    /// one level-1 file, numbered from 10 on.
+    fn write_manifest(dbname: &Path, n: u64) -> Vec<u64> {
+        write_manifest_with(dbname, n, &Options::default())
+    }
+
+    fn write_manifest_with(dbname: &Path, n: u64, options: &Options) -> Vec<u64> {
+        let mut vs = VersionSet::new(dbname, options);
+        vs.manifest_file_number = 1;
+        vs.mark_file_number_used(100);
+        let mut sizes = Vec::new();
+        for i in 0..n {
+            let mut edit = VersionEdit::new();
+            edit.add_file(1, FileMetaData {
+                number: 10 + i,
+                file_size: 1000,
+                smallest: ikey(&format!("{}a", i), 1),
+                largest: ikey(&format!("{}z", i), 1),
+                smallest_seqno: 1,
+                largest_seqno: 1,
+                ..FileMetaData::default()
+            });
+            vs.set_last_sequence(i + 1);
+            vs.log_and_apply(&mut edit).expect("log and apply failed");
+            sizes.push(std::fs::metadata(descriptor_file_name(dbname, 1)).unwrap().len());
+        }
+        sizes
+    }
+
+    fn recover(dbname: &Path, paranoid_checks: bool) -> Result<VersionSet> {
+        let options = Options { paranoid_checks, ..Options::default() };
+        let mut vs = VersionSet::new(dbname, &options);
+        vs.recover()?;
+        Ok(vs)
+    }
+
+    fn apply(vs: &VersionSet, edit: &VersionEdit) -> Version {
+        let mut builder = Builder::new(&vs.icmp, vs.current.clone());
+        builder.apply(edit);
+        builder.save_to().unwrap()
+    }
+
+    #[test]
+    fn test_builder() {
+        let icmp = InternalKeyComparator::new(bytewise_compare);
+        let mut edit = VersionEdit::default();
+        edit.add_file(1, file(1, "a", "b"));
+        edit.add_file(1, file(2, "c", "d"));
+        edit.add_file(2, file(3, "a", "z"));
+        let mut builder = Builder::new(&icmp, Rc::new(Version::new()));
+        builder.apply(&edit);
+        let base = Rc::new(builder.save_to().unwrap());
+
+        let mut builder = Builder::new(&icmp, base.clone());
+        let mut edit = VersionEdit::default();
+        edit.remove_file(1, 1);
+        edit.add_file(1, file(4, "e", "f"));
+        edit.set_compact_pointer(1, &ikey("b", 1));
+        builder.apply(&edit);
+        let mut edit = VersionEdit::default();
+        // Files added by an earlier edit can be deleted by a later one
+        edit.remove_file(1, 4);
+        edit.add_file(1, file(5, "0", "1"));
+        edit.add_file(2, FileMetaData { file_size: 2000, ..file(3, "a", "z") });
+        edit.set_compact_pointer(1, &ikey("d", 1));
+        builder.apply(&edit);
+        let v = builder.save_to().unwrap();
+        let numbers = |level: usize| v.files(level).iter().map(|f| f.number).collect::<Vec<_>>();
+        assert_eq!(vec![5, 2], numbers(1));
+        assert_eq!(vec![3], numbers(2));
+        assert_eq!(2000, v.files(2)[0].file_size);
+        assert_eq!(vec![(1, ikey("d", 1).as_slice())], builder.compact_pointers().collect::<Vec<_>>());
+        // The base version is left untouched
+        assert_eq!(2, base.num_files(1));
+
+        // Overlapping files above level-0 are refused
+        let mut builder = Builder::new(&icmp, base);
+        let mut edit = VersionEdit::default();
+        edit.add_file(1, file(6, "b", "c"));
+        builder.apply(&edit);
+        assert_eq!(Err(Corruption), builder.save_to().map(|_| ()));
+    }
+
+    #[test]
+    fn test_seek_compaction() {
+        let dbname = db_dir("seek_compaction");
+        let mut vs = VersionSet::new(&dbname, &Options::default());
+        let table_cache = TableCache::new(&dbname, &Options::default(), 10);
+        let mut edit = VersionEdit::default();
+        edit.add_file(1, file(1, "a", "m"));
+        edit.add_file(2, file(2, "k", "z"));
+        edit.add_file(3, FileMetaData { file_size: 16384 * 200, ..file(3, "n", "z") });
+        vs.append_version(apply(&vs, &edit));
+        assert!(vs.pick_compaction(&table_cache).is_none());
+
+        // Small files allow 100 seeks, larger ones one every 16KB
+        assert_eq!(100, vs.current.files(1)[0].allowed_seeks.get());
+        assert_eq!(200, vs.current.files(3)[0].allowed_seeks.get());
+
+        // Keys found in a single file are never charged
+        let sample = |key: &str| vs.current.record_read_sample(bytewise_compare, &Slice::from_bytes(&ikey(key, 100)));
+        assert!(!sample("b"));
+        assert_eq!(100, vs.current.files(1)[0].allowed_seeks.get());
+        for _ in 0..99 {
+            assert!(!sample("l"));
+        }
+        assert!(vs.current.file_to_compact().is_none());
+        assert!(sample("l"));
+        assert_eq!(Some((1, 1)), vs.current.file_to_compact().map(|(level, f)| (level, f.number)));
+        // Level-2 file 2 is not charged, as only the first file read is
+        assert_eq!(100, vs.current.files(2)[0].allowed_seeks.get());
+
+        let mut c = vs.pick_compaction(&table_cache).expect("no compaction picked");
+        assert_eq!(1, c.level());
+        assert_eq!(vec![1], c.inputs(0).iter().map(|f| f.number).collect::<Vec<_>>());
+        assert_eq!(vec![2], c.inputs(1).iter().map(|f| f.number).collect::<Vec<_>>());
+        assert_eq!(vec![(1, ikey("m", 1))], c.edit().compact_pointers);
+    }
+
+    #[test]
+    fn test_find_file() {
+        let icmp = InternalKeyComparator::new(bytewise_compare);
+        let find = |files: &[Rc<FileMetaData>], key: &str| find_file(&icmp, files, &Slice::from_bytes(&ikey(key, 100)));
+        assert_eq!(0, find(&[], "foo"));
+
+        let files = vec![Rc::new(file(1, "p", "q"))];
+        assert_eq!(0, find(&files, "a"));
+        assert_eq!(0, find(&files, "p"));
+        assert_eq!(0, find(&files, "q"));
+        assert_eq!(1, find(&files, "q1"));
+        assert_eq!(1, find(&files, "z"));
+
+        let files = vec![Rc::new(file(1, "150", "200")), Rc::new(file(2, "200", "250")),
+                         Rc::new(file(3, "300", "350")), Rc::new(file(4, "400", "450"))];
+        assert_eq!(0, find(&files, "100"));
+        assert_eq!(0, find(&files, "150"));
+        assert_eq!(2, find(&files, "251"));
+        assert_eq!(2, find(&files, "300"));
+        assert_eq!(3, find(&files, "400"));
+        assert_eq!(4, find(&files, "451"));
+        // Entries newer than a file's largest key come before it
+        assert_eq!(0, find(&files, "200"));
+        assert_eq!(1, find_file(&icmp, &files, &Slice::from_bytes(&ikey("200", 0))));
+    }
+
+    #[test]
+    fn test_overlapping_level0_inputs() {
+        let icmp = InternalKeyComparator::new(bytewise_compare);
+        let mut edit = VersionEdit::default();
+        edit.add_file(0, file(1, "a", "c"));
+        edit.add_file(0, file(2, "b", "e"));
+        edit.add_file(0, file(3, "d", "f"));
+        edit.add_file(0, file(4, "h", "i"));
+        let mut builder = Builder::new(&icmp, Rc::new(Version::new()));
+        builder.apply(&edit);
+        let v = builder.save_to().unwrap();
+        let numbers = |begin: &str, end: &str| -> Vec<u64> {
+            v.get_overlapping_inputs(bytewise_compare, 0, Some(&Slice::from_str(begin)), Some(&Slice::from_str(end)))
+                .iter().map(|f| f.number).collect()
+        };
+        // Picking "f" pulls in the files overlapping its range, and the
+        // files overlapping theirs
+        assert_eq!(vec![1, 2, 3], numbers("f", "f"));
+        assert_eq!(vec![1, 2, 3], numbers("a", "a"));
+        assert_eq!(vec![4], numbers("g", "h"));
+        assert!(numbers("g", "g").is_empty());
+    }
+
+    #[test]
+    fn test_some_file_overlaps_range() {
+        let icmp = InternalKeyComparator::new(bytewise_compare);
+        let overlaps = |files: &[Rc<FileMetaData>], disjoint: bool, smallest: Option<&str>, largest: Option<&str>| {
+            let smallest = smallest.map(Slice::from_str);
+            let largest = largest.map(Slice::from_str);
+            some_file_overlaps_range(&icmp, disjoint, files, smallest.as_ref(), largest.as_ref())
+        };
+        assert!(!overlaps(&[], true, Some("a"), Some("z")));
+        assert!(!overlaps(&[], true, None, None));
+
+        let files = vec![Rc::new(file(1, "150", "200")), Rc::new(file(2, "200", "250")),
+                         Rc::new(file(3, "300", "350")), Rc::new(file(4, "400", "450"))];
+        for disjoint in [true, false] {
+            assert!(!overlaps(&files, disjoint, Some("100"), Some("149")));
+            assert!(!overlaps(&files, disjoint, Some("251"), Some("299")));
+            assert!(!overlaps(&files, disjoint, Some("451"), Some("500")));
+            assert!(!overlaps(&files, disjoint, Some("351"), Some("399")));
+            assert!(!overlaps(&files, disjoint, None, Some("149")));
+            assert!(!overlaps(&files, disjoint, Some("451"), None));
+            assert!(overlaps(&files, disjoint, Some("100"), Some("150")));
+            assert!(overlaps(&files, disjoint, Some("200"), Some("200")));
+            assert!(overlaps(&files, disjoint, Some("190"), Some("310")));
+            assert!(overlaps(&files, disjoint, Some("450"), Some("500")));
+            assert!(overlaps(&files, disjoint, None, Some("150")));
+            assert!(overlaps(&files, disjoint, Some("450"), None));
+            assert!(overlaps(&files, disjoint, None, None));
+        }
+
+        // Level-0 files are checked one by one
+        let files = vec![Rc::new(file(1, "150", "600")), Rc::new(file(2, "400", "500"))];
+        assert!(overlaps(&files, false, Some("100"), Some("150")));
+        assert!(overlaps(&files, false, Some("450"), Some("450")));
+        assert!(!overlaps(&files, false, Some("601"), Some("700")));
+
+        let mut edit = VersionEdit::default();
+        edit.add_file(1, file(1, "b", "c"));
+        let mut builder = Builder::new(&icmp, Rc::new(Version::new()));
+        builder.apply(&edit);
+        let v = builder.save_to().unwrap();
+        assert!(v.overlap_in_level(bytewise_compare, 1, Some(&Slice::from_str("a")), Some(&Slice::from_str("b"))));
+        assert!(!v.overlap_in_level(bytewise_compare, 1, Some(&Slice::from_str("d")), None));
+        assert!(!v.overlap_in_level(bytewise_compare, 2, None, None));
+    }
+
+    #[test]
+    fn test_overlapping_files() {
+        let mut edit = VersionEdit::default();
+        edit.add_file(1, file(1, "b", "d"));
+        edit.add_file(1, file(2, "f", "h"));
+        let icmp = InternalKeyComparator::new(bytewise_compare);
+        let mut builder = Builder::new(&icmp, Rc::new(Version::new()));
+        builder.apply(&edit);
+        let v = builder.save_to().unwrap();
+        let numbers = |begin: Option<&str>, end: Option<&str>| -> Vec<u64> {
+            let begin = begin.map(Slice::from_str);
+            let end = end.map(Slice::from_str);
+            v.get_overlapping_inputs(bytewise_compare, 1, begin.as_ref(), end.as_ref())
+                .iter().map(|f| f.number).collect()
+        };
+        assert_eq!(vec![1, 2], numbers(None, None));
+        assert_eq!(vec![1], numbers(Some("a"), Some("b")));
+        assert_eq!(vec![1, 2], numbers(Some("d"), Some("f")));
+        assert!(numbers(Some("e"), Some("e")).is_empty());
+        assert_eq!(vec![2], numbers(Some("e"), None));
+        assert!(numbers(Some("i"), None).is_empty());
+        assert!(v.files_marked_for_compaction().is_empty());
+    }
+
+    #[test]
+    fn test_debug_string() {
+        let dbname = db_dir("debug_string");
+        write_manifest(&dbname, 2);
+        let vs = recover(&dbname, false).expect("recover failed");
+        let mut expected = String::from("--- level 0 ---\n--- level 1 ---\n");
+        expected.push_str(" 10:1000['0a' .. '0z']\n 11:1000['1a' .. '1z']\n");
+        for level in 2..K_NUM_LEVELS {
+            expected.push_str(&format!("--- level {} ---\n", level));
+        }
+        assert_eq!(expected, vs.current().debug_string());
+    }
+
+    #[test]
+    fn test_level_summary() {
+        let dbname = db_dir("level_summary");
+        let mut vs = VersionSet::new(&dbname, &Options::default());
+        assert_eq!("files[ 0 0 0 0 0 0 0 ]", vs.level_summary());
+        write_manifest(&dbname, 2);
+        vs.recover().expect("recover failed");
+        assert_eq!("files[ 0 2 0 0 0 0 0 ]", vs.level_summary());
+        assert_eq!((0, 0), (vs.num_level_files(0), vs.num_level_bytes(0)));
+        assert_eq!((2, 2000), (vs.num_level_files(1), vs.num_level_bytes(1)));
+    }
+
+    fn file(number: u64, smallest: &str, largest: &str) -> FileMetaData {
+        FileMetaData {
+            number,
+            file_size: 1000,
+            smallest: ikey(smallest, 1),
+            largest: ikey(largest, 1),
+            smallest_seqno: 1,
+            largest_seqno: 1,
+            ..FileMetaData::default()
+        }
+    }
+
+    #[test]
+    fn test_pick_compaction() {
+        let dbname = db_dir("pick_compaction");
+        let mut vs = VersionSet::new(&dbname, &Options::default());
+        let table_cache = TableCache::new(&dbname, &Options::default(), 10);
+        let mut edit = VersionEdit::default();
+        for i in 0..K_L0_COMPACTION_TRIGGER as u64 - 1 {
+            edit.add_file(0, file(10 + i, "c", "e"));
+        }
+        edit.add_file(1, file(1, "a", "b"));
+        edit.add_file(1, file(2, "d", "f"));
+        edit.add_file(1, file(3, "h", "i"));
+        vs.append_version(apply(&vs, &edit));
+        assert!(vs.pick_compaction(&table_cache).is_none());
+
+        // Every level-0 file is compacted, with the level-1 files overlapping
+        // the range of all of them
+        let mut edit = VersionEdit::default();
+        edit.add_file(0, file(20, "f", "g"));
+        vs.append_version(apply(&vs, &edit));
+        let c = vs.pick_compaction(&table_cache).expect("no compaction picked");
+        assert_eq!(0, c.level());
+        let mut inputs: Vec<u64> = c.inputs(0).iter().map(|f| f.number).collect();
+        inputs.sort();
+        assert_eq!(vec![10, 11, 12, 20], inputs);
+        assert_eq!(vec![2], c.inputs(1).iter().map(|f| f.number).collect::<Vec<_>>());
+        assert!(!c.is_trivial_move());
+
+        let mut edit = VersionEdit::new();
+        c.add_input_deletions(&mut edit);
+        assert_eq!(5, edit.deleted_files.len());
+        assert!(edit.deleted_files.contains(&(1, 2)));
+    }
+
+    #[test]
+    fn test_pick_compaction_by_size() {
+        let dbname = db_dir("pick_compaction_by_size");
+        let big = |number: u64, smallest: &str, largest: &str, size: u64, largest_seqno: u64| FileMetaData {
+            file_size: size << 20,
+            largest_seqno,
+            ..file(number, smallest, largest)
+        };
+        let pick = |compaction_pri: CompactionPri| -> Vec<u64> {
+            let options = Options { compaction_pri, ..Options::default() };
+            let mut vs = VersionSet::new(&dbname, &options);
+            let table_cache = TableCache::new(&dbname, &options, 10);
+            let mut edit = VersionEdit::default();
+            edit.add_file(1, big(1, "a", "b", 5, 9));
+            edit.add_file(1, big(2, "c", "d", 3, 7));
+            edit.add_file(1, big(3, "e", "f", 4, 3));
+            edit.add_file(2, file(4, "c", "c"));
+            edit.add_file(2, file(5, "d", "x"));
+            vs.append_version(apply(&vs, &edit));
+            vs.compact_pointer[1] = ikey("b", 1);
+            assert!(vs.current.compaction_score() >= 1.2);
+            let c = vs.pick_compaction(&table_cache).expect("no compaction picked");
+            assert_eq!(1, c.level());
+            c.inputs(0).iter().chain(c.inputs(1)).map(|f| f.number).collect()
+        };
+        // Level-1 holds 12MB, above its 10MB limit. File 3 joins file 2,
+        // as it only overlaps level-2 files file 2 already pulls in.
+        assert_eq!(vec![2, 3, 4, 5], pick(CompactionPri::RoundRobin));
+        assert_eq!(vec![2, 3, 4, 5], pick(CompactionPri::SmallestFirst));
+        // Adding file 2 to file 3 would pull in file 4 as well
+        assert_eq!(vec![3, 5], pick(CompactionPri::OldestDataFirst));
+    }
+
+    #[test]
+    fn test_expand_inputs() {
+        let dbname = db_dir("expand_inputs");
+        let mut vs = VersionSet::new(&dbname, &Options::default());
+        let table_cache = TableCache::new(&dbname, &Options::default(), 10);
+        let mut edit = VersionEdit::default();
+        edit.add_file(1, FileMetaData { file_size: 11 << 20, ..file(1, "a", "b") });
+        edit.add_file(1, file(2, "c", "d"));
+        edit.add_file(1, file(3, "m", "n"));
+        edit.add_file(2, file(4, "a", "d"));
+        vs.append_version(apply(&vs, &edit));
+
+        // Level-2 file 4 also overlaps file 2, which joins the compaction
+        let mut c = vs.pick_compaction(&table_cache).expect("no compaction picked");
+        assert_eq!(vec![1, 2], c.inputs(0).iter().map(|f| f.number).collect::<Vec<_>>());
+        assert_eq!(vec![4], c.inputs(1).iter().map(|f| f.number).collect::<Vec<_>>());
+        assert_eq!(vec![(1, ikey("d", 1))], c.edit().compact_pointers);
+
+        // Not when it would grow past max_compaction_bytes
+        let options = Options { max_compaction_bytes: 11 << 20, ..Options::default() };
+        let mut vs = VersionSet::new(&dbname, &options);
+        vs.append_version(apply(&vs, &edit));
+        let c = vs.pick_compaction(&table_cache).expect("no compaction picked");
+        assert_eq!(vec![1], c.inputs(0).iter().map(|f| f.number).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_trivial_move_over_grandparents() {
+        let dbname = db_dir("trivial_move");
+        let table_cache = TableCache::new(&dbname, &Options::default(), 10);
+        let pick = |grandparent_size: u64| {
+            let mut vs = VersionSet::new(&dbname, &Options::default());
+            let mut edit = VersionEdit::default();
+            edit.add_file(1, FileMetaData { file_size: 11 << 20, ..file(1, "a", "b") });
+            edit.add_file(3, FileMetaData { file_size: grandparent_size, ..file(2, "a", "z") });
+            vs.append_version(apply(&vs, &edit));
+            vs.pick_compaction(&table_cache).expect("no compaction picked")
+        };
+        assert!(pick(1 << 20).is_trivial_move());
+        // Moving the file would leave a level-2 file overlapping too much
+        // of level-3
+        assert!(!pick(100 << 20).is_trivial_move());
+    }
+
+    #[test]
+    fn test_pick_marked_files() {
+        let dbname = db_dir("pick_marked_files");
+        let mut vs = VersionSet::new(&dbname, &Options::default());
+        let table_cache = TableCache::new(&dbname, &Options::default(), 10);
+        let mut edit = VersionEdit::default();
+        edit.add_file(2, file(1, "a", "b"));
+        edit.add_file(K_NUM_LEVELS - 1, file(2, "a", "b"));
+        vs.append_version(apply(&vs, &edit));
+        assert!(vs.current.compaction_score() < 1.0);
+        assert!(vs.pick_compaction(&table_cache).is_none());
+
+        // The last level cannot be compacted any further
+        vs.current.files[K_NUM_LEVELS - 1][0].marked_for_compaction.set(true);
+        assert!(vs.pick_compaction(&table_cache).is_none());
+        vs.current.files[2][0].marked_for_compaction.set(true);
+        let c = vs.pick_compaction(&table_cache).expect("no compaction picked");
+        assert_eq!(2, c.level());
+        assert!(c.is_trivial_move());
+    }
+
+    #[test]
+    fn test_live_files_of_pinned_versions() {
+        let dbname = db_dir("live_files");
+        let mut vs = VersionSet::new(&dbname, &Options::default());
+        vs.manifest_file_number = 1;
+        vs.mark_file_number_used(100);
+        let mut edit = VersionEdit::new();
+        edit.add_file(1, file(10, "a", "c"));
+        edit.add_file(1, file(11, "d", "f"));
+        vs.log_and_apply(&mut edit).expect("log and apply failed");
+        assert_eq!(1, vs.num_live_versions());
+
+        // A reader holds the version while file 10 is compacted into 12
+        let pinned = vs.current();
+        let mut edit = VersionEdit::new();
+        edit.remove_file(1, 10);
+        edit.add_file(2, file(12, "a", "c"));
+        vs.log_and_apply(&mut edit).expect("log and apply failed");
+        assert_eq!(2, vs.num_live_versions());
+        let mut live = HashSet::new();
+        vs.add_live_files(&mut live);
+        assert_eq!(HashSet::from([10, 11, 12]), live);
+
+        drop(pinned);
+        assert_eq!(1, vs.num_live_versions());
+        let mut live = HashSet::new();
+        vs.add_live_files(&mut live);
+        assert_eq!(HashSet::from([11, 12]), live);
+
+        // Versions dropped by readers are forgotten as new ones are installed
+        let mut edit = VersionEdit::new();
+        edit.remove_file(1, 11);
+        vs.log_and_apply(&mut edit).expect("log and apply failed");
+        assert_eq!(1, vs.old_versions.len());
+    }
+
+    #[test]
+    fn test_consistency_check() {
+        let dbname = db_dir("consistency_check");
+        let mut vs = VersionSet::new(&dbname, &Options::default());
+        vs.manifest_file_number = 1;
+        vs.mark_file_number_used(100);
+        let mut edit = VersionEdit::new();
+        edit.add_file(1, file(10, "c", "e"));
+        // Level 0 files may overlap
+        edit.add_file(0, file(11, "a", "z"));
+        edit.add_file(0, file(12, "b", "y"));
+        vs.log_and_apply(&mut edit).expect("log and apply failed");
+
+        let bad_edits = [
+            // smallest after largest
+            (2, file(20, "z", "a")),
+            // overlaps file 10 at level 1
+            (1, file(21, "d", "f")),
+            // file 10 already lives at level 1
+            (2, file(10, "m", "n")),
+        ];
+        for (level, f) in bad_edits {
+            let mut edit = VersionEdit::new();
+            edit.add_file(level, f);
+            assert_eq!(Err(Corruption), vs.log_and_apply(&mut edit));
+            assert_eq!("files[ 2 1 0 0 0 0 0 ]", vs.level_summary());
+        }
+
+        // The rejected edits were never logged
+        drop(vs);
+        let vs = recover(&dbname, true).expect("recover failed");
+        assert_eq!("files[ 2 1 0 0 0 0 0 ]", vs.level_summary());
+
+        // A MANIFEST describing an inconsistent version is refused
+        let dbname = db_dir("consistency_check_recover");
+        write_manifest(&dbname, 1);
+        let mut edit = VersionEdit::new();
+        edit.add_file(1, file(50, "0b", "0c"));
+        edit.set_next_file(101);
+        let mut record = Vec::new();
+        edit.encode_to(&mut record);
+        let name = descriptor_file_name(&dbname, 1);
+        let file = OpenOptions::new().append(true).open(&name).unwrap();
+        let mut writer = log_writer::Writer::new(Rc::new(RefCell::new(PosixWritableFile::new(&name, file))));
+        writer.add_record(&Slice::from_bytes(&record)).unwrap();
+        assert_eq!(Err(Corruption), recover(&dbname, false).map(|_| ()));
+    }
+
+    #[test]
+    fn test_recover() {
+        let dbname = db_dir("recover");
+        write_manifest(&dbname, 3);
+        let vs = recover(&dbname, true).expect("recover failed");
+        assert_eq!(3, vs.current().num_files(1));
+        assert_eq!(3, vs.last_sequence());
+        assert_eq!(101, vs.manifest_file_number());
+    }
+
+    #[test]
+    fn test_recover_progress() {
+        let dbname = db_dir("recover_progress");
+        let sizes = write_manifest(&dbname, 3);
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let reported = progress.clone();
+        let options = Options {
+            recovery_progress: Some(Arc::new(move |p: &RecoveryProgress| {
+                assert_eq!(Some("MANIFEST-000001".as_ref()), p.file_name.file_name());
+                reported.lock().unwrap().push((p.records_applied, p.bytes_processed, p.file_size));
+            })),
+            ..Options::default()
+        };
+        let mut vs = VersionSet::new(&dbname, &options);
+        vs.recover().expect("recover failed");
+
+        // The first edit is preceded by a snapshot of the empty version
+        let file_size = sizes[2];
+        let progress = progress.lock().unwrap();
+        assert_eq!(4, progress.len());
+        for (i, (records, bytes, size)) in progress.iter().enumerate() {
+            assert_eq!(i as u64 + 1, *records);
+            assert_eq!(file_size, *size);
+            assert!(i == 0 || *bytes > progress[i - 1].1);
+        }
+        assert_eq!(sizes[1], progress[2].1);
+        assert_eq!(file_size, progress[3].1);
+    }
+
+    #[test]
+    fn test_recover_truncated_tail() {
+        let dbname = db_dir("truncated_tail");
+        let sizes = write_manifest(&dbname, 3);
+        let manifest = descriptor_file_name(&dbname, 1);
+        let data = std::fs::read(&manifest).unwrap();
+        std::fs::write(&manifest, &data[..sizes[2] as usize - 3]).unwrap();
+
+        assert_eq!(Err(Corruption), recover(&dbname, true).map(|_| ()));
+        // A log file written after the lost edit must not be reused
+        std::fs::write(dbname.join("000200.log"), "").unwrap();
+        let vs = recover(&dbname, false).expect("recover failed");
+        assert_eq!(2, vs.current().num_files(1));
+        assert_eq!(2, vs.last_sequence());
+        assert_eq!(201, vs.manifest_file_number());
+        drop(vs);
+
+        // The recovered state was written to a fresh MANIFEST right away,
+        // leaving the damaged one in place
+        assert_eq!("MANIFEST-000201\n", std::fs::read_to_string(current_file_name(&dbname)).unwrap());
+        assert_eq!(sizes[2] - 3, std::fs::metadata(&manifest).unwrap().len());
+        let vs = recover(&dbname, true).expect("recover failed");
+        assert_eq!(2, vs.current().num_files(1));
+        assert_eq!(2, vs.last_sequence());
+    }
+
+    #[test]
+    fn test_recover_corrupted_record() {
+        let dbname = db_dir("corrupted_record");
+        let sizes = write_manifest(&dbname, 4);
+        let manifest = descriptor_file_name(&dbname, 1);
+        let mut data = std::fs::read(&manifest).unwrap();
+        data[sizes[1] as usize + kHeaderSize] ^= 0x80;
+        std::fs::write(&manifest, &data).unwrap();
+
+        assert_eq!(Err(Corruption), recover(&dbname, true).map(|_| ()));
+        // Intact records after the damaged one are not applied either
+        let vs = recover(&dbname, false).expect("recover failed");
+        assert_eq!(2, vs.current().num_files(1));
+        assert_eq!(2, vs.last_sequence());
+    }
+
+    #[test]
+    fn test_recover_bad_current() {
+        let dbname = db_dir("bad_current");
+        write_manifest(&dbname, 1);
+        let current = current_file_name(&dbname);
+        std::fs::write(&current, "MANIFEST-000001").unwrap();
+        assert_eq!(Err(Corruption), recover(&dbname, false).map(|_| ()));
+        std::fs::write(&current, "MANIFEST-000009\n").unwrap();
+        assert_eq!(Err(Corruption), recover(&dbname, false).map(|_| ()));
+    }
+
+    #[test]
+    fn test_recover_format_version() {
+        let dbname = db_dir("format_version");
+        write_manifest(&dbname, 1);
+        let vs = recover(&dbname, false).expect("recover failed");
+        assert_eq!(Options::default().format_version, vs.format_version);
+
+        // Reopening with an older version keeps the recorded one
+        let latest = Options { format_version: K_LATEST_FORMAT_VERSION, ..Options::default() };
+        write_manifest_with(&dbname, 1, &latest);
+        let mut vs = recover(&dbname, false).expect("recover failed");
+        assert_eq!(K_LATEST_FORMAT_VERSION, vs.format_version);
+        vs.manifest_file_number = vs.new_file_number();
+        vs.log_and_apply(&mut VersionEdit::new()).expect("log and apply failed");
+        assert_eq!(K_LATEST_FORMAT_VERSION, vs.format_version);
+        let vs = recover(&dbname, false).expect("recover failed");
+        assert_eq!(K_LATEST_FORMAT_VERSION, vs.format_version);
+
+        // A MANIFEST from a newer release is refused
+        let newer = Options { format_version: K_LATEST_FORMAT_VERSION + 1, ..Options::default() };
+        write_manifest_with(&dbname, 1, &newer);
+        assert_eq!(Err(NotSupport), recover(&dbname, false).map(|_| ()));
+    }
+}
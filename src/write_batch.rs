@@ -10,9 +10,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::coding::{decode_fix32, decode_fixed64, encode_fixed32, encode_fixed64, get_length_prefixed_slice, put_length_prefixed_slice};
+use crate::coding::{decode_fix32, decode_fixed64, encode_fixed32, encode_fixed64, get_length_prefixed_slice, get_varint32, get_varint64, put_length_prefixed_slice, put_varint64};
 use crate::dbformat::{SequenceNumber, ValueType};
 use crate::memtable::MemTable;
+use crate::Error::{Corruption, InvalidArgument, NotSupport};
+use crate::Result;
 use crate::slice::Slice;
 
 const K_HEADER:usize = 12;
@@ -21,11 +23,86 @@ pub struct WriteBatch {
     rep: Vec<u8>
 }
 
+/// Id of the keyspace records without an explicit keyspace belong to.
+pub const DEFAULT_KEYSPACE_ID: u32 = 0;
+
+// Record tags that only appear inside write batches. A keyspace record
+// stores the varint32 keyspace id right after the tag, followed by the
+// same payload as its default keyspace counterpart.
+const K_TYPE_MERGE: u8 = 0x2;
+const K_TYPE_KEYSPACE_DELETION: u8 = 0x4;
+const K_TYPE_KEYSPACE_VALUE: u8 = 0x5;
+const K_TYPE_KEYSPACE_MERGE: u8 = 0x6;
+const K_TYPE_KEYSPACE_RANGE_DELETION: u8 = 0xE;
+
+// Markers bracketing the batches of a group committed atomically by
+// DB::write_group. They store the varint64 group id, carry no data and
+// are not included in the count of the batch.
+const K_TYPE_BEGIN_GROUP: u8 = 0x10;
+const K_TYPE_COMMIT_GROUP: u8 = 0x11;
+
+/// Callbacks invoked by WriteBatch::iterate for each record.
+///
+/// Only put and delete are required. Every other record kind has a default
+/// implementation, so new kinds can be added without breaking existing
+/// handlers: the keyspace aware `*_cf` methods forward records of the
+/// default keyspace to their plain counterparts and reject others, and the
+/// plain methods of optional kinds reject the record with NotSupport,
+/// which makes iterate fail instead of silently dropping data.
 pub trait Handler {
 
     fn put(&mut self, key: &Slice, value: &Slice);
 
     fn delete(&mut self, key: &Slice);
+
+    fn delete_range(&mut self, _begin_key: &Slice, _end_key: &Slice) -> Result<()> {
+        Err(NotSupport)
+    }
+
+    fn merge(&mut self, _key: &Slice, _value: &Slice) -> Result<()> {
+        Err(NotSupport)
+    }
+
+    /// Called for the marker opening the group `group_id`. Markers carry
+    /// no data, so they are ignored by default.
+    fn mark_begin_group(&mut self, _group_id: u64) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called for the marker committing the group `group_id`.
+    fn mark_commit_group(&mut self, _group_id: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn put_cf(&mut self, keyspace_id: u32, key: &Slice, value: &Slice) -> Result<()> {
+        if keyspace_id != DEFAULT_KEYSPACE_ID {
+            return Err(InvalidArgument);
+        }
+        self.put(key, value);
+        Ok(())
+    }
+
+    fn delete_cf(&mut self, keyspace_id: u32, key: &Slice) -> Result<()> {
+        if keyspace_id != DEFAULT_KEYSPACE_ID {
+            return Err(InvalidArgument);
+        }
+        self.delete(key);
+        Ok(())
+    }
+
+    fn delete_range_cf(&mut self, keyspace_id: u32, begin_key: &Slice, end_key: &Slice) -> Result<()> {
+        if keyspace_id != DEFAULT_KEYSPACE_ID {
+            return Err(InvalidArgument);
+        }
+        self.delete_range(begin_key, end_key)
+    }
+
+    fn merge_cf(&mut self, keyspace_id: u32, key: &Slice, value: &Slice) -> Result<()> {
+        if keyspace_id != DEFAULT_KEYSPACE_ID {
+            return Err(InvalidArgument);
+        }
+        self.merge(key, value)
+    }
 }
 
 impl WriteBatch {
@@ -36,6 +113,44 @@ impl WriteBatch {
         }
     }
 
+    /// Create an empty batch whose buffer can grow to `capacity` bytes,
+    /// header included, without reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut rep = Vec::with_capacity(std::cmp::max(capacity, K_HEADER));
+        rep.resize(K_HEADER, 0);
+        WriteBatch {
+            rep
+        }
+    }
+
+    /// Rebuild a batch from its serialized form as returned by data(), e.g.
+    /// after receiving it over the network. Only the header is checked
+    /// here; malformed records are reported by iterate() and by the write.
+    pub fn from_data(data: Vec<u8>) -> Result<Self> {
+        if data.len() < K_HEADER {
+            // malformed WriteBatch (too small)
+            return Err(Corruption);
+        }
+        Ok(WriteBatch {
+            rep: data
+        })
+    }
+
+    /// Reserve capacity for at least `additional` more bytes of records.
+    pub fn reserve(&mut self, additional: usize) {
+        self.rep.reserve(additional);
+    }
+
+    /// Returns the number of bytes the batch can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.rep.capacity()
+    }
+
+    /// Returns the serialized form of the batch, header included.
+    pub fn data(&self) -> &[u8] {
+        &self.rep
+    }
+
     pub fn clear(&mut self) {
         self.rep.clear();
         self.rep.resize(K_HEADER, 0);
@@ -54,6 +169,15 @@ impl WriteBatch {
         put_length_prefixed_slice(self.rep.as_mut(), key);
     }
 
+    /// Remove the database entries in the range ["begin_key", "end_key"),
+    /// i.e., including "begin_key" and excluding "end_key".
+    pub fn delete_range(&mut self, begin_key: &Slice, end_key: &Slice) {
+        set_count(self, count(self) + 1);
+        self.rep.push(ValueType::KTypeRangeDeletion as u8);
+        put_length_prefixed_slice(self.rep.as_mut(), begin_key);
+        put_length_prefixed_slice(self.rep.as_mut(), end_key);
+    }
+
     pub fn approximate_size(&self) -> usize {
         self.rep.len()
     }
@@ -76,68 +200,117 @@ impl WriteBatch {
         Slice::from_bytes(self.rep.as_slice())
     }
 
-    pub fn iterate(&self, handler: &mut dyn Handler) {
+    /// Calls the handler for every record of the batch, in order. Returns a
+    /// Corruption error if a record cannot be decoded or the number of
+    /// records does not match the count in the header.
+    pub fn iterate(&self, handler: &mut dyn Handler) -> Result<()> {
         let mut input = Slice::from_bytes(&self.rep);
+        if input.size() < K_HEADER {
+            // malformed WriteBatch (too small)
+            return Err(Corruption);
+        }
         input.remove_prefix(K_HEADER);
         let mut found = 0;
         while !input.empty() {
+            let mut tag = input.data()[0];
+            input.remove_prefix(1);
+            if tag == K_TYPE_BEGIN_GROUP || tag == K_TYPE_COMMIT_GROUP {
+                let group_id = next_varint64(&mut input)?;
+                if tag == K_TYPE_BEGIN_GROUP {
+                    handler.mark_begin_group(group_id)?;
+                } else {
+                    handler.mark_commit_group(group_id)?;
+                }
+                continue;
+            }
             found += 1;
-            let data = input.data();
-            let tag = data[0];
-            let mut offset = 1;
-            match ValueType::from(tag) {
-                ValueType::KTypeValue => {
-                    let mut len = 0;
-                    match get_length_prefixed_slice(&data[offset..]) {
-                        Ok((key, skip_len)) => {
-                            len += skip_len + key.size();
-                            match get_length_prefixed_slice(&data[offset + len..]) {
-                                Ok((value, skip_len)) => {
-                                    handler.put(&key, &value);
-                                    len += skip_len + value.size();
-                                },
-                                Err(_) => {
-
-                                }
-                            }
-                            input.remove_prefix(len + 1);
-                            offset += len;
-                        },
-                        Err(_) => {
-                            //
-                        }
-                    };
+            let mut keyspace_id = DEFAULT_KEYSPACE_ID;
+            if matches!(tag, K_TYPE_KEYSPACE_VALUE | K_TYPE_KEYSPACE_DELETION | K_TYPE_KEYSPACE_MERGE | K_TYPE_KEYSPACE_RANGE_DELETION) {
+                keyspace_id = next_varint32(&mut input)?;
+                tag = match tag {
+                    K_TYPE_KEYSPACE_VALUE => ValueType::KTypeValue as u8,
+                    K_TYPE_KEYSPACE_DELETION => ValueType::KTypeDeletion as u8,
+                    K_TYPE_KEYSPACE_MERGE => K_TYPE_MERGE,
+                    _ => ValueType::KTypeRangeDeletion as u8
+                };
+            }
+            match tag {
+                t if t == ValueType::KTypeValue as u8 => {
+                    let key = next_length_prefixed_slice(&mut input)?;
+                    let value = next_length_prefixed_slice(&mut input)?;
+                    handler.put_cf(keyspace_id, &key, &value)?;
                 },
-                ValueType::KTypeDeletion => {
-                    match get_length_prefixed_slice(input.data()) {
-                        Ok((key, skip_len)) => {
-                            handler.delete(&key)
-                        },
-                        Err(_) => {
-
-                        }
-                    }
+                t if t == ValueType::KTypeDeletion as u8 => {
+                    let key = next_length_prefixed_slice(&mut input)?;
+                    handler.delete_cf(keyspace_id, &key)?;
+                },
+                t if t == ValueType::KTypeRangeDeletion as u8 => {
+                    let begin_key = next_length_prefixed_slice(&mut input)?;
+                    let end_key = next_length_prefixed_slice(&mut input)?;
+                    handler.delete_range_cf(keyspace_id, &begin_key, &end_key)?;
+                },
+                K_TYPE_MERGE => {
+                    let key = next_length_prefixed_slice(&mut input)?;
+                    let value = next_length_prefixed_slice(&mut input)?;
+                    handler.merge_cf(keyspace_id, &key, &value)?;
+                },
+                _ => {
+                    // unknown WriteBatch tag, written by a newer release if
+                    // it is reserved for a record type
+                    return Err(ValueType::unknown_tag_error(tag));
                 }
             }
         }
         if found != count(self) {
-            //
+            // WriteBatch has wrong count
+            Err(Corruption)
         } else {
-
+            Ok(())
         }
     }
 }
 
+/// Decode a varint32 from the front of input and advance input past it.
+fn next_varint32(input: &mut Slice) -> Result<u32> {
+    match get_varint32(input.data(), 0, input.size()) {
+        Ok((value, len)) => {
+            input.remove_prefix(len);
+            Ok(value)
+        },
+        Err(_) => Err(Corruption)
+    }
+}
+
+/// Decode a varint64 from the front of input and advance input past it.
+fn next_varint64(input: &mut Slice) -> Result<u64> {
+    match get_varint64(input.data(), 0, input.size()) {
+        Ok((value, len)) => {
+            input.remove_prefix(len);
+            Ok(value)
+        },
+        Err(_) => Err(Corruption)
+    }
+}
+
+/// Decode a length prefixed slice from the front of input and advance input
+/// past it.
+fn next_length_prefixed_slice<'a>(input: &mut Slice<'a>) -> Result<Slice<'a>> {
+    let data = input.data();
+    let (result, skip_len) = get_length_prefixed_slice(data)?;
+    input.remove_prefix(skip_len + result.size());
+    Ok(result)
+}
+
 struct MemTableInserter<'a> {
 
     sequence: SequenceNumber,
 
-    mem: &'a mut MemTable
+    mem: &'a MemTable
 }
 
 impl <'a> MemTableInserter<'a> {
 
-    pub fn new(mem: &'a mut MemTable, sequence: SequenceNumber) -> Self {
+    pub fn new(mem: &'a MemTable, sequence: SequenceNumber) -> Self {
         MemTableInserter {
             mem,
             sequence
@@ -155,6 +328,12 @@ impl <'a> Handler for MemTableInserter<'a> {
         self.mem.add(self.sequence, ValueType::KTypeDeletion, key, &Slice::from_empty());
         self.sequence += 1;
     }
+
+    fn delete_range(&mut self, begin_key: &Slice, end_key: &Slice) -> Result<()> {
+        self.mem.add(self.sequence, ValueType::KTypeRangeDeletion, begin_key, end_key);
+        self.sequence += 1;
+        Ok(())
+    }
 }
 
 pub fn count(b: &WriteBatch) -> u32 {
@@ -166,7 +345,7 @@ pub fn set_count(b: &mut WriteBatch, n: u32) {
 }
 
 pub fn sequence(b: &WriteBatch) -> SequenceNumber {
-    decode_fixed64(&b.rep[8..], 0)
+    decode_fixed64(&b.rep, 0)
 }
 
 pub fn append(dst: &mut WriteBatch, src: &WriteBatch) {
@@ -175,9 +354,21 @@ pub fn append(dst: &mut WriteBatch, src: &WriteBatch) {
     dst.rep.extend_from_slice(&src.rep[K_HEADER..K_HEADER + length]);
 }
 
-pub fn insert_into(b: &WriteBatch, mem: &mut MemTable) {
+pub fn insert_into(b: &WriteBatch, mem: &MemTable) -> Result<()> {
     let mut inserter = MemTableInserter::new(mem, sequence(b));
-    b.iterate(&mut inserter);
+    b.iterate(&mut inserter)
+}
+
+/// Check, without inserting anything, that insert_into() would apply all
+/// the records of a batch handed in by the user. Group markers are only
+/// added by DB::write_group(), so they are refused too.
+pub fn check_insertable(b: &WriteBatch) -> Result<()> {
+    let mut markers = GroupMarkers::default();
+    b.iterate(&mut markers)?;
+    if markers.begin.is_some() || markers.commit.is_some() {
+        return Err(InvalidArgument);
+    }
+    Ok(())
 }
 
 pub fn set_contents(b: &mut WriteBatch, contents: &Slice) {
@@ -190,13 +381,444 @@ pub fn byte_size(batch: &WriteBatch) -> usize {
     batch.rep.len()
 }
 
+/// Append the marker opening the group `group_id` to the batch.
+pub fn mark_begin_group(b: &mut WriteBatch, group_id: u64) {
+    b.rep.push(K_TYPE_BEGIN_GROUP);
+    put_varint64(&mut b.rep, group_id);
+}
+
+/// Append the marker committing the group `group_id` to the batch.
+pub fn mark_commit_group(b: &mut WriteBatch, group_id: u64) {
+    b.rep.push(K_TYPE_COMMIT_GROUP);
+    put_varint64(&mut b.rep, group_id);
+}
+
+/// Collects the group markers of a batch. Like MemTableInserter it rejects
+/// merges and keyspaces other than the default one, so a batch accepted by
+/// GroupReplay::add() is sure to be inserted in full.
+#[derive(Default)]
+struct GroupMarkers {
+
+    begin: Option<u64>,
+
+    commit: Option<u64>
+}
+
+impl Handler for GroupMarkers {
+    fn put(&mut self, _key: &Slice, _value: &Slice) {}
+
+    fn delete(&mut self, _key: &Slice) {}
+
+    fn delete_range(&mut self, _begin_key: &Slice, _end_key: &Slice) -> Result<()> {
+        Ok(())
+    }
+
+    fn mark_begin_group(&mut self, group_id: u64) -> Result<()> {
+        if self.begin.replace(group_id).is_some() {
+            // several groups opened in one batch
+            return Err(Corruption);
+        }
+        Ok(())
+    }
+
+    fn mark_commit_group(&mut self, group_id: u64) -> Result<()> {
+        if self.commit.replace(group_id).is_some() {
+            // several groups committed in one batch
+            return Err(Corruption);
+        }
+        Ok(())
+    }
+}
+
+/// Holds back the batches of a group while the batches read from a log are
+/// replayed, so that recovery applies a group only once its commit marker
+/// has been read, and drops it if the log ends before that.
+///
+/// The batches of a group are logged back to back with consecutive
+/// sequence numbers. A batch that does not follow the open group, or opens
+/// another one, means the group was abandoned, e.g. by a write_group()
+/// that failed part way, so the group is dropped.
+#[derive(Default)]
+pub struct GroupReplay {
+
+    // Id and batches of the group being read, along with the sequence
+    // number its next batch starts at
+    pending: Option<(u64, SequenceNumber, Vec<WriteBatch>)>
+}
+
+impl GroupReplay {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed the next batch read from the log. Returns the batches that can
+    /// be applied now, in log order: the batch itself if it is outside of
+    /// any group, all the batches of a group once it is committed, and
+    /// nothing while a group is open. A batch that cannot be applied drops
+    /// the open group along with it.
+    pub fn add(&mut self, batch: WriteBatch) -> Result<Vec<WriteBatch>> {
+        let mut markers = GroupMarkers::default();
+        if let Err(e) = batch.iterate(&mut markers) {
+            self.abort();
+            return Err(e);
+        }
+        let next_sequence = sequence(&batch) + count(&batch) as u64;
+        if let Some((_, expected, _)) = &self.pending {
+            if markers.begin.is_some() || sequence(&batch) != *expected {
+                self.abort();
+            }
+        }
+        if let Some(group_id) = markers.begin {
+            self.pending = Some((group_id, next_sequence, Vec::new()));
+        }
+        match self.pending.take() {
+            None => {
+                if markers.commit.is_some() {
+                    // commit of a group that was never opened
+                    return Err(Corruption);
+                }
+                Ok(vec![batch])
+            },
+            Some((group_id, _, mut batches)) => {
+                batches.push(batch);
+                match markers.commit {
+                    None => {
+                        self.pending = Some((group_id, next_sequence, batches));
+                        Ok(Vec::new())
+                    },
+                    Some(id) if id == group_id => Ok(batches),
+                    Some(_) => {
+                        // commit of another group than the open one
+                        Err(Corruption)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Discard the batches of the open group, e.g. because a record of the
+    /// log was lost, which may have belonged to the group. Returns the
+    /// number of discarded batches.
+    pub fn abort(&mut self) -> usize {
+        self.pending.take().map_or(0, |(_, _, batches)| batches.len())
+    }
+
+    /// Finish the replay, discarding the batches of a group left open by a
+    /// crash before its commit. Returns the number of discarded batches.
+    pub fn finish(mut self) -> usize {
+        self.abort()
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
+    use crate::coding::put_varint32;
+    use crate::comparator::bytewise_compare;
+    use crate::dbformat::{InternalKeyComparator, LookupKey};
     use super::*;
 
+    #[derive(Default)]
+    struct Recorder {
+        ops: String
+    }
+
+    impl Handler for Recorder {
+        fn put(&mut self, key: &Slice, value: &Slice) {
+            self.ops.push_str(&format!("Put({}, {})", String::from_utf8_lossy(key.data()), String::from_utf8_lossy(value.data())));
+        }
+
+        fn delete(&mut self, key: &Slice) {
+            self.ops.push_str(&format!("Delete({})", String::from_utf8_lossy(key.data())));
+        }
+
+        fn delete_range(&mut self, begin_key: &Slice, end_key: &Slice) -> Result<()> {
+            self.ops.push_str(&format!("DeleteRange({}, {})", String::from_utf8_lossy(begin_key.data()), String::from_utf8_lossy(end_key.data())));
+            Ok(())
+        }
+    }
+
+    /// Only implements the required callbacks
+    #[derive(Default)]
+    struct PointRecorder {
+        ops: String
+    }
+
+    impl Handler for PointRecorder {
+        fn put(&mut self, key: &Slice, value: &Slice) {
+            self.ops.push_str(&format!("Put({}, {})", String::from_utf8_lossy(key.data()), String::from_utf8_lossy(value.data())));
+        }
+
+        fn delete(&mut self, key: &Slice) {
+            self.ops.push_str(&format!("Delete({})", String::from_utf8_lossy(key.data())));
+        }
+    }
+
+    /// Records the keyspace of every put
+    #[derive(Default)]
+    struct KeyspaceRecorder {
+        ops: String
+    }
+
+    impl Handler for KeyspaceRecorder {
+        fn put(&mut self, _key: &Slice, _value: &Slice) {
+            unreachable!("put_cf is overridden")
+        }
+
+        fn delete(&mut self, key: &Slice) {
+            self.ops.push_str(&format!("Delete({})", String::from_utf8_lossy(key.data())));
+        }
+
+        fn put_cf(&mut self, keyspace_id: u32, key: &Slice, value: &Slice) -> Result<()> {
+            self.ops.push_str(&format!("Put({}, {}, {})", keyspace_id, String::from_utf8_lossy(key.data()), String::from_utf8_lossy(value.data())));
+            Ok(())
+        }
+    }
+
+    fn push_record(batch: &mut WriteBatch, tag: u8, keyspace_id: Option<u32>, parts: &[&str]) {
+        set_count(batch, count(batch) + 1);
+        batch.rep.push(tag);
+        if let Some(id) = keyspace_id {
+            put_varint32(&mut batch.rep, id);
+        }
+        for part in parts {
+            put_length_prefixed_slice(&mut batch.rep, &Slice::from_str(part));
+        }
+    }
+
     #[test]
     fn test() {
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_group_markers() {
+        let mut batch = WriteBatch::new();
+        mark_begin_group(&mut batch, 300);
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        mark_commit_group(&mut batch, 300);
+        // Markers are skipped by handlers and not counted
+        assert_eq!(1, batch.count());
+        assert_eq!("Put(foo, bar)", print_contents(&batch));
+
+        let mut markers = GroupMarkers::default();
+        batch.iterate(&mut markers).unwrap();
+        assert_eq!(Some(300), markers.begin);
+        assert_eq!(Some(300), markers.commit);
+
+        // Truncated group id
+        let mut truncated = WriteBatch::new();
+        truncated.rep.push(K_TYPE_BEGIN_GROUP);
+        assert_eq!(Err(Corruption), truncated.iterate(&mut GroupMarkers::default()));
+    }
+
+    #[test]
+    fn test_group_replay() {
+        let batch = |key: &str, seq: u64, begin: Option<u64>, commit: Option<u64>| {
+            let mut batch = WriteBatch::new();
+            if let Some(id) = begin {
+                mark_begin_group(&mut batch, id);
+            }
+            batch.put(&Slice::from_str(key), &Slice::from_str("v"));
+            if let Some(id) = commit {
+                mark_commit_group(&mut batch, id);
+            }
+            batch.set_sequence(seq);
+            batch
+        };
+        let keys = |batches: Vec<WriteBatch>| batches.iter().map(print_contents).collect::<Vec<_>>().join("");
+
+        let mut replay = GroupReplay::new();
+        assert_eq!("Put(a, v)", keys(replay.add(batch("a", 1, None, None)).unwrap()));
+        assert_eq!("", keys(replay.add(batch("b", 2, Some(2), None)).unwrap()));
+        assert_eq!("", keys(replay.add(batch("c", 3, None, None)).unwrap()));
+        assert_eq!("Put(b, v)Put(c, v)Put(d, v)", keys(replay.add(batch("d", 4, None, Some(2))).unwrap()));
+        // A group of a single batch
+        assert_eq!("Put(e, v)", keys(replay.add(batch("e", 5, Some(5), Some(5))).unwrap()));
+        assert_eq!(0, replay.finish());
+
+        // The log ends before the commit
+        let mut replay = GroupReplay::new();
+        assert_eq!("", keys(replay.add(batch("a", 1, Some(1), None)).unwrap()));
+        assert_eq!("", keys(replay.add(batch("b", 2, None, None)).unwrap()));
+        assert_eq!(2, replay.finish());
+
+        // A batch that does not follow the group, as written after a
+        // write_group() that failed, ends the group without committing it
+        let mut replay = GroupReplay::new();
+        assert_eq!("", keys(replay.add(batch("a", 1, Some(1), None)).unwrap()));
+        assert_eq!("Put(b, v)", keys(replay.add(batch("b", 1, None, None)).unwrap()));
+        assert_eq!(Err(Corruption), replay.add(batch("c", 2, None, Some(1))).map(|_| ()));
+        assert_eq!(0, replay.finish());
+
+        // So does a batch opening another group
+        let mut replay = GroupReplay::new();
+        assert_eq!("", keys(replay.add(batch("a", 1, Some(1), None)).unwrap()));
+        assert_eq!("", keys(replay.add(batch("b", 2, Some(2), None)).unwrap()));
+        assert_eq!("Put(b, v)Put(c, v)", keys(replay.add(batch("c", 3, None, Some(2))).unwrap()));
+
+        // A batch that cannot be applied drops the open group
+        let mut replay = GroupReplay::new();
+        assert_eq!("", keys(replay.add(batch("a", 1, Some(1), None)).unwrap()));
+        let mut merge = batch("b", 2, None, None);
+        push_record(&mut merge, K_TYPE_MERGE, None, &["k", "v"]);
+        assert_eq!(Err(NotSupport), replay.add(merge).map(|_| ()));
+        assert_eq!(0, replay.abort());
+
+        let mut replay = GroupReplay::new();
+        replay.add(batch("a", 1, Some(1), None)).unwrap();
+        assert_eq!(1, replay.abort());
+        assert_eq!(Err(Corruption), replay.add(batch("a", 1, None, Some(1))).map(|_| ()));
+        let mut replay = GroupReplay::new();
+        replay.add(batch("a", 1, Some(1), None)).unwrap();
+        assert_eq!(Err(Corruption), replay.add(batch("b", 2, None, Some(2))).map(|_| ()));
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        batch.delete_range(&Slice::from_str("a"), &Slice::from_str("c"));
+        batch.put(&Slice::from_str("baz"), &Slice::from_str("boo"));
+        batch.set_sequence(100);
+        assert_eq!(3, batch.count());
+
+        let mut recorder = Recorder::default();
+        batch.iterate(&mut recorder).expect("iterate failed");
+        assert_eq!("Put(foo, bar)DeleteRange(a, c)Put(baz, boo)", recorder.ops);
+
+        // Round trip through the encoded contents, as the log does
+        let mut copy = WriteBatch::new();
+        set_contents(&mut copy, &batch.contents());
+        let mem = MemTable::new(InternalKeyComparator::new(bytewise_compare));
+        insert_into(&copy, &mem).expect("insert failed");
+        assert_eq!(101, mem.max_covering_tombstone_seq(&Slice::from_str("baz"), 102));
+        assert_eq!(0, mem.max_covering_tombstone_seq(&Slice::from_str("foo"), 102));
+        let result = mem.get(&LookupKey::new(&Slice::from_str("baz"), 102));
+        assert_eq!(b"boo".to_vec(), result.1.unwrap());
+    }
+
+    fn print_contents(b: &WriteBatch) -> String {
+        let mut recorder = Recorder::default();
+        match b.iterate(&mut recorder) {
+            Ok(()) => recorder.ops,
+            Err(_) => format!("{}ParseError()", recorder.ops)
+        }
+    }
+
+    #[test]
+    fn test_iterate() {
+        let mut batch = WriteBatch::new();
+        assert_eq!("", print_contents(&batch));
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        batch.delete(&Slice::from_str("box"));
+        batch.put(&Slice::from_str("baz"), &Slice::from_str("boo"));
+        assert_eq!("Put(foo, bar)Delete(box)Put(baz, boo)", print_contents(&batch));
+    }
+
+    #[test]
+    fn test_corruption() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        batch.delete(&Slice::from_str("box"));
+        let contents = batch.contents().data().to_vec();
+
+        // Truncated in the middle of the delete record
+        set_contents(&mut batch, &Slice::from_bytes(&contents[..contents.len() - 1]));
+        assert_eq!("Put(foo, bar)ParseError()", print_contents(&batch));
+
+        // Count in the header does not match the records
+        set_contents(&mut batch, &Slice::from_bytes(&contents));
+        set_count(&mut batch, 3);
+        assert_eq!("Put(foo, bar)Delete(box)ParseError()", print_contents(&batch));
+
+        // Unknown record tag
+        let mut unknown = contents.clone();
+        unknown.push(0x7f);
+        set_contents(&mut batch, &Slice::from_bytes(&unknown));
+        assert_eq!("Put(foo, bar)Delete(box)ParseError()", print_contents(&batch));
+
+        // Too small to hold the header
+        batch.rep = vec![0; K_HEADER - 1];
+        let mut recorder = Recorder::default();
+        assert_eq!(Err(Corruption), batch.iterate(&mut recorder));
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut batch = WriteBatch::with_capacity(1024);
+        assert!(batch.capacity() >= 1024);
+        assert_eq!(0, batch.count());
+        assert_eq!("", print_contents(&batch));
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        assert!(batch.capacity() >= 1024);
+
+        let mut small = WriteBatch::with_capacity(0);
+        assert_eq!(K_HEADER, small.data().len());
+        small.reserve(4096);
+        assert!(small.capacity() >= K_HEADER + 4096);
+    }
+
+    #[test]
+    fn test_data_round_trip() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        batch.delete(&Slice::from_str("box"));
+        batch.set_sequence(7);
+
+        let copy = WriteBatch::from_data(batch.data().to_vec()).expect("from data failed");
+        assert_eq!(batch.data(), copy.data());
+        assert_eq!(2, copy.count());
+        assert_eq!(7, sequence(&copy));
+        assert_eq!("Put(foo, bar)Delete(box)", print_contents(&copy));
+
+        assert_eq!(Err(Corruption), WriteBatch::from_data(vec![0; K_HEADER - 1]).map(|b| b.count()));
+    }
+
+    #[test]
+    fn test_handler_defaults() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        batch.delete(&Slice::from_str("box"));
+        let mut recorder = PointRecorder::default();
+        batch.iterate(&mut recorder).expect("iterate failed");
+        assert_eq!("Put(foo, bar)Delete(box)", recorder.ops);
+
+        // Optional record kinds are rejected unless the handler supports them
+        batch.delete_range(&Slice::from_str("a"), &Slice::from_str("b"));
+        let mut recorder = PointRecorder::default();
+        assert_eq!(Err(NotSupport), batch.iterate(&mut recorder));
+        assert_eq!("Put(foo, bar)Delete(box)", recorder.ops);
+
+        let mut merge = WriteBatch::new();
+        push_record(&mut merge, K_TYPE_MERGE, None, &["k", "+1"]);
+        assert_eq!(Err(NotSupport), merge.iterate(&mut PointRecorder::default()));
+        let mem = MemTable::new(InternalKeyComparator::new(bytewise_compare));
+        assert_eq!(Err(NotSupport), insert_into(&merge, &mem));
+    }
+
+    #[test]
+    fn test_keyspace_records() {
+        let mut batch = WriteBatch::new();
+        batch.put(&Slice::from_str("foo"), &Slice::from_str("bar"));
+        push_record(&mut batch, K_TYPE_KEYSPACE_VALUE, Some(0), &["k0", "v0"]);
+        push_record(&mut batch, K_TYPE_KEYSPACE_VALUE, Some(300), &["k1", "v1"]);
+        push_record(&mut batch, K_TYPE_KEYSPACE_DELETION, Some(0), &["k2"]);
+
+        let mut recorder = KeyspaceRecorder::default();
+        batch.iterate(&mut recorder).expect("iterate failed");
+        assert_eq!("Put(0, foo, bar)Put(0, k0, v0)Put(300, k1, v1)Delete(k2)", recorder.ops);
+
+        // Handlers unaware of keyspaces only accept the default keyspace
+        let mut recorder = PointRecorder::default();
+        assert_eq!(Err(InvalidArgument), batch.iterate(&mut recorder));
+        assert_eq!("Put(foo, bar)Put(k0, v0)", recorder.ops);
+
+        // Keyspace id cut short
+        let mut truncated = WriteBatch::new();
+        set_count(&mut truncated, 1);
+        truncated.rep.extend_from_slice(&[K_TYPE_KEYSPACE_VALUE, 0x80]);
+        assert_eq!(Err(Corruption), truncated.iterate(&mut KeyspaceRecorder::default()));
+    }
+}
@@ -0,0 +1,394 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A WriteBatchWithIndex is a WriteBatch that also keeps its updates in a
+//! searchable index, so that they can be read back before the batch is
+//! written: alone with get_from_batch(), or on top of the contents of a DB
+//! with get_from_batch_and_db() and new_iterator_with_base().
+//!
+//! The index is a memtable in which every update is stored under its
+//! position in the batch, counted from 1, in place of a sequence number.
+//! Reads through a DB shift the positions past the sequence number they
+//! read at, so that the updates of the batch are newer than anything they
+//! see in the DB.
+
+use std::cmp::Ordering;
+use crate::db::DB;
+use crate::db_iter::DBIterator;
+use crate::dbformat::{append_internal_key, parse_internal_key, InternalKeyComparator, LookupKey, SequenceNumber, ValueType, K_MAX_SEQUENCE_NUMBER};
+use crate::iterator::InternalIterator;
+use crate::memtable::{MemTable, MemTableIterator};
+use crate::options::ReadOptions;
+use crate::Result;
+use crate::slice::Slice;
+use crate::write_batch::WriteBatch;
+
+pub struct WriteBatchWithIndex {
+
+    batch: WriteBatch,
+
+    // Updates of the batch keyed by their position in it
+    index: MemTable,
+
+    comparator: fn(a: &Slice, b: &Slice) -> Ordering,
+
+    // Number of updates in the batch
+    count: SequenceNumber
+}
+
+impl WriteBatchWithIndex {
+
+    /// Create an empty batch. `comparator` must be the comparator of the
+    /// DB the batch is read with and written to.
+    pub fn new(comparator: fn(a: &Slice, b: &Slice) -> Ordering) -> Self {
+        WriteBatchWithIndex {
+            batch: WriteBatch::new(),
+            index: MemTable::new(InternalKeyComparator::new(comparator)),
+            comparator,
+            count: 0
+        }
+    }
+
+    pub fn put(&mut self, key: &Slice, value: &Slice) {
+        self.batch.put(key, value);
+        self.add(ValueType::KTypeValue, key, value);
+    }
+
+    pub fn delete(&mut self, key: &Slice) {
+        self.batch.delete(key);
+        self.add(ValueType::KTypeDeletion, key, &Slice::from_str(""));
+    }
+
+    /// Remove the entries in the range ["begin_key", "end_key").
+    pub fn delete_range(&mut self, begin_key: &Slice, end_key: &Slice) {
+        self.batch.delete_range(begin_key, end_key);
+        self.add(ValueType::KTypeRangeDeletion, begin_key, end_key);
+    }
+
+    fn add(&mut self, value_type: ValueType, key: &Slice, value: &Slice) {
+        self.count += 1;
+        self.index.add(self.count, value_type, key, value);
+    }
+
+    /// Clear all updates buffered in this batch.
+    pub fn clear(&mut self) {
+        self.batch.clear();
+        self.index = MemTable::new(InternalKeyComparator::new(self.comparator));
+        self.count = 0;
+    }
+
+    /// Returns the number of updates in the batch.
+    pub fn count(&self) -> u32 {
+        self.batch.count()
+    }
+
+    /// Returns the batch of updates, as written by DB::write().
+    pub fn write_batch(&self) -> &WriteBatch {
+        &self.batch
+    }
+
+    /// Returns the batch of updates, dropping the index.
+    pub fn into_write_batch(self) -> WriteBatch {
+        self.batch
+    }
+
+    /// Looks `key` up in the batch only. Fails with NotFound if the batch
+    /// does not hold a value for the key, whether it deletes the key or
+    /// does not update it at all.
+    pub fn get_from_batch(&self, key: &Slice) -> Result<Vec<u8>> {
+        self.index.get(&LookupKey::new(key, K_MAX_SEQUENCE_NUMBER)).1
+    }
+
+    /// Looks `key` up in the batch, and in `db` if the batch does not
+    /// update it, i.e. returns what a get() would once the batch is
+    /// written. Only the lookup in the db uses `options`.
+    pub fn get_from_batch_and_db(&self, db: &DB, options: &ReadOptions, key: &Slice) -> Result<Vec<u8>> {
+        match self.index.get(&LookupKey::new(key, K_MAX_SEQUENCE_NUMBER)) {
+            (true, result) => result,
+            (false, _) => db.get(options, key)
+        }
+    }
+
+    /// Returns an iterator over the contents of `db` as of now, or as of
+    /// options.snapshot, with the updates of the batch applied on top. Like
+    /// DB::new_iterator(), the iterator starts unpositioned.
+    pub fn new_iterator_with_base<'a>(&'a self, db: &'a DB, options: &ReadOptions) -> DBIterator<'a> {
+        db.new_iterator_with_batch(options, &self.index, self.count)
+    }
+}
+
+/// Iterates over the index of a WriteBatchWithIndex, adding `base` to the
+/// sequence numbers of its entries.
+pub(crate) struct SequenceOffsetIterator<'a> {
+
+    iter: MemTableIterator<'a>,
+
+    base: SequenceNumber,
+
+    // Key of the current entry, with its sequence number shifted
+    key: Vec<u8>
+}
+
+impl<'a> SequenceOffsetIterator<'a> {
+
+    pub fn new(iter: MemTableIterator<'a>, base: SequenceNumber) -> Self {
+        SequenceOffsetIterator { iter, base, key: Vec::new() }
+    }
+
+    fn shift_key(&mut self) {
+        self.key.clear();
+        if !self.iter.valid() {
+            return;
+        }
+        let key = self.iter.key();
+        // Keys of the index are always well formed
+        let ikey = parse_internal_key(&key).expect("bad key in batch index");
+        append_internal_key(&mut self.key, &ikey.user_key, ikey.sequence + self.base, ikey.value_type);
+    }
+}
+
+impl<'a> InternalIterator for SequenceOffsetIterator<'a> {
+
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first();
+        self.shift_key();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+        self.shift_key();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        match parse_internal_key(target) {
+            Ok(ikey) => {
+                // Every entry of the index is newer than a target at or
+                // below base, which then sorts after all of them
+                let (sequence, value_type) = match ikey.sequence.checked_sub(self.base) {
+                    Some(sequence) => (sequence, ikey.value_type),
+                    None => (0, ValueType::KTypeDeletion)
+                };
+                let mut shifted = Vec::new();
+                append_internal_key(&mut shifted, &ikey.user_key, sequence, value_type);
+                self.iter.seek(&Slice::from_bytes(&shifted));
+            },
+            Err(_) => self.iter.seek(target)
+        }
+        self.shift_key();
+    }
+
+    fn next(&mut self) {
+        self.iter.next();
+        self.shift_key();
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev();
+        self.shift_key();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        assert!(self.valid());
+        Slice::from_bytes(&self.key)
+    }
+
+    fn value(&self) -> Slice<'_> {
+        self.iter.value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+}
+
+/// Hides the entries of `iter` newer than `sequence`. Entries whose key
+/// cannot be parsed are kept, so that readers see the corruption.
+pub(crate) struct SnapshotIterator<'a> {
+
+    iter: Box<dyn InternalIterator + 'a>,
+
+    sequence: SequenceNumber
+}
+
+impl<'a> SnapshotIterator<'a> {
+
+    pub fn new(iter: Box<dyn InternalIterator + 'a>, sequence: SequenceNumber) -> Self {
+        SnapshotIterator { iter, sequence }
+    }
+
+    fn hidden(&self) -> bool {
+        parse_internal_key(&self.iter.key()).is_ok_and(|ikey| ikey.sequence > self.sequence)
+    }
+
+    fn skip_forward(&mut self) {
+        while self.iter.valid() && self.hidden() {
+            self.iter.next();
+        }
+    }
+
+    fn skip_backward(&mut self) {
+        while self.iter.valid() && self.hidden() {
+            self.iter.prev();
+        }
+    }
+}
+
+impl<'a> InternalIterator for SnapshotIterator<'a> {
+
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.iter.seek_to_first();
+        self.skip_forward();
+    }
+
+    fn seek_to_last(&mut self) {
+        self.iter.seek_to_last();
+        self.skip_backward();
+    }
+
+    fn seek(&mut self, target: &Slice) {
+        self.iter.seek(target);
+        self.skip_forward();
+    }
+
+    fn next(&mut self) {
+        self.iter.next();
+        self.skip_forward();
+    }
+
+    fn prev(&mut self) {
+        self.iter.prev();
+        self.skip_backward();
+    }
+
+    fn key(&self) -> Slice<'_> {
+        self.iter.key()
+    }
+
+    fn value(&self) -> Slice<'_> {
+        self.iter.value()
+    }
+
+    fn status(&self) -> Result<()> {
+        self.iter.status()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::comparator::bytewise_compare;
+    use crate::Error::NotFound;
+    use crate::options::{Options, WriteOptions};
+    use super::*;
+
+    fn db_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("revel_write_batch_with_index_test_{}", name));
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    fn entries(iter: &mut DBIterator) -> Vec<String> {
+        let mut result = Vec::new();
+        iter.seek_to_first();
+        while iter.valid() {
+            result.push(format!("{}->{}", String::from_utf8_lossy(iter.key().data()), String::from_utf8_lossy(iter.value().data())));
+            iter.next();
+        }
+        result
+    }
+
+    fn reverse_entries(iter: &mut DBIterator) -> Vec<String> {
+        let mut result = Vec::new();
+        iter.seek_to_last();
+        while iter.valid() {
+            result.push(format!("{}->{}", String::from_utf8_lossy(iter.key().data()), String::from_utf8_lossy(iter.value().data())));
+            iter.prev();
+        }
+        result.reverse();
+        result
+    }
+
+    #[test]
+    fn test_get_from_batch() {
+        let mut batch = WriteBatchWithIndex::new(bytewise_compare);
+        batch.put(&Slice::from_str("a"), &Slice::from_str("1"));
+        batch.put(&Slice::from_str("b"), &Slice::from_str("2"));
+        batch.put(&Slice::from_str("a"), &Slice::from_str("3"));
+        batch.delete(&Slice::from_str("b"));
+        batch.put(&Slice::from_str("d"), &Slice::from_str("4"));
+        batch.delete_range(&Slice::from_str("c"), &Slice::from_str("e"));
+        batch.put(&Slice::from_str("c"), &Slice::from_str("5"));
+        assert_eq!(7, batch.count());
+        assert_eq!(Ok(b"3".to_vec()), batch.get_from_batch(&Slice::from_str("a")));
+        assert_eq!(Err(NotFound), batch.get_from_batch(&Slice::from_str("b")));
+        assert_eq!(Ok(b"5".to_vec()), batch.get_from_batch(&Slice::from_str("c")));
+        assert_eq!(Err(NotFound), batch.get_from_batch(&Slice::from_str("d")));
+        assert_eq!(Err(NotFound), batch.get_from_batch(&Slice::from_str("x")));
+
+        batch.clear();
+        assert_eq!(0, batch.count());
+        assert_eq!(Err(NotFound), batch.get_from_batch(&Slice::from_str("a")));
+    }
+
+    #[test]
+    fn test_read_with_db() {
+        let mut db = DB::open(&Options::default(), db_path("read_with_db")).unwrap();
+        for (key, value) in [("a", "db"), ("b", "db"), ("c", "db"), ("d", "db"), ("f", "db")] {
+            db.put(&WriteOptions::default(), &Slice::from_str(key), &Slice::from_str(value)).unwrap();
+        }
+        let snapshot = db.get_snapshot();
+        db.put(&WriteOptions::default(), &Slice::from_str("g"), &Slice::from_str("db")).unwrap();
+
+        let mut batch = WriteBatchWithIndex::new(bytewise_compare);
+        batch.put(&Slice::from_str("b"), &Slice::from_str("batch"));
+        batch.delete(&Slice::from_str("c"));
+        batch.delete_range(&Slice::from_str("d"), &Slice::from_str("f"));
+        batch.put(&Slice::from_str("e"), &Slice::from_str("batch"));
+        let get = |key: &str| batch.get_from_batch_and_db(&db, &ReadOptions::default(), &Slice::from_str(key));
+        assert_eq!(Ok(b"db".to_vec()), get("a"));
+        assert_eq!(Ok(b"batch".to_vec()), get("b"));
+        assert_eq!(Err(NotFound), get("c"));
+        assert_eq!(Err(NotFound), get("d"));
+        assert_eq!(Ok(b"batch".to_vec()), get("e"));
+        assert_eq!(Ok(b"db".to_vec()), get("f"));
+
+        let expected = vec!["a->db", "b->batch", "e->batch", "f->db", "g->db"];
+        let mut iter = batch.new_iterator_with_base(&db, &ReadOptions::default());
+        assert_eq!(expected, entries(&mut iter));
+        assert_eq!(expected, reverse_entries(&mut iter));
+        iter.seek(&Slice::from_str("c"));
+        assert_eq!(b"e", iter.key().data());
+        iter.prev();
+        assert_eq!(b"b", iter.key().data());
+        drop(iter);
+
+        // Entries written after the snapshot stay hidden, the batch does not
+        let at_snapshot = ReadOptions { snapshot: Some(&snapshot), ..ReadOptions::default() };
+        let mut iter = batch.new_iterator_with_base(&db, &at_snapshot);
+        assert_eq!(vec!["a->db", "b->batch", "e->batch", "f->db"], entries(&mut iter));
+        assert_eq!(vec!["a->db", "b->batch", "e->batch", "f->db"], reverse_entries(&mut iter));
+        drop(iter);
+
+        // Once written, the db reads the same as the batch did
+        db.write(&WriteOptions::default(), batch.into_write_batch()).unwrap();
+        let mut iter = db.new_iterator(&ReadOptions::default());
+        assert_eq!(expected, entries(&mut iter));
+    }
+}
@@ -0,0 +1,204 @@
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A WriteBufferManager bounds the memory used by the memtables of one or
+//! more dbs. Each db sharing the manager registers its memtables and keeps
+//! the manager informed of their size, counting an immutable memtable until
+//! its flush is done. Once the combined size exceeds the budget, any db
+//! writing to a non-empty memtable is asked to flush it, so the budget
+//! holds even if the db holding the most memory is idle.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub struct WriteBufferManager {
+
+    buffer_size: usize,
+
+    state: Mutex<State>
+}
+
+struct State {
+
+    last_id: u64,
+
+    // Memory used by the memtables of every registered db
+    usage: HashMap<u64, MemberUsage>
+}
+
+#[derive(Clone, Copy, Default)]
+struct MemberUsage {
+
+    // Memtable taking the writes
+    mutable: usize,
+
+    // Memtable being flushed
+    immutable: usize
+}
+
+impl State {
+
+    fn total(&self) -> usize {
+        self.usage.values().map(|usage| usage.mutable + usage.immutable).sum()
+    }
+}
+
+impl WriteBufferManager {
+
+    /// Create a manager limiting the combined memtable size of the dbs
+    /// sharing it to `buffer_size` bytes. 0 disables the limit, leaving
+    /// the manager to only track the memory used.
+    pub fn new(buffer_size: usize) -> Self {
+        WriteBufferManager {
+            buffer_size,
+            state: Mutex::new(State {
+                last_id: 0,
+                usage: HashMap::new()
+            })
+        }
+    }
+
+    /// Return the budget the manager was created with.
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Return the combined memory used by the memtables of the registered
+    /// dbs, including the ones being flushed.
+    pub fn memory_usage(&self) -> usize {
+        self.state.lock().unwrap().total()
+    }
+
+    /// Register the memtables of a db. Their memory is accounted until the
+    /// returned member is dropped.
+    pub fn register(self: &Arc<Self>) -> WriteBufferMember {
+        let mut state = self.state.lock().unwrap();
+        state.last_id += 1;
+        let id = state.last_id;
+        state.usage.insert(id, MemberUsage::default());
+        WriteBufferMember {
+            manager: self.clone(),
+            id
+        }
+    }
+}
+
+/// The registration of a db with a WriteBufferManager.
+pub struct WriteBufferMember {
+
+    manager: Arc<WriteBufferManager>,
+
+    id: u64
+}
+
+impl WriteBufferMember {
+
+    fn update(&self, f: impl FnOnce(&mut MemberUsage)) {
+        let mut state = self.manager.state.lock().unwrap();
+        f(state.usage.get_mut(&self.id).expect("registered member"));
+    }
+
+    /// Record the current memory usage of the memtable taking the writes.
+    pub fn set_memory_usage(&self, usage: usize) {
+        self.update(|member| member.mutable = usage);
+    }
+
+    /// Record that the memtable became immutable: its memory stays
+    /// accounted until free_immutable(), while the new memtable starts
+    /// empty.
+    pub fn mark_immutable(&self) {
+        self.update(|member| {
+            member.immutable += member.mutable;
+            member.mutable = 0;
+        });
+    }
+
+    /// Record that the immutable memtable was flushed and dropped.
+    pub fn free_immutable(&self) {
+        self.update(|member| member.immutable = 0);
+    }
+
+    /// Returns true if the budget is exceeded and the memtable of this db
+    /// holds data, which a flush would free.
+    pub fn should_flush(&self) -> bool {
+        if self.manager.buffer_size == 0 {
+            return false;
+        }
+        let state = self.manager.state.lock().unwrap();
+        state.total() > self.manager.buffer_size && state.usage[&self.id].mutable > 0
+    }
+}
+
+impl Drop for WriteBufferMember {
+    fn drop(&mut self) {
+        self.manager.state.lock().unwrap().usage.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_any_writer_flushes() {
+        let manager = Arc::new(WriteBufferManager::new(1000));
+        let a = manager.register();
+        let b = manager.register();
+        let c = manager.register();
+        a.set_memory_usage(300);
+        b.set_memory_usage(500);
+        c.set_memory_usage(200);
+        assert_eq!(1000, manager.memory_usage());
+        assert!(!a.should_flush() && !b.should_flush() && !c.should_flush());
+
+        // Not only the largest memtable is asked to flush, as its db may
+        // be idle
+        c.set_memory_usage(201);
+        assert!(a.should_flush() && b.should_flush() && c.should_flush());
+
+        // An empty memtable has nothing to free
+        a.set_memory_usage(0);
+        b.set_memory_usage(900);
+        assert!(!a.should_flush());
+        assert!(b.should_flush() && c.should_flush());
+
+        // Dropped members no longer count
+        drop(b);
+        assert_eq!(201, manager.memory_usage());
+        assert!(!c.should_flush());
+    }
+
+    #[test]
+    fn test_immutable_memtables_count() {
+        let manager = Arc::new(WriteBufferManager::new(1000));
+        let a = manager.register();
+        a.set_memory_usage(800);
+        a.mark_immutable();
+        assert_eq!(800, manager.memory_usage());
+        a.set_memory_usage(300);
+        assert_eq!(1100, manager.memory_usage());
+        assert!(a.should_flush());
+
+        a.free_immutable();
+        assert_eq!(300, manager.memory_usage());
+        assert!(!a.should_flush());
+    }
+
+    #[test]
+    fn test_disabled_limit() {
+        let manager = Arc::new(WriteBufferManager::new(0));
+        let a = manager.register();
+        a.set_memory_usage(1 << 30);
+        assert_eq!(1 << 30, manager.memory_usage());
+        assert!(!a.should_flush());
+    }
+}